@@ -1,6 +1,8 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{
+    CudaDevice, CudaFunction, CudaSlice, DeviceRepr, DeviceSlice, LaunchConfig,
+};
 
 use luminal::{
     op::*,
@@ -9,16 +11,20 @@ use luminal::{
 use rustc_hash::FxHashMap;
 
 use crate::{
-    compile_and_load_kernel, constant, get_buffer_from_tensor, get_idx_valid_exps, input_dyn_dims,
+    alloc, alloc_zeros, compile_and_load_kernel, constant, cuda_device, cuda_op_marker, download,
+    elementwise_launch_config, get_buffer_from_tensor, get_idx_valid_exps, grid_size, index_type,
+    input_dyn_dims,
     other::CudaARange,
-    prim::{CudaAdd, CudaLessThan, CudaMul, CudaSumReduce},
-    render_dyn_dim_inputs, CudaData, CudaFloat,
+    prim::{CudaAdd, CudaConstant, CudaExp2, CudaLessThan, CudaLog2, CudaMul, CudaSumReduce},
+    render_dyn_dim_inputs, upload, CudaCompileError, CudaData, CudaFloat, CudaInt,
+    LaunchOnDeviceStream, DEFAULT_BLOCK_SIZE,
 };
 
 #[derive(LuminalEqTrue, LuminalPrint, Clone)]
 pub struct CudaSub<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
@@ -30,29 +36,31 @@ impl<T: CudaFloat> CudaSub<T> {
         b_shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel) {{
         out[idx] =
             (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}])
             - (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 
@@ -62,7 +70,7 @@ impl<T: CudaFloat> Operator for CudaSub<T> {
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -73,20 +81,38 @@ impl<T: CudaFloat> Operator for CudaSub<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
 #[derive(LuminalPrint, Default)]
-pub struct CudaSubtractionCompiler<T: CudaFloat>(PhantomData<T>);
+pub struct CudaSubtractionCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> CudaSubtractionCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
 
 impl<T: CudaFloat> Compiler for CudaSubtractionCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = cuda_device(self.0);
         let (lhs, rhs) = (node(), node());
         let mul = binary::<CudaMul<T>>(rhs.clone(), constant::<T>(-1.));
         let add = binary::<CudaAdd<T>>(lhs.clone(), mul.clone());
@@ -124,12 +150,7 @@ impl<T: CudaFloat> Compiler for CudaSubtractionCompiler<T> {
                 continue;
             }
             let sub = graph
-                .add_op(CudaSub::<T>::new(
-                    a_edge.2,
-                    b_edge.2,
-                    dev.clone(),
-                    &graph.dyn_map,
-                ))
+                .add_op(CudaSub::<T>::new(a_edge.2, b_edge.2, dev.clone(), &graph.dyn_map).unwrap())
                 .input(a, a_edge.1, a_edge.2)
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
@@ -145,6 +166,7 @@ impl<T: CudaFloat> Compiler for CudaSubtractionCompiler<T> {
 pub struct CudaEqual<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
@@ -156,29 +178,31 @@ impl<T: CudaFloat> CudaEqual<T> {
         b_shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel) {{
         {type_name} a_val = ({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}];
         {type_name} b_val = ({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}];
         out[idx] = ({type_name})(a_val == b_val);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 
@@ -188,7 +212,7 @@ impl<T: CudaFloat> Operator for CudaEqual<T> {
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -199,20 +223,38 @@ impl<T: CudaFloat> Operator for CudaEqual<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
 #[derive(LuminalPrint, Default)]
-pub struct CudaEqualCompiler<T: CudaFloat>(PhantomData<T>);
+pub struct CudaEqualCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> CudaEqualCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
 
 impl<T: CudaFloat> Compiler for CudaEqualCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = cuda_device(self.0);
         let one = constant::<T>(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<CudaLessThan<T>>(lhs.clone(), rhs.clone());
@@ -246,12 +288,9 @@ impl<T: CudaFloat> Compiler for CudaEqualCompiler<T> {
                 .as_data()
                 .unwrap();
             let equals = graph
-                .add_op(CudaEqual::<T>::new(
-                    a_edge.2,
-                    b_edge.2,
-                    dev.clone(),
-                    &graph.dyn_map,
-                ))
+                .add_op(
+                    CudaEqual::<T>::new(a_edge.2, b_edge.2, dev.clone(), &graph.dyn_map).unwrap(),
+                )
                 .input(lhs, a_edge.1, a_edge.2)
                 .input(rhs, b_edge.1, b_edge.2)
                 .finish();
@@ -263,62 +302,245 @@ impl<T: CudaFloat> Compiler for CudaEqualCompiler<T> {
     }
 }
 
-#[derive(LuminalPrint, Clone, LuminalEqFalse)]
-pub struct CudaGather<T> {
+/// Raise each element of the first input to the power of the second. Unlike `exp2(log2(a) * b)`, this is exact
+/// for negative bases with integer exponents.
+#[derive(LuminalEqTrue, LuminalPrint, Clone)]
+pub struct CudaPow<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
-    pub embed_dim: usize,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
 }
 
-impl<T: CudaFloat> CudaGather<T> {
-    pub fn new(device: Arc<CudaDevice>, embed_dim: usize) -> Self {
-        let type_name = T::type_name();
-        let code = format!("
+impl<T: CudaFloat> CudaPow<T> {
+    pub fn new(
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
+        let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        // Half types are raised in their accumulator type
+        let code = format!(
+            "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *weights, const float *inp, int n_embeddings, int embedding_dim) {{
-    int x = blockIdx.x * blockDim.x + threadIdx.x;
-    int y = blockIdx.y * blockDim.y + threadIdx.y;
-    if (x < n_embeddings && y < embedding_dim) {{
-        out[x * embedding_dim + y] = weights[(int)inp[x] * embedding_dim + y];
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {acc} base = ({a_valid}) == 0 ? ({acc})0.0 : ({acc})inp_a[{a_idx}];
+        {acc} exponent = ({b_valid}) == 0 ? ({acc})0.0 : ({acc})inp_b[{b_idx}];
+        out[idx] = ({type_name})pow(base, exponent);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
-            embed_dim,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
-        }
+            dyn_symbols,
+            dyn_map,
+        })
     }
 }
 
-impl<T: CudaFloat> Operator for CudaGather<T> {
-    fn process(&mut self, inputs: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = inputs[0]
-            .0
-            .borrowed()
-            .data
-            .as_any()
-            .downcast_ref::<Vec<f32>>()
-            .unwrap();
-        let weights = get_buffer_from_tensor::<T>(&inputs[1].0);
+impl<T: CudaFloat> Operator for CudaPow<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
 
-        let mut indexes_buffer = unsafe { self.device.alloc::<f32>(indexes.len()).unwrap() };
-        self.device
-            .htod_copy_into(indexes.clone(), &mut indexes_buffer)
-            .unwrap();
-        let mut out = self
-            .device
-            .alloc_zeros::<T>(indexes.len() * self.embed_dim)
-            .unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            a.as_kernel_param(),
+            b.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
         unsafe {
             self.function
                 .clone()
-                .launch(
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace `exp2(log2(a) * b)`, the primitive form of `a` to the power of `b`, with `CudaPow`
+#[derive(LuminalPrint, Default)]
+pub struct PowCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> PowCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for PowCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        let (base, exponent) = (node(), node());
+        let log = unary::<CudaLog2<T>>(base.clone());
+        let mul = binary::<CudaMul<T>>(log.clone(), exponent.clone());
+        let exp = unary::<CudaExp2<T>>(mul.clone());
+        let mut s = exp.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[exp.id]) {
+                continue;
+            }
+            let (log, mul, exp) = (s.get(&log), s.get(&mul), s.get(&exp));
+            // The log works on the base's physical elements, so the multiply's view of it is a view of the base
+            let a_shape = graph
+                .graph
+                .edges_connecting(log, mul)
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap()
+                .2;
+            // Padding would read as 0 inside the log rather than as a 0 base
+            if a_shape.is_padded() || a_shape.is_sliced() {
+                continue;
+            }
+            let (base, base_output) = graph
+                .graph
+                .edges_connecting(s.get(&base), log)
+                .next()
+                .map(|e| (e.source(), e.weight().as_data().unwrap().1))
+                .unwrap();
+            let (exponent, b_edge) = graph
+                .graph
+                .edges_connecting(s.get(&exponent), mul)
+                .next()
+                .map(|e| (e.source(), e.weight().as_data().unwrap()))
+                .unwrap();
+            let pow = graph
+                .add_op(CudaPow::<T>::new(a_shape, b_edge.2, dev.clone(), &graph.dyn_map).unwrap())
+                .input(base, base_output, a_shape)
+                .input(exponent, b_edge.1, b_edge.2)
+                .finish();
+            move_outgoing_edge(exp, pow, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                exp,
+                pow,
+            );
+            graph.graph.remove_node(exp);
+            s.try_delete();
+        }
+    }
+}
+
+/// What `CudaGather` does with an index outside the embedding table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GatherOutOfRange {
+    /// The gathered row is all zeros
+    #[default]
+    Zero,
+    /// The index is clamped to the first or last row
+    Clamp,
+}
+
+/// Gather rows of an embedding table. The indexes can be float or integer tensors, on the host or device.
+/// Integer indexes are used as is, so they can address rows past f32's exact-integer range.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaGather<T> {
+    /// Kernels for each index type we've been given
+    functions: FxHashMap<&'static str, CudaFunction>,
+    device: Arc<CudaDevice>,
+    pub embed_dim: usize,
+    pub out_of_range: GatherOutOfRange,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaGather<T> {
+    pub fn new(device: Arc<CudaDevice>, embed_dim: usize, out_of_range: GatherOutOfRange) -> Self {
+        Self {
+            functions: FxHashMap::default(),
+            device,
+            embed_dim,
+            out_of_range,
+            _phantom: Default::default(),
+        }
+    }
+
+    fn function(&mut self, index_type: &'static str, is_float: bool) -> CudaFunction {
+        let device = &self.device;
+        self.functions
+            .entry(index_type)
+            .or_insert_with(|| {
+                let type_name = T::type_name();
+                let index = if is_float {
+                    "(long long)(float)inp[x]"
+                } else {
+                    "(long long)inp[x]"
+                };
+                let code = format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *weights, const {index_type} *inp, int n_embeddings, int embedding_dim, long long n_rows, int clamp) {{
+    int x = blockIdx.x * blockDim.x + threadIdx.x;
+    int y = blockIdx.y * blockDim.y + threadIdx.y;
+    if (x < n_embeddings && y < embedding_dim) {{
+        long long row = {index};
+        if (row < 0 || row >= n_rows) {{
+            if (!clamp) {{
+                out[(long long)x * embedding_dim + y] = ({type_name})0.0;
+                return;
+            }}
+            row = row < 0 ? 0 : n_rows - 1;
+        }}
+        out[(long long)x * embedding_dim + y] = weights[row * embedding_dim + y];
+    }}
+}}");
+                compile_and_load_kernel(code, device).unwrap()
+            })
+            .clone()
+    }
+
+    fn gather<I: DeviceRepr>(
+        &mut self,
+        index_type: &'static str,
+        is_float: bool,
+        indexes: &CudaSlice<I>,
+        weights: &CudaSlice<T>,
+    ) -> CudaSlice<T> {
+        let n_indexes = indexes.len();
+        let n_rows = weights
+            .len()
+            .checked_div(self.embed_dim)
+            .unwrap_or_default();
+        let clamp = (self.out_of_range == GatherOutOfRange::Clamp) as i32;
+        let mut out = alloc_zeros::<T>(&self.device, n_indexes * self.embed_dim).unwrap();
+        unsafe {
+            self.function(index_type, is_float)
+                .launch_on_device_stream(
+                    &self.device,
                     LaunchConfig {
                         grid_dim: (
-                            indexes.len().div_ceil(16) as u32,
+                            grid_size(n_indexes, 16),
                             self.embed_dim.div_ceil(16) as u32,
                             1,
                         ),
@@ -328,28 +550,173 @@ impl<T: CudaFloat> Operator for CudaGather<T> {
                     (
                         &mut out,
                         weights,
-                        &indexes_buffer,
-                        indexes.len(),
+                        indexes,
+                        n_indexes,
                         self.embed_dim,
+                        n_rows,
+                        clamp,
                     ),
                 )
                 .unwrap();
         }
+        out
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaGather<T> {
+    fn process(&mut self, inputs: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Inp 1 is the indexes and inp 2 is the embedding table
+        let indexes = inputs[0].0.borrowed().data.as_any();
+        let weights = get_buffer_from_tensor::<T>(&inputs[1].0);
+
+        let out = if let Some(indexes) = indexes.downcast_ref::<CudaData<i32>>() {
+            self.gather(i32::type_name(), false, &indexes.0, weights)
+        } else if let Some(indexes) = indexes.downcast_ref::<CudaData<u32>>() {
+            self.gather(u32::type_name(), false, &indexes.0, weights)
+        } else if let Some(indexes) = indexes.downcast_ref::<CudaData<T>>() {
+            self.gather(T::type_name(), true, &indexes.0, weights)
+        } else {
+            // Float indexes still on the host
+            let indexes = indexes
+                .downcast_ref::<Vec<f32>>()
+                .expect("Gather indexes must be float or integer tensors")
+                .iter()
+                .map(|i| *i as i32)
+                .collect::<Vec<_>>();
+            let indexes = CudaData::new(upload(&self.device, &indexes).unwrap());
+            self.gather(i32::type_name(), false, &indexes.0, weights)
+        };
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
 
-        vec![Tensor {
-            data: Box::new(CudaData(out)),
-        }]
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
     }
 }
 
+/// Gather elements (or trailing slices) of a source tensor by coordinate tuples.
+/// Inputs are a `(..., k)` coordinate tensor and a contiguous source tensor with at least k dimensions.
+/// The output has the coordinate batch shape followed by the source dimensions after the first k.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaGatherNd<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub k: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaGatherNd<T> {
+    pub fn new(device: Arc<CudaDevice>, k: usize) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *src, const {type_name} *coords, int *out_of_bounds, const int *dims, long long n_coords, int k, long long slice_size) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n_coords * slice_size) {{
+        long long c = i / slice_size;
+        long long offset = 0;
+        bool valid = true;
+        for (int j = 0; j < k; j++) {{
+            int coord = (int)(float)coords[c * k + j];
+            if (coord < 0 || coord >= dims[j]) {{
+                valid = false;
+            }}
+            offset = offset * dims[j] + coord;
+        }}
+        if (valid) {{
+            out[i] = src[offset * slice_size + i % slice_size];
+        }} else {{
+            out[i] = ({type_name})0.0;
+            out_of_bounds[0] = 1;
+        }}
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            k,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaGatherNd<T> {
+    fn process(&mut self, inputs: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Inp 1 is the coordinates and inp 2 is the source
+        let coords = get_buffer_from_tensor::<T>(&inputs[0].0);
+        let src = get_buffer_from_tensor::<T>(&inputs[1].0);
+        let src_dims = inputs[1]
+            .1
+            .shape()
+            .into_iter()
+            .map(|d| d.to_usize().unwrap() as i32)
+            .collect::<Vec<_>>();
+        assert!(
+            src_dims.len() >= self.k,
+            "GatherNd coordinates have {} components but the source only has {} dimensions",
+            self.k,
+            src_dims.len()
+        );
+        let n_coords = inputs[0].1.n_elements().to_usize().unwrap() / self.k;
+        let slice_size = src_dims[self.k..]
+            .iter()
+            .map(|d| *d as usize)
+            .product::<usize>();
+
+        let dims = CudaData::new(upload(&self.device, &src_dims[..self.k]).unwrap());
+        let mut out_of_bounds = CudaData::new(alloc_zeros::<i32>(&self.device, 1).unwrap());
+        let mut out = alloc_zeros::<T>(&self.device, n_coords * slice_size).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_coords * slice_size, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut out,
+                        src,
+                        coords,
+                        &mut *out_of_bounds.0,
+                        &*dims.0,
+                        n_coords,
+                        self.k,
+                        slice_size,
+                    ),
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            out_of_bounds.to_vec()[0],
+            0,
+            "GatherNd coordinate out of bounds for source shape {src_dims:?}"
+        );
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace one-hot embedding lookups with `CudaGather`, handling indexes outside the table as the compiler's
+/// `GatherOutOfRange` says. Defaults to `GatherOutOfRange::Zero`, gathering a row of zeros.
 #[derive(LuminalPrint, Default)]
-pub struct MetalGatherCompiler<T: CudaFloat>(PhantomData<T>);
+pub struct MetalGatherCompiler<T: CudaFloat>(pub usize, pub GatherOutOfRange, PhantomData<T>);
+
+impl<T: CudaFloat> MetalGatherCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, GatherOutOfRange::default(), PhantomData)
+    }
+}
 
 impl<T: CudaFloat> Compiler for MetalGatherCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = cuda_device(self.0);
         let arange = op::<CudaARange<T>>();
-        let eq = unary::<CudaEqual<T>>(arange);
+        let eq = unary::<CudaEqual<T>>(arange.clone());
         let inp = node();
         let mul = binary::<CudaMul<T>>(eq.clone(), inp.clone());
         let sum_reduce = unary::<CudaSumReduce<T>>(mul.clone());
@@ -370,14 +737,444 @@ impl<T: CudaFloat> Compiler for MetalGatherCompiler<T> {
                 .shape()[2]
                 .to_usize()
                 .unwrap();
+            // The indexes are whatever the arange is compared against, and the table whatever the one hot is multiplied by
+            let (indexes, indexes_out, indexes_shape) = graph
+                .get_sources(s.get(&eq))
+                .into_iter()
+                .find(|(n, _, _)| *n != s.get(&arange))
+                .unwrap();
+            let (weights, weights_out, weights_shape) = graph
+                .get_sources(s.get(&mul))
+                .into_iter()
+                .find(|(n, _, _)| *n != s.get(&eq))
+                .unwrap();
             let gather = graph
-                .add_op(CudaGather::<T>::new(dev.clone(), embed_dim))
+                .add_op(CudaGather::<T>::new(dev.clone(), embed_dim, self.1))
+                .input(indexes, indexes_out, indexes_shape)
+                .input(weights, weights_out, weights_shape)
                 .finish();
-            move_incoming_edge(s.get(&eq), gather, &mut graph.graph);
             graph.safe_remove_node(s.get(&eq), 1);
-            move_incoming_edge(s.get(&mul), gather, &mut graph.graph);
             move_outgoing_edge(s.get(&sum_reduce), gather, &mut graph.graph);
             s.try_delete();
         }
     }
 }
+
+/// Accumulate the second input into the first input's buffer (`dst += src`), broadcasting the second input.
+/// The destination must be contiguous. Its buffer is mutated in place when the graph hands over ownership of it,
+/// which only happens when this op is its last consumer and it isn't kept, otherwise a copy is accumulated into.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaAccumulate<T> {
+    function: CudaFunction,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaAccumulate<T> {
+    pub fn new(
+        src_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(src_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[src_shape]);
+        let index = index_type(&[src_shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *dst, const {type_name} *src, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel && ({valid}) != 0) {{
+        dst[idx] = dst[idx] + src[{idx}];
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaAccumulate<T> {
+    fn process(&mut self, mut tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        assert!(
+            tensors[0].1.is_contiguous(),
+            "Accumulation destination must be contiguous"
+        );
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let (src, _) = tensors.pop().unwrap();
+        let (dst, _) = tensors.pop().unwrap();
+        // Takes the destination if owned, otherwise copies it
+        let mut dst = dst.cloned();
+        let dst_buffer = &mut *dst
+            .data
+            .as_any_mut()
+            .downcast_mut::<CudaData<T>>()
+            .unwrap()
+            .0;
+        let device = dst_buffer.device();
+        let mut params = vec![
+            (&*dst_buffer).as_kernel_param(),
+            get_buffer_from_tensor::<T>(&src).as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![dst]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Scatter-add along the first dimension (`index_add`): `dst[indexes[i]] += src[i]` for each row `i` of the source.
+/// Inputs are the destination, the float indexes and the source, all contiguous, with one source row per index.
+/// Indexes outside the destination are skipped. Like `CudaAccumulate`, the destination buffer is added into in
+/// place when the graph hands over ownership of it.
+///
+/// Rows are accumulated with atomic adds by default, so when indexes repeat, the order the additions land in, and
+/// so the result's rounding, can change from run to run. With `deterministic`, the indexes are instead sorted on
+/// the host and each destination row sums its sources in index order, at the cost of copying the indexes back.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaScatterAdd<T> {
+    atomic_function: CudaFunction,
+    sorted_function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub deterministic: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaScatterAdd<T> {
+    pub fn new(device: Arc<CudaDevice>, deterministic: bool) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let acc = T::accumulator_type_name();
+        // There's no atomicAdd for every 16 bit type on every architecture, so those swap the 32 bit word
+        // holding the element with a compare-and-swap loop
+        let (atomic_add, helper) = if std::mem::size_of::<T>() == 2 {
+            (
+                "atomic_add_16",
+                format!(
+                    "
+__device__ void atomic_add_16({type_name} *address, {type_name} value) {{
+    unsigned int *word = (unsigned int *)((size_t)address & ~(size_t)2);
+    bool upper = ((size_t)address & 2) != 0;
+    unsigned int old = *word, assumed;
+    do {{
+        assumed = old;
+        unsigned short bits = upper ? (unsigned short)(assumed >> 16) : (unsigned short)(assumed & 0xffff);
+        {type_name} current = *({type_name} *)&bits;
+        {type_name} sum = ({type_name})((float)current + (float)value);
+        unsigned int sum_bits = *(unsigned short *)&sum;
+        unsigned int updated = upper ? (assumed & 0xffff) | (sum_bits << 16) : (assumed & 0xffff0000) | sum_bits;
+        old = atomicCAS(word, assumed, updated);
+    }} while (assumed != old);
+}}"
+                ),
+            )
+        } else {
+            ("atomicAdd", String::new())
+        };
+        let atomic = format!(
+            "
+#include \"cuda_fp16.h\"
+{helper}
+extern \"C\" __global__ void kernel({type_name} *dst, const {type_name} *indexes, const {type_name} *src, long long n_src, int row_size, long long n_rows) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n_src) {{
+        long long row = (long long)(float)indexes[i / row_size];
+        if (row >= 0 && row < n_rows) {{
+            {atomic_add}(&dst[row * row_size + i % row_size], src[i]);
+        }}
+    }}
+}}"
+        );
+        // Sources for each destination row are listed in order[offsets[row]..offsets[row + 1]]
+        let sorted = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *dst, const int *order, const int *offsets, const {type_name} *src, long long n_dst, int row_size) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < n_dst) {{
+        long long row = i / row_size;
+        {acc} sum = ({acc})dst[i];
+        for (int j = offsets[row]; j < offsets[row + 1]; j++) {{
+            sum += ({acc})src[(long long)order[j] * row_size + i % row_size];
+        }}
+        dst[i] = ({type_name})sum;
+    }}
+}}"
+        );
+        Ok(Self {
+            atomic_function: compile_and_load_kernel(atomic, &device)?,
+            sorted_function: compile_and_load_kernel(sorted, &device)?,
+            device,
+            deterministic,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaScatterAdd<T> {
+    fn process(&mut self, mut tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        assert!(
+            tensors[0].1.is_contiguous() && tensors[2].1.is_contiguous(),
+            "Scatter-add destination and source must be contiguous"
+        );
+        let n_dst = tensors[0].1.n_elements().to_usize().unwrap();
+        let n_src = tensors[2].1.n_elements().to_usize().unwrap();
+        let n_indexes = tensors[1].1.n_elements().to_usize().unwrap();
+        let row_size = n_src.checked_div(n_indexes).unwrap_or_default();
+        let n_rows = n_dst.checked_div(row_size).unwrap_or_default();
+        let (src, _) = tensors.pop().unwrap();
+        let (indexes, _) = tensors.pop().unwrap();
+        let (dst, _) = tensors.pop().unwrap();
+        let (src, indexes) = (
+            get_buffer_from_tensor::<T>(&src),
+            get_buffer_from_tensor::<T>(&indexes),
+        );
+        // Takes the destination if owned, otherwise copies it
+        let mut dst = dst.cloned();
+        let dst_buffer = &mut *dst
+            .data
+            .as_any_mut()
+            .downcast_mut::<CudaData<T>>()
+            .unwrap()
+            .0;
+        if !self.deterministic {
+            unsafe {
+                self.atomic_function
+                    .clone()
+                    .launch_on_device_stream(
+                        &self.device,
+                        elementwise_launch_config(n_src, DEFAULT_BLOCK_SIZE),
+                        (&*dst_buffer, indexes, src, n_src, row_size as i32, n_rows),
+                    )
+                    .unwrap();
+            }
+            return vec![dst];
+        }
+
+        // Stable sort the in-range sources by destination row
+        let rows = download(indexes)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.to_f32() as i64)
+            .collect::<Vec<_>>();
+        let mut order = (0..n_indexes as i32)
+            .filter(|i| (0..n_rows as i64).contains(&rows[*i as usize]))
+            .collect::<Vec<_>>();
+        order.sort_by_key(|i| rows[*i as usize]);
+        let mut offsets = vec![0; n_rows + 1];
+        for i in &order {
+            offsets[rows[*i as usize] as usize + 1] += 1;
+        }
+        for row in 0..n_rows {
+            offsets[row + 1] += offsets[row];
+        }
+        let order_buffer = CudaData::new(upload(&self.device, &order).unwrap());
+        let offsets_buffer = CudaData::new(upload(&self.device, &offsets).unwrap());
+        unsafe {
+            self.sorted_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_dst, DEFAULT_BLOCK_SIZE),
+                    (
+                        &*dst_buffer,
+                        &*order_buffer.0,
+                        &*offsets_buffer.0,
+                        src,
+                        n_dst,
+                        row_size as i32,
+                    ),
+                )
+                .unwrap();
+        }
+
+        vec![dst]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// The op a `CudaScalarBinary` applies between its input and its scalar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarOp {
+    Add,
+    Mul,
+}
+
+/// An add or multiply by a constant float, which is baked into the kernel instead of being read from a buffer
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaScalarBinary<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub block_size: u32,
+    pub op: ScalarOp,
+    pub value: f32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaScalarBinary<T> {
+    pub fn new(
+        op: ScalarOp,
+        value: f32,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let operator = match op {
+            ScalarOp::Add => "+",
+            ScalarOp::Mul => "*",
+        };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        out[idx] = (({valid}) == 0 ? ({type_name})0.0 : inp[{idx}]) {operator} ({type_name}){value:?}f;
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            block_size: DEFAULT_BLOCK_SIZE,
+            op,
+            value,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaScalarBinary<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = unsafe { alloc::<T>(&self.device, inp_size).unwrap() };
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace adds and multiplies by a float constant with a `CudaScalarBinary`, so the constant's buffer isn't read
+/// for every element. This runs after elementwise fusion, which already takes care of constants in fused chains.
+#[derive(LuminalPrint, Default)]
+pub struct ScalarOperandCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> ScalarOperandCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for ScalarOperandCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        for scalar_op in [ScalarOp::Add, ScalarOp::Mul] {
+            let (inp, constant) = (node(), op::<CudaConstant<T>>());
+            let out = match scalar_op {
+                ScalarOp::Add => binary::<CudaAdd<T>>(inp.clone(), constant.clone()),
+                ScalarOp::Mul => binary::<CudaMul<T>>(inp.clone(), constant.clone()),
+            };
+            let mut s = out.clone().search(graph);
+            while s.next_match() {
+                if s.check_no_delete(&[out.id]) {
+                    continue;
+                }
+                let (inp, constant, out) = (s.get(&inp), s.get(&constant), s.get(&out));
+                let ConstantValue::Float(value) = graph
+                    .node_weight(constant)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<CudaConstant<T>>()
+                    .unwrap()
+                    .value
+                else {
+                    continue;
+                };
+                let sources = graph.get_sources(out);
+                let (_, inp_output, inp_shape) =
+                    *sources.iter().find(|(n, _, _)| *n == inp).unwrap();
+                // Padding would zero out the constant in places
+                let (_, _, constant_shape) =
+                    *sources.iter().find(|(n, _, _)| *n == constant).unwrap();
+                if !value.is_finite() || constant_shape.is_padded() || constant_shape.is_sliced() {
+                    continue;
+                }
+                let scalar = graph
+                    .add_op(
+                        CudaScalarBinary::<T>::new(
+                            scalar_op,
+                            value,
+                            inp_shape,
+                            dev.clone(),
+                            &graph.dyn_map,
+                        )
+                        .unwrap(),
+                    )
+                    .input(inp, inp_output, inp_shape)
+                    .finish();
+                move_outgoing_edge(out, scalar, &mut graph.graph);
+                move_references(
+                    &mut remap,
+                    &mut graph.no_delete,
+                    &mut graph.to_retrieve,
+                    out,
+                    scalar,
+                );
+                graph.graph.remove_node(out);
+                s.try_delete();
+            }
+        }
+    }
+}
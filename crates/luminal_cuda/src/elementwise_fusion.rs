@@ -1,42 +1,358 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
-use luminal_cudarc::{
-    cublas::{sys::cublasOperation_t::*, CudaBlas},
-    driver::{CudaDevice, CudaFunction, DevicePtr, DevicePtrMut},
-};
-use rustc_hash::FxHashMap;
+use itertools::Itertools;
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr};
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
-    prim::{CudaMul, CudaSumReduce},
-    CudaData, CudaFloat,
+    alloc_zeros, compile_and_load_kernel, cuda_device, cuda_op_marker, elementwise_launch_config,
+    get_buffer_from_tensor, get_idx_valid_exps, index_type, input_dyn_dims,
+    prim::{
+        CudaAdd, CudaExp2, CudaLessThan, CudaLog2, CudaMod, CudaMul, CudaRecip, CudaSin, CudaSqrt,
+    },
+    render_dyn_dim_inputs, CudaCompileError, CudaData, CudaFloat, LaunchOnDeviceStream,
+    DEFAULT_BLOCK_SIZE,
 };
 use luminal::{
     op::{InputTensor, Operator},
-    prelude::*,
+    prelude::{
+        petgraph::{visit::EdgeRef, Direction},
+        *,
+    },
 };
 
+/// Fuse trees of elementwise ops into single kernels, so intermediates never round trip through global memory.
+/// Division comes out of the frontend as `a * recip(b)`, which is fused into one `a / b` kernel.
 #[derive(Debug, Default)]
-pub struct ElementwiseFusionCompiler<T>(PhantomData<T>);
+pub struct ElementwiseFusionCompiler<T>(pub usize, PhantomData<T>);
 
-impl<T> Compiler for ElementwiseFusionCompiler<T> {
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, remap: To) {
-        // Track fused ops
-        let mut fused_ops = FxHashMap::<NodeIndex, (String, Vec<ShapeTracker>)>::default();
+impl<T> ElementwiseFusionCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
     }
 }
 
-#[derive(LuminalPrint, Clone)]
-pub struct FusedElementwiseOp<T> {
-    kernel: Option<CudaFunction>,
-    dyn_map: *const FxHashMap<char, usize>,
-    dyn_chars: Vec<char>,
-    equantion: String,
+impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        let mut fused_ops = FxHashSet::default();
+        for mul in graph.node_indices().collect::<Vec<_>>() {
+            // Reciprocals we've already fused away are gone from the graph
+            if !graph
+                .node_weight(mul)
+                .map(|op| op.as_any().is::<CudaMul<T>>())
+                .unwrap_or_default()
+            {
+                continue;
+            }
+            let inputs = graph.get_sources(mul);
+            let Some(recip_input) = inputs
+                .iter()
+                .position(|(n, _, _)| graph.node_weight(*n).unwrap().as_any().is::<CudaRecip<T>>())
+            else {
+                continue;
+            };
+            let (recip, _, recip_out_shape) = inputs[recip_input];
+            if !can_fuse_away(graph, recip) {
+                continue;
+            }
+            // The recip kernel maps its input's physical elements one to one, so the mul's view of it reads
+            // the input just the same. Padding would divide by the zeros it fills in with, though
+            if recip_out_shape.is_sliced() || recip_out_shape.is_padded() {
+                continue;
+            }
+            let (b, b_out, _) = graph.get_sources(recip)[0];
+            let (a, a_out, a_shape) = inputs[1 - recip_input];
+            let fused = graph
+                .add_op(CudaFusedElementwise::<T>::new(
+                    "input0 / input1".to_string(),
+                    dev.clone(),
+                    &graph.dyn_map,
+                ))
+                .input(a, a_out, a_shape)
+                .input(b, b_out, recip_out_shape)
+                .finish();
+            move_outgoing_edge(mul, fused, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                mul,
+                fused,
+            );
+            graph.remove_node(mul);
+            graph.remove_node(recip);
+            fused_ops.insert(fused);
+        }
+
+        // Fold producers into their consumers until no edge between two elementwise ops is left
+        let mut matched = true;
+        while matched {
+            matched = false;
+            for edge in graph.edge_indices().collect::<Vec<_>>() {
+                let Some((a, b)) = graph.edge_endpoints(edge) else {
+                    continue;
+                };
+                let Some((_, connecting_input, connecting_shape)) =
+                    graph.edge_weight(edge).unwrap().as_data()
+                else {
+                    continue;
+                };
+                let Some((a_equation, a_unary)) = elementwise_equation::<T>(graph, a) else {
+                    continue;
+                };
+                let Some((b_equation, b_unary)) = elementwise_equation::<T>(graph, b) else {
+                    continue;
+                };
+                if !can_fuse_away(graph, a) {
+                    continue;
+                }
+                let identity_view = connecting_shape.is_contiguous()
+                    && !connecting_shape.is_sliced()
+                    && !connecting_shape.is_padded();
+                // Unary ops map physical elements one to one, so a consumer can read through them with any
+                // view that doesn't fill in padding. Everything else writes out its result contiguously,
+                // which we can only inline where the consumer indexes it the same way. A unary consumer
+                // works on its input's physical elements, which are exactly what a non-unary producer writes,
+                // so it can take the producer in whole (e.g. the sqrt of a residual add read transposed)
+                if !identity_view
+                    && (a_unary == b_unary
+                        || connecting_shape.is_sliced()
+                        || connecting_shape.is_padded())
+                {
+                    continue;
+                }
+
+                // b's inputs, minus the connecting edge, followed by a's inputs
+                let mut inputs = graph.get_sources(b);
+                inputs.remove(connecting_input as usize);
+                let a_offset = inputs.len();
+                if a_unary {
+                    let (src, out, _) = graph.get_sources(a)[0];
+                    inputs.push((src, out, connecting_shape));
+                } else {
+                    inputs.extend(graph.get_sources(a));
+                }
+                let a_equation =
+                    substitute_inputs(&a_equation, |i| format!("input{}", i + a_offset));
+                let equation =
+                    substitute_inputs(&b_equation, |i| match i.cmp(&(connecting_input as usize)) {
+                        std::cmp::Ordering::Less => format!("input{i}"),
+                        std::cmp::Ordering::Equal => format!("({a_equation})"),
+                        std::cmp::Ordering::Greater => format!("input{}", i - 1),
+                    });
+
+                let mut fused = graph.add_op(CudaFusedElementwise::<T>::new(
+                    equation,
+                    dev.clone(),
+                    &graph.dyn_map,
+                ));
+                for (src, out, shape) in inputs {
+                    fused = fused.input(src, out, shape);
+                }
+                let fused = fused.finish();
+                move_outgoing_edge(b, fused, &mut graph.graph);
+                for n in [a, b] {
+                    move_references(
+                        &mut remap,
+                        &mut graph.no_delete,
+                        &mut graph.to_retrieve,
+                        n,
+                        fused,
+                    );
+                    graph.remove_node(n);
+                    fused_ops.remove(&n);
+                }
+                fused_ops.insert(fused);
+                matched = true;
+            }
+        }
+
+        // Compile the kernels now their inputs are settled
+        for fused in fused_ops {
+            let shapes = graph
+                .get_sources(fused)
+                .into_iter()
+                .map(|(_, _, sh)| sh)
+                .collect::<Vec<_>>();
+            graph
+                .node_weight_mut(fused)
+                .unwrap()
+                .as_any_mut()
+                .downcast_mut::<CudaFusedElementwise<T>>()
+                .unwrap()
+                .compile(&shapes)
+                .unwrap();
+        }
+    }
+}
+
+/// Whether a node's result only lives to feed a single consumer, so it can be inlined into it
+fn can_fuse_away(graph: &Graph, node: NodeIndex) -> bool {
+    !graph.no_delete.contains(&node)
+        && graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter(|e| !e.weight().is_schedule())
+            .count()
+            == 1
+}
+
+/// The expression an elementwise op computes over its inputs, and whether it's a unary op working on
+/// physical elements rather than through input views
+fn elementwise_equation<T: CudaFloat>(graph: &Graph, node: NodeIndex) -> Option<(String, bool)> {
+    let op = graph.node_weight(node).unwrap().as_any();
+    if let Some(fused) = op.downcast_ref::<CudaFusedElementwise<T>>() {
+        return Some((fused.equation.clone(), false));
+    }
+    let type_name = T::type_name();
+    let unary = if op.is::<CudaLog2<T>>() {
+        Some("log2(input0)".to_string())
+    } else if op.is::<CudaExp2<T>>() {
+        Some("exp2(input0)".to_string())
+    } else if op.is::<CudaSin<T>>() {
+        Some("sin(input0)".to_string())
+    } else if op.is::<CudaSqrt<T>>() {
+        Some(match type_name {
+            "float" | "double" => "sqrt(input0)".to_string(),
+            _ => "hsqrt(input0)".to_string(),
+        })
+    } else if op.is::<CudaRecip<T>>() {
+        Some(match type_name {
+            "float" => "__frcp_rn(input0)".to_string(),
+            "double" => "__drcp_rn(input0)".to_string(),
+            _ => "hrcp(input0)".to_string(),
+        })
+    } else {
+        None
+    };
+    if let Some(equation) = unary {
+        return Some((equation, true));
+    }
+    let binary = if op.is::<CudaAdd<T>>() {
+        "input0 + input1".to_string()
+    } else if op.is::<CudaMul<T>>() {
+        "input0 * input1".to_string()
+    } else if op.is::<CudaMod<T>>() {
+        "fmod(input0, input1)".to_string()
+    } else if op.is::<CudaLessThan<T>>() {
+        format!("input0 < input1 ? ({type_name})1.0 : ({type_name})0.0")
+    } else {
+        return None;
+    };
+    Some((binary, false))
+}
+
+/// Swap each `inputN` in an equation for whatever `f` gives for N
+fn substitute_inputs(equation: &str, f: impl Fn(usize) -> String) -> String {
+    let mut out = String::with_capacity(equation.len());
+    let mut rest = equation;
+    while let Some(pos) = rest.find("input") {
+        out.push_str(&rest[..pos]);
+        let after = &rest[pos + "input".len()..];
+        let digits = after.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            out.push_str("input");
+        } else {
+            out.push_str(&f(after[..digits].parse().unwrap()));
+        }
+        rest = &after[digits..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A generated kernel evaluating an elementwise `equation` over its inputs, which it refers to as `input0`, `input1`, etc.
+/// The kernel is built by `compile` once the input shapes are known.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaFusedElementwise<T> {
+    function: Option<CudaFunction>,
+    pub equation: String,
     device: Arc<CudaDevice>,
-    input_views: Vec<ShapeTracker>,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
     _phantom: PhantomData<T>,
 }
-impl<T> PartialEq for FusedElementwiseOp<T> {
-    fn eq(&self, other: &Self) -> bool {
-        self.equantion == other.equantion && self.input_views == other.input_views
+
+impl<T: CudaFloat> CudaFusedElementwise<T> {
+    pub fn new(
+        equation: String,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        Self {
+            function: None,
+            equation,
+            device,
+            block_size: DEFAULT_BLOCK_SIZE,
+            dyn_symbols: vec![],
+            dyn_map,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Build the kernel for inputs viewed through these shapes
+    pub fn compile(&mut self, input_shapes: &[ShapeTracker]) -> Result<(), CudaCompileError> {
+        let type_name = T::type_name();
+        let rendered_equation = substitute_inputs(&self.equation, |i| {
+            let (idx, valid) = get_idx_valid_exps(input_shapes[i]);
+            format!("(({valid}) == 0 ? ({type_name})0.0 : inp_{i}[{idx}])")
+        });
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(input_shapes);
+        let index = index_type(input_shapes);
+        let inputs = (0..input_shapes.len())
+            .map(|i| format!(", const {type_name} *inp_{i}"))
+            .join("");
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out{inputs}, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        out[idx] = {rendered_equation};
+    }}
+}}"
+        );
+        self.function = Some(compile_and_load_kernel(code, &self.device)?);
+        self.dyn_symbols = dyn_symbols;
+        Ok(())
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaFusedElementwise<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let inputs = tensors
+            .iter()
+            .map(|(t, _)| get_buffer_from_tensor::<T>(t))
+            .collect::<Vec<_>>();
+        let mut params = vec![(&out).as_kernel_param()];
+        for inp in &inputs {
+            params.push(inp.as_kernel_param());
+        }
+        params.push(inp_size.as_kernel_param());
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .expect("Fused elementwise kernel wasn't compiled")
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
@@ -3,19 +3,64 @@ mod elementwise_fusion;
 mod matmul;
 mod other;
 mod prim;
+mod quantized;
+mod trace;
+mod unary;
+
+pub use binary::{
+    CudaAccumulate, CudaGather, CudaGatherNd, CudaPow, CudaScalarBinary, CudaScatterAdd,
+    GatherOutOfRange, PowCompiler, ScalarOp, ScalarOperandCompiler,
+};
+pub use elementwise_fusion::{CudaFusedElementwise, ElementwiseFusionCompiler};
+pub use matmul::{CudaGroupedMatMul, CudaMatMulCompiler, CudaTiledMatmul2D};
+pub use other::{
+    CudaAffine, CudaApplyRepetitionPenalty, CudaArgSort, CudaAssert, CudaCosineSim, CudaHistogram,
+    CudaKLDiv, CudaMeanVar, CudaMultiHeadReshape, CudaMultiReduce, CudaOnlineSoftmax, CudaPrefetch,
+    CudaPrefetchCompiler, CudaQKVSplit, CudaRMSNorm, CudaReduceBroadcastDiv, CudaRingAppend,
+    CudaSoftLabelCrossEntropy, CudaSoftmax, CudaVarlenKVGather, RMSNormCompiler, SoftmaxCompiler,
+};
+pub use prim::{
+    check_cuda_ops, CheckCudaOpsCompiler, ContiguousFusionCompiler, CudaMaxReduce, CudaMeanReduce,
+    CudaMinReduce, CudaProdReduce, CudaSumReduce, PinnedStagingCompiler, ReduceInit,
+};
+pub use quantized::*;
+pub use trace::{
+    execute_timed, CudaErrorReport, CudaErrorReportCompiler, CudaLaunchCounted, CudaLaunchStats,
+    CudaLaunchStatsCompiler, CudaTrace, CudaTraceCompiler, CudaTraced, ExecutionTiming,
+    LaunchStats, TraceEvent,
+};
+pub use unary::{
+    CudaFloatToInt, CudaGelu, CudaIntToFloat, CudaNanToNum, CudaTanh, GeluApproximation,
+};
 
 #[cfg(test)]
 mod tests;
 
 use itertools::Itertools;
 use luminal_cudarc::{
-    driver::{CudaDevice, CudaFunction, CudaSlice, DeviceRepr},
-    nvrtc::{compile_ptx_with_opts, CompileOptions},
+    driver::{
+        result, sys, CudaDevice, CudaFunction, CudaSlice, CudaStream, DevicePtr, DevicePtrMut,
+        DeviceRepr, DeviceSlice, DriverError, LaunchAsync, LaunchConfig, ValidAsZeroBits,
+    },
+    nvrtc::{compile_ptx_with_opts, CompileError, CompileOptions, Ptx},
 };
 use prim::CudaConstant;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
-use std::{collections::hash_map::DefaultHasher, ffi::c_void, fmt::Write, hash::Hasher, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::hash_map::DefaultHasher,
+    ffi::c_void,
+    fmt::Write,
+    hash::Hasher,
+    mem::ManuallyDrop,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
 
 use luminal::{op::InputTensor, prelude::*};
 
@@ -23,26 +68,119 @@ use self::symbolic::{BigExpression, Term};
 
 pub type CudaCompiler<T> = (
     prim::CudaPrimitiveCompiler<T>,
+    SpecialOpsCompiler<T>,
+    elementwise_fusion::ElementwiseFusionCompiler<T>,
+    binary::ScalarOperandCompiler<T>,
+    prim::ContiguousFusionCompiler<T>,
+    prim::CopyCompiler<T>,
+    prim::CheckCudaOpsCompiler,
+);
+
+/// A `CudaCompiler` running graphs in half precision
+pub type CudaFp16Compiler = CudaCompiler<f16>;
+
+/// A `CudaCompiler` running graphs in bfloat16, which keeps f32's range at half precision
+pub type CudaBf16Compiler = CudaCompiler<bf16>;
+
+/// A `CudaCompiler` running graphs in double precision
+pub type CudaF64Compiler = CudaCompiler<f64>;
+
+/// Compiler to replace cuda ops with specialized variants
+pub type SpecialOpsCompiler<T> = (
+    unary::MishCompiler<T>,
+    unary::TanhCompiler<T>,
+    unary::GeluCompiler<T>,
     binary::CudaSubtractionCompiler<T>,
     binary::CudaEqualCompiler<T>,
     other::ARangeCompiler<T>,
     binary::MetalGatherCompiler<T>,
     matmul::CudaMatMulCompiler<T>,
-    prim::CopyCompiler<T>,
+    prim::CudaMeanReduceCompiler<T>,
+    prim::CudaMinReduceCompiler<T>,
+    binary::PowCompiler<T>,
+    // Normalization fusions
+    (other::RMSNormCompiler<T>, other::SoftmaxCompiler<T>),
 );
 
+/// A `CudaCompiler` whose ops run on the device with this ordinal. `CudaCompiler::default()` uses device 0.
+pub fn cuda_compiler_on_device<T: CudaFloat>(ordinal: usize) -> CudaCompiler<T> {
+    (
+        prim::CudaPrimitiveCompiler::new(ordinal),
+        (
+            unary::MishCompiler::new(ordinal),
+            unary::TanhCompiler::new(ordinal),
+            unary::GeluCompiler::new(ordinal),
+            binary::CudaSubtractionCompiler::new(ordinal),
+            binary::CudaEqualCompiler::new(ordinal),
+            other::ARangeCompiler::new(ordinal),
+            binary::MetalGatherCompiler::new(ordinal),
+            matmul::CudaMatMulCompiler::new(ordinal),
+            prim::CudaMeanReduceCompiler::new(ordinal),
+            prim::CudaMinReduceCompiler::new(ordinal),
+            binary::PowCompiler::new(ordinal),
+            (
+                other::RMSNormCompiler::new(ordinal),
+                other::SoftmaxCompiler::new(ordinal),
+            ),
+        ),
+        elementwise_fusion::ElementwiseFusionCompiler::new(ordinal),
+        binary::ScalarOperandCompiler::new(ordinal),
+        prim::ContiguousFusionCompiler::new(ordinal),
+        prim::CopyCompiler::new(ordinal),
+        prim::CheckCudaOpsCompiler,
+    )
+}
+
+/// Like `cuda_compiler_on_device`, but sets up the device first, so a missing or broken device is returned as an
+/// error here rather than panicking once the compiler runs. Use this to fall back to another backend.
+pub fn try_cuda_compiler_on_device<T: CudaFloat>(
+    ordinal: usize,
+) -> Result<CudaCompiler<T>, DriverError> {
+    try_cuda_device(ordinal)?;
+    Ok(cuda_compiler_on_device(ordinal))
+}
+
+/// Whether there's a CUDA device with this ordinal. Only the driver is asked, no device is set up.
+pub fn cuda_device_available(ordinal: usize) -> bool {
+    result::init().is_ok()
+        && result::device::get_count()
+            .map(|count| ordinal < count as usize)
+            .unwrap_or_default()
+}
+
+/// Whether `CudaCompiler::default()` has a device to run on
+pub fn cuda_available() -> bool {
+    cuda_device_available(0)
+}
+
+/// The device a compiled graph's ops run on, going by its copies to the device, including wrapped ones.
+/// None if it has none.
+fn graph_device<T: CudaFloat>(graph: &Graph) -> Option<Arc<CudaDevice>> {
+    graph.node_indices().find_map(|n| {
+        trace::unwrap_op(graph.node_weight(n).unwrap().as_ref())
+            .as_any()
+            .downcast_ref::<prim::CudaCopyToDevice<T>>()
+            .map(|op| op.device().clone())
+    })
+}
+
 pub trait CudaFloat:
     std::fmt::Debug
     + Copy
     + luminal_cudarc::driver::DeviceRepr
     + std::marker::Unpin
     + luminal_cudarc::driver::ValidAsZeroBits
+    + Default
     + 'static
 {
     fn to_f32(self) -> f32;
     fn from_f32(a: f32) -> Self;
     fn is_f32() -> bool;
     fn type_name() -> &'static str;
+    /// The type reductions accumulate in
+    fn accumulator_type_name() -> &'static str {
+        "float"
+    }
 }
 
 impl CudaFloat for f32 {
@@ -59,12 +197,171 @@ impl CudaFloat for f32 {
         "float"
     }
 }
+/// A device buffer. Buffers made with `share` point at the same allocation, which is freed with the last of them.
 #[derive(Debug)]
-pub struct CudaData<T>(CudaSlice<T>);
+pub struct CudaData<T>(ManuallyDrop<CudaSlice<T>>, Rc<()>);
+
+impl<T> CudaData<T> {
+    /// Wrap a buffer from `alloc` and the helpers built on it, which already count it as in use on its device
+    fn new(slice: CudaSlice<T>) -> Self {
+        Self(ManuallyDrop::new(slice), Rc::new(()))
+    }
+
+    /// Wrap a buffer allocated directly with cudarc, counting it as in use on its device
+    pub fn from_slice(slice: CudaSlice<T>) -> Self {
+        track_memory(
+            slice.device().ordinal(),
+            slice.len() * std::mem::size_of::<T>(),
+        );
+        Self::new(slice)
+    }
+
+    /// Another handle to this buffer's device memory, without copying or allocating.
+    /// Lets a second graph on the same device use weights already uploaded by the first.
+    /// Writes through any handle are seen by all of them, so only share buffers that are read.
+    pub fn share(&self) -> Self {
+        let slice = unsafe {
+            self.0
+                .device()
+                .upgrade_device_ptr(*self.0.device_ptr(), self.0.len())
+        };
+        Self(ManuallyDrop::new(slice), self.1.clone())
+    }
+
+    /// Whether this buffer's memory is also used by another handle made with `share`
+    pub fn is_shared(&self) -> bool {
+        Rc::strong_count(&self.1) > 1
+    }
+}
+
+impl<T> Drop for CudaData<T> {
+    fn drop(&mut self) {
+        let slice = unsafe { ManuallyDrop::take(&mut self.0) };
+        if self.is_shared() {
+            // Another handle still uses the allocation
+            slice.leak();
+        } else {
+            let size = slice.len() * std::mem::size_of::<T>();
+            let device = slice.device();
+            if buffer_reuse() && size > 0 {
+                // Hold on to the allocation for the next buffer of the same size. It stays counted as in use
+                // on its device until the pool lets go of it.
+                let mut bytes =
+                    Some(unsafe { device.upgrade_device_ptr::<u8>(slice.leak(), size) });
+                // The pool is gone once the thread is exiting, and then the buffer is freed as usual
+                let _ = BUFFER_POOL.try_with(|p| {
+                    p.borrow_mut()
+                        .0
+                        .entry((device.ordinal(), size))
+                        .or_default()
+                        .extend(bytes.take())
+                });
+                if let Some(bytes) = bytes {
+                    release_memory(device.ordinal(), size);
+                    free_buffer(bytes);
+                }
+                return;
+            }
+            release_memory(device.ordinal(), size);
+            free_buffer(slice);
+        }
+    }
+}
 
 impl<T: DeviceRepr> Clone for CudaData<T> {
     fn clone(&self) -> Self {
-        Self(self.0.try_clone().unwrap())
+        Self::new(alloc_copy(&self.0).unwrap())
+    }
+}
+
+// Host buffers are copied to and from the little-endian device byte for byte
+#[cfg(not(target_endian = "little"))]
+compile_error!("luminal_cuda needs a little-endian host");
+
+impl<T: DeviceRepr> CudaData<T> {
+    /// Copy the buffer to the host in its native element type, such as `Vec<f16>` for half
+    /// or `Vec<bf16>` for bfloat16 buffers, without converting through f32
+    pub fn to_vec(&self) -> Vec<T>
+    where
+        T: ValidAsZeroBits,
+    {
+        download(&self.0).unwrap()
+    }
+
+    /// Copy the buffer to the host as raw little-endian bytes, in element order
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.0.len() * std::mem::size_of::<T>()];
+        unsafe { copy_to_host(&self.0.device(), &mut bytes, *self.0.device_ptr()) }.unwrap();
+        bytes
+    }
+
+    /// Upload raw little-endian bytes (as produced by `to_bytes`) directly into a device buffer.
+    /// Returns an error if the bytes don't split evenly into elements.
+    pub fn from_bytes(device: &Arc<CudaDevice>, bytes: &[u8]) -> Result<Self, CudaError> {
+        let element_size = std::mem::size_of::<T>();
+        if bytes.len() % element_size != 0 {
+            return Err(CudaError::ByteLength {
+                len: bytes.len(),
+                element_size,
+            });
+        }
+        let mut slice = unsafe { alloc::<T>(device, bytes.len() / element_size) }?;
+        unsafe { copy_to_device(device, *slice.device_ptr_mut(), bytes) }?;
+        Ok(Self::new(slice))
+    }
+}
+
+impl<T: CudaFloat> CudaData<T> {
+    /// Upload host data to a new device buffer, converting from f32 to the buffer's type.
+    /// This is how to feed tensors made outside a graph, by setting them as a node's output.
+    pub fn from_host(device: &Arc<CudaDevice>, data: &[f32]) -> Self {
+        let data = data.iter().copied().map(T::from_f32).collect::<Vec<_>>();
+        Self::new(upload(device, &data).unwrap())
+    }
+
+    /// Download the buffer to the host, converting to f32
+    pub fn to_host(&self) -> Vec<f32> {
+        self.to_vec().into_iter().map(T::to_f32).collect()
+    }
+
+    /// Convert the buffer to another precision on the device, leaving this buffer intact.
+    /// Lets a single loaded tensor feed both the f16 and f32 parts of a mixed-precision graph.
+    pub fn cast<U: CudaFloat>(&self) -> CudaData<U> {
+        let device = self.0.device();
+        let (from, to) = (T::type_name(), U::type_name());
+        let function = CAST_KERNELS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry((device.ordinal(), from, to))
+            .or_insert_with(|| {
+                compile_and_load_kernel(
+                    format!(
+                        "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({to} *out, const {from} *inp, long long numel) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        out[idx] = ({to})inp[idx];
+    }}
+}}"
+                    ),
+                    &device,
+                )
+                .unwrap()
+            })
+            .clone();
+        let mut out = unsafe { alloc::<U>(&device, self.0.len()) }.unwrap();
+        unsafe {
+            function
+                .launch_on_device_stream(
+                    &device,
+                    elementwise_launch_config(self.0.len(), DEFAULT_BLOCK_SIZE),
+                    (&mut out, &*self.0, self.0.len() as i64),
+                )
+                .unwrap();
+        }
+        CudaData::new(out)
     }
 }
 
@@ -78,6 +375,170 @@ impl<T: CudaFloat> Data for CudaData<T> {
     }
 }
 
+/// Retrieve another node's output from a compiled graph, inserting a copy back to the host.
+/// Returns the id of the node to read the data from, which is also recorded in the graph's retrieval map.
+pub fn add_retrieval<T: CudaFloat>(graph: &mut Graph, node: NodeIndex) -> NodeIndex {
+    let op = trace::unwrap_op(graph.node_weight(node).unwrap().as_ref()).as_any();
+    let id = if op.is::<prim::CudaCopyFromDevice<T>>() || op.is::<luminal::op::Function>() {
+        // Already on the host
+        node
+    } else {
+        let device =
+            graph_device::<T>(graph).expect("Graph has no tensors copied to a CUDA device");
+        graph
+            .add_op(prim::CudaCopyFromDevice::<T>::new(device))
+            .input(node, 0, ShapeTracker::new(&[]))
+            .finish()
+    };
+    graph.no_delete.insert(id);
+    graph.to_retrieve.insert(id);
+    graph.retrieval_map.insert(node, id);
+    graph.compile((), ());
+    id
+}
+
+/// Stop retrieving a tensor from a compiled graph, removing the copy back to the host if nothing else uses it
+pub fn remove_retrieval<T: CudaFloat>(graph: &mut Graph, original: NodeIndex) {
+    let Some(id) = graph.retrieval_map.remove(&original) else {
+        return;
+    };
+    graph.no_delete.remove(&id);
+    graph.to_retrieve.remove(&id);
+    graph.tensors.retain(|(n, _), _| *n != id);
+    if trace::unwrap_op(graph.node_weight(id).unwrap().as_ref())
+        .as_any()
+        .is::<prim::CudaCopyFromDevice<T>>()
+        && graph
+            .neighbors_directed(id, petgraph::Direction::Outgoing)
+            .count()
+            == 0
+    {
+        graph.remove_node(id);
+    }
+    graph.compile((), ());
+}
+
+/// Execute only the nodes needed to produce another node's output in a compiled graph, and return it on the host.
+/// A copy back to the host is inserted for the run and removed after, leaving the graph as it was compiled.
+pub fn execute_until<T: CudaFloat>(graph: &mut Graph, node: NodeIndex) -> Tensor {
+    // A tensor that's already retrieved keeps its copy
+    let retrieved = graph.retrieval_map.contains_key(&node);
+    let id = if retrieved {
+        graph.retrieval_map[&node]
+    } else {
+        add_retrieval::<T>(graph, node)
+    };
+    graph.execute_until(id);
+    let tensor = graph.tensors.remove(&(id, 0)).unwrap();
+    if !retrieved {
+        remove_retrieval::<T>(graph, node);
+    }
+    tensor
+}
+
+/// Copy device data onto another device.
+///
+/// A peer-to-peer copy is used when the destination device can access the source device's memory
+/// (both GPUs must report peer access through `cuDeviceCanAccessPeer`, e.g. over NVLink or a shared PCIe switch),
+/// otherwise the data is staged through host memory.
+pub fn transfer_cuda_data<T: CudaFloat>(
+    src: &CudaData<T>,
+    dst_device: &Arc<CudaDevice>,
+) -> CudaData<T> {
+    let src_device = src.0.device();
+    if src_device.ordinal() == dst_device.ordinal() {
+        return CudaData::new(alloc_copy(&src.0).unwrap());
+    }
+    let mut dst = unsafe { alloc::<T>(dst_device, src.0.len()) }.unwrap();
+    let mut can_access_peer = 0;
+    unsafe {
+        sys::cuDeviceCanAccessPeer(
+            &mut can_access_peer,
+            *dst_device.cu_device(),
+            *src_device.cu_device(),
+        )
+        .result()
+        .unwrap();
+    }
+    if can_access_peer != 0 {
+        synchronize_device_stream(&src_device).unwrap();
+        dst_device.bind_to_thread().unwrap();
+        unsafe {
+            match sys::cuCtxEnablePeerAccess(*src_device.cu_primary_ctx(), 0) {
+                sys::CUresult::CUDA_SUCCESS
+                | sys::CUresult::CUDA_ERROR_PEER_ACCESS_ALREADY_ENABLED => {}
+                e => e.result().unwrap(),
+            }
+            on_device_stream(dst_device, |stream| {
+                sys::cuMemcpyPeerAsync(
+                    *dst.device_ptr_mut(),
+                    *dst_device.cu_primary_ctx(),
+                    *src.0.device_ptr(),
+                    *src_device.cu_primary_ctx(),
+                    src.0.len() * std::mem::size_of::<T>(),
+                    stream,
+                )
+            })
+            .result()
+            .unwrap();
+        }
+        synchronize_device_stream(dst_device).unwrap();
+    } else {
+        let host = download(&src.0).unwrap();
+        unsafe { copy_to_device(dst_device, *dst.device_ptr_mut(), &host) }.unwrap();
+    }
+    CudaData::new(dst)
+}
+
+/// Let nodes in another graph on the same device read the device data of nodes in this graph,
+/// without copying it. Unlike `transfer_data`, the source graph keeps its data.
+pub fn share_weights<T: CudaFloat, A: ToIds, B: ToIds>(
+    srcs: A,
+    src_graph: &Graph,
+    dests: B,
+    dest_graph: &mut Graph,
+) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
+        let mut output_num = 0;
+        while let Some(tensor) = src_graph.tensors.get(&(src, output_num)) {
+            let data = tensor
+                .data
+                .as_any()
+                .downcast_ref::<CudaData<T>>()
+                .expect("Only device data can be shared");
+            dest_graph
+                .tensors
+                .insert((dest, output_num), Tensor::new(data.share()));
+            output_num += 1;
+        }
+    }
+}
+
+/// Raw little-endian f16 bytes on the host, such as an f16 weight read straight from disk.
+/// `CudaCopyToDevice` uploads these verbatim when the device dtype is f16, skipping the f32 round trip.
+#[derive(Debug, Clone)]
+pub struct RawF16Bytes(pub Vec<u8>);
+
+impl Data for RawF16Bytes {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+impl Data for CudaData<u8> {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
 impl CudaFloat for f16 {
     fn from_f32(a: f32) -> Self {
         f16::from_f32(a)
@@ -93,43 +554,806 @@ impl CudaFloat for f16 {
     }
 }
 
+impl CudaFloat for bf16 {
+    fn from_f32(a: f32) -> Self {
+        bf16::from_f32(a)
+    }
+    fn to_f32(self) -> f32 {
+        self.to_f32()
+    }
+    fn is_f32() -> bool {
+        false
+    }
+    fn type_name() -> &'static str {
+        "__nv_bfloat16"
+    }
+}
+
+impl CudaFloat for f64 {
+    fn from_f32(a: f32) -> Self {
+        a as f64
+    }
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn is_f32() -> bool {
+        false
+    }
+    fn type_name() -> &'static str {
+        "double"
+    }
+    fn accumulator_type_name() -> &'static str {
+        "double"
+    }
+}
+
+/// Integer element types, for token ids and indices that f32 can't hold exactly past 2^24
+pub trait CudaInt:
+    std::fmt::Debug
+    + Copy
+    + luminal_cudarc::driver::DeviceRepr
+    + std::marker::Unpin
+    + luminal_cudarc::driver::ValidAsZeroBits
+    + 'static
+{
+    fn type_name() -> &'static str;
+}
+
+impl CudaInt for i32 {
+    fn type_name() -> &'static str {
+        "int"
+    }
+}
+
+impl CudaInt for u32 {
+    fn type_name() -> &'static str {
+        "unsigned int"
+    }
+}
+
+impl Data for CudaData<i32> {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+impl Data for CudaData<u32> {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum CudaError {
+    /// An allocation would have pushed the outstanding device memory past the budget
+    BudgetExceeded {
+        used: usize,
+        requested: usize,
+        limit: usize,
+    },
+    Driver(DriverError),
+    /// Bytes given for a buffer that aren't a whole number of elements
+    ByteLength {
+        len: usize,
+        element_size: usize,
+    },
+    /// Ops left in a compiled graph that don't run on the device
+    UnsupportedOps(Vec<String>),
+    /// A kernel that didn't compile or load
+    Compile(CudaCompileError),
+    /// An op built with settings it can't run with
+    InvalidOp(String),
+}
+
+impl std::fmt::Display for CudaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CudaError::BudgetExceeded {
+                used,
+                requested,
+                limit,
+            } => write!(
+                f,
+                "Allocating {requested} bytes would exceed the CUDA memory budget of {limit} bytes ({used} bytes currently in use)"
+            ),
+            CudaError::Driver(e) => write!(f, "{e}"),
+            CudaError::ByteLength { len, element_size } => write!(
+                f,
+                "Byte length {len} isn't a multiple of the element size {element_size}"
+            ),
+            CudaError::UnsupportedOps(ops) => write!(
+                f,
+                "The CUDA backend doesn't support these ops: {}",
+                ops.join(", ")
+            ),
+            CudaError::Compile(e) => write!(f, "{e}"),
+            CudaError::InvalidOp(reason) => write!(f, "Invalid op: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for CudaError {}
+
+impl From<DriverError> for CudaError {
+    fn from(value: DriverError) -> Self {
+        CudaError::Driver(value)
+    }
+}
+
+impl From<CudaCompileError> for CudaError {
+    fn from(value: CudaCompileError) -> Self {
+        CudaError::Compile(value)
+    }
+}
+
+thread_local! {
+    static KERNEL_CAPTURE: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    static NVRTC_COMPILES: Cell<usize> = const { Cell::new(0) };
+    static KERNEL_LAUNCHES: Cell<(usize, u64)> = const { Cell::new((0, 0)) };
+    static BUFFER_REUSE: Cell<bool> = const { Cell::new(false) };
+    static BUFFER_POOL: RefCell<BufferPool> = RefCell::new(BufferPool::default());
+}
+
+/// Cap the bytes of outstanding `CudaData` allocations on the device with this ordinal, from any thread.
+/// Allocations past the cap fail with `CudaError::BudgetExceeded` instead of reaching the driver.
+/// `None` removes the cap.
+pub fn set_memory_budget(ordinal: usize, limit: Option<usize>) {
+    device_entry(ordinal)
+        .unwrap()
+        .memory
+        .limit
+        .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// Bytes of `CudaData` currently allocated on the device with this ordinal, counting buffers pooled for reuse
+pub fn memory_in_use(ordinal: usize) -> usize {
+    set_up_device(ordinal)
+        .map(|entry| entry.memory.used.load(Ordering::Relaxed))
+        .unwrap_or_default()
+}
+
+/// The bytes in use on a device, and the cap on them
+struct MemoryBudget {
+    /// Cap on `used`, `usize::MAX` when there's none
+    limit: AtomicUsize,
+    /// Bytes of live `CudaData` and pooled buffers on the device, from every thread
+    used: AtomicUsize,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self {
+            limit: AtomicUsize::new(usize::MAX),
+            used: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MemoryBudget {
+    /// Count `requested` more bytes as in use, unless that goes past the limit. The check and the count are one
+    /// atomic update, so allocations racing on other threads can't all fit in the same room under the limit.
+    fn reserve(&self, requested: usize) -> Result<(), CudaError> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(requested).filter(|total| *total <= limit)
+            })
+            .map(|_| ())
+            .map_err(|used| CudaError::BudgetExceeded {
+                used,
+                requested,
+                limit,
+            })
+    }
+
+    fn release(&self, bytes: usize) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(bytes))
+            });
+    }
+}
+
+fn reserve_memory(device: &CudaDevice, requested: usize) -> Result<(), CudaError> {
+    device_entry(device.ordinal())?.memory.reserve(requested)
+}
+
+fn track_memory(ordinal: usize, bytes: usize) {
+    if let Ok(entry) = device_entry(ordinal) {
+        entry.memory.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+fn release_memory(ordinal: usize, bytes: usize) {
+    if let Some(entry) = set_up_device(ordinal) {
+        entry.memory.release(bytes);
+    }
+}
+
+/// Freed device buffers held for reuse on this thread, keyed by device ordinal and size in bytes
+#[derive(Default)]
+struct BufferPool(FxHashMap<(usize, usize), Vec<CudaSlice<u8>>>);
+
+impl BufferPool {
+    fn clear(&mut self) {
+        for ((ordinal, size), buffers) in self.0.drain() {
+            release_memory(ordinal, size * buffers.len());
+            buffers.into_iter().for_each(free_buffer);
+        }
+    }
+}
+
+impl Drop for BufferPool {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// Keep device buffers freed on this thread in a pool keyed by size, and hand them back out for later
+/// allocations of the same size instead of going to the driver. Graphs with stable shapes, like a decode
+/// loop, then reuse the same buffers every execution. Disabling the reuse releases the pooled buffers.
+///
+/// The setting and the pool belong to the calling thread: only buffers freed on it are pooled, and only
+/// allocations on it reuse them, so execute a graph from one thread to get its buffers back. Pooled buffers
+/// count towards the memory budget. An allocation past the budget frees this thread's pool and tries again
+/// before failing, but pools on other threads keep their buffers until those threads clear them or exit.
+pub fn set_buffer_reuse(enabled: bool) {
+    BUFFER_REUSE.with(|r| r.set(enabled));
+    if !enabled {
+        clear_buffer_pool();
+    }
+}
+
+fn buffer_reuse() -> bool {
+    BUFFER_REUSE.with(|r| r.get())
+}
+
+/// Free the buffers pooled for reuse on this thread
+pub fn clear_buffer_pool() {
+    let _ = BUFFER_POOL.try_with(|p| p.borrow_mut().clear());
+}
+
+/// Take a pooled buffer of the right size, if there is one
+fn pooled_buffer<T: DeviceRepr>(device: &Arc<CudaDevice>, len: usize) -> Option<CudaSlice<T>> {
+    if !buffer_reuse() {
+        return None;
+    }
+    let size = len * std::mem::size_of::<T>();
+    // The buffer stays counted as in use
+    let bytes = BUFFER_POOL.with(|p| p.borrow_mut().0.get_mut(&(device.ordinal(), size))?.pop())?;
+    Some(unsafe { device.upgrade_device_ptr::<T>(bytes.leak(), len) })
+}
+
+/// A zero-length buffer for zero-element tensors. CUDA can't allocate zero bytes, so this holds a one element
+/// allocation that is freed as usual when the slice drops.
+fn empty_buffer<T: DeviceRepr>(device: &Arc<CudaDevice>) -> Result<CudaSlice<T>, CudaError> {
+    let slice = unsafe { driver_alloc::<T>(device, 1)? };
+    Ok(unsafe { device.upgrade_device_ptr::<T>(slice.leak(), 0) })
+}
+
+/// Allocate a zeroed buffer, respecting the memory budget
+fn alloc_zeros<T: ValidAsZeroBits + DeviceRepr>(
+    device: &Arc<CudaDevice>,
+    len: usize,
+) -> Result<CudaSlice<T>, CudaError> {
+    let mut slice = unsafe { alloc::<T>(device, len) }?;
+    if len > 0 {
+        on_device_stream(device, |stream| unsafe {
+            result::memset_d8_async(*slice.device_ptr_mut(), 0, slice.num_bytes(), stream)
+        })?;
+    }
+    Ok(slice)
+}
+
+/// Allocate an uninitialized buffer, respecting the memory budget. The buffer counts as in use on its device
+/// until it's dropped as a `CudaData`, so it should end up in one.
+///
+/// # Safety
+/// The buffer must be written before it is read
+unsafe fn alloc<T: DeviceRepr>(
+    device: &Arc<CudaDevice>,
+    len: usize,
+) -> Result<CudaSlice<T>, CudaError> {
+    if len == 0 {
+        return empty_buffer(device);
+    }
+    if let Some(slice) = pooled_buffer::<T>(device, len) {
+        return Ok(slice);
+    }
+    let size = len * std::mem::size_of::<T>();
+    reserve_memory(device, size).or_else(|_| {
+        // Buffers pooled on this thread may be what's taking up the budget
+        clear_buffer_pool();
+        reserve_memory(device, size)
+    })?;
+    driver_alloc::<T>(device, len).map_err(|e| {
+        release_memory(device.ordinal(), size);
+        e.into()
+    })
+}
+
+/// Allocate memory from the driver, on the device's stream if it has one
+unsafe fn driver_alloc<T: DeviceRepr>(
+    device: &Arc<CudaDevice>,
+    len: usize,
+) -> Result<CudaSlice<T>, DriverError> {
+    let Some(stream) = device_stream(device) else {
+        return device.alloc::<T>(len);
+    };
+    device.bind_to_thread()?;
+    let ptr = result::malloc_async(stream.stream, len * std::mem::size_of::<T>())?;
+    Ok(device.upgrade_device_ptr(ptr, len))
+}
+
+/// Free a buffer once the work queued on its device's stream is done with it
+fn free_buffer<T>(slice: CudaSlice<T>) {
+    let device = slice.device();
+    let Some(stream) = device_stream(&device) else {
+        return drop(slice);
+    };
+    device.bind_to_thread().unwrap();
+    unsafe { result::free_async(slice.leak(), stream.stream) }.unwrap();
+}
+
+/// Copy a buffer into a new allocation, respecting the memory budget
+fn alloc_copy<T: DeviceRepr>(slice: &CudaSlice<T>) -> Result<CudaSlice<T>, CudaError> {
+    let device = slice.device();
+    let mut copy = unsafe { alloc::<T>(&device, slice.len()) }?;
+    if slice.len() > 0 {
+        on_device_stream(&device, |stream| unsafe {
+            result::memcpy_dtod_async(
+                *copy.device_ptr_mut(),
+                *slice.device_ptr(),
+                slice.num_bytes(),
+                stream,
+            )
+        })?;
+    }
+    Ok(copy)
+}
+
+/// Upload host data to a new buffer, respecting the memory budget
+fn upload<T: DeviceRepr>(device: &Arc<CudaDevice>, data: &[T]) -> Result<CudaSlice<T>, CudaError> {
+    let mut slice = unsafe { alloc::<T>(device, data.len()) }?;
+    unsafe { copy_to_device(device, *slice.device_ptr_mut(), data) }?;
+    Ok(slice)
+}
+
+/// Download a buffer to the host once the work writing it is done
+fn download<T: DeviceRepr + ValidAsZeroBits>(slice: &CudaSlice<T>) -> Result<Vec<T>, DriverError> {
+    let mut data = std::iter::repeat_with(|| unsafe { std::mem::zeroed() })
+        .take(slice.len())
+        .collect::<Vec<T>>();
+    unsafe { copy_to_host(&slice.device(), &mut data, *slice.device_ptr()) }?;
+    Ok(data)
+}
+
+/// Copy host data to device memory in order with the device's stream, returning once the host data can be reused
+///
+/// # Safety
+/// `dst` must point to at least as many bytes as `src` holds
+unsafe fn copy_to_device<T>(
+    device: &CudaDevice,
+    dst: sys::CUdeviceptr,
+    src: &[T],
+) -> Result<(), DriverError> {
+    if src.is_empty() {
+        return Ok(());
+    }
+    on_device_stream(device, |stream| {
+        result::memcpy_htod_async(dst, src, stream)?;
+        result::stream::synchronize(stream)
+    })
+}
+
+/// Copy device memory to the host once the work queued on the device's stream is done
+///
+/// # Safety
+/// `src` must point to at least as many bytes as `dst` holds
+unsafe fn copy_to_host<T>(
+    device: &CudaDevice,
+    dst: &mut [T],
+    src: sys::CUdeviceptr,
+) -> Result<(), DriverError> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+    on_device_stream(device, |stream| {
+        result::memcpy_dtoh_async(dst, src, stream)?;
+        result::stream::synchronize(stream)
+    })
+}
+
+/// Run ops on `device` on `stream`, forked from it, instead of the device's default stream. This holds for every
+/// graph and thread using the device. Their buffers are allocated, copied and freed on the stream too, so work
+/// submitted to the default stream or any other stream in the meantime overlaps with theirs, and pinned staging
+/// copies (see `PinnedStagingCompiler`) become asynchronous. Retrieving a tensor synchronizes the stream.
+/// `None` goes back to the default stream, once the work queued on the previous stream is done.
+///
+/// Panics if the stream belongs to another device, or the device can't allocate memory in stream order.
+pub fn set_cuda_stream(device: &Arc<CudaDevice>, stream: Option<Arc<CudaStream>>) {
+    if let Some(stream) = &stream {
+        let mut context = std::ptr::null_mut();
+        unsafe { sys::cuStreamGetCtx(stream.stream, &mut context) }
+            .result()
+            .unwrap();
+        assert!(
+            context == *device.cu_primary_ctx(),
+            "The stream wasn't forked from device {}",
+            device.ordinal()
+        );
+        assert!(
+            device
+                .attribute(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_MEMORY_POOLS_SUPPORTED)
+                .unwrap()
+                != 0,
+            "Device {} can't allocate memory on a stream",
+            device.ordinal()
+        );
+        // Buffers written on the default stream so far are ready before the stream uses them
+        stream.wait_for_default().unwrap();
+    }
+    let entry = device_entry(device.ordinal()).unwrap();
+    let previous = std::mem::replace(&mut *entry.stream.lock().unwrap(), stream.map(DeviceStream));
+    if let Some(DeviceStream(previous)) = previous {
+        // Buffers used on the previous stream can't be freed or reused elsewhere before it's done with them
+        unsafe { result::stream::synchronize(previous.stream) }.unwrap();
+    }
+}
+
+/// The stream set for the device with `set_cuda_stream`, if any
+fn device_stream(device: &CudaDevice) -> Option<Arc<CudaStream>> {
+    let entry = set_up_device(device.ordinal())?;
+    let stream = entry.stream.lock().unwrap().clone()?;
+    Some(stream.0)
+}
+
+/// Call `f` with the stream ops on the device run on, either the one set with `set_cuda_stream` or the default stream
+fn on_device_stream<R>(device: &CudaDevice, f: impl FnOnce(sys::CUstream) -> R) -> R {
+    let stream = device_stream(device);
+    f(stream.as_ref().map_or(*device.cu_stream(), |s| s.stream))
+}
+
+/// Wait for the work queued on the device's stream
+fn synchronize_device_stream(device: &CudaDevice) -> Result<(), DriverError> {
+    on_device_stream(device, |stream| unsafe {
+        result::stream::synchronize(stream)
+    })
+}
+
+/// Launch a kernel loaded on `device` on the stream set for it with `set_cuda_stream`, or its default stream
+trait LaunchOnDeviceStream<Params> {
+    unsafe fn launch_on_device_stream(
+        self,
+        device: &CudaDevice,
+        cfg: LaunchConfig,
+        params: Params,
+    ) -> Result<(), DriverError>;
+}
+
+impl<Params> LaunchOnDeviceStream<Params> for CudaFunction
+where
+    CudaFunction: LaunchAsync<Params>,
+{
+    unsafe fn launch_on_device_stream(
+        self,
+        device: &CudaDevice,
+        cfg: LaunchConfig,
+        params: Params,
+    ) -> Result<(), DriverError> {
+        // Zero-element tensors have nothing to compute, and CUDA rejects empty grids
+        if cfg.grid_dim.0 == 0 || cfg.grid_dim.1 == 0 || cfg.grid_dim.2 == 0 {
+            return Ok(());
+        }
+        let threads = [cfg.grid_dim, cfg.block_dim]
+            .into_iter()
+            .map(|(x, y, z)| x as u64 * y as u64 * z as u64)
+            .product::<u64>();
+        KERNEL_LAUNCHES.with(|l| {
+            let (launches, total_threads) = l.get();
+            l.set((launches + 1, total_threads + threads));
+        });
+        match device_stream(device) {
+            Some(stream) => self.launch_on_stream(&stream, cfg, params),
+            None => self.launch(cfg, params),
+        }
+    }
+}
+
+/// Kernels launched on this thread so far, and the total threads they were launched with
+fn kernel_launches() -> (usize, u64) {
+    KERNEL_LAUNCHES.with(|l| l.get())
+}
+
+/// Threads per block elementwise ops launch with, unless changed with `ElementwiseBlockSizeCompiler`
+const DEFAULT_BLOCK_SIZE: u32 = 1024;
+
+/// Launch the elementwise ops of a compiled graph with this many threads per block. Each op keeps the size in
+/// its `block_size` field, which can also be changed directly. Run it after `CudaCompiler`.
+#[derive(Debug)]
+pub struct ElementwiseBlockSizeCompiler(u32);
+
+impl ElementwiseBlockSizeCompiler {
+    pub fn new(threads: u32) -> Self {
+        assert!(
+            (1..=1024).contains(&threads),
+            "Block size must be between 1 and 1024 threads, got {threads}"
+        );
+        Self(threads)
+    }
+}
+
+impl Compiler for ElementwiseBlockSizeCompiler {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            graph
+                .node_weight_mut(node)
+                .unwrap()
+                .custom("elementwise_block_size", Box::new(self.0));
+        }
+    }
+}
+
+/// The C type of the element index and count in a kernel reading through `shapes`. Tensors past what an `int`
+/// indexes (2^31 - 1 elements) get 64-bit indexes, as do dynamic shapes, which can grow that large.
+fn index_type(shapes: &[ShapeTracker]) -> &'static str {
+    let too_large = |n: BigExpression| n.to_usize().is_none_or(|n| n > i32::MAX as usize);
+    if shapes
+        .iter()
+        .any(|s| too_large(s.n_elements()) || too_large(s.n_physical_elements()))
+    {
+        "long long"
+    } else {
+        "int"
+    }
+}
+
+/// Launch a thread per element in blocks of `block_size` threads
+fn elementwise_launch_config(numel: usize, block_size: u32) -> LaunchConfig {
+    LaunchConfig {
+        grid_dim: (grid_size(numel, block_size), 1, 1),
+        block_dim: (block_size, 1, 1),
+        shared_mem_bytes: 0,
+    }
+}
+
+/// Blocks of `block_size` threads needed to cover `n` threads, computed in 64 bits
+fn grid_size(n: usize, block_size: u32) -> u32 {
+    let blocks = (n as u64).div_ceil(block_size as u64);
+    // The x dimension of a grid holds at most 2^31 - 1 blocks
+    assert!(
+        blocks <= i32::MAX as u64,
+        "Launching {n} threads in blocks of {block_size} needs {blocks} blocks, past the grid limit of {}",
+        i32::MAX
+    );
+    blocks as u32
+}
+
+/// Compile kernels for the device with this ordinal for a specific architecture, such as `"sm_80"`, rather than
+/// the device's own. Useful when cross-compiling. `None` goes back to detecting it.
+pub fn set_cuda_arch(ordinal: usize, arch: Option<&str>) {
+    *device_entry(ordinal).unwrap().arch.lock().unwrap() = arch.map(intern_kernel_name);
+}
+
+/// Directory compiled PTX is cached in, if any
+static PTX_CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Keep compiled PTX in this directory, so kernels compiled by earlier runs are loaded rather than recompiled.
+/// Entries are keyed by the kernel source, the architecture and the NVRTC version, so upgrading the
+/// toolkit doesn't load stale PTX. The cache is off until a directory is set, and `None` turns it back off.
+pub fn set_ptx_cache_dir(dir: Option<PathBuf>) {
+    *PTX_CACHE_DIR.lock().unwrap() = dir;
+}
+
+/// The NVRTC version kernels are compiled with, if it can be queried
+fn nvrtc_version() -> Option<(i32, i32)> {
+    static VERSION: OnceLock<Option<(i32, i32)>> = OnceLock::new();
+    *VERSION.get_or_init(|| {
+        let (mut major, mut minor) = (0, 0);
+        unsafe { luminal_cudarc::nvrtc::sys::nvrtcVersion(&mut major, &mut minor) }
+            .result()
+            .ok()
+            .map(|_| (major, minor))
+    })
+}
+
+/// Directory of CUDA headers set with `set_cuda_include_path`
+static INCLUDE_OVERRIDE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Compile kernels against the CUDA headers in this directory, rather than searching for them.
+/// `None` goes back to searching.
+pub fn set_cuda_include_path(dir: Option<PathBuf>) {
+    *INCLUDE_OVERRIDE.lock().unwrap() = dir;
+}
+
+/// The directory of CUDA headers kernels are compiled against: the first containing `cuda_fp16.h` out of
+/// the override from `set_cuda_include_path`, `$CUDA_PATH`, `$CUDA_HOME`, `$CUDA_ROOT` and `$CONDA_PREFIX`,
+/// then the usual install locations. The error names every directory searched.
+pub fn cuda_include_path() -> Result<PathBuf, String> {
+    let candidates = INCLUDE_OVERRIDE
+        .lock()
+        .unwrap()
+        .clone()
+        .into_iter()
+        .chain(
+            ["CUDA_PATH", "CUDA_HOME", "CUDA_ROOT", "CONDA_PREFIX"]
+                .into_iter()
+                .filter_map(|var| std::env::var_os(var))
+                .map(|root| PathBuf::from(root).join("include")),
+        )
+        .chain(
+            [
+                "/usr/local/cuda/include",
+                "/opt/cuda/include",
+                "/usr/include",
+            ]
+            .into_iter()
+            .map(PathBuf::from),
+        )
+        .collect::<Vec<_>>();
+    candidates
+        .iter()
+        .find(|dir| dir.join("cuda_fp16.h").is_file())
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "Couldn't find cuda_fp16.h in any of: {}. Set CUDA_PATH or call set_cuda_include_path.",
+                candidates.iter().map(|d| d.display()).join(", ")
+            )
+        })
+}
+
+/// The architecture kernels get compiled for on this device: the override from `set_cuda_arch` if set,
+/// otherwise the device's compute capability, falling back to `sm_75` if it can't be queried.
+pub fn cuda_arch(device: &CudaDevice) -> &'static str {
+    if let Some(arch) = set_up_device(device.ordinal()).and_then(|e| *e.arch.lock().unwrap()) {
+        return arch;
+    }
+    let capability = |attrib| device.attribute(attrib).ok();
+    match (
+        capability(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR),
+        capability(sys::CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR),
+    ) {
+        (Some(major), Some(minor)) => intern_kernel_name(&format!("sm_{major}{minor}")),
+        _ => "sm_75",
+    }
+}
+
+/// Pinned host memory, grown as needed and reused across copies. Clones start out empty.
+struct PinnedBuffer<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T> Default for PinnedBuffer<T> {
+    fn default() -> Self {
+        Self {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> Clone for PinnedBuffer<T> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl<T: DeviceRepr + ValidAsZeroBits> PinnedBuffer<T> {
+    /// Get a pinned slice of `len` elements, reallocating if the buffer is too small
+    fn slice_mut(&mut self, device: &Arc<CudaDevice>, len: usize) -> &mut [T] {
+        if self.len < len {
+            self.free();
+            device.bind_to_thread().unwrap();
+            let mut ptr = std::ptr::null_mut();
+            unsafe {
+                sys::cuMemAllocHost_v2(&mut ptr, len * std::mem::size_of::<T>())
+                    .result()
+                    .unwrap();
+                // Zero the new memory so it's always valid to read
+                std::ptr::write_bytes(ptr as *mut T, 0, len);
+            }
+            self.ptr = ptr as *mut T;
+            self.len = len;
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, len) }
+    }
+}
+
+impl<T> PinnedBuffer<T> {
+    fn free(&mut self) {
+        if !self.ptr.is_null() {
+            unsafe {
+                sys::cuMemFreeHost(self.ptr as *mut c_void)
+                    .result()
+                    .unwrap()
+            };
+            self.ptr = std::ptr::null_mut();
+            self.len = 0;
+        }
+    }
+}
+
+impl<T> Drop for PinnedBuffer<T> {
+    fn drop(&mut self) {
+        self.free();
+    }
+}
+
 fn expr_to_cuda_string(expr: BigExpression) -> String {
     let mut symbols = vec![];
     for term in expr.terms {
         let new_symbol = match term {
+            // Constants past an int are 64-bit literals, so the math around them is too
+            Term::Num(n) if i32::try_from(n).is_err() => format!("({n}LL)"),
+            // Parenthesized so subtracting a negative doesn't render as a decrement
+            Term::Num(n) if n < 0 => format!("({n})"),
             Term::Num(n) => n.to_string(),
             Term::Var(c) => {
                 if c == 'z' {
-                    "(int)idx".to_string()
+                    "idx".to_string()
                 } else {
                     c.to_string()
                 }
             }
-            Term::Max => format!(
-                "max((int){}, (int){})",
-                symbols.pop().unwrap(),
-                symbols.pop().unwrap()
-            ),
-            Term::Min => format!(
-                "min((int){}, (int){})",
-                symbols.pop().unwrap(),
-                symbols.pop().unwrap()
-            ),
-            _ => format!(
-                "({}{term:?}{})",
-                symbols.pop().unwrap(),
-                symbols.pop().unwrap()
-            ),
+            term => {
+                // The top of the stack is the left operand, as in BigExpression::exec
+                let (a, b) = (symbols.pop().unwrap(), symbols.pop().unwrap());
+                let op = match term {
+                    Term::Max => "max",
+                    Term::Min => "min",
+                    Term::Add => "+",
+                    Term::Sub => "-",
+                    Term::Mul => "*",
+                    Term::Div => "/",
+                    Term::Mod => "%",
+                    // Comparisons and logic give 0 or 1, as in BigExpression::exec
+                    Term::And => "&&",
+                    Term::Or => "||",
+                    Term::Gte => ">=",
+                    Term::Lt => "<",
+                    Term::Num(_) | Term::Var(_) => unreachable!(),
+                };
+                if matches!(term, Term::Max | Term::Min) {
+                    // Both operands take the type of the kernel's index, which picks a single overload without
+                    // truncating 64-bit index math
+                    format!("{op}((decltype(idx))({a}), (decltype(idx))({b}))")
+                } else {
+                    format!("({a} {op} {b})")
+                }
+            }
         };
         symbols.push(new_symbol);
     }
     symbols.pop().unwrap()
 }
 
+/// Known bounds of the variables in `shape`'s expressions. Kernels only evaluate them for logical indexes below
+/// the element count, which lets static shapes fold their index math down before it's rendered.
+fn index_bounds(shape: &ShapeTracker) -> FxHashMap<char, usize> {
+    shape
+        .n_elements()
+        .to_usize()
+        .map(|n| ('z', n))
+        .into_iter()
+        .collect()
+}
+
 fn get_idx_valid_exps(shape: ShapeTracker) -> (String, String) {
+    let bounds = index_bounds(&shape);
     (
-        expr_to_cuda_string(shape.index_expression()),
-        expr_to_cuda_string(shape.valid_expression()),
+        expr_to_cuda_string(shape.index_expression().simplify(&bounds)),
+        expr_to_cuda_string(shape.valid_expression().simplify(&bounds)),
     )
 }
 
@@ -183,6 +1407,15 @@ fn hash<T: std::hash::Hash>(obj: T) -> u64 {
     hasher.finish()
 }
 
+/// What a cuda op returns from `Operator::custom` for keys it doesn't handle itself. Answering the `"cuda_op"` key
+/// marks it as running on the device for `check_cuda_ops`, so ops outside this crate should return this too.
+pub fn cuda_op_marker(key: &str) -> Option<Box<dyn std::any::Any>> {
+    if key == "cuda_op" {
+        return Some(Box::new(()));
+    }
+    None
+}
+
 fn get_buffer_from_tensor<'a, T: 'static>(tensor: &'a InputTensor) -> &'a CudaSlice<T> {
     &tensor
         .borrowed()
@@ -204,25 +1437,201 @@ fn input_dyn_dims(
     }
 }
 
-fn compile_and_load_kernel(mut code: String, device: &Arc<CudaDevice>) -> CudaFunction {
+/// A device set up by `cuda_device`, with the memory its buffers use
+struct DeviceEntry {
+    device: Arc<CudaDevice>,
+    /// Bytes of live `CudaData` and pooled buffers on the device, from every thread
+    memory: MemoryBudget,
+    /// Architecture to compile kernels for instead of the device's own
+    arch: Mutex<Option<&'static str>>,
+    /// Stream ops on the device run on instead of the default stream, set with `set_cuda_stream`
+    stream: Mutex<Option<DeviceStream>>,
+}
+
+/// A stream shared by every thread using its device
+#[derive(Clone)]
+struct DeviceStream(Arc<CudaStream>);
+
+// Safety: CUDA streams can be used from any thread, like the device they belong to
+unsafe impl Send for DeviceStream {}
+unsafe impl Sync for DeviceStream {}
+
+/// Devices set up so far, by ordinal
+static DEVICES: OnceLock<Mutex<FxHashMap<usize, Arc<DeviceEntry>>>> = OnceLock::new();
+
+/// The entry for a device, setting it up if it hasn't been yet
+fn device_entry(ordinal: usize) -> Result<Arc<DeviceEntry>, DriverError> {
+    let mut devices = DEVICES.get_or_init(Default::default).lock().unwrap();
+    if let Some(entry) = devices.get(&ordinal) {
+        return Ok(entry.clone());
+    }
+    let entry = Arc::new(DeviceEntry {
+        device: CudaDevice::new(ordinal)?,
+        memory: MemoryBudget::default(),
+        arch: Mutex::new(None),
+        stream: Mutex::new(None),
+    });
+    devices.insert(ordinal, entry.clone());
+    Ok(entry)
+}
+
+/// The entry for a device, if it's been set up
+fn set_up_device(ordinal: usize) -> Option<Arc<DeviceEntry>> {
+    DEVICES.get()?.lock().unwrap().get(&ordinal).cloned()
+}
+
+/// Ordinals of the devices set up so far
+pub(crate) fn set_up_devices() -> Vec<usize> {
+    DEVICES
+        .get()
+        .map(|d| d.lock().unwrap().keys().copied().sorted().collect())
+        .unwrap_or_default()
+}
+
+/// The shared handle to the device with this ordinal, so every compiler and graph uses the same one.
+/// The device is set up the first time it's asked for.
+pub fn cuda_device(ordinal: usize) -> Arc<CudaDevice> {
+    try_cuda_device(ordinal).unwrap()
+}
+
+/// Like `cuda_device`, but returns an error if the device can't be set up
+pub fn try_cuda_device(ordinal: usize) -> Result<Arc<CudaDevice>, DriverError> {
+    Ok(device_entry(ordinal)?.device.clone())
+}
+
+type CastKernels = FxHashMap<(usize, &'static str, &'static str), CudaFunction>;
+
+/// Kernels loaded by `CudaData::cast`, by device ordinal and source and destination type
+static CAST_KERNELS: OnceLock<Mutex<CastKernels>> = OnceLock::new();
+
+/// Kernel names passed to the driver, which must live for the rest of the program
+static KERNEL_NAMES: OnceLock<Mutex<FxHashSet<&'static str>>> = OnceLock::new();
+
+/// Get a static copy of a kernel name. Each distinct name is only leaked once, no matter how
+/// many times or onto how many devices its kernel gets loaded.
+fn intern_kernel_name(name: &str) -> &'static str {
+    let mut names = KERNEL_NAMES.get_or_init(Default::default).lock().unwrap();
+    if let Some(interned) = names.get(name) {
+        return interned;
+    }
+    let interned: &'static str = name.to_string().leak();
+    names.insert(interned);
+    interned
+}
+
+/// Start keeping the source of each kernel compiled or loaded on this thread
+fn capture_kernels() {
+    KERNEL_CAPTURE.with(|k| *k.borrow_mut() = Some(vec![]));
+}
+
+/// Stop capturing kernels, returning the sources captured
+fn take_captured_kernels() -> Vec<String> {
+    KERNEL_CAPTURE
+        .with(|k| k.borrow_mut().take())
+        .unwrap_or_default()
+}
+
+/// Compile a kernel named `kernel` (or load it from the PTX cache) onto the device, returning an error with
+/// NVRTC's log and the source if it doesn't compile or load. Op constructors pass this error on, but the
+/// compilers building them can't return one, so they panic with it.
+pub fn compile_and_load_kernel(
+    mut code: String,
+    device: &Arc<CudaDevice>,
+) -> Result<CudaFunction, CudaCompileError> {
+    // Kernels are written against cuda_fp16.h, which doesn't declare the bf16 type and intrinsics
+    if code.contains("__nv_bfloat16") && !code.contains("cuda_bf16.h") {
+        code = format!("#include \"cuda_bf16.h\"\n{code}");
+    }
     let name = format!("kernel_{}", hash(&code));
     code = code.replace("kernel", &name);
+    KERNEL_CAPTURE.with(|k| {
+        if let Some(captured) = k.borrow_mut().as_mut() {
+            captured.push(code.clone());
+        }
+    });
     if !device.has_func(&name, &name) {
-        device
-            .load_ptx(
-                compile_ptx_with_opts(
-                    code,
-                    CompileOptions {
-                        arch: Some("sm_75"),
-                        include_paths: vec!["/usr/local/cuda/include".to_string()],
-                        ..Default::default()
-                    },
-                )
-                .unwrap(),
-                &name,
-                &[name.clone().leak()],
+        let arch = cuda_arch(device);
+        let cache_path = PTX_CACHE_DIR
+            .lock()
+            .unwrap()
+            .clone()
+            .map(|dir| dir.join(format!("{name}_{:x}.ptx", hash((arch, nvrtc_version())))));
+        // A missing, unreadable or corrupt cache entry falls back to compiling, which rewrites it
+        let cached = cache_path
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .filter(|ptx| ptx.contains(&name))
+            .is_some_and(|ptx| {
+                device
+                    .load_ptx(Ptx::from_src(ptx), &name, &[intern_kernel_name(&name)])
+                    .is_ok()
+            });
+        if !cached {
+            NVRTC_COMPILES.with(|c| c.set(c.get() + 1));
+            let error = |log| CudaCompileError {
+                log,
+                source: code.clone(),
+            };
+            // Kernels without includes don't need the headers
+            let include_paths = match cuda_include_path() {
+                Ok(dir) => vec![dir.to_string_lossy().into_owned()],
+                Err(e) if code.contains("#include") => return Err(error(e)),
+                Err(_) => vec![],
+            };
+            let ptx = compile_ptx_with_opts(
+                &code,
+                CompileOptions {
+                    arch: Some(arch),
+                    include_paths,
+                    ..Default::default()
+                },
             )
-            .unwrap();
+            .map_err(|e| match e {
+                CompileError::CompileError { log, .. } => error(log.to_string_lossy().into_owned()),
+                e => error(format!("{e:?}")),
+            })?;
+            if let Some(path) = &cache_path {
+                write_ptx_cache(path, &ptx.to_src());
+            }
+            device
+                .load_ptx(ptx, &name, &[intern_kernel_name(&name)])
+                .map_err(|e| error(format!("Failed to load the compiled PTX: {e}")))?;
+        }
+    }
+    Ok(device.get_func(&name, &name).unwrap())
+}
+
+/// A kernel that failed to compile or load
+#[derive(Debug, Clone)]
+pub struct CudaCompileError {
+    /// NVRTC's compilation log, or the error loading the compiled kernel
+    pub log: String,
+    /// The kernel source, as passed to NVRTC
+    pub source: String,
+}
+
+impl std::fmt::Display for CudaCompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Failed to compile CUDA kernel:\n{}\nSource:\n{}",
+            self.log, self.source
+        )
+    }
+}
+
+impl std::error::Error for CudaCompileError {}
+
+/// Write a PTX cache entry through a temporary file, so readers never see a partial entry.
+/// Failing to write just means the kernel gets compiled again next time.
+fn write_ptx_cache(path: &Path, ptx: &str) {
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    let written = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&tmp, ptx))
+        .and_then(|_| std::fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&tmp);
     }
-    device.get_func(&name, &name).unwrap()
 }
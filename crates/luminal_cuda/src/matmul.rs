@@ -1,22 +1,163 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, marker::PhantomData, sync::Arc};
 
 use luminal_cudarc::{
-    cublas::{sys::cublasOperation_t::*, CudaBlas},
-    driver::{CudaDevice, DevicePtr, DevicePtrMut},
+    cublas::{
+        result,
+        sys::{
+            cublasComputeType_t, cublasGemmAlgo_t,
+            cublasOperation_t::{self, *},
+            cudaDataType_t,
+        },
+        CudaBlas,
+    },
+    driver::{sys::CUdeviceptr, CudaDevice, CudaFunction, DevicePtr, DevicePtrMut, LaunchConfig},
 };
 
 use crate::{
+    alloc_zeros, compile_and_load_kernel, cuda_device, cuda_op_marker, device_stream,
+    elementwise_launch_config, get_buffer_from_tensor,
     prim::{CudaMul, CudaSumReduce},
-    CudaData, CudaFloat,
+    CudaCompileError, CudaData, CudaFloat, LaunchOnDeviceStream, DEFAULT_BLOCK_SIZE,
 };
 use luminal::{
     op::{InputTensor, Operator},
     prelude::*,
 };
 
-/// Multiplies a MxK matrix with a KxN matrix, resulting in a MxN matrix
+/// Runs a strided batched GEMM in the precision of `T`. Each of A, B and C is given as a device pointer, leading
+/// dimension and batch stride, following cuBLAS's column-major convention. f16 inputs accumulate in f32 if
+/// `half_f32_accumulation` is set.
+#[allow(clippy::too_many_arguments)]
+unsafe fn gemm_strided_batched<T: CudaFloat>(
+    blas: &CudaBlas,
+    half_f32_accumulation: bool,
+    (transa, transb): (cublasOperation_t, cublasOperation_t),
+    (m, n, k): (i32, i32, i32),
+    (a, lda, stride_a): (CUdeviceptr, i32, i64),
+    (b, ldb, stride_b): (CUdeviceptr, i32, i64),
+    (c, ldc, stride_c): (CUdeviceptr, i32, i64),
+    batch_size: i32,
+) {
+    match T::type_name() {
+        "float" => result::sgemm_strided_batched(
+            *blas.handle(),
+            transa,
+            transb,
+            m,
+            n,
+            k,
+            &1.0_f32,
+            a as *const f32,
+            lda,
+            stride_a,
+            b as *const f32,
+            ldb,
+            stride_b,
+            &0.0_f32,
+            c as *mut f32,
+            ldc,
+            stride_c,
+            batch_size,
+        ),
+        "__half" if half_f32_accumulation => result::gemm_strided_batched_ex(
+            *blas.handle(),
+            transa,
+            transb,
+            m,
+            n,
+            k,
+            &1.0_f32 as *const f32 as *const _,
+            a as *const _,
+            cudaDataType_t::CUDA_R_16F,
+            lda,
+            stride_a,
+            b as *const _,
+            cudaDataType_t::CUDA_R_16F,
+            ldb,
+            stride_b,
+            &0.0_f32 as *const f32 as *const _,
+            c as *mut _,
+            cudaDataType_t::CUDA_R_16F,
+            ldc,
+            stride_c,
+            batch_size,
+            cublasComputeType_t::CUBLAS_COMPUTE_32F,
+            cublasGemmAlgo_t::CUBLAS_GEMM_DEFAULT,
+        ),
+        "__half" => result::hgemm_strided_batched(
+            *blas.handle(),
+            transa,
+            transb,
+            m,
+            n,
+            k,
+            &f16::from_f32(1.0),
+            a as *const f16,
+            lda,
+            stride_a,
+            b as *const f16,
+            ldb,
+            stride_b,
+            &f16::from_f32(0.0),
+            c as *mut f16,
+            ldc,
+            stride_c,
+            batch_size,
+        ),
+        "double" => result::dgemm_strided_batched(
+            *blas.handle(),
+            transa,
+            transb,
+            m,
+            n,
+            k,
+            &1.0_f64,
+            a as *const f64,
+            lda,
+            stride_a,
+            b as *const f64,
+            ldb,
+            stride_b,
+            &0.0_f64,
+            c as *mut f64,
+            ldc,
+            stride_c,
+            batch_size,
+        ),
+        // cuBLAS has no bf16 GEMM of its own, so bf16 always goes through the mixed precision entry point
+        "__nv_bfloat16" => result::gemm_strided_batched_ex(
+            *blas.handle(),
+            transa,
+            transb,
+            m,
+            n,
+            k,
+            &1.0_f32 as *const f32 as *const _,
+            a as *const _,
+            cudaDataType_t::CUDA_R_16BF,
+            lda,
+            stride_a,
+            b as *const _,
+            cudaDataType_t::CUDA_R_16BF,
+            ldb,
+            stride_b,
+            &0.0_f32 as *const f32 as *const _,
+            c as *mut _,
+            cudaDataType_t::CUDA_R_16BF,
+            ldc,
+            stride_c,
+            batch_size,
+            cublasComputeType_t::CUBLAS_COMPUTE_32F,
+            cublasGemmAlgo_t::CUBLAS_GEMM_DEFAULT,
+        ),
+        other => panic!("cuBLAS matmuls aren't supported for {other}"),
+    }
+    .unwrap();
+}
+
+/// Multiplies a MxK matrix with a KxN matrix, resulting in a MxN matrix. The flag accumulates f16 in f32.
 #[derive(LuminalPrint, LuminalEqFalse, Clone)]
-pub struct CudaMatmul2D<T>(Arc<CudaBlas>, Arc<CudaDevice>, PhantomData<T>);
+pub struct CudaMatmul2D<T>(Arc<CudaBlas>, Arc<CudaDevice>, bool, PhantomData<T>);
 
 impl<T: CudaFloat + 'static> Operator for CudaMatmul2D<T>
 where
@@ -43,7 +184,7 @@ where
             .as_any()
             .downcast_ref::<CudaData<T>>()
             .unwrap();
-        let mut out = self.1.alloc_zeros::<T>((m * n) as usize).unwrap();
+        let mut out = alloc_zeros::<T>(&self.1, (m * n) as usize).unwrap();
         let (a_row_major, b_row_major) = (
             inp[0].1.indexes[1] > inp[0].1.indexes[0],
             inp[1].1.indexes[1] > inp[1].1.indexes[0],
@@ -54,57 +195,35 @@ where
             (false, true) => (CUBLAS_OP_N, CUBLAS_OP_T),
             (true, false) => (CUBLAS_OP_T, CUBLAS_OP_N),
         };
-        if T::is_f32() {
-            unsafe {
-                luminal_cudarc::cublas::result::sgemm(
-                    *self.0.handle(),
-                    transa,
-                    transb,
-                    n,
-                    m,
-                    k,
-                    &1.0_f32 as *const f32,
-                    *b.0.device_ptr() as *const f32,
-                    if b_row_major { n } else { k },
-                    *a.0.device_ptr() as *const f32,
-                    if a_row_major { k } else { m },
-                    &0.0_f32 as *const f32,
-                    *out.device_ptr_mut() as *mut f32,
-                    n,
-                )
-                .unwrap();
-            }
-        } else {
-            unsafe {
-                luminal_cudarc::cublas::result::hgemm(
-                    *self.0.handle(),
-                    transa,
-                    transb,
-                    n,
-                    m,
-                    k,
-                    &f16::from_f32(1.0) as *const f16,
-                    *b.0.device_ptr() as *const f16,
-                    if b_row_major { n } else { k },
-                    *a.0.device_ptr() as *const f16,
-                    if a_row_major { k } else { m },
-                    &f16::from_f32(0.0) as *const f16,
-                    *out.device_ptr_mut() as *mut f16,
-                    n,
-                )
+        unsafe {
+            self.0
+                .set_stream(device_stream(&self.1).as_deref())
                 .unwrap();
-            }
+            gemm_strided_batched::<T>(
+                &self.0,
+                self.2,
+                (transa, transb),
+                (n, m, k),
+                (*b.0.device_ptr(), if b_row_major { n } else { k }, 0),
+                (*a.0.device_ptr(), if a_row_major { k } else { m }, 0),
+                (*out.device_ptr_mut(), n, 0),
+                1,
+            );
         }
 
         vec![Tensor {
-            data: Box::new(CudaData(out)),
+            data: Box::new(CudaData::new(out)),
         }]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
 }
 
-/// Multiplies a BxMxK matrix with a BxKxN matrix, resulting in a BxMxN matrix
+/// Multiplies a BxMxK matrix with a BxKxN matrix, resulting in a BxMxN matrix. The flag accumulates f16 in f32.
 #[derive(LuminalPrint, LuminalEqFalse, Clone)]
-pub struct CudaBatchMatmul2D<T>(Arc<CudaBlas>, Arc<CudaDevice>, PhantomData<T>);
+pub struct CudaBatchMatmul2D<T>(Arc<CudaBlas>, Arc<CudaDevice>, bool, PhantomData<T>);
 
 impl<T: CudaFloat + 'static> Operator for CudaBatchMatmul2D<T>
 where
@@ -133,10 +252,7 @@ where
             .as_any()
             .downcast_ref::<CudaData<T>>()
             .unwrap();
-        let mut out = self
-            .1
-            .alloc_zeros::<T>((m * n * batch_size) as usize)
-            .unwrap();
+        let mut out = alloc_zeros::<T>(&self.1, (m * n * batch_size) as usize).unwrap();
         let (a_row_major, b_row_major) = (
             inp[0].1.indexes[2] > inp[0].1.indexes[1],
             inp[1].1.indexes[1] > inp[1].1.indexes[0],
@@ -147,71 +263,332 @@ where
             (false, true) => (CUBLAS_OP_N, CUBLAS_OP_T),
             (true, false) => (CUBLAS_OP_T, CUBLAS_OP_N),
         };
-        if T::is_f32() {
-            unsafe {
-                luminal_cudarc::cublas::result::sgemm_strided_batched(
-                    *self.0.handle(),
-                    transa,
-                    transb,
-                    n,
-                    m,
-                    k,
-                    &1.0_f32 as *const f32,
-                    *b.0.device_ptr() as *const f32,
-                    if b_row_major { n } else { k },
-                    0,
-                    *a.0.device_ptr() as *const f32,
+        unsafe {
+            self.0
+                .set_stream(device_stream(&self.1).as_deref())
+                .unwrap();
+            gemm_strided_batched::<T>(
+                &self.0,
+                self.2,
+                (transa, transb),
+                (n, m, k),
+                (*b.0.device_ptr(), if b_row_major { n } else { k }, 0),
+                (
+                    *a.0.device_ptr(),
                     if a_row_major { k } else { m },
                     a_strides[0].to_usize().unwrap() as i64,
-                    &0.0_f32 as *const f32,
-                    *out.device_ptr_mut() as *mut f32,
-                    n,
-                    (m * n) as i64,
-                    batch_size,
+                ),
+                (*out.device_ptr_mut(), n, (m * n) as i64),
+                batch_size,
+            );
+        }
+
+        vec![Tensor {
+            data: Box::new(CudaData::new(out)),
+        }]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Multiplies a MxK matrix with a KxN matrix, resulting in a MxN matrix, without cuBLAS. Each block computes
+/// a `tile` x `tile` square of the output, staging tiles of both inputs through shared memory. Inputs are read
+/// through their strides, so transposed inputs don't need to be made contiguous first.
+#[derive(LuminalPrint, LuminalEqFalse, Clone)]
+pub struct CudaTiledMatmul2D<T> {
+    function: CudaFunction,
+    tile: u32,
+    device: Arc<CudaDevice>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaTiledMatmul2D<T> {
+    pub fn new(tile: u32, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        assert!(
+            (1..=32).contains(&tile),
+            "Matmul tile size must be between 1 and 32, got {tile}"
+        );
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+#define TILE {tile}
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *a, const {type_name} *b, int m, int k, int n, int a_row_stride, int a_col_stride, int b_row_stride, int b_col_stride) {{
+    __shared__ {type_name} a_tile[TILE][TILE];
+    __shared__ {type_name} b_tile[TILE][TILE];
+    int row = blockIdx.y * TILE + threadIdx.y;
+    int col = blockIdx.x * TILE + threadIdx.x;
+    {acc} sum = 0.0;
+    for (int t = 0; t < k; t += TILE) {{
+        int a_k = t + threadIdx.x;
+        int b_k = t + threadIdx.y;
+        a_tile[threadIdx.y][threadIdx.x] = row < m && a_k < k ? a[(long long)row * a_row_stride + (long long)a_k * a_col_stride] : ({type_name})0.0;
+        b_tile[threadIdx.y][threadIdx.x] = b_k < k && col < n ? b[(long long)b_k * b_row_stride + (long long)col * b_col_stride] : ({type_name})0.0;
+        __syncthreads();
+        for (int i = 0; i < TILE; i++) {{
+            sum += ({acc})a_tile[threadIdx.y][i] * ({acc})b_tile[i][threadIdx.x];
+        }}
+        __syncthreads();
+    }}
+    if (row < m && col < n) {{
+        out[(long long)row * n + col] = ({type_name})sum;
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            tile,
+            device,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaTiledMatmul2D<T> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
+        let (m, k, n) = (
+            a_shape[0].to_usize().unwrap() as i32,
+            a_shape[1].to_usize().unwrap() as i32,
+            b_shape[1].to_usize().unwrap() as i32,
+        );
+        let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
+        let a = get_buffer_from_tensor::<T>(&inp[0].0);
+        let b = get_buffer_from_tensor::<T>(&inp[1].0);
+        let mut out = alloc_zeros::<T>(&self.device, (m * n) as usize).unwrap();
+        let blocks = |dim: i32| (dim as u32).div_ceil(self.tile);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    LaunchConfig {
+                        grid_dim: (blocks(n), blocks(m), 1),
+                        block_dim: (self.tile, self.tile, 1),
+                        shared_mem_bytes: 0,
+                    },
+                    (
+                        &mut out,
+                        a,
+                        b,
+                        m,
+                        k,
+                        n,
+                        a_strides[0].to_usize().unwrap() as i32,
+                        a_strides[1].to_usize().unwrap() as i32,
+                        b_strides[0].to_usize().unwrap() as i32,
+                        b_strides[1].to_usize().unwrap() as i32,
+                    ),
                 )
                 .unwrap();
-            }
-        } else {
-            unsafe {
-                luminal_cudarc::cublas::result::hgemm_strided_batched(
-                    *self.0.handle(),
-                    transa,
-                    transb,
-                    n,
-                    m,
-                    k,
-                    &f16::from_f32(1.0) as *const f16,
-                    *b.0.device_ptr() as *const f16,
-                    if b_row_major { n } else { k },
-                    0,
-                    *a.0.device_ptr() as *const f16,
-                    if a_row_major { k } else { m },
-                    a_strides[0].to_usize().unwrap() as i64,
-                    &f16::from_f32(0.0) as *const f16,
-                    *out.device_ptr_mut() as *mut f16,
-                    n,
-                    (m * n) as i64,
-                    batch_size,
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Multiplies each row of a TxK input with the KxN weight of the expert assigned to it, out of an ExKxN
+/// stack of expert weights, resulting in a TxN matrix. Expert assignments are a T vector of indexes.
+/// The assigned weights are gathered into a TxKxN batch and multiplied with a batched matmul. If cuBLAS can't
+/// be loaded on the device, each token is multiplied with a `CudaTiledMatmul2D` kernel instead.
+///
+/// Out of range expert assignments gather a zero weight. Setting `check_range` panics on them instead, at the
+/// cost of waiting on the device to read the flag back every run, so it's off by default.
+#[derive(LuminalPrint, LuminalEqFalse, Clone)]
+pub struct CudaGroupedMatMul<T> {
+    gather_function: CudaFunction,
+    backend: GroupedMatMulBackend<T>,
+    /// Accumulate f16 matmuls in f32, on by default
+    pub half_f32_accumulation: bool,
+    /// Check expert assignments are in range, off by default
+    pub check_range: bool,
+    device: Arc<CudaDevice>,
+    _phantom: PhantomData<T>,
+}
+
+#[derive(Clone)]
+enum GroupedMatMulBackend<T> {
+    Blas(Arc<CudaBlas>),
+    Tiled(CudaTiledMatmul2D<T>),
+}
+
+impl<T: CudaFloat> CudaGroupedMatMul<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *weights, const {type_name} *experts, int *out_of_range, int n_experts, long long weight_size, long long numel) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        int expert = (int)(float)experts[idx / weight_size];
+        if (expert < 0 || expert >= n_experts) {{
+            out[idx] = ({type_name})0.0;
+            if (out_of_range) out_of_range[0] = 1;
+        }} else {{
+            out[idx] = weights[expert * weight_size + idx % weight_size];
+        }}
+    }}
+}}"
+        );
+        let backend = match CudaBlas::new(device.clone()) {
+            Ok(blas) => GroupedMatMulBackend::Blas(Arc::new(blas)),
+            Err(_) => GroupedMatMulBackend::Tiled(CudaTiledMatmul2D::new(16, device.clone())?),
+        };
+        Ok(Self {
+            gather_function: compile_and_load_kernel(code, &device)?,
+            backend,
+            half_f32_accumulation: true,
+            check_range: false,
+            device,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaGroupedMatMul<T> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Inp 1 is the input, inp 2 is the expert weights and inp 3 is the expert assignments
+        let (a_shape, w_shape) = (inp[0].1.shape(), inp[1].1.shape());
+        let (tokens, k, n_experts, n) = (
+            a_shape[0].to_usize().unwrap() as i32,
+            a_shape[1].to_usize().unwrap() as i32,
+            w_shape[0].to_usize().unwrap() as i32,
+            w_shape[2].to_usize().unwrap() as i32,
+        );
+        let a = get_buffer_from_tensor::<T>(&inp[0].0);
+        let weights = get_buffer_from_tensor::<T>(&inp[1].0);
+        let experts = get_buffer_from_tensor::<T>(&inp[2].0);
+
+        // Gather each token's expert weight
+        let weight_size = k as usize * n as usize;
+        let numel = tokens as usize * weight_size;
+        let mut gathered = CudaData::new(alloc_zeros::<T>(&self.device, numel).unwrap());
+        // Only allocated when checking, the kernel skips flagging through a null pointer
+        let out_of_range = self
+            .check_range
+            .then(|| CudaData::new(alloc_zeros::<i32>(&self.device, 1).unwrap()));
+        let out_of_range_ptr = out_of_range.as_ref().map_or(0, |flag| *flag.0.device_ptr());
+        unsafe {
+            self.gather_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(numel, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut *gathered.0,
+                        weights,
+                        experts,
+                        out_of_range_ptr,
+                        n_experts,
+                        weight_size,
+                        numel,
+                    ),
                 )
                 .unwrap();
+        }
+        if let Some(out_of_range) = out_of_range {
+            assert_eq!(
+                out_of_range.to_vec()[0],
+                0,
+                "GroupedMatMul expert assignment out of range for {n_experts} experts"
+            );
+        }
+
+        // Multiply each 1xK row with its KxN weight
+        let mut out = alloc_zeros::<T>(&self.device, (tokens * n) as usize).unwrap();
+        match &self.backend {
+            GroupedMatMulBackend::Blas(blas) => unsafe {
+                blas.set_stream(device_stream(&self.device).as_deref())
+                    .unwrap();
+                gemm_strided_batched::<T>(
+                    blas,
+                    self.half_f32_accumulation,
+                    (CUBLAS_OP_N, CUBLAS_OP_N),
+                    (n, 1, k),
+                    (*gathered.0.device_ptr(), n, (k * n) as i64),
+                    (*a.device_ptr(), k, k as i64),
+                    (*out.device_ptr_mut(), n, n as i64),
+                    tokens,
+                );
+            },
+            GroupedMatMulBackend::Tiled(tiled) => {
+                let (k, n) = (k as usize, n as usize);
+                for t in 0..tokens as usize {
+                    unsafe {
+                        tiled
+                            .function
+                            .clone()
+                            .launch_on_device_stream(
+                                &self.device,
+                                LaunchConfig {
+                                    grid_dim: ((n as u32).div_ceil(tiled.tile), 1, 1),
+                                    block_dim: (tiled.tile, tiled.tile, 1),
+                                    shared_mem_bytes: 0,
+                                },
+                                (
+                                    &mut out.slice_mut(t * n..(t + 1) * n),
+                                    &a.slice(t * k..(t + 1) * k),
+                                    &gathered.0.slice(t * k * n..(t + 1) * k * n),
+                                    1,
+                                    k as i32,
+                                    n as i32,
+                                    k as i32,
+                                    1,
+                                    n as i32,
+                                    1,
+                                ),
+                            )
+                            .unwrap();
+                    }
+                }
             }
         }
 
-        vec![Tensor {
-            data: Box::new(CudaData(out)),
-        }]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Replaces 2D and batched matmul patterns (a broadcasted mul followed by a sum reduce) with cuBLAS GEMMs,
+/// deriving the transpose flags from the input shape trackers. If cuBLAS can't be loaded on the device, 2D
+/// matmuls run as `CudaTiledMatmul2D` and batched matmuls are left as the primitive mul and sum reduce kernels.
+///
+/// The flag accumulates f16 matmuls in f32, casting the result back to half on store, and is on by default.
+/// Turning it off uses cuBLAS's pure half precision GEMM, which is faster but loses precision on long reductions.
+/// bf16 matmuls always accumulate in f32.
+pub struct CudaMatMulCompiler<T>(pub usize, pub bool, PhantomData<T>);
+
+impl<T> Default for CudaMatMulCompiler<T> {
+    fn default() -> Self {
+        Self::new(0)
     }
 }
 
-#[derive(Default)]
-pub struct CudaMatMulCompiler<T>(PhantomData<T>);
+impl<T> CudaMatMulCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, true, PhantomData)
+    }
+}
 
 impl<T: CudaFloat + 'static> Compiler for CudaMatMulCompiler<T>
 where
     CudaData<T>: Data,
 {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = cuda_device(self.0);
+        let blas = CudaBlas::new(dev.clone()).ok().map(Arc::new);
+        let half_f32_accumulation = self.1;
         // Look for the matmul pattern
         // Mul ([A, C(fake), B] | [A(fake), C, B]) -> SumReduce(2) -> [A, C]
         // Actually starts at [A,B] | [B, C]
@@ -238,19 +615,32 @@ where
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            // Strides can't express reversed dimensions
+            if srcs.iter().any(|(_, _, sh)| sh.is_reversed()) {
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
-            let new_op = graph
-                .add_op(CudaMatmul2D::<T>(
-                    Arc::new(CudaBlas::new(dev.clone()).unwrap()),
-                    dev.clone(),
-                    Default::default(),
-                ))
-                .input(srcs[0].0, 0, srcs[0].2)
-                .input(srcs[1].0, 0, srcs[1].2)
-                .finish();
+            let new_op = if let Some(blas) = &blas {
+                graph
+                    .add_op(CudaMatmul2D::<T>(
+                        blas.clone(),
+                        dev.clone(),
+                        half_f32_accumulation,
+                        Default::default(),
+                    ))
+                    .input(srcs[0].0, 0, srcs[0].2)
+                    .input(srcs[1].0, 0, srcs[1].2)
+                    .finish()
+            } else {
+                graph
+                    .add_op(CudaTiledMatmul2D::<T>::new(16, dev.clone()).unwrap())
+                    .input(srcs[0].0, 0, srcs[0].2)
+                    .input(srcs[1].0, 0, srcs[1].2)
+                    .finish()
+            };
 
             // Create edges to dests
             move_outgoing_edge(sum_reduce, new_op, &mut graph.graph);
@@ -274,6 +664,10 @@ where
             graph.graph.remove_node(sum_reduce);
         }
 
+        let Some(blas) = blas else {
+            return;
+        };
+
         // Look for the batch matmul pattern
         // Mul ([A, C(fake), B] | [A(fake), C, B]) -> SumReduce(2) -> [A, C]
         // Actually starts at [A,B] | [B, C]
@@ -300,6 +694,9 @@ where
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert BatchMatMul2D op
             let mut srcs = graph.get_sources(mul);
+            if srcs.iter().any(|(_, _, sh)| sh.is_reversed()) {
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(2);
             srcs[1].2.remove_dim(1);
@@ -307,8 +704,9 @@ where
             srcs[1].2.permute(&[1, 0]);
             let new_op = graph
                 .add_op(CudaBatchMatmul2D::<T>(
-                    Arc::new(CudaBlas::new(dev.clone()).unwrap()),
+                    blas.clone(),
                     dev.clone(),
+                    half_f32_accumulation,
                     Default::default(),
                 ))
                 .input(srcs[0].0, 0, srcs[0].2)
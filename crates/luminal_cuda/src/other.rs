@@ -1,6 +1,9 @@
-use std::{marker::PhantomData, sync::Arc};
+use std::{any::Any, ffi::c_void, marker::PhantomData, sync::Arc};
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, LaunchAsync, LaunchConfig};
+use itertools::Itertools;
+use luminal_cudarc::driver::{
+    sys, CudaDevice, CudaFunction, DevicePtr, DeviceRepr, DeviceSlice, LaunchConfig,
+};
 
 use luminal::{
     op::*,
@@ -10,10 +13,19 @@ use luminal::{
 use rustc_hash::FxHashMap;
 
 use crate::{
+    alloc, alloc_copy, alloc_zeros,
     binary::CudaSub,
-    compile_and_load_kernel, constant,
-    prim::{CudaContiguous, CudaSumReduce},
-    CudaData, CudaFloat,
+    compile_and_load_kernel, constant, cuda_device, cuda_op_marker, elementwise_launch_config,
+    get_buffer_from_tensor, get_idx_valid_exps, graph_device, grid_size, index_type,
+    input_dyn_dims, on_device_stream,
+    prim::{
+        CudaAdd, CudaConstant, CudaContiguous, CudaCopyToDevice, CudaExp2, CudaMaxReduce,
+        CudaMeanReduce, CudaMul, CudaRecip, CudaSqrt, CudaSumReduce,
+    },
+    render_dyn_dim_inputs,
+    trace::unwrap_op,
+    upload, CudaCompileError, CudaData, CudaError, CudaFloat, LaunchOnDeviceStream,
+    DEFAULT_BLOCK_SIZE,
 };
 
 #[derive(LuminalPrint, Clone, LuminalEqFalse)]
@@ -30,25 +42,25 @@ impl<T: CudaFloat> CudaARange<T> {
         device: Arc<CudaDevice>,
         size: BigExpression,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, int n_elements) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, long long n_elements) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < n_elements) {{
         out[idx] = ({type_name})idx;
     }}
 }}"
         );
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
             size,
             _phantom: Default::default(),
             dyn_map,
-        }
+        })
     }
 }
 
@@ -58,29 +70,41 @@ impl<T: CudaFloat> Operator for CudaARange<T> {
             .size
             .exec(unsafe { self.dyn_map.as_ref().unwrap() })
             .unwrap();
-        let mut out = self.device.alloc_zeros::<T>(n_elements).unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, n_elements).unwrap();
         unsafe {
             self.function
                 .clone()
-                .launch(
-                    LaunchConfig::for_num_elems(n_elements as u32),
-                    (&mut out, n_elements as i32),
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_elements, DEFAULT_BLOCK_SIZE),
+                    (&mut out, n_elements as i64),
                 )
                 .unwrap();
         }
 
         vec![Tensor {
-            data: Box::new(CudaData(out)),
+            data: Box::new(CudaData::new(out)),
         }]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
 }
 
 #[derive(LuminalPrint, Default)]
-pub struct ARangeCompiler<T: CudaFloat>(PhantomData<T>);
+pub struct ARangeCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> ARangeCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
 
 impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = cuda_device(self.0);
         // TODO: Make sure this actually checks the shape transformations to ensure pooling happens
         let one = constant::<T>(1.);
         let contig1 = unary::<CudaContiguous<T>>(one.clone());
@@ -110,11 +134,10 @@ impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
                 sh.dims[sh.indexes[sh.len() - 1]]
             };
             let arange_op = graph
-                .add_op(CudaARange::<T>::new(
-                    dev.clone(),
-                    arange_amount.into(),
-                    &graph.dyn_map,
-                ))
+                .add_op(
+                    CudaARange::<T>::new(dev.clone(), arange_amount.into(), &graph.dyn_map)
+                        .unwrap(),
+                )
                 .finish();
             move_outgoing_edge(s.get(&sub), arange_op, &mut graph.graph);
             graph.graph.remove_node(s.get(&sub));
@@ -122,3 +145,2181 @@ impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
         }
     }
 }
+
+/// Cross-entropy against soft labels, computing -sum(target * log_softmax(logits)) over the last dimension.
+/// Takes contiguous logits and target probabilities of the same shape and outputs one loss per row.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaSoftLabelCrossEntropy<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaSoftLabelCrossEntropy<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *logits, const {type_name} *target, long long n_rows, int row_size) {{
+    long long row = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (row < n_rows) {{
+        const {type_name} *x = logits + row * row_size;
+        const {type_name} *t = target + row * row_size;
+        {acc} max_value = -({acc})__int_as_float(0x7f800000);
+        for (int i = 0; i < row_size; i++) {{
+            max_value = max(max_value, ({acc})x[i]);
+        }}
+        {acc} exp_sum = 0.0;
+        for (int i = 0; i < row_size; i++) {{
+            exp_sum += exp(({acc})x[i] - max_value);
+        }}
+        {acc} log_sum = log(exp_sum) + max_value;
+        {acc} loss = 0.0;
+        for (int i = 0; i < row_size; i++) {{
+            loss -= ({acc})t[i] * (({acc})x[i] - log_sum);
+        }}
+        out[row] = ({type_name})loss;
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSoftLabelCrossEntropy<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        assert!(
+            tensors
+                .iter()
+                .all(|(_, sh)| sh.is_contiguous() && !sh.is_sliced() && !sh.is_padded()),
+            "Cross-entropy inputs must be contiguous"
+        );
+        let logits = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let target = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let row_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let n_rows = tensors[0].1.n_elements().to_usize().unwrap() / row_size;
+        let mut out = alloc_zeros::<T>(&self.device, n_rows).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_rows, DEFAULT_BLOCK_SIZE),
+                    (&mut out, logits, target, n_rows, row_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Count the values of a contiguous tensor into `num_bins` equal-width bins over `[min, max]`.
+/// Out-of-range values are clamped into the edge bins, or dropped if `drop_out_of_range` is set.
+/// The counts are output as `u32`.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaHistogram<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub num_bins: usize,
+    pub min: f64,
+    pub max: f64,
+    pub drop_out_of_range: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaHistogram<T> {
+    pub fn new(
+        device: Arc<CudaDevice>,
+        num_bins: usize,
+        min: f64,
+        max: f64,
+        drop_out_of_range: bool,
+    ) -> Result<Self, CudaError> {
+        if min.is_nan() || max.is_nan() || max <= min {
+            return Err(CudaError::InvalidOp(format!(
+                "Histogram range [{min}, {max}] must have max greater than min"
+            )));
+        }
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel(unsigned int *counts, const {type_name} *inp, long long numel, double min_bound, double max_bound, int num_bins, int drop_out_of_range) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {acc} x = ({acc})inp[idx];
+        {acc} min_value = ({acc})min_bound, max_value = ({acc})max_bound;
+        if (isnan(x) || (drop_out_of_range != 0 && (x < min_value || x > max_value))) {{
+            return;
+        }}
+        int bin = (int)((x - min_value) / (max_value - min_value) * ({acc})num_bins);
+        bin = min(max(bin, 0), num_bins - 1);
+        atomicAdd(&counts[bin], 1u);
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            num_bins,
+            min,
+            max,
+            drop_out_of_range,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaHistogram<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = tensors[0].1;
+        assert!(
+            shape.is_contiguous() && !shape.is_sliced() && !shape.is_padded(),
+            "Histogram input must be contiguous"
+        );
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let mut counts = alloc_zeros::<u32>(&self.device, self.num_bins).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut counts,
+                        inp,
+                        inp_size,
+                        self.min,
+                        self.max,
+                        self.num_bins,
+                        self.drop_out_of_range as i32,
+                    ),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(counts))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Argsort each row of a contiguous tensor along the last dimension, outputting the `i32` indices of the
+/// values in descending order. Ties are broken by the lowest index.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaArgSort<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaArgSort<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        // Each thread finds the rank of one element in its row and writes its index there
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel(int *out, const {type_name} *inp, long long numel, int row_size) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        const {type_name} *row = inp + (idx / row_size) * row_size;
+        int i = idx % row_size;
+        {acc} x = ({acc})row[i];
+        int rank = 0;
+        for (int j = 0; j < row_size; j++) {{
+            {acc} y = ({acc})row[j];
+            if (y > x || (y == x && j < i)) {{
+                rank++;
+            }}
+        }}
+        out[idx - i + rank] = i;
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaArgSort<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = tensors[0].1;
+        assert!(
+            shape.is_contiguous() && !shape.is_sliced() && !shape.is_padded(),
+            "Argsort input must be contiguous"
+        );
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let row_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let mut out = alloc_zeros::<i32>(&self.device, inp_size).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    (&mut out, inp, inp_size, row_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Split a contiguous `(seq, heads * head_dim)` tensor into a contiguous `(heads, seq, head_dim)` tensor in one kernel,
+/// or merge the heads back when built with `merge`.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaMultiHeadReshape<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub heads: usize,
+    pub head_dim: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaMultiHeadReshape<T> {
+    /// `(seq, heads * head_dim)` -> `(heads, seq, head_dim)`
+    pub fn split(
+        device: Arc<CudaDevice>,
+        heads: usize,
+        head_dim: usize,
+    ) -> Result<Self, CudaCompileError> {
+        Self::new(
+            device,
+            heads,
+            head_dim,
+            "long long h = idx / (seq * head_dim);
+        long long s = (idx / head_dim) % seq;
+        long long d = idx % head_dim;
+        out[idx] = inp[s * heads * head_dim + h * head_dim + d];",
+        )
+    }
+
+    /// `(heads, seq, head_dim)` -> `(seq, heads * head_dim)`
+    pub fn merge(
+        device: Arc<CudaDevice>,
+        heads: usize,
+        head_dim: usize,
+    ) -> Result<Self, CudaCompileError> {
+        Self::new(
+            device,
+            heads,
+            head_dim,
+            "long long s = idx / (heads * head_dim);
+        long long h = (idx / head_dim) % heads;
+        long long d = idx % head_dim;
+        out[idx] = inp[h * seq * head_dim + s * head_dim + d];",
+        )
+    }
+
+    fn new(
+        device: Arc<CudaDevice>,
+        heads: usize,
+        head_dim: usize,
+        body: &str,
+    ) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel, int heads, int head_dim) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        long long seq = numel / (heads * head_dim);
+        {body}
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            heads,
+            head_dim,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaMultiHeadReshape<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = tensors[0].1;
+        assert!(
+            shape.is_contiguous() && !shape.is_sliced() && !shape.is_padded(),
+            "Multi-head reshape input must be contiguous"
+        );
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    (&mut out, inp, inp_size, self.heads, self.head_dim),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Split a contiguous `(rows, q + k + v)` fused projection output along the last dimension into three contiguous
+/// outputs in one kernel. With a `head_dim`, each output is also split into heads, giving `(heads, rows, head_dim)`.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaQKVSplit<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub sizes: [usize; 3],
+    pub head_dim: Option<usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaQKVSplit<T> {
+    pub fn new(
+        device: Arc<CudaDevice>,
+        sizes: [usize; 3],
+        head_dim: Option<usize>,
+    ) -> Result<Self, CudaCompileError> {
+        if let Some(head_dim) = head_dim {
+            assert!(
+                sizes.iter().all(|s| s % head_dim == 0),
+                "Split sizes {sizes:?} must be multiples of the head dim {head_dim}"
+            );
+        }
+        let type_name = T::type_name();
+        let out_idx = if let Some(head_dim) = head_dim {
+            format!("(c / {head_dim}) * n_rows * {head_dim} + row * {head_dim} + c % {head_dim}")
+        } else {
+            "row * size + c".to_string()
+        };
+        let [q, k, v] = sizes;
+        let (qk, total) = (q + k, q + k + v);
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out_q, {type_name} *out_k, {type_name} *out_v, const {type_name} *inp, long long numel) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        long long n_rows = numel / {total};
+        long long row = idx / {total};
+        int c = idx % {total};
+        {type_name} *out;
+        int size;
+        if (c < {q}) {{
+            out = out_q;
+            size = {q};
+        }} else if (c < {qk}) {{
+            out = out_k;
+            size = {k};
+            c -= {q};
+        }} else {{
+            out = out_v;
+            size = {v};
+            c -= {qk};
+        }}
+        out[{out_idx}] = inp[idx];
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            sizes,
+            head_dim,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaQKVSplit<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let n_rows = inp_size / self.sizes.iter().sum::<usize>();
+        let [mut q, mut k, mut v] = self
+            .sizes
+            .map(|s| alloc_zeros::<T>(&self.device, n_rows * s).unwrap());
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    (&mut q, &mut k, &mut v, inp, inp_size),
+                )
+                .unwrap();
+        }
+
+        vec![
+            Tensor::new(CudaData::new(q)),
+            Tensor::new(CudaData::new(k)),
+            Tensor::new(CudaData::new(v)),
+        ]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// A fixed-capacity device ring buffer. Each execution appends the input to the buffer, overwriting the oldest
+/// entries once full, and outputs the current window ordered from oldest to newest. Unfilled entries are zero.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaRingAppend<T: CudaFloat> {
+    append_function: CudaFunction,
+    window_function: CudaFunction,
+    device: Arc<CudaDevice>,
+    buffer: CudaData<T>,
+    /// Position of the next write, which is also the oldest entry
+    head: usize,
+    pub capacity: usize,
+}
+
+impl<T: CudaFloat> CudaRingAppend<T> {
+    pub fn new(device: Arc<CudaDevice>, capacity: usize) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let append_code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *buffer, const {type_name} *inp, long long numel, long long head, long long capacity) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        buffer[(head + idx) % capacity] = inp[idx];
+    }}
+}}"
+        );
+        let window_code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *buffer, long long head, long long capacity) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < capacity) {{
+        out[idx] = buffer[(head + idx) % capacity];
+    }}
+}}"
+        );
+        Ok(Self {
+            append_function: compile_and_load_kernel(append_code, &device)?,
+            window_function: compile_and_load_kernel(window_code, &device)?,
+            buffer: CudaData::new(alloc_zeros::<T>(&device, capacity).unwrap()),
+            device,
+            head: 0,
+            capacity,
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaRingAppend<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        assert!(
+            inp_size <= self.capacity,
+            "Can't append {inp_size} elements to a ring buffer with capacity {}",
+            self.capacity
+        );
+        let mut out = alloc_zeros::<T>(&self.device, self.capacity).unwrap();
+        unsafe {
+            self.append_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    (&mut *self.buffer.0, inp, inp_size, self.head, self.capacity),
+                )
+                .unwrap();
+        }
+        self.head = (self.head + inp_size) % self.capacity;
+        unsafe {
+            self.window_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(self.capacity, DEFAULT_BLOCK_SIZE),
+                    (&mut out, &*self.buffer.0, self.head, self.capacity),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Sum or max reduce several dimensions at once. Each thread computes one output element, walking every
+/// combination of the reduced dimensions. The output is the input shape with the reduced dimensions removed
+/// (or kept with size 1, which has the same layout).
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaMultiReduce<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub dims: Vec<usize>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaMultiReduce<T> {
+    pub fn sum(
+        dims: Vec<usize>,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        Self::new(dims, shape, device, dyn_map, "0.0", "reduce_value + x")
+    }
+
+    pub fn max(
+        dims: Vec<usize>,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        Self::new(
+            dims,
+            shape,
+            device,
+            dyn_map,
+            "-__int_as_float(0x7f800000)",
+            "max(reduce_value, x)",
+        )
+    }
+
+    fn new(
+        dims: Vec<usize>,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+        init: &str,
+        reduce: &str,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const long long *kept_sizes, const long long *kept_strides, int n_kept, const long long *reduced_sizes, const long long *reduced_strides, int n_reduced, {index} reduce_numel, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (i_ < numel) {{
+        {index} base = 0;
+        {index} rem = i_;
+        for (int k = n_kept - 1; k >= 0; k--) {{
+            base += (rem % kept_sizes[k]) * kept_strides[k];
+            rem /= kept_sizes[k];
+        }}
+        float reduce_value = {init};
+        for ({index} r_ = 0; r_ < reduce_numel; r_++) {{
+            {index} idx = base;
+            rem = r_;
+            for (int k = n_reduced - 1; k >= 0; k--) {{
+                idx += (rem % reduced_sizes[k]) * reduced_strides[k];
+                rem /= reduced_sizes[k];
+            }}
+            if (({valid}) != 0) {{
+                float x = (float)inp[{idx}];
+                reduce_value = {reduce};
+            }}
+        }}
+        out[i_] = ({type_name})reduce_value;
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            dims,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaMultiReduce<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let sizes = tensors[0]
+            .1
+            .shape()
+            .into_iter()
+            .map(|d| d.to_usize().unwrap() as i64)
+            .collect::<Vec<_>>();
+        // Logical strides of the full shape, split between kept and reduced dimensions
+        let mut strides = vec![1; sizes.len()];
+        for i in (0..sizes.len().saturating_sub(1)).rev() {
+            strides[i] = strides[i + 1] * sizes[i + 1];
+        }
+        let (reduced, kept): (Vec<_>, Vec<_>) =
+            (0..sizes.len()).partition(|i| self.dims.contains(i));
+        let upload_dims = |dims: &[usize], values: &[i64]| {
+            let values = dims.iter().map(|i| values[*i]).collect::<Vec<_>>();
+            CudaData::new(upload(&self.device, &values).unwrap())
+        };
+        let (kept_sizes, kept_strides) = (upload_dims(&kept, &sizes), upload_dims(&kept, &strides));
+        let (reduced_sizes, reduced_strides) = (
+            upload_dims(&reduced, &sizes),
+            upload_dims(&reduced, &strides),
+        );
+        let reduce_numel = reduced
+            .iter()
+            .map(|i| sizes[*i] as usize)
+            .product::<usize>();
+        let out_size = kept.iter().map(|i| sizes[*i] as usize).product::<usize>();
+
+        let out = alloc_zeros::<T>(&self.device, out_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            (&*kept_sizes.0).as_kernel_param(),
+            (&*kept_strides.0).as_kernel_param(),
+            kept.len().as_kernel_param(),
+            (&*reduced_sizes.0).as_kernel_param(),
+            (&*reduced_strides.0).as_kernel_param(),
+            reduced.len().as_kernel_param(),
+            reduce_numel.as_kernel_param(),
+            out_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(out_size, DEFAULT_BLOCK_SIZE),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Divide each element of a contiguous tensor by the sum or max of its row along `dim`, like
+/// `x / x.sum_reduce().expand()`. One thread handles each row, reducing it then writing the normalized values.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaReduceBroadcastDiv<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub dim: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaReduceBroadcastDiv<T> {
+    pub fn sum(dim: usize, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        Self::new(dim, device, "0.0", "denom + x")
+    }
+
+    pub fn max(dim: usize, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        Self::new(dim, device, "-__int_as_float(0x7f800000)", "max(denom, x)")
+    }
+
+    fn new(
+        dim: usize,
+        device: Arc<CudaDevice>,
+        init: &str,
+        reduce: &str,
+    ) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int back_size, int dim_size, long long n_rows) {{
+    long long row = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (row < n_rows) {{
+        long long start = (row / back_size) * dim_size * back_size + row % back_size;
+        float denom = {init};
+        for (long long c = 0; c < dim_size; c++) {{
+            float x = (float)inp[start + c * back_size];
+            denom = {reduce};
+        }}
+        for (long long c = 0; c < dim_size; c++) {{
+            out[start + c * back_size] = ({type_name})((float)inp[start + c * back_size] / denom);
+        }}
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            dim,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaReduceBroadcastDiv<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let shape = tensors[0]
+            .1
+            .shape()
+            .into_iter()
+            .map(|d| d.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let back_size = shape[self.dim + 1..].iter().product::<usize>();
+        let dim_size = shape[self.dim];
+        let inp_size = shape.iter().product::<usize>();
+        let n_rows = inp_size / dim_size;
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_rows, DEFAULT_BLOCK_SIZE),
+                    (&mut out, inp, back_size, dim_size, n_rows),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Ask the driver to migrate a weight onto the device ahead of its use. Only unified memory
+/// can be migrated; ordinary device allocations are already resident and are left alone.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaPrefetch<T> {
+    device: Arc<CudaDevice>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> CudaPrefetch<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Self {
+        Self {
+            device,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaPrefetch<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let buffer = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let mut managed = 0u32;
+        unsafe {
+            let queried = sys::cuPointerGetAttribute(
+                &mut managed as *mut u32 as *mut c_void,
+                sys::CUpointer_attribute::CU_POINTER_ATTRIBUTE_IS_MANAGED,
+                *buffer.device_ptr(),
+            );
+            if queried == sys::CUresult::CUDA_SUCCESS && managed != 0 {
+                on_device_stream(&self.device, |stream| {
+                    sys::cuMemPrefetchAsync(
+                        *buffer.device_ptr(),
+                        buffer.num_bytes(),
+                        self.device.ordinal() as sys::CUdevice,
+                        stream,
+                    )
+                })
+                .result()
+                .unwrap();
+            }
+        }
+        vec![]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Prefetch each layer's weights while the layer before it runs. Layers are the distinct first
+/// consumers of the weights, taken in topological order, and prefetches are issued in that order.
+/// Weights must be graph inputs. Add it after `CudaCompiler` to turn prefetching on.
+#[derive(LuminalPrint)]
+pub struct CudaPrefetchCompiler<T> {
+    weights: Vec<NodeIndex>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> CudaPrefetchCompiler<T> {
+    pub fn new(weights: Vec<NodeIndex>) -> Self {
+        Self {
+            weights,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T: CudaFloat> Compiler for CudaPrefetchCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        // Without copies to the device there are no weights on it to prefetch
+        let Some(dev) = graph_device::<T>(graph) else {
+            return;
+        };
+        let position = petgraph::algo::toposort(&graph.graph, None)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(i, n)| (n, i))
+            .collect::<FxHashMap<_, _>>();
+
+        // Group the weights by the first node that uses them
+        let mut layers = FxHashMap::<NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>>::default();
+        for weight in &self.weights {
+            // Host weights are prefetched through their device copy
+            let weight = graph
+                .edges_directed(*weight, petgraph::Direction::Outgoing)
+                .map(|e| e.target())
+                .find(|n| {
+                    unwrap_op(graph.node_weight(*n).unwrap().as_ref())
+                        .as_any()
+                        .is::<CudaCopyToDevice<T>>()
+                })
+                .unwrap_or(*weight);
+            let Some((consumer, (_, output, shape))) = graph
+                .edges_directed(weight, petgraph::Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|d| (e.target(), d)))
+                .min_by_key(|(n, _)| position[n])
+            else {
+                continue;
+            };
+            layers
+                .entry(consumer)
+                .or_default()
+                .push((weight, output, shape));
+        }
+        let layers = layers
+            .into_iter()
+            .sorted_by_key(|(consumer, _)| position[consumer])
+            .collect::<Vec<_>>();
+
+        let mut last_prefetch = None;
+        for (i, (_, weights)) in layers.iter().enumerate() {
+            for (weight, output, shape) in weights {
+                let prefetch = graph
+                    .add_op(CudaPrefetch::<T>::new(dev.clone()))
+                    .input(*weight, *output, *shape)
+                    .finish();
+                // Issue after the layer two back finishes, so the fetch overlaps the previous layer
+                graph.add_schedule_dependency(prefetch, layers[i.saturating_sub(1)].0);
+                if i >= 2 {
+                    graph.add_schedule_dependency(layers[i - 2].0, prefetch);
+                }
+                if let Some(last) = last_prefetch {
+                    graph.add_schedule_dependency(last, prefetch);
+                }
+                last_prefetch = Some(prefetch);
+            }
+        }
+    }
+}
+
+/// Check an invariant mid-graph, passing the input through untouched. Every element must be
+/// nonzero, or within `[min, max]` if a range is given, otherwise execution panics with the message.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaAssert<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub message: String,
+    pub range: Option<(f32, f32)>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaAssert<T> {
+    pub fn new(
+        message: &str,
+        range: Option<(f32, f32)>,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let check = if range.is_some() {
+            "x >= min_value && x <= max_value"
+        } else {
+            "x != 0.0"
+        };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel(const {type_name} *inp, unsigned long long *failures, long long *first_failure, {index} numel, float min_value, float max_value{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        float x = ({valid}) == 0 ? 0.0 : (float)inp[{idx}];
+        if (!({check})) {{
+            atomicAdd(failures, 1ull);
+            atomicMin(first_failure, (long long)idx);
+        }}
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            message: message.to_string(),
+            range,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaAssert<T> {
+    fn process(&mut self, mut tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let (min, max) = self.range.unwrap_or_default();
+        let failures = CudaData::new(alloc_zeros::<u64>(&self.device, 1).unwrap());
+        let first_failure = CudaData::new(upload(&self.device, &[i64::MAX]).unwrap());
+        let mut params = vec![
+            inp.as_kernel_param(),
+            (&*failures.0).as_kernel_param(),
+            (&*first_failure.0).as_kernel_param(),
+            inp_size.as_kernel_param(),
+            min.as_kernel_param(),
+            max.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    &mut params,
+                )
+                .unwrap();
+        }
+        let failures = failures.to_vec()[0];
+        if failures > 0 {
+            let expected = match self.range {
+                Some((min, max)) => format!("within [{min}, {max}]"),
+                None => "nonzero".to_string(),
+            };
+            panic!(
+                "Assertion failed: {}: {failures} of {inp_size} elements weren't {expected}, first at index {}",
+                self.message,
+                first_failure.to_vec()[0]
+            );
+        }
+
+        vec![tensors.pop().unwrap().0.cloned()]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Softmax(scores) x V, computed incrementally over tiles of the scores. Each execution takes a
+/// (rows, tile) tile of scores and the matching (tile, d) tile of V, folds them into a running max,
+/// running sum and unnormalized output per row, and outputs the (rows, d) result over every tile so far.
+/// Call `reset` before starting on a new set of rows.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaOnlineSoftmax<T> {
+    stats_function: CudaFunction,
+    output_function: CudaFunction,
+    device: Arc<CudaDevice>,
+    /// Running max and sum per row, and the unnormalized (rows, d) output
+    state: Option<(CudaData<f32>, CudaData<f32>, CudaData<f32>)>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaOnlineSoftmax<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let stats_code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel(float *running_max, float *running_sum, float *rescale, const {type_name} *scores, long long rows, int tile) {{
+    long long row = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (row < rows) {{
+        float old_max = running_max[row];
+        float new_max = old_max;
+        for (int t = 0; t < tile; t++) {{
+            new_max = fmaxf(new_max, (float)scores[row * tile + t]);
+        }}
+        // Rows that are entirely -inf so far have nothing to rescale
+        float scale = old_max == -__int_as_float(0x7f800000) ? 0.0 : expf(old_max - new_max);
+        float sum = running_sum[row] * scale;
+        if (new_max != -__int_as_float(0x7f800000)) {{
+            for (int t = 0; t < tile; t++) {{
+                sum += expf((float)scores[row * tile + t] - new_max);
+            }}
+        }}
+        running_max[row] = new_max;
+        running_sum[row] = sum;
+        rescale[row] = scale;
+    }}
+}}"
+        );
+        let output_code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, float *acc, const float *running_max, const float *running_sum, const float *rescale, const {type_name} *scores, const {type_name} *v, long long rows, int tile, int d) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < rows * d) {{
+        long long row = idx / d;
+        int col = idx % d;
+        float m = running_max[row];
+        float value = acc[idx] * rescale[row];
+        if (m != -__int_as_float(0x7f800000)) {{
+            for (long long t = 0; t < tile; t++) {{
+                value += expf((float)scores[row * tile + t] - m) * (float)v[t * d + col];
+            }}
+        }}
+        acc[idx] = value;
+        out[idx] = ({type_name})(running_sum[row] == 0.0 ? 0.0 : value / running_sum[row]);
+    }}
+}}"
+        );
+        Ok(Self {
+            stats_function: compile_and_load_kernel(stats_code, &device)?,
+            output_function: compile_and_load_kernel(output_code, &device)?,
+            device,
+            state: None,
+            _phantom: Default::default(),
+        })
+    }
+
+    /// Forget the tiles seen so far
+    pub fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaOnlineSoftmax<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        // Inp 1 is the score tile and inp 2 is the V tile
+        let scores = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let v = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let (score_shape, v_shape) = (tensors[0].1.shape(), tensors[1].1.shape());
+        let (rows, tile, d) = (
+            score_shape[0].to_usize().unwrap(),
+            score_shape[1].to_usize().unwrap(),
+            v_shape[1].to_usize().unwrap(),
+        );
+        assert_eq!(
+            v_shape[0].to_usize().unwrap(),
+            tile,
+            "V tile doesn't have a row per score"
+        );
+        let (running_max, running_sum, acc) = self.state.get_or_insert_with(|| {
+            (
+                CudaData::new(upload(&self.device, &vec![f32::NEG_INFINITY; rows]).unwrap()),
+                CudaData::new(alloc_zeros::<f32>(&self.device, rows).unwrap()),
+                CudaData::new(alloc_zeros::<f32>(&self.device, rows * d).unwrap()),
+            )
+        });
+        assert_eq!(
+            acc.0.len(),
+            rows * d,
+            "Tile shapes changed without resetting the online softmax"
+        );
+        let mut rescale = CudaData::new(alloc_zeros::<f32>(&self.device, rows).unwrap());
+        let mut out = alloc_zeros::<T>(&self.device, rows * d).unwrap();
+        unsafe {
+            self.stats_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(rows, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut *running_max.0,
+                        &mut *running_sum.0,
+                        &mut *rescale.0,
+                        scores,
+                        rows,
+                        tile,
+                    ),
+                )
+                .unwrap();
+            self.output_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(rows * d, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut out,
+                        &mut *acc.0,
+                        &*running_max.0,
+                        &*running_sum.0,
+                        &*rescale.0,
+                        scores,
+                        v,
+                        rows,
+                        tile,
+                        d,
+                    ),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Pairwise cosine similarity between the rows of contiguous `(m, d)` and `(n, d)` tensors, giving `(m, n)`.
+/// Norms are clamped to at least `eps` so zero vectors get a similarity of 0. Accumulates in f32.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaCosineSim<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    eps: f32,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaCosineSim<T> {
+    pub fn new(eps: f32, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *a, const {type_name} *b, long long m, long long n, int d, float eps) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < m * n) {{
+        const {type_name} *x = a + (idx / n) * d;
+        const {type_name} *y = b + (idx % n) * d;
+        float dot = 0.0, x_norm = 0.0, y_norm = 0.0;
+        for (int i = 0; i < d; i++) {{
+            float xi = (float)x[i], yi = (float)y[i];
+            dot += xi * yi;
+            x_norm += xi * xi;
+            y_norm += yi * yi;
+        }}
+        out[idx] = ({type_name})(dot / (fmaxf(sqrtf(x_norm), eps) * fmaxf(sqrtf(y_norm), eps)));
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            eps,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaCosineSim<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let d = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let m = tensors[0].1.n_elements().to_usize().unwrap() / d;
+        let n = tensors[1].1.n_elements().to_usize().unwrap() / d;
+        let mut out = alloc_zeros::<T>(&self.device, m * n).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(m * n, DEFAULT_BLOCK_SIZE),
+                    (&mut out, a, b, m, n, d, self.eps),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Gather keys or values of variable-length sequences, packed back to back in a `(total_len, d)` cache,
+/// into a padded `(batch, max_len, d)` tensor. The second input is each sequence's length.
+///
+/// Also outputs a `(batch, max_len)` mask to add to the attention scores, 0 at valid positions and -inf at padding,
+/// so a batch attends to exactly what each sequence would alone. Padded keys and values are zeroed.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaVarlenKVGather<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    max_len: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaVarlenKVGather<T> {
+    pub fn new(max_len: usize, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, {type_name} *mask, const {type_name} *cache, const {type_name} *lengths, int max_len, int d, long long numel) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        long long batch = idx / ((long long)max_len * d);
+        int pos = (idx / d) % max_len;
+        long long offset = 0;
+        for (long long i = 0; i < batch; i++) {{
+            offset += (long long)(float)lengths[i];
+        }}
+        bool valid = pos < (int)(float)lengths[batch];
+        out[idx] = valid ? cache[(offset + pos) * d + idx % d] : ({type_name})0.0;
+        if (idx % d == 0) {{
+            mask[idx / d] = valid ? ({type_name})0.0 : ({type_name})(-__int_as_float(0x7f800000));
+        }}
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            max_len,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaVarlenKVGather<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let cache = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let lengths = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let d = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let batch = tensors[1].1.n_elements().to_usize().unwrap();
+        let numel = batch * self.max_len * d;
+        let mut out = alloc_zeros::<T>(&self.device, numel).unwrap();
+        let mut mask = alloc_zeros::<T>(&self.device, batch * self.max_len).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(numel, DEFAULT_BLOCK_SIZE),
+                    (&mut out, &mut mask, cache, lengths, self.max_len, d, numel),
+                )
+                .unwrap();
+        }
+
+        vec![
+            Tensor::new(CudaData::new(out)),
+            Tensor::new(CudaData::new(mask)),
+        ]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// The mean and variance along `dim` in one pass over the input, for norm layers that need both.
+/// Outputs the mean then the variance, each the input shape with `dim` removed. Uses Welford's algorithm
+/// with f32 accumulation. The variance is the population variance, or the sample variance if `unbiased` is set.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaMeanVar<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub dim: usize,
+    pub unbiased: bool,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaMeanVar<T> {
+    pub fn new(
+        dim: usize,
+        unbiased: bool,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let divisor = if unbiased { "dim_size - 1" } else { "dim_size" };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *mean_out, {type_name} *var_out, const {type_name} *inp, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (i_ < numel) {{
+        {index} a_ = i_ / back_size;
+        {index} b_ = i_ % back_size;
+        float mean = 0.0;
+        float m2 = 0.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
+            float x = (({valid}) != 0) ? (float)inp[{idx}] : 0.0;
+            float delta = x - mean;
+            mean += delta / (c_ + 1);
+            m2 += delta * (x - mean);
+        }}
+        mean_out[i_] = ({type_name})mean;
+        var_out[i_] = ({type_name})(m2 / ({divisor}));
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            dim,
+            unbiased,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaMeanVar<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut shape = tensors[0].1;
+        shape.remove_dim(self.dim);
+        let out_size = shape.n_elements().to_usize().unwrap();
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let back_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
+
+        let mean = alloc_zeros::<T>(&self.device, out_size).unwrap();
+        let var = alloc_zeros::<T>(&self.device, out_size).unwrap();
+        let mut params = vec![
+            (&mean).as_kernel_param(),
+            (&var).as_kernel_param(),
+            inp.as_kernel_param(),
+            back_size.as_kernel_param(),
+            (dim_size as i32).as_kernel_param(),
+            out_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(out_size, DEFAULT_BLOCK_SIZE),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![
+            Tensor::new(CudaData::new(mean)),
+            Tensor::new(CudaData::new(var)),
+        ]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// `x * gamma + beta` in one kernel, with `gamma` and `beta` vectors broadcast along every dimension but `dim`,
+/// like the affine epilogue of a norm layer. Takes the input, then contiguous `gamma` and `beta` with one value
+/// per index of `dim`, and outputs a contiguous tensor of the input's shape.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaAffine<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub dim: usize,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaAffine<T> {
+    pub fn new(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const {type_name} *gamma, const {type_name} *beta, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        int channel = (idx / back_size) % dim_size;
+        float x = (({valid}) != 0) ? (float)inp[{idx}] : 0.0;
+        out[idx] = ({type_name})(x * (float)gamma[channel] + (float)beta[channel]);
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            dim,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaAffine<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let gamma = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let beta = get_buffer_from_tensor::<T>(&tensors[2].0);
+        let shape = tensors[0].1.shape();
+        let back_size: usize = shape
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let dim_size = shape[self.dim].to_usize().unwrap();
+        let numel = tensors[0].1.n_elements().to_usize().unwrap();
+
+        let out = alloc_zeros::<T>(&self.device, numel).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            gamma.as_kernel_param(),
+            beta.as_kernel_param(),
+            back_size.as_kernel_param(),
+            (dim_size as i32).as_kernel_param(),
+            numel.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(numel, DEFAULT_BLOCK_SIZE),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Apply a repetition penalty to contiguous logits on the device, following the Hugging Face convention:
+/// the logits of tokens in the history are divided by `penalty` if positive, and multiplied by it otherwise.
+/// Takes the logits, with the vocabulary as the last dimension, then the history token ids. Every row is penalized
+/// with the same history, and a token repeated in the history is only penalized once.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaApplyRepetitionPenalty<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub penalty: f32,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaApplyRepetitionPenalty<T> {
+    pub fn new(penalty: f32, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *logits, const {type_name} *history, int *out_of_range, float penalty, int vocab_size, int history_len, long long numel) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        long long row = idx / history_len;
+        int token = (int)(float)history[idx % history_len];
+        if (token < 0 || token >= vocab_size) {{
+            out_of_range[0] = 1;
+        }} else {{
+            // Reads the original logits, so duplicate tokens write the same value
+            float logit = (float)logits[row * vocab_size + token];
+            out[row * vocab_size + token] = ({type_name})(logit > 0.0 ? logit / penalty : logit * penalty);
+        }}
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            penalty,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaApplyRepetitionPenalty<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let logits = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let history = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let vocab_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let rows = tensors[0].1.n_elements().to_usize().unwrap() / vocab_size;
+        let history_len = tensors[1].1.n_elements().to_usize().unwrap();
+        let mut out = alloc_copy(logits).unwrap();
+        let numel = rows * history_len;
+        if numel == 0 {
+            return vec![Tensor::new(CudaData::new(out))];
+        }
+        let mut out_of_range = CudaData::new(alloc_zeros::<i32>(&self.device, 1).unwrap());
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(numel, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut out,
+                        logits,
+                        history,
+                        &mut *out_of_range.0,
+                        self.penalty,
+                        vocab_size,
+                        history_len,
+                        numel,
+                    ),
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            out_of_range.to_vec()[0],
+            0,
+            "Repetition penalty history has token ids outside the vocabulary of {vocab_size}"
+        );
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// KL divergence `sum(p * log(p / q))` of each row along the last dimension, taking contiguous `p` then `q`
+/// and outputting one value per row. Entries where `p` is 0 contribute 0. Accumulates in f32.
+/// With `from_logits`, both inputs are logits and are softmaxed first.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaKLDiv<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub from_logits: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaKLDiv<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        Self::compile(false, device)
+    }
+
+    pub fn from_logits(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        Self::compile(true, device)
+    }
+
+    fn compile(from_logits: bool, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        // Logits are converted to log-probabilities with a log-softmax
+        let (log_sums, log_p, p_i, log_q) = if from_logits {
+            (
+                "float p_log_sum = log_sum_exp(p_row, row_size);
+        float q_log_sum = log_sum_exp(q_row, row_size);",
+                "(float)p_row[i] - p_log_sum",
+                "expf(log_p)",
+                "(float)q_row[i] - q_log_sum",
+            )
+        } else {
+            (
+                "",
+                "logf((float)p_row[i])",
+                "(float)p_row[i]",
+                "logf((float)q_row[i])",
+            )
+        };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+__device__ float log_sum_exp(const {type_name} *x, int row_size) {{
+    float max_value = -__int_as_float(0x7f800000);
+    for (int i = 0; i < row_size; i++) {{
+        max_value = max(max_value, (float)x[i]);
+    }}
+    float exp_sum = 0.0;
+    for (int i = 0; i < row_size; i++) {{
+        exp_sum += expf((float)x[i] - max_value);
+    }}
+    return logf(exp_sum) + max_value;
+}}
+
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *p, const {type_name} *q, long long n_rows, int row_size) {{
+    long long row = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (row < n_rows) {{
+        const {type_name} *p_row = p + row * row_size;
+        const {type_name} *q_row = q + row * row_size;
+        {log_sums}
+        float kl = 0.0;
+        for (int i = 0; i < row_size; i++) {{
+            float log_p = {log_p};
+            float p_i = {p_i};
+            if (p_i > 0.0) {{
+                kl += p_i * (log_p - ({log_q}));
+            }}
+        }}
+        out[row] = ({type_name})kl;
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            from_logits,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaKLDiv<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let p = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let q = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let row_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let n_rows = tensors[0].1.n_elements().to_usize().unwrap() / row_size;
+        let mut out = alloc_zeros::<T>(&self.device, n_rows).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_rows, DEFAULT_BLOCK_SIZE),
+                    (&mut out, p, q, n_rows, row_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Threads per block in `CudaSoftmax`'s per-row kernel
+const SOFTMAX_ROW_THREADS: u32 = 256;
+
+/// Folds a partial `(max, sum of exp(x - max))` into a running one, rescaling the sum that has the smaller max.
+/// Partials with no elements have a max of -inf and leave the other untouched.
+const ONLINE_SOFTMAX_UPDATE: &str = "
+__device__ void online_softmax_update(float &max_value, float &exp_sum, float other_max, float other_sum) {
+    float new_max = max(max_value, other_max);
+    if (new_max == -__int_as_float(0x7f800000)) return;
+    exp_sum = exp_sum * expf(max_value - new_max) + other_sum * expf(other_max - new_max);
+    max_value = new_max;
+}";
+
+/// Softmax of a contiguous tensor along any `dim`.
+///
+/// The max and the sum of exponentials are found in a single online pass, rescaling the running sum whenever the
+/// max grows, so large inputs never overflow `exp`.
+///
+/// Short rows are handled by one thread each, striding through the input. Rows of at least `transpose_min_dim`
+/// elements are instead softmaxed a block per row with coalesced reads, the threads' partial results combined
+/// through shared memory. Along a non-last `dim` these rows are transposed so `dim` is last first and written back
+/// transposed, which is much faster for long strided rows.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaSoftmax<T> {
+    strided_function: CudaFunction,
+    transpose_function: CudaFunction,
+    row_function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub dim: usize,
+    pub transpose_min_dim: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaSoftmax<T> {
+    pub fn new(dim: usize, device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
+        let type_name = T::type_name();
+        let strided = format!(
+            "
+#include \"cuda_fp16.h\"
+{ONLINE_SOFTMAX_UPDATE}
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int back_size, int dim_size, long long n_rows) {{
+    long long row = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (row < n_rows) {{
+        long long start = (row / back_size) * dim_size * back_size + row % back_size;
+        float max_value = -__int_as_float(0x7f800000);
+        float exp_sum = 0.0;
+        for (long long c = 0; c < dim_size; c++) {{
+            online_softmax_update(max_value, exp_sum, (float)inp[start + c * back_size], 1.0);
+        }}
+        for (long long c = 0; c < dim_size; c++) {{
+            out[start + c * back_size] = ({type_name})(expf((float)inp[start + c * back_size] - max_value) / exp_sum);
+        }}
+    }}
+}}"
+        );
+        // Moves dim to the end: (front, dim, back) -> (front, back, dim)
+        let transpose = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int back_size, int dim_size, long long numel) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (i < numel) {{
+        long long a = i / ((long long)back_size * dim_size);
+        long long b = (i / dim_size) % back_size;
+        long long c = i % dim_size;
+        out[i] = inp[a * dim_size * back_size + c * back_size + b];
+    }}
+}}"
+        );
+        // One block per contiguous row, writing back to the (front, dim, back) layout
+        let row = format!(
+            "
+#include \"cuda_fp16.h\"
+{ONLINE_SOFTMAX_UPDATE}
+__device__ void warp_softmax_reduce(float &max_value, float &exp_sum) {{
+    for (int offset = 16; offset > 0; offset /= 2) {{
+        float other_max = __shfl_xor_sync(0xffffffff, max_value, offset);
+        float other_sum = __shfl_xor_sync(0xffffffff, exp_sum, offset);
+        online_softmax_update(max_value, exp_sum, other_max, other_sum);
+    }}
+}}
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int back_size, int dim_size) {{
+    __shared__ float warp_maxes[{SOFTMAX_ROW_THREADS} / 32];
+    __shared__ float warp_sums[{SOFTMAX_ROW_THREADS} / 32];
+    long long row = blockIdx.x;
+    int warp = threadIdx.x / 32, lane = threadIdx.x % 32;
+    const {type_name} *x = inp + row * dim_size;
+    float max_value = -__int_as_float(0x7f800000);
+    float exp_sum = 0.0;
+    for (int c = threadIdx.x; c < dim_size; c += blockDim.x) {{
+        online_softmax_update(max_value, exp_sum, (float)x[c], 1.0);
+    }}
+    warp_softmax_reduce(max_value, exp_sum);
+    if (lane == 0) {{
+        warp_maxes[warp] = max_value;
+        warp_sums[warp] = exp_sum;
+    }}
+    __syncthreads();
+    max_value = lane < blockDim.x / 32 ? warp_maxes[lane] : -__int_as_float(0x7f800000);
+    exp_sum = lane < blockDim.x / 32 ? warp_sums[lane] : 0.0;
+    warp_softmax_reduce(max_value, exp_sum);
+    long long start = (row / back_size) * dim_size * back_size + row % back_size;
+    for (int c = threadIdx.x; c < dim_size; c += blockDim.x) {{
+        out[start + (long long)c * back_size] = ({type_name})(expf((float)x[c] - max_value) / exp_sum);
+    }}
+}}"
+        );
+        Ok(Self {
+            strided_function: compile_and_load_kernel(strided, &device)?,
+            transpose_function: compile_and_load_kernel(transpose, &device)?,
+            row_function: compile_and_load_kernel(row, &device)?,
+            device,
+            dim,
+            transpose_min_dim: 256,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSoftmax<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let shape = tensors[0]
+            .1
+            .shape()
+            .into_iter()
+            .map(|d| d.to_usize().unwrap())
+            .collect::<Vec<_>>();
+        let back_size = shape[self.dim + 1..].iter().product::<usize>();
+        let dim_size = shape[self.dim];
+        let inp_size = shape.iter().product::<usize>();
+        let n_rows = inp_size / dim_size;
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        if dim_size < self.transpose_min_dim {
+            unsafe {
+                self.strided_function
+                    .clone()
+                    .launch_on_device_stream(
+                        &self.device,
+                        elementwise_launch_config(n_rows, DEFAULT_BLOCK_SIZE),
+                        (&mut out, inp, back_size, dim_size, n_rows),
+                    )
+                    .unwrap();
+            }
+            return vec![Tensor::new(CudaData::new(out))];
+        }
+
+        let transposed = if back_size == 1 {
+            None
+        } else {
+            let mut transposed =
+                CudaData::new(unsafe { alloc::<T>(&self.device, inp_size) }.unwrap());
+            unsafe {
+                self.transpose_function
+                    .clone()
+                    .launch_on_device_stream(
+                        &self.device,
+                        elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                        (&mut *transposed.0, inp, back_size, dim_size, inp_size),
+                    )
+                    .unwrap();
+            }
+            Some(transposed)
+        };
+        let rows = transposed.as_ref().map_or(inp, |t| &t.0);
+        unsafe {
+            self.row_function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    LaunchConfig {
+                        grid_dim: (grid_size(n_rows, 1), 1, 1),
+                        block_dim: (SOFTMAX_ROW_THREADS, 1, 1),
+                        shared_mem_bytes: 0,
+                    },
+                    (&mut out, rows, back_size, dim_size),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Threads per block in `CudaRMSNorm`, each block normalizing one row
+const RMS_NORM_THREADS: u32 = 256;
+
+/// RMS normalization along the last dimension, scaled by a weight: `x / sqrt(mean(x^2) + epsilon) * weight`.
+/// Each row is normalized by one block, which reduces the mean square through shared memory and then scales the
+/// row, so the whole norm is a single launch.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaRMSNorm<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub epsilon: f32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaRMSNorm<T> {
+    pub fn new(
+        epsilon: f32,
+        shape: ShapeTracker,
+        weight_shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (weight_idx, weight_valid) = get_idx_valid_exps(weight_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape, weight_shape]);
+        let index = index_type(&[shape, weight_shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const {type_name} *weight, float epsilon, int dim_size{rendered}) {{
+    __shared__ {acc} partials[{RMS_NORM_THREADS}];
+    {index} row = blockIdx.x;
+    {acc} sum = 0.0;
+    for (int c = threadIdx.x; c < dim_size; c += blockDim.x) {{
+        {index} idx = row * dim_size + c;
+        {acc} x = ({valid}) == 0 ? ({acc})0.0 : ({acc})inp[{idx}];
+        sum += x * x;
+    }}
+    partials[threadIdx.x] = sum;
+    __syncthreads();
+    for (int stride = blockDim.x / 2; stride > 0; stride /= 2) {{
+        if (threadIdx.x < stride) {{
+            partials[threadIdx.x] += partials[threadIdx.x + stride];
+        }}
+        __syncthreads();
+    }}
+    {acc} scale = ({acc})1.0 / sqrt(partials[0] / dim_size + epsilon);
+    for (int c = threadIdx.x; c < dim_size; c += blockDim.x) {{
+        {index} idx = row * dim_size + c;
+        {acc} x = ({valid}) == 0 ? ({acc})0.0 : ({acc})inp[{idx}];
+        {acc} w = ({weight_valid}) == 0 ? ({acc})0.0 : ({acc})weight[{weight_idx}];
+        out[idx] = ({type_name})(x * scale * w);
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            epsilon,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaRMSNorm<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let weight = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let numel = tensors[0].1.n_elements().to_usize().unwrap();
+        let dim_size = tensors[0].1.shape().last().unwrap().to_usize().unwrap();
+        let n_rows = numel.checked_div(dim_size).unwrap_or_default();
+        let out = alloc_zeros::<T>(&self.device, numel).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            weight.as_kernel_param(),
+            self.epsilon.as_kernel_param(),
+            (dim_size as i32).as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    LaunchConfig {
+                        grid_dim: (grid_size(n_rows, 1), 1, 1),
+                        block_dim: (RMS_NORM_THREADS, 1, 1),
+                        shared_mem_bytes: 0,
+                    },
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace RMSNorm's `std_norm` over the last dimension followed by a weight multiply, which otherwise runs as
+/// separate square, mean, add, sqrt, recip and multiply kernels, with a `CudaRMSNorm`. The epsilon is read from
+/// the graph's constant.
+#[derive(LuminalPrint, Default)]
+pub struct RMSNormCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> RMSNormCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for RMSNormCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        let (x, weight) = (node(), node());
+        let square = op::<CudaMul<T>>();
+        let mean = unary::<CudaMeanReduce<T>>(square.clone());
+        let epsilon = op::<CudaConstant<T>>();
+        let add = binary::<CudaAdd<T>>(mean.clone(), epsilon.clone());
+        let recip = unary::<CudaRecip<T>>(unary::<CudaSqrt<T>>(add.clone()));
+        let norm = binary::<CudaMul<T>>(recip.clone(), x.clone());
+        let out = binary::<CudaMul<T>>(norm.clone(), weight.clone());
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                continue;
+            }
+            let (x, weight, out) = (s.get(&x), s.get(&weight), s.get(&out));
+            let ConstantValue::Float(epsilon) = graph
+                .node_weight(s.get(&epsilon))
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaConstant<T>>()
+                .unwrap()
+                .value
+            else {
+                continue;
+            };
+            let x_edge = graph
+                .get_sources(s.get(&norm))
+                .into_iter()
+                .find(|(n, _, _)| *n == x)
+                .unwrap();
+            // The mean must be of the square of the same view of x, over its last dimension
+            let squared = graph.get_sources(s.get(&square));
+            if squared.len() != 2
+                || squared
+                    .iter()
+                    .any(|(n, o, sh)| *n != x || *o != x_edge.1 || *sh != x_edge.2)
+            {
+                continue;
+            }
+            let dim = graph
+                .node_weight(s.get(&mean))
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaMeanReduce<T>>()
+                .unwrap()
+                .dim;
+            if dim != x_edge.2.len() - 1 {
+                continue;
+            }
+            let (_, weight_output, weight_shape) = graph
+                .get_sources(out)
+                .into_iter()
+                .find(|(n, _, _)| *n == weight)
+                .unwrap();
+            let rms_norm = graph
+                .add_op(
+                    CudaRMSNorm::<T>::new(
+                        epsilon,
+                        x_edge.2,
+                        weight_shape,
+                        dev.clone(),
+                        &graph.dyn_map,
+                    )
+                    .unwrap(),
+                )
+                .input(x, x_edge.1, x_edge.2)
+                .input(weight, weight_output, weight_shape)
+                .finish();
+            move_outgoing_edge(out, rms_norm, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                out,
+                rms_norm,
+            );
+            graph.graph.remove_node(out);
+            s.try_delete();
+        }
+    }
+}
+
+/// Replace softmax's max reduce, subtract, exp, sum reduce and divide with a single `CudaSoftmax`
+#[derive(LuminalPrint, Default)]
+pub struct SoftmaxCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> SoftmaxCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for SoftmaxCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        // mul(recip(sum_reduce(exp)), exp) where exp = exp2(sub(x, max_reduce(x)) * (1 / ln 2))
+        let max = op::<CudaMaxReduce<T>>();
+        let sub = unary::<CudaSub<T>>(max.clone());
+        let exp = unary::<CudaExp2<T>>(binary::<CudaMul<T>>(
+            sub.clone(),
+            constant::<T>(1.0 / f32::ln(2.)),
+        ));
+        let sum = unary::<CudaSumReduce<T>>(exp.clone());
+        let recip = unary::<CudaRecip<T>>(sum.clone());
+        let out = unary::<CudaMul<T>>(recip.clone());
+        let mut s = out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[out.id]) {
+                continue;
+            }
+            let (max, sub, exp, sum, recip, out) = (
+                s.get(&max),
+                s.get(&sub),
+                s.get(&exp),
+                s.get(&sum),
+                s.get(&recip),
+                s.get(&out),
+            );
+            let (x, x_output, x_shape) = graph.get_sources(max)[0];
+            let dim = graph
+                .node_weight(max)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaMaxReduce<T>>()
+                .unwrap()
+                .dim;
+            // The max must be subtracted from the same contiguous x, the exps summed over the same dim, and the
+            // normalized values must be those exps, read as is and divided by their sum broadcast back along dim
+            let out_sources = graph.get_sources(out);
+            if !graph
+                .get_sources(sub)
+                .iter()
+                .any(|(n, o, sh)| *n == x && *o == x_output && *sh == x_shape)
+                || !x_shape.is_contiguous()
+                || x_shape.is_sliced()
+                || x_shape.is_padded()
+                || graph
+                    .node_weight(sum)
+                    .unwrap()
+                    .as_any()
+                    .downcast_ref::<CudaSumReduce<T>>()
+                    .unwrap()
+                    .dim
+                    != dim
+                || !out_sources.iter().any(|(n, _, sh)| {
+                    *n == exp && sh.is_contiguous() && !sh.is_sliced() && !sh.is_padded()
+                })
+                || !out_sources
+                    .iter()
+                    .any(|(n, _, sh)| *n == recip && sh.fake[sh.indexes[dim]])
+            {
+                continue;
+            }
+            let softmax = graph
+                .add_op(CudaSoftmax::<T>::new(dim, dev.clone()).unwrap())
+                .input(x, x_output, x_shape)
+                .finish();
+            move_outgoing_edge(out, softmax, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                out,
+                softmax,
+            );
+            graph.graph.remove_node(out);
+            s.try_delete();
+        }
+    }
+}
@@ -1,6 +1,11 @@
-use crate::{compile_and_load_kernel, get_buffer_from_tensor, input_dyn_dims, CudaData, CudaFloat};
+use crate::{
+    alloc, alloc_zeros, compile_and_load_kernel, constant, copy_to_host, cuda_device,
+    cuda_op_marker, device_stream, elementwise_launch_config, expr_to_cuda_string,
+    get_buffer_from_tensor, grid_size, input_dyn_dims, upload, CudaCompileError, CudaData,
+    CudaError, CudaFloat, LaunchOnDeviceStream, PinnedBuffer, RawF16Bytes, DEFAULT_BLOCK_SIZE,
+};
 
-use super::{get_idx_valid_exps, render_dyn_dim_inputs};
+use super::{get_idx_valid_exps, index_bounds, index_type, render_dyn_dim_inputs};
 use itertools::Itertools;
 use rustc_hash::FxHashMap;
 
@@ -11,20 +16,32 @@ use std::{
     sync::Arc,
 };
 
-use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
+use luminal_cudarc::driver::{
+    result, CudaDevice, CudaFunction, DevicePtr, DevicePtrMut, DeviceRepr, DeviceSlice,
+    LaunchConfig,
+};
 
 use luminal::{
     op::{Function as LFunction, *},
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::{petgraph::visit::EdgeRef, symbolic::BigExpression, *},
 };
 
-/// Copy a tensor to the GPU
+/// Copy a tensor to the GPU, staging it through a pinned host buffer if one is set
 #[derive(Clone, LuminalEqFalse, LuminalPrint)]
-pub struct CudaCopyToDevice<T>(Arc<CudaDevice>, PhantomData<T>);
+pub struct CudaCopyToDevice<T>(Arc<CudaDevice>, Option<PinnedBuffer<T>>);
 
 impl<T> CudaCopyToDevice<T> {
     pub fn new(dev: Arc<CudaDevice>) -> Self {
-        CudaCopyToDevice(dev, Default::default())
+        CudaCopyToDevice(dev, None)
+    }
+
+    /// Stage copies through a reusable pinned (page-locked) host buffer rather than pageable memory
+    pub fn pinned(dev: Arc<CudaDevice>) -> Self {
+        CudaCopyToDevice(dev, Some(Default::default()))
+    }
+
+    pub fn device(&self) -> &Arc<CudaDevice> {
+        &self.0
     }
 }
 
@@ -34,6 +51,29 @@ impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
             // Already on device
             return vec![inp.pop().unwrap().0.cloned()];
         }
+        // Integer tensors keep their type on the device
+        let data = inp[0].0.borrowed().data.as_any();
+        if data.is::<CudaData<i32>>() || data.is::<CudaData<u32>>() {
+            return vec![inp.pop().unwrap().0.cloned()];
+        }
+        if let Some(ints) = data.downcast_ref::<Vec<i32>>() {
+            return vec![Tensor::new(CudaData::new(upload(&self.0, ints).unwrap()))];
+        }
+        if let Some(ints) = data.downcast_ref::<Vec<u32>>() {
+            return vec![Tensor::new(CudaData::new(upload(&self.0, ints).unwrap()))];
+        }
+        if let Some(RawF16Bytes(bytes)) = inp[0].0.borrowed().data.as_any().downcast_ref() {
+            if T::type_name() == f16::type_name() {
+                return vec![Tensor::new(
+                    CudaData::<T>::from_bytes(&self.0, bytes).unwrap_or_else(|e| panic!("{e}")),
+                )];
+            }
+            let vec = bytes
+                .chunks_exact(2)
+                .map(|c| T::from_f32(f16::from_le_bytes([c[0], c[1]]).to_f32()))
+                .collect::<Vec<_>>();
+            return vec![Tensor::new(CudaData::new(upload(&self.0, &vec).unwrap()))];
+        }
         let cpu_data = inp[0]
             .0
             .borrowed()
@@ -41,24 +81,45 @@ impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
             .as_any()
             .downcast_ref::<Vec<f32>>()
             .unwrap();
-        let vec = cpu_data
-            .iter()
-            .copied()
-            .map(CudaFloat::from_f32)
-            .collect::<Vec<_>>();
-        let mut a = unsafe { self.0.alloc::<T>(vec.len()).unwrap() };
-        self.0.htod_copy_into(vec, &mut a).unwrap();
-        vec![Tensor::new(CudaData(a))]
+        let Some(pinned) = &mut self.1 else {
+            return vec![Tensor::new(CudaData::<T>::from_host(&self.0, cpu_data))];
+        };
+        let mut a = unsafe { alloc::<T>(&self.0, cpu_data.len()).unwrap() };
+        let stream = device_stream(&self.0);
+        if let Some(stream) = &stream {
+            // The last upload from the staging buffer has to finish before it's overwritten
+            unsafe { result::stream::synchronize(stream.stream) }.unwrap();
+        }
+        let staging = pinned.slice_mut(&self.0, cpu_data.len());
+        for (s, d) in staging.iter_mut().zip(cpu_data) {
+            *s = T::from_f32(*d);
+        }
+        if let Some(stream) = stream {
+            unsafe { result::memcpy_htod_async(*a.device_ptr_mut(), staging, stream.stream) }
+                .unwrap();
+        } else {
+            self.0.htod_sync_copy_into(staging, &mut a).unwrap();
+        }
+        vec![Tensor::new(CudaData::new(a))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
     }
 }
 
-/// Copy a tensor from the GPU
+/// Copy a tensor from the GPU, staging it through a pinned host buffer if one is set
 #[derive(Clone, LuminalEqFalse, LuminalPrint)]
-pub struct CudaCopyFromDevice<T>(Arc<CudaDevice>, PhantomData<T>);
+pub struct CudaCopyFromDevice<T>(Arc<CudaDevice>, Option<PinnedBuffer<T>>);
 
 impl<T> CudaCopyFromDevice<T> {
     pub fn new(dev: Arc<CudaDevice>) -> Self {
-        CudaCopyFromDevice(dev, Default::default())
+        CudaCopyFromDevice(dev, None)
+    }
+
+    /// Stage copies through a reusable pinned (page-locked) host buffer rather than pageable memory
+    pub fn pinned(dev: Arc<CudaDevice>) -> Self {
+        CudaCopyFromDevice(dev, Some(Default::default()))
     }
 }
 
@@ -68,15 +129,32 @@ impl<T: CudaFloat> Operator for CudaCopyFromDevice<T> {
             // Already off device
             return vec![inp.pop().unwrap().0.cloned()];
         }
+        let data = inp[0].0.borrowed().data.as_any();
+        if let Some(ints) = data.downcast_ref::<CudaData<i32>>() {
+            return vec![Tensor::new(ints.to_vec())];
+        }
+        if let Some(ints) = data.downcast_ref::<CudaData<u32>>() {
+            return vec![Tensor::new(ints.to_vec())];
+        }
+        let data = data.downcast_ref::<CudaData<T>>().unwrap();
+        let Some(pinned) = &mut self.1 else {
+            return vec![Tensor::new(data.to_host())];
+        };
+        let staging = pinned.slice_mut(&self.0, data.0.len());
+        // Retrieval is where we wait for the device's stream
+        unsafe { copy_to_host(&self.0, staging, *data.0.device_ptr()) }.unwrap();
         vec![Tensor::new(
-            self.0
-                .dtoh_sync_copy(get_buffer_from_tensor::<T>(&inp[0].0))
-                .unwrap()
-                .into_iter()
+            staging
+                .iter()
+                .copied()
                 .map(CudaFloat::to_f32)
                 .collect::<Vec<_>>(),
         )]
     }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
 }
 
 /// Constant value on device
@@ -110,15 +188,19 @@ impl<T> CudaConstant<T> {
 
 impl<T: CudaFloat> Operator for CudaConstant<T> {
     fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let mut a = unsafe { self.device.alloc::<T>(1).unwrap() };
         let value = match &self.value {
             ConstantValue::Expression(e) => {
                 T::from_f32(e.exec(unsafe { self.dyn_map.as_ref().unwrap() }).unwrap() as f32)
             }
             ConstantValue::Float(f) => T::from_f32(*f),
         };
-        self.device.htod_copy_into(vec![value], &mut a).unwrap();
-        vec![Tensor::new(CudaData(a))]
+        vec![Tensor::new(CudaData::new(
+            upload(&self.device, &[value]).unwrap(),
+        ))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
     }
 }
 
@@ -126,9 +208,12 @@ impl<T: CudaFloat> Operator for CudaConstant<T> {
 pub struct CudaContiguous<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
+    /// The views the input is read through, innermost first. Chains of contiguous ops collapse into one of these.
+    views: Vec<ShapeTracker>,
 }
 
 impl<T: CudaFloat> CudaContiguous<T> {
@@ -136,34 +221,68 @@ impl<T: CudaFloat> CudaContiguous<T> {
         shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
-        let (idx, valid) = get_idx_valid_exps(shape);
-        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+    ) -> Result<Self, CudaCompileError> {
+        Self::from_views(vec![shape], device, dyn_map)
+    }
+
+    /// Materialize the input as read through each view in turn, as a chain of contiguous ops would, in one kernel
+    pub fn from_views(
+        views: Vec<ShapeTracker>,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        // Each view maps its logical index into the output of the view before it
+        let mut index = views[0].index_expression();
+        let mut valid = views[0].valid_expression();
+        for view in &views[1..] {
+            let outer_index = view.index_expression();
+            valid =
+                (view.valid_expression() & valid.substitute('z', outer_index.clone())).minimize();
+            index = index.substitute('z', outer_index).minimize();
+        }
+        let bounds = index_bounds(views.last().unwrap());
+        let (idx, valid) = (
+            expr_to_cuda_string(index.simplify(&bounds)),
+            expr_to_cuda_string(valid.simplify(&bounds)),
+        );
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&views);
+        let index = index_type(&views);
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel && ({valid}) != 0) {{
         out[idx] = inp_a[{idx}];
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+            views,
+        })
     }
 }
 impl<T: CudaFloat> Operator for CudaContiguous<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let res_shape = tensors[0].1.contiguous();
-        let inp_size = res_shape.n_elements().to_usize().unwrap();
+        let inp_size = if self.views.len() == 1 {
+            tensors[0].1.contiguous().n_elements().to_usize().unwrap()
+        } else {
+            // The output takes the shape of the outermost view
+            self.views
+                .last()
+                .unwrap()
+                .n_elements()
+                .exec(unsafe { self.dyn_map.as_ref().unwrap() })
+                .unwrap()
+        };
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -173,11 +292,99 @@ impl<T: CudaFloat> Operator for CudaContiguous<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Collapse chains of contiguous ops, which repeated permutes and reshapes leave behind, into single kernels
+/// that read the first op's input through every view at once instead of materializing each step
+#[derive(Debug, Default)]
+pub struct ContiguousFusionCompiler<T>(pub usize, PhantomData<T>);
+
+impl<T> ContiguousFusionCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for ContiguousFusionCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let device = cuda_device(self.0);
+        let mut matched = true;
+        while matched {
+            matched = false;
+            for edge in graph.edge_indices().collect::<Vec<_>>() {
+                let Some((first, second)) = graph.edge_endpoints(edge) else {
+                    continue;
+                };
+                let Some((_, _, outer)) = graph.edge_weight(edge).unwrap().as_data() else {
+                    continue;
+                };
+                let (Some(first_op), Some(second_op)) = (
+                    graph
+                        .node_weight(first)
+                        .unwrap()
+                        .as_any()
+                        .downcast_ref::<CudaContiguous<T>>(),
+                    graph
+                        .node_weight(second)
+                        .unwrap()
+                        .as_any()
+                        .downcast_ref::<CudaContiguous<T>>(),
+                ) else {
+                    continue;
+                };
+                // The first result has to be used only by the second to disappear
+                if graph.no_delete.contains(&first)
+                    || graph
+                        .edges_directed(first, petgraph::Direction::Outgoing)
+                        .filter(|e| !e.weight().is_schedule())
+                        .count()
+                        != 1
+                {
+                    continue;
+                }
+                // The second op reads the first's output through the connecting edge's view, not its own first view
+                let mut views = first_op.views.clone();
+                views.push(outer);
+                views.extend(&second_op.views[1..]);
+                let (src, src_out, src_shape) = graph.get_sources(first)[0];
+                let fused = graph
+                    .add_op(
+                        CudaContiguous::<T>::from_views(views, device.clone(), &graph.dyn_map)
+                            .unwrap(),
+                    )
+                    .input(src, src_out, src_shape)
+                    .finish();
+                move_outgoing_edge(second, fused, &mut graph.graph);
+                move_references(
+                    &mut remap,
+                    &mut graph.no_delete,
+                    &mut graph.to_retrieve,
+                    second,
+                    fused,
+                );
+                graph.remove_node(first);
+                graph.remove_node(second);
+                matched = true;
+            }
+        }
     }
 }
 
@@ -185,27 +392,29 @@ impl<T: CudaFloat> Operator for CudaContiguous<T> {
 pub struct CudaLog2<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
 }
 
 impl<T: CudaFloat> CudaLog2<T> {
-    pub fn new(device: Arc<CudaDevice>) -> Self {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
     if (i < numel) {{
         out[i] = log2(inp[i]);
     }}
 }}"
         );
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
-        }
+        })
     }
 }
 
@@ -213,18 +422,26 @@ impl<T: CudaFloat> Operator for CudaLog2<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         unsafe {
             self.function
                 .clone()
-                .launch(
-                    LaunchConfig::for_num_elems(inp_size as u32),
-                    (&mut out, inp, inp_size),
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    (&mut out, inp, inp_size as i64),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -232,45 +449,55 @@ impl<T: CudaFloat> Operator for CudaLog2<T> {
 pub struct CudaExp2<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
 }
 
 impl<T: CudaFloat> CudaExp2<T> {
-    pub fn new(device: Arc<CudaDevice>) -> Self {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
     if (i < numel) {{
         out[i] = exp2(inp[i]);
     }}
 }}"
         );
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
-        }
+        })
     }
 }
 impl<T: CudaFloat> Operator for CudaExp2<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         unsafe {
             self.function
                 .clone()
-                .launch(
-                    LaunchConfig::for_num_elems(inp_size as u32),
-                    (&mut out, inp, inp_size),
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    (&mut out, inp, inp_size as i64),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -278,46 +505,59 @@ impl<T: CudaFloat> Operator for CudaExp2<T> {
 pub struct CudaSqrt<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
 }
 
 impl<T: CudaFloat> CudaSqrt<T> {
-    pub fn new(device: Arc<CudaDevice>) -> Self {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
     if (i < numel) {{
         out[i] = {}(inp[i]);
     }}
 }}",
-            if T::is_f32() { "sqrt" } else { "hsqrt" }
+            match type_name {
+                "float" | "double" => "sqrt",
+                _ => "hsqrt",
+            }
         );
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
-        }
+        })
     }
 }
 impl<T: CudaFloat> Operator for CudaSqrt<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         unsafe {
             self.function
                 .clone()
-                .launch(
-                    LaunchConfig::for_num_elems(inp_size as u32),
-                    (&mut out, inp, inp_size),
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    (&mut out, inp, inp_size as i64),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -325,27 +565,29 @@ impl<T: CudaFloat> Operator for CudaSqrt<T> {
 pub struct CudaSin<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
 }
 
 impl<T: CudaFloat> CudaSin<T> {
-    pub fn new(device: Arc<CudaDevice>) -> Self {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
     if (i < numel) {{
         out[i] = sin(inp[i]);
     }}
 }}"
         );
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
-        }
+        })
     }
 }
 
@@ -353,18 +595,26 @@ impl<T: CudaFloat> Operator for CudaSin<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         unsafe {
             self.function
                 .clone()
-                .launch(
-                    LaunchConfig::for_num_elems(inp_size as u32),
-                    (&mut out, inp, inp_size),
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    (&mut out, inp, inp_size as i64),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -372,28 +622,34 @@ impl<T: CudaFloat> Operator for CudaSin<T> {
 pub struct CudaRecip<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
 }
 
 impl<T: CudaFloat> CudaRecip<T> {
-    pub fn new(device: Arc<CudaDevice>) -> Self {
+    pub fn new(device: Arc<CudaDevice>) -> Result<Self, CudaCompileError> {
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, int numel) {{
-    int i = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel) {{
+    long long i = (long long)blockIdx.x * blockDim.x + threadIdx.x;
     if (i < numel) {{
         out[i] = {}(inp[i]);
     }}
 }}",
-            if T::is_f32() { "__frcp_rn" } else { "hrcp" }
+            match type_name {
+                "float" => "__frcp_rn",
+                "double" => "__drcp_rn",
+                _ => "hrcp",
+            }
         );
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
-        }
+        })
     }
 }
 
@@ -401,18 +657,26 @@ impl<T: CudaFloat> Operator for CudaRecip<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
         let inp_size = tensors[0].1.n_physical_elements().to_usize().unwrap();
-        let mut out = self.device.alloc_zeros::<T>(inp_size).unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
         unsafe {
             self.function
                 .clone()
-                .launch(
-                    LaunchConfig::for_num_elems(inp_size as u32),
-                    (&mut out, inp, inp_size),
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    (&mut out, inp, inp_size as i64),
                 )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -420,6 +684,7 @@ impl<T: CudaFloat> Operator for CudaRecip<T> {
 pub struct CudaAdd<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -431,29 +696,31 @@ impl<T: CudaFloat> CudaAdd<T> {
         b_shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
         let type_name = T::type_name();
         let code = format!(
             "
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel) {{
         out[idx] =
             (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}])
             + (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 
@@ -462,7 +729,7 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { alloc::<T>(&self.device, inp_size).unwrap() };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -474,11 +741,22 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -486,6 +764,7 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
 pub struct CudaMul<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -497,26 +776,28 @@ impl<T: CudaFloat> CudaMul<T> {
         b_shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
         let type_name = T::type_name();
         let code = format!("
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel) {{
         out[idx] = (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}]) * (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 
@@ -525,7 +806,7 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { alloc::<T>(&self.device, inp_size).unwrap() };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -537,11 +818,22 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -549,6 +841,7 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
 pub struct CudaMod<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -560,26 +853,28 @@ impl<T: CudaFloat> CudaMod<T> {
         b_shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
         let type_name = T::type_name();
         let code = format!("
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel) {{
         out[idx] = fmod((({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}]), (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]));
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 
@@ -588,7 +883,7 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { alloc::<T>(&self.device, inp_size).unwrap() };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -600,11 +895,22 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
     }
 }
 
@@ -612,6 +918,7 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
 pub struct CudaLessThan<T> {
     function: CudaFunction,
     device: Arc<CudaDevice>,
+    pub block_size: u32,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -623,15 +930,16 @@ impl<T: CudaFloat> CudaLessThan<T> {
         b_shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let index = index_type(&[a_shape, b_shape]);
         let type_name = T::type_name();
         let code = format!("
 #include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
-    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
     if (idx < numel) {{
         {type_name} a_t = (({a_valid}) != 0) ? inp_a[{a_idx}] : ({type_name})0.0;
         {type_name} b_t = (({b_valid}) != 0) ? inp_b[{b_idx}] : ({type_name})0.0;
@@ -642,13 +950,14 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         }}
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
             device,
+            block_size: DEFAULT_BLOCK_SIZE,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 
@@ -657,7 +966,7 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
         let a = get_buffer_from_tensor::<T>(&tensors[0].0);
         let b = get_buffer_from_tensor::<T>(&tensors[1].0);
         let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
+        let out = unsafe { alloc::<T>(&self.device, inp_size).unwrap() };
         let mut params = vec![
             (&out).as_kernel_param(),
             a.as_kernel_param(),
@@ -669,19 +978,108 @@ impl<T: CudaFloat> Operator for CudaLessThan<T> {
         unsafe {
             self.function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
                 .unwrap();
         }
 
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// The value a reduction's accumulator starts from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReduceInit {
+    /// The reduction's identity: 0 for sums and -inf for max
+    Identity,
+    /// The same value for every output
+    Constant(f32),
+    /// A value per output, from a second input shaped like the output and stored contiguously
+    Tensor,
+}
+
+/// Threads per block in the parallel reduction kernels
+const PARALLEL_REDUCE_THREADS: u32 = 256;
+
+/// A reduction kernel using a block per output. Each thread reduces a strided part of the dimension, then the
+/// block combines them in a tree through shared memory. `combine` folds `x` into `reduce_value`, and `output`
+/// maps the final `reduce_value` to the stored result.
+fn parallel_reduce_kernel<T: CudaFloat>(
+    (init_input, init_value): (&str, &str),
+    identity: &str,
+    combine: &str,
+    output: &str,
+    (idx, valid): (&str, &str),
+    rendered: &str,
+    index: &str,
+) -> String {
+    let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+    format!("#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {init_input}const int front_size, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    __shared__ {acc} partials[{PARALLEL_REDUCE_THREADS}];
+    {index} i_ = blockIdx.x;
+    {index} a_ = i_ / back_size;
+    {index} b_ = i_ % back_size;
+    {acc} reduce_value = {identity};
+    for (int c_ = threadIdx.x; c_ < dim_size; c_ += blockDim.x) {{
+        {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
+        if (({valid}) != 0) {{
+            {acc} x = ({acc})inp[{idx}];
+            reduce_value = {combine};
+        }}
+    }}
+    partials[threadIdx.x] = reduce_value;
+    __syncthreads();
+    for (int stride = blockDim.x / 2; stride > 0; stride /= 2) {{
+        if (threadIdx.x < stride) {{
+            {acc} x = partials[threadIdx.x + stride];
+            reduce_value = partials[threadIdx.x];
+            partials[threadIdx.x] = {combine};
+        }}
+        __syncthreads();
+    }}
+    if (threadIdx.x == 0) {{
+        {acc} x = partials[0];
+        reduce_value = {init_value};
+        reduce_value = {combine};
+        out[i_] = ({type_name})({output});
+    }}
+}}")
+}
+
+/// Launch a reduction with a thread per output, or with `parallel_reduce_kernel`'s block per output
+fn reduce_launch_config(numel: usize, parallel: bool) -> LaunchConfig {
+    if parallel {
+        LaunchConfig {
+            grid_dim: (grid_size(numel, 1), 1, 1),
+            block_dim: (PARALLEL_REDUCE_THREADS, 1, 1),
+            shared_mem_bytes: 0,
+        }
+    } else {
+        elementwise_launch_config(numel, DEFAULT_BLOCK_SIZE)
     }
 }
 
+/// Sum reduce along `dim`. Dimensions of at least `parallel_min_dim` elements are reduced by a block of threads
+/// per output rather than a single thread.
 #[derive(LuminalEqFalse, LuminalPrint, Clone)]
 pub struct CudaSumReduce<T> {
     function: CudaFunction,
+    parallel_function: CudaFunction,
     pub device: Arc<CudaDevice>,
     pub dim: usize,
+    pub init: ReduceInit,
+    pub parallel_min_dim: usize,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -693,35 +1091,67 @@ impl<T: CudaFloat> CudaSumReduce<T> {
         shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
+        Self::with_init(dim, shape, device, dyn_map, ReduceInit::Identity)
+    }
+
+    /// Reduce starting from `init` rather than the identity. `ReduceInit::Tensor` takes the
+    /// starting values from a second input.
+    pub fn with_init(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+        init: ReduceInit,
+    ) -> Result<Self, CudaCompileError> {
         let (idx, valid) = get_idx_valid_exps(shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
-        let type_name = T::type_name();
+        let index = index_type(&[shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let (init_input, init_value) = match init {
+            ReduceInit::Tensor => (
+                format!("const {type_name} *init, "),
+                format!("({acc})init[i_]"),
+            ),
+            _ => ("float init_value, ".to_string(), "init_value".to_string()),
+        };
+        let parallel_code = parallel_reduce_kernel::<T>(
+            (&init_input, &init_value),
+            "0.0",
+            "reduce_value + x",
+            "reduce_value",
+            (&idx, &valid),
+            &rendered,
+            index,
+        );
         let code = format!("#include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const int front_size, const int back_size, const int dim_size, int numel{rendered}) {{
-    int i_ = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {init_input}const int front_size, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
 
     if (i_ < numel) {{
-        int a_ = i_ / back_size;
-        int b_ = i_ % back_size;
-        float reduce_value = 0.0;
+        {index} a_ = i_ / back_size;
+        {index} b_ = i_ % back_size;
+        {acc} reduce_value = {init_value};
         for (int c_ = 0; c_ < dim_size; c_++) {{
-            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
             if (({valid}) != 0) {{
-                reduce_value = reduce_value + (float)inp[{idx}];
+                reduce_value = reduce_value + ({acc})inp[{idx}];
             }}
         }}
         out[i_] = ({type_name})reduce_value;
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            parallel_function: compile_and_load_kernel(parallel_code, &device)?,
             device,
             dim,
+            init,
+            parallel_min_dim: 1024,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 impl<T> Operator for CudaSumReduce<T>
@@ -750,31 +1180,58 @@ where
             .product();
         let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            inp.as_kernel_param(),
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![(&out).as_kernel_param(), inp.as_kernel_param()];
+        let init_value = match self.init {
+            ReduceInit::Constant(v) => v,
+            _ => 0.0,
+        };
+        if self.init == ReduceInit::Tensor {
+            params.push(get_buffer_from_tensor::<T>(&tensors[1].0).as_kernel_param());
+        } else {
+            params.push(init_value.as_kernel_param());
+        }
+        params.extend([
             front_size.as_kernel_param(),
             back_size.as_kernel_param(),
             dim_size.as_kernel_param(),
             inp_size.as_kernel_param(),
-        ];
+        ]);
         input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        let parallel = dim_size >= self.parallel_min_dim;
+        let function = if parallel {
+            &self.parallel_function
+        } else {
+            &self.function
+        };
         unsafe {
-            self.function
+            function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    reduce_launch_config(inp_size, parallel),
+                    &mut params,
+                )
                 .unwrap();
         }
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
     }
 }
 
+/// Max reduce along `dim`. Dimensions of at least `parallel_min_dim` elements are reduced by a block of threads
+/// per output rather than a single thread.
 #[derive(LuminalEqFalse, LuminalPrint, Clone)]
 pub struct CudaMaxReduce<T> {
     function: CudaFunction,
+    parallel_function: CudaFunction,
     pub device: Arc<CudaDevice>,
     pub dim: usize,
+    pub init: ReduceInit,
+    pub parallel_min_dim: usize,
     _phantom: PhantomData<T>,
     dyn_symbols: Vec<char>,
     dyn_map: *const FxHashMap<char, usize>,
@@ -786,35 +1243,67 @@ impl<T: CudaFloat> CudaMaxReduce<T> {
         shape: ShapeTracker,
         device: Arc<CudaDevice>,
         dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    ) -> Result<Self, CudaCompileError> {
+        Self::with_init(dim, shape, device, dyn_map, ReduceInit::Identity)
+    }
+
+    /// Reduce starting from `init` rather than the identity. `ReduceInit::Tensor` takes the
+    /// starting values from a second input.
+    pub fn with_init(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+        init: ReduceInit,
+    ) -> Result<Self, CudaCompileError> {
         let (idx, valid) = get_idx_valid_exps(shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
-        let type_name = T::type_name();
+        let index = index_type(&[shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let (init_input, init_value) = match init {
+            ReduceInit::Tensor => (
+                format!("const {type_name} *init, "),
+                format!("({acc})init[i_]"),
+            ),
+            _ => ("float init_value, ".to_string(), "init_value".to_string()),
+        };
+        let parallel_code = parallel_reduce_kernel::<T>(
+            (&init_input, &init_value),
+            "-__int_as_float(0x7f800000)",
+            "max(reduce_value, x)",
+            "reduce_value",
+            (&idx, &valid),
+            &rendered,
+            index,
+        );
         let code = format!("#include \"cuda_fp16.h\"
-extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const int front_size, const int back_size, const int dim_size, int numel{rendered}) {{
-    int i_ = blockIdx.x * blockDim.x + threadIdx.x;
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {init_input}const int front_size, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
 
     if (i_ < numel) {{
-        int a_ = i_ / back_size;
-        int b_ = i_ % back_size;
-        float reduce_value = -__int_as_float(0x7f800000);
+        {index} a_ = i_ / back_size;
+        {index} b_ = i_ % back_size;
+        {acc} reduce_value = {init_value};
         for (int c_ = 0; c_ < dim_size; c_++) {{
-            int idx = a_ * dim_size * back_size + c_ * back_size + b_;
+            {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
             if (({valid}) != 0) {{
-                reduce_value = max(reduce_value, (float)inp[{idx}]);
+                reduce_value = max(reduce_value, ({acc})inp[{idx}]);
             }}
         }}
         out[i_] = ({type_name})reduce_value;
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            parallel_function: compile_and_load_kernel(parallel_code, &device)?,
             device,
             dim,
+            init,
+            parallel_min_dim: 1024,
             _phantom: Default::default(),
             dyn_symbols,
             dyn_map,
-        }
+        })
     }
 }
 impl<T: CudaFloat> Operator for CudaMaxReduce<T> {
@@ -839,76 +1328,659 @@ impl<T: CudaFloat> Operator for CudaMaxReduce<T> {
             .product();
         let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
 
-        let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            inp.as_kernel_param(),
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![(&out).as_kernel_param(), inp.as_kernel_param()];
+        let init_value = match self.init {
+            ReduceInit::Constant(v) => v,
+            _ => f32::NEG_INFINITY,
+        };
+        if self.init == ReduceInit::Tensor {
+            params.push(get_buffer_from_tensor::<T>(&tensors[1].0).as_kernel_param());
+        } else {
+            params.push(init_value.as_kernel_param());
+        }
+        params.extend([
             front_size.as_kernel_param(),
             back_size.as_kernel_param(),
             dim_size.as_kernel_param(),
             inp_size.as_kernel_param(),
-        ];
+        ]);
         input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        let parallel = dim_size >= self.parallel_min_dim;
+        let function = if parallel {
+            &self.parallel_function
+        } else {
+            &self.function
+        };
         unsafe {
-            self.function
+            function
                 .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
+                .launch_on_device_stream(
+                    &self.device,
+                    reduce_launch_config(inp_size, parallel),
+                    &mut params,
+                )
                 .unwrap();
         }
-        vec![Tensor::new(CudaData(out))]
+        vec![Tensor::new(CudaData::new(out))]
     }
-}
 
-/// Convert all primitive ops to cuda primitive ops, and insert copy to and from device ops
-#[derive(LuminalPrint, Default)]
-pub struct CudaPrimitiveCompiler<T>(PhantomData<T>);
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
 
-impl<T: CudaFloat> Compiler for CudaPrimitiveCompiler<T> {
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
-        let dev = CudaDevice::new(0).unwrap();
-        // Go through the graph and insert copy ops
-        // Copy function output to device and input from device
-        for function_node in graph
-            .node_indices()
-            .filter(|n| {
-                graph.node_weight(*n).unwrap().as_any().is::<Function>()
-                    && graph.edges(*n).count() != 0
-            })
-            .collect::<Vec<_>>()
-        {
-            // Create copy node
-            let copy_node = graph
-                .add_op(CudaCopyToDevice::<T>::new(dev.clone()))
-                .input(function_node, 0, ShapeTracker::new(&[]))
-                .finish();
+/// Min reduce along `dim`. Dimensions of at least `parallel_min_dim` elements are reduced by a block of threads
+/// per output rather than a single thread.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaMinReduce<T> {
+    function: CudaFunction,
+    parallel_function: CudaFunction,
+    pub device: Arc<CudaDevice>,
+    pub dim: usize,
+    pub init: ReduceInit,
+    pub parallel_min_dim: usize,
+    _phantom: PhantomData<T>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
 
-            // Switch outgoing edges from input to copy_node
-            for (edge_id, weight, dest) in graph
-                .edges_directed(function_node, petgraph::Direction::Outgoing)
-                .map(|e| (e.id(), *e.weight(), e.target()))
-                .filter(|(_, _, trg)| *trg != copy_node)
-                .collect::<Vec<_>>()
-            {
-                graph.add_edge(copy_node, dest, weight);
-                graph.remove_edge(edge_id);
-            }
+impl<T: CudaFloat> CudaMinReduce<T> {
+    pub fn new(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        Self::with_init(dim, shape, device, dyn_map, ReduceInit::Identity)
+    }
 
-            if graph.to_retrieve.contains(&function_node) {
-                graph.to_retrieve.insert(copy_node);
-            }
+    /// Reduce starting from `init` rather than the identity. `ReduceInit::Tensor` takes the
+    /// starting values from a second input.
+    pub fn with_init(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+        init: ReduceInit,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let (init_input, init_value) = match init {
+            ReduceInit::Tensor => (
+                format!("const {type_name} *init, "),
+                format!("({acc})init[i_]"),
+            ),
+            _ => ("float init_value, ".to_string(), "init_value".to_string()),
+        };
+        let parallel_code = parallel_reduce_kernel::<T>(
+            (&init_input, &init_value),
+            "__int_as_float(0x7f800000)",
+            "min(reduce_value, x)",
+            "reduce_value",
+            (&idx, &valid),
+            &rendered,
+            index,
+        );
+        let code = format!("#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {init_input}const int front_size, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
 
-            // Insert copy from device for function inputs
-            for (source, edge, edge_weight) in graph
-                .edges_directed(function_node, petgraph::Direction::Incoming)
-                .map(|e| (e.source(), e.id(), *e.weight()))
-                .collect::<Vec<_>>()
-            {
-                let copy_from_node = graph
-                    .add_op(CudaCopyFromDevice::<T>::new(dev.clone()))
-                    .input(source, 0, ShapeTracker::new(&[]))
-                    .finish();
-                graph.add_edge(copy_from_node, function_node, edge_weight);
-                graph.remove_edge(edge);
+    if (i_ < numel) {{
+        {index} a_ = i_ / back_size;
+        {index} b_ = i_ % back_size;
+        {acc} reduce_value = {init_value};
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
+            if (({valid}) != 0) {{
+                reduce_value = min(reduce_value, ({acc})inp[{idx}]);
+            }}
+        }}
+        out[i_] = ({type_name})reduce_value;
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            parallel_function: compile_and_load_kernel(parallel_code, &device)?,
+            device,
+            dim,
+            init,
+            parallel_min_dim: 1024,
+            _phantom: Default::default(),
+            dyn_symbols,
+            dyn_map,
+        })
+    }
+}
+impl<T: CudaFloat> Operator for CudaMinReduce<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut shape = tensors[0].1;
+        shape.remove_dim(self.dim);
+        let inp_size = shape.n_elements().to_usize().unwrap();
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let front_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .take(self.dim)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let back_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
+
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![(&out).as_kernel_param(), inp.as_kernel_param()];
+        let init_value = match self.init {
+            ReduceInit::Constant(v) => v,
+            _ => f32::INFINITY,
+        };
+        if self.init == ReduceInit::Tensor {
+            params.push(get_buffer_from_tensor::<T>(&tensors[1].0).as_kernel_param());
+        } else {
+            params.push(init_value.as_kernel_param());
+        }
+        params.extend([
+            front_size.as_kernel_param(),
+            back_size.as_kernel_param(),
+            dim_size.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ]);
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        let parallel = dim_size >= self.parallel_min_dim;
+        let function = if parallel {
+            &self.parallel_function
+        } else {
+            &self.function
+        };
+        unsafe {
+            function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    reduce_launch_config(inp_size, parallel),
+                    &mut params,
+                )
+                .unwrap();
+        }
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace a max reduce between two negations, which is how min reductions are expressed, with a `CudaMinReduce`
+#[derive(Default)]
+pub struct CudaMinReduceCompiler<T>(pub usize, PhantomData<T>);
+
+impl<T> CudaMinReduceCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for CudaMinReduceCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        let inp = node();
+        let neg_inp = binary::<CudaMul<T>>(inp.clone(), constant::<T>(-1.));
+        let mut max_reduce = unary::<CudaMaxReduce<T>>(neg_inp.clone());
+        max_reduce.check(|o, _| {
+            o.as_any()
+                .downcast_ref::<CudaMaxReduce<T>>()
+                .map(|m| m.init == ReduceInit::Identity)
+                .unwrap_or_default()
+        });
+        let neg_out = binary::<CudaMul<T>>(max_reduce.clone(), constant::<T>(-1.));
+        let mut s = neg_out.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[neg_out.id]) {
+                continue;
+            }
+            let (inp, neg_inp, max_reduce, neg_out) = (
+                s.get(&inp),
+                s.get(&neg_inp),
+                s.get(&max_reduce),
+                s.get(&neg_out),
+            );
+            let (_, inp_output, inp_shape) = graph
+                .get_sources(neg_inp)
+                .into_iter()
+                .find(|(n, _, _)| *n == inp)
+                .unwrap();
+            // The reduce indexes into the negation's contiguous output, so the input must share its layout
+            if !inp_shape.is_contiguous() || inp_shape.is_sliced() || inp_shape.is_padded() {
+                continue;
+            }
+            // The min reduce writes the max reduce's contiguous layout, so the negation must read it as is
+            let (_, _, out_shape) = graph
+                .get_sources(neg_out)
+                .into_iter()
+                .find(|(n, _, _)| *n == max_reduce)
+                .unwrap();
+            if !out_shape.is_contiguous() || out_shape.is_sliced() || out_shape.is_padded() {
+                continue;
+            }
+            let reduce_shape = graph.get_sources(max_reduce)[0].2;
+            let dim = graph
+                .node_weight(max_reduce)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaMaxReduce<T>>()
+                .unwrap()
+                .dim;
+            let min_reduce = graph
+                .add_op(
+                    CudaMinReduce::<T>::new(dim, reduce_shape, dev.clone(), &graph.dyn_map)
+                        .unwrap(),
+                )
+                .input(inp, inp_output, reduce_shape)
+                .finish();
+            move_outgoing_edge(neg_out, min_reduce, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                neg_out,
+                min_reduce,
+            );
+            graph.graph.remove_node(neg_out);
+            s.try_delete();
+        }
+    }
+}
+
+/// Mean reduce along `dim`, dividing the sum by the dimension's size inside the kernel. Replaces the sum reduce
+/// and multiply by a reciprocal constant that `mean_reduce` lowers to. Dimensions of at least `parallel_min_dim`
+/// elements are reduced by a block of threads per output rather than a single thread.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaMeanReduce<T> {
+    function: CudaFunction,
+    parallel_function: CudaFunction,
+    pub device: Arc<CudaDevice>,
+    pub dim: usize,
+    pub parallel_min_dim: usize,
+    _phantom: PhantomData<T>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl<T: CudaFloat> CudaMeanReduce<T> {
+    pub fn new(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let parallel_code = parallel_reduce_kernel::<T>(
+            ("", "0.0"),
+            "0.0",
+            "reduce_value + x",
+            &format!("reduce_value / ({acc})dim_size"),
+            (&idx, &valid),
+            &rendered,
+            index,
+        );
+        let code = format!("#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const int front_size, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+
+    if (i_ < numel) {{
+        {index} a_ = i_ / back_size;
+        {index} b_ = i_ % back_size;
+        {acc} reduce_value = 0.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
+            if (({valid}) != 0) {{
+                reduce_value = reduce_value + ({acc})inp[{idx}];
+            }}
+        }}
+        out[i_] = ({type_name})(reduce_value / ({acc})dim_size);
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            parallel_function: compile_and_load_kernel(parallel_code, &device)?,
+            device,
+            dim,
+            parallel_min_dim: 1024,
+            _phantom: Default::default(),
+            dyn_symbols,
+            dyn_map,
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaMeanReduce<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut shape = tensors[0].1;
+        shape.remove_dim(self.dim);
+        let inp_size = shape.n_elements().to_usize().unwrap();
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let front_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .take(self.dim)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let back_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        // Dynamic dimensions are resolved here, so the size is always passed to the kernel
+        let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
+
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            front_size.as_kernel_param(),
+            back_size.as_kernel_param(),
+            dim_size.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        let parallel = dim_size >= self.parallel_min_dim;
+        let function = if parallel {
+            &self.parallel_function
+        } else {
+            &self.function
+        };
+        unsafe {
+            function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    reduce_launch_config(inp_size, parallel),
+                    &mut params,
+                )
+                .unwrap();
+        }
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Fuse the sum reduce and multiply by the reciprocal of the reduced dimension's size into a `CudaMeanReduce`
+#[derive(Default)]
+pub struct CudaMeanReduceCompiler<T>(pub usize, PhantomData<T>);
+
+impl<T> CudaMeanReduceCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for CudaMeanReduceCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        let mut sum_reduce = op::<CudaSumReduce<T>>();
+        sum_reduce.check(|o, _| {
+            o.as_any()
+                .downcast_ref::<CudaSumReduce<T>>()
+                .map(|s| s.init == ReduceInit::Identity)
+                .unwrap_or_default()
+        });
+        let constant = op::<CudaConstant<T>>();
+        let recip = unary::<CudaRecip<T>>(constant.clone());
+        let mul = binary::<CudaMul<T>>(sum_reduce.clone(), recip.clone());
+        let mut s = mul.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[mul.id]) {
+                continue;
+            }
+            let (sum_reduce, constant, mul) = (s.get(&sum_reduce), s.get(&constant), s.get(&mul));
+            // The mean reduce writes the sum reduce's contiguous layout, so the multiply must read it as is
+            let (_, _, sum_shape) = graph
+                .get_sources(mul)
+                .into_iter()
+                .find(|(n, _, _)| *n == sum_reduce)
+                .unwrap();
+            if !sum_shape.is_contiguous() || sum_shape.is_sliced() || sum_shape.is_padded() {
+                continue;
+            }
+            let (src, src_output, src_shape) = graph.get_sources(sum_reduce)[0];
+            let dim = graph
+                .node_weight(sum_reduce)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaSumReduce<T>>()
+                .unwrap()
+                .dim;
+            // Only a multiply by the reciprocal of the reduced dimension's size is a mean
+            let ConstantValue::Expression(size) = &graph
+                .node_weight(constant)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaConstant<T>>()
+                .unwrap()
+                .value
+            else {
+                continue;
+            };
+            let mut reduced_shape = src_shape;
+            if *size != BigExpression::from(reduced_shape.remove_dim(dim)).minimize() {
+                continue;
+            }
+            let mean = graph
+                .add_op(
+                    CudaMeanReduce::<T>::new(dim, src_shape, dev.clone(), &graph.dyn_map).unwrap(),
+                )
+                .input(src, src_output, src_shape)
+                .finish();
+            move_outgoing_edge(mul, mean, &mut graph.graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                mul,
+                mean,
+            );
+            graph.graph.remove_node(mul);
+            s.try_delete();
+        }
+    }
+}
+
+/// Product reduce along `dim`. Padded elements are skipped, as though they were 1. Dimensions of at least
+/// `parallel_min_dim` elements are reduced by a block of threads per output rather than a single thread.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaProdReduce<T> {
+    function: CudaFunction,
+    parallel_function: CudaFunction,
+    pub device: Arc<CudaDevice>,
+    pub dim: usize,
+    pub parallel_min_dim: usize,
+    _phantom: PhantomData<T>,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+
+impl<T: CudaFloat> CudaProdReduce<T> {
+    pub fn new(
+        dim: usize,
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let parallel_code = parallel_reduce_kernel::<T>(
+            ("", "1.0"),
+            "1.0",
+            "reduce_value * x",
+            "reduce_value",
+            (&idx, &valid),
+            &rendered,
+            index,
+        );
+        let code = format!("#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, const int front_size, const int back_size, const int dim_size, {index} numel{rendered}) {{
+    {index} i_ = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+
+    if (i_ < numel) {{
+        {index} a_ = i_ / back_size;
+        {index} b_ = i_ % back_size;
+        {acc} reduce_value = 1.0;
+        for (int c_ = 0; c_ < dim_size; c_++) {{
+            {index} idx = a_ * dim_size * back_size + ({index})c_ * back_size + b_;
+            if (({valid}) != 0) {{
+                reduce_value = reduce_value * ({acc})inp[{idx}];
+            }}
+        }}
+        out[i_] = ({type_name})reduce_value;
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            parallel_function: compile_and_load_kernel(parallel_code, &device)?,
+            device,
+            dim,
+            parallel_min_dim: 1024,
+            _phantom: Default::default(),
+            dyn_symbols,
+            dyn_map,
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaProdReduce<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let mut shape = tensors[0].1;
+        shape.remove_dim(self.dim);
+        let inp_size = shape.n_elements().to_usize().unwrap();
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let front_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .take(self.dim)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let back_size: usize = tensors[0]
+            .1
+            .shape()
+            .iter()
+            .skip(self.dim + 1)
+            .map(|i| i.to_usize().unwrap())
+            .product();
+        let dim_size = tensors[0].1.shape()[self.dim].to_usize().unwrap();
+
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            front_size.as_kernel_param(),
+            back_size.as_kernel_param(),
+            dim_size.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        let parallel = dim_size >= self.parallel_min_dim;
+        let function = if parallel {
+            &self.parallel_function
+        } else {
+            &self.function
+        };
+        unsafe {
+            function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    reduce_launch_config(inp_size, parallel),
+                    &mut params,
+                )
+                .unwrap();
+        }
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Convert all primitive ops to cuda primitive ops, and insert copy to and from device ops
+#[derive(LuminalPrint, Default)]
+pub struct CudaPrimitiveCompiler<T>(pub usize, PhantomData<T>);
+
+impl<T> CudaPrimitiveCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for CudaPrimitiveCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        // Go through the graph and insert copy ops
+        // Copy function output to device and input from device
+        for function_node in graph
+            .node_indices()
+            .filter(|n| {
+                graph.node_weight(*n).unwrap().as_any().is::<Function>()
+                    && graph.edges(*n).count() != 0
+            })
+            .collect::<Vec<_>>()
+        {
+            // Create copy node
+            let copy_node = graph
+                .add_op(CudaCopyToDevice::<T>::new(dev.clone()))
+                .input(function_node, 0, ShapeTracker::new(&[]))
+                .finish();
+
+            // Switch outgoing edges from input to copy_node
+            for (edge_id, weight, dest) in graph
+                .edges_directed(function_node, petgraph::Direction::Outgoing)
+                .map(|e| (e.id(), *e.weight(), e.target()))
+                .filter(|(_, _, trg)| *trg != copy_node)
+                .collect::<Vec<_>>()
+            {
+                graph.add_edge(copy_node, dest, weight);
+                graph.remove_edge(edge_id);
+            }
+
+            if graph.to_retrieve.contains(&function_node) {
+                graph.to_retrieve.insert(copy_node);
+            }
+
+            // Insert copy from device for function inputs
+            for (source, edge, edge_weight) in graph
+                .edges_directed(function_node, petgraph::Direction::Incoming)
+                .map(|e| (e.source(), e.id(), *e.weight()))
+                .collect::<Vec<_>>()
+            {
+                let copy_from_node = graph
+                    .add_op(CudaCopyFromDevice::<T>::new(dev.clone()))
+                    .input(source, 0, ShapeTracker::new(&[]))
+                    .finish();
+                graph.add_edge(copy_from_node, function_node, edge_weight);
+                graph.remove_edge(edge);
             }
         }
 
@@ -1016,11 +2088,11 @@ impl<T: CudaFloat> Compiler for CudaPrimitiveCompiler<T> {
             let op = graph.node_weight(id).unwrap().as_any().type_id();
             let op_ref = graph.graph.node_weight_mut(id).unwrap();
             if is::<Log2>(op) {
-                *op_ref = Box::new(CudaLog2::<T>::new(dev.clone()));
+                *op_ref = Box::new(CudaLog2::<T>::new(dev.clone()).unwrap());
             } else if is::<Exp2>(op) {
-                *op_ref = Box::new(CudaExp2::<T>::new(dev.clone()));
+                *op_ref = Box::new(CudaExp2::<T>::new(dev.clone()).unwrap());
             } else if is::<Sin>(op) {
-                *op_ref = Box::new(CudaSin::<T>::new(dev.clone()));
+                *op_ref = Box::new(CudaSin::<T>::new(dev.clone()).unwrap());
             } else if let Some(c) = op_ref.as_any().downcast_ref::<Constant>() {
                 *op_ref = Box::new(CudaConstant::<T>::new(
                     dev.clone(),
@@ -1028,77 +2100,101 @@ impl<T: CudaFloat> Compiler for CudaPrimitiveCompiler<T> {
                     &graph.dyn_map,
                 ));
             } else if is::<Recip>(op) {
-                *op_ref = Box::new(CudaRecip::<T>::new(dev.clone()));
+                *op_ref = Box::new(CudaRecip::<T>::new(dev.clone()).unwrap());
             } else if is::<Sqrt>(op) {
-                *op_ref = Box::new(CudaSqrt::<T>::new(dev.clone()));
+                *op_ref = Box::new(CudaSqrt::<T>::new(dev.clone()).unwrap());
             } else if is::<Add>(op) {
-                *op_ref = Box::new(CudaAdd::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaAdd::<T>::new(shapes[0], shapes[1], dev.clone(), &graph.dyn_map).unwrap(),
+                );
             } else if is::<Mul>(op) {
-                *op_ref = Box::new(CudaMul::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaMul::<T>::new(shapes[0], shapes[1], dev.clone(), &graph.dyn_map).unwrap(),
+                );
             } else if is::<Mod>(op) {
-                *op_ref = Box::new(CudaMod::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaMod::<T>::new(shapes[0], shapes[1], dev.clone(), &graph.dyn_map).unwrap(),
+                );
             } else if is::<LessThan>(op) {
-                *op_ref = Box::new(CudaLessThan::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaLessThan::<T>::new(shapes[0], shapes[1], dev.clone(), &graph.dyn_map)
+                        .unwrap(),
+                );
             } else if is::<Contiguous>(op) {
-                *op_ref = Box::new(CudaContiguous::<T>::new(
-                    shapes[0],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaContiguous::<T>::new(shapes[0], dev.clone(), &graph.dyn_map).unwrap(),
+                );
             } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
-                *op_ref = Box::new(CudaSumReduce::<T>::new(
-                    *dim,
-                    shapes[0],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaSumReduce::<T>::new(*dim, shapes[0], dev.clone(), &graph.dyn_map).unwrap(),
+                );
             } else if let Some(MaxReduce(dim)) = op_ref.as_any().downcast_ref() {
-                *op_ref = Box::new(CudaMaxReduce::<T>::new(
-                    *dim,
-                    shapes[0],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(
+                    CudaMaxReduce::<T>::new(*dim, shapes[0], dev.clone(), &graph.dyn_map).unwrap(),
+                );
             }
         }
     }
 }
 
-// Sometimes CopyTo -> CopyFrom and CopyFrom -> CopyTo patterns remain, so let's clean them up
+// Sometimes CopyTo -> CopyFrom and CopyFrom -> CopyTo patterns remain, so let's clean them up.
+// Copies to the device of the same source, or of something already on the device, are merged too.
+// Only copies to the device with this ordinal are touched.
 #[derive(Debug, Default)]
-pub struct CopyCompiler<T>(PhantomData<T>);
+pub struct CopyCompiler<T>(pub usize, PhantomData<T>);
+
+impl<T> CopyCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
 
 impl<T: CudaFloat> Compiler for CopyCompiler<T> {
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let on_device = |graph: &Graph, n: NodeIndex| {
+            graph
+                .node_weight(n)
+                .unwrap()
+                .as_any()
+                .downcast_ref::<CudaCopyToDevice<T>>()
+                .map(|op| op.device().ordinal() == self.0)
+                .unwrap_or_default()
+        };
+        let mut copies = FxHashMap::default();
+        for copy in graph
+            .node_indices()
+            .filter(|n| on_device(graph, *n))
+            .collect::<Vec<_>>()
+        {
+            let (src, src_out, _) = graph.get_sources(copy)[0];
+            let existing = if on_device(graph, src) {
+                src
+            } else {
+                match copies.entry((src, src_out)) {
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        e.insert(copy);
+                        continue;
+                    }
+                    std::collections::hash_map::Entry::Occupied(e) => *e.get(),
+                }
+            };
+            move_outgoing_edge(copy, existing, graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                copy,
+                existing,
+            );
+            graph.remove_node(copy);
+        }
+
         for (first, second) in graph
             .edge_indices()
             .filter_map(|e| graph.edge_endpoints(e))
             .filter(|(a, b)| {
-                (graph
-                    .node_weight(*a)
-                    .unwrap()
-                    .as_any()
-                    .is::<CudaCopyToDevice<T>>()
+                (on_device(graph, *a)
                     && graph
                         .node_weight(*b)
                         .unwrap()
@@ -1109,11 +2205,7 @@ impl<T: CudaFloat> Compiler for CopyCompiler<T> {
                         .unwrap()
                         .as_any()
                         .is::<CudaCopyFromDevice<T>>()
-                        && graph
-                            .node_weight(*b)
-                            .unwrap()
-                            .as_any()
-                            .is::<CudaCopyToDevice<T>>())
+                        && on_device(graph, *b))
             })
             .unique_by(|n| n.0)
             .unique_by(|n| n.1)
@@ -1170,3 +2262,67 @@ impl<T: CudaFloat> Compiler for CopyCompiler<T> {
         }
     }
 }
+
+/// Stage the copies to and from the device in a compiled graph through reusable pinned (page-locked) host buffers
+/// rather than pageable memory, which speeds up repeated transfers like the per-step logits copy.
+/// Run it after `CudaCompiler`.
+#[derive(Debug, Default)]
+pub struct PinnedStagingCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for PinnedStagingCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let op = graph.graph.node_weight_mut(node).unwrap().as_any_mut();
+            if let Some(CudaCopyToDevice(_, staging)) = op.downcast_mut::<CudaCopyToDevice<T>>() {
+                staging.get_or_insert_with(Default::default);
+            } else if let Some(CudaCopyFromDevice(_, staging)) =
+                op.downcast_mut::<CudaCopyFromDevice<T>>()
+            {
+                staging.get_or_insert_with(Default::default);
+            }
+        }
+    }
+}
+
+/// Make sure every op left in a compiled graph runs on the device. Anything that slipped through without being
+/// swapped for a cuda op would otherwise fail at execution time when it can't read the device buffers.
+/// Ops mark themselves as running on the device by returning `cuda_op_marker` from `Operator::custom`.
+pub fn check_cuda_ops(graph: &mut Graph) -> Result<(), CudaError> {
+    let unsupported = graph
+        .node_indices()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|n| {
+            let op = graph.graph.node_weight_mut(n).unwrap();
+            // Wrappers pass the key on to the op they wrap
+            if op.custom("cuda_op", Box::new(())).is_some() {
+                return None;
+            }
+            // Functions and prints are handed host data by the primitive compiler
+            let inner = crate::trace::unwrap_op(op.as_ref()).as_any();
+            if inner.is::<LFunction>() || inner.is::<Print>() {
+                None
+            } else {
+                Some(format!("{op:?} (node {})", n.index()))
+            }
+        })
+        .collect::<Vec<_>>();
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(CudaError::UnsupportedOps(unsupported))
+    }
+}
+
+/// Run `check_cuda_ops` once everything else is compiled, panicking on ops that weren't lowered to the device,
+/// so they fail here rather than when they try to read device buffers during execution
+#[derive(Debug, Default)]
+pub struct CheckCudaOpsCompiler;
+
+impl Compiler for CheckCudaOpsCompiler {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        if let Err(e) = check_cuda_ops(graph) {
+            panic!("{e}");
+        }
+    }
+}
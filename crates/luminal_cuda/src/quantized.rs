@@ -0,0 +1,117 @@
+use std::{any::Any, marker::PhantomData, sync::Arc};
+
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceSlice};
+
+use luminal::{
+    op::{InputTensor, Operator},
+    prelude::*,
+};
+
+use crate::{
+    alloc_zeros, compile_and_load_kernel, cuda_op_marker, elementwise_launch_config,
+    get_buffer_from_tensor, CudaData, CudaError, CudaFloat, LaunchOnDeviceStream,
+    DEFAULT_BLOCK_SIZE,
+};
+
+/// Dequantize packed integer weights into a float tensor, computing `(q - zero) * scale` per group.
+///
+/// Inputs are the packed data as `CudaData<u8>`, then one scale and one zero point per group as `CudaData<T>`.
+/// 8 bit data is read as signed bytes. 4 bit data is unsigned and packed like GGUF's Q4_0: within a group,
+/// byte `i` holds element `i` in its low nibble and element `i + group_size / 2` in its high nibble.
+#[derive(LuminalPrint, Clone, LuminalEqFalse)]
+pub struct CudaDequantize<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub bits: usize,
+    pub group_size: usize,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaDequantize<T> {
+    pub fn new(device: Arc<CudaDevice>, bits: usize, group_size: usize) -> Result<Self, CudaError> {
+        if group_size == 0 {
+            return Err(CudaError::InvalidOp(
+                "Group size must be nonzero".to_string(),
+            ));
+        }
+        if bits == 4 && !group_size.is_multiple_of(2) {
+            return Err(CudaError::InvalidOp(format!(
+                "4 bit groups must fill whole bytes, got a group size of {group_size}"
+            )));
+        }
+        let type_name = T::type_name();
+        let read_quant = match bits {
+            4 => {
+                "unsigned char byte = packed[group * (group_size / 2) + element % (group_size / 2)];
+        float q = (float)(element < group_size / 2 ? (byte & 0xF) : (byte >> 4));"
+            }
+            8 => "float q = (float)((signed char)packed[idx]);",
+            _ => {
+                return Err(CudaError::InvalidOp(format!(
+                    "Only 4 and 8 bit dequantization is supported, got {bits} bits"
+                )))
+            }
+        };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const unsigned char *packed, const {type_name} *scales, const {type_name} *zeros, long long numel, int group_size) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        long long group = idx / group_size;
+        int element = idx % group_size;
+        {read_quant}
+        out[idx] = ({type_name})((q - (float)zeros[group]) * (float)scales[group]);
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            bits,
+            group_size,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaDequantize<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let packed = get_buffer_from_tensor::<u8>(&tensors[0].0);
+        let scales = get_buffer_from_tensor::<T>(&tensors[1].0);
+        let zeros = get_buffer_from_tensor::<T>(&tensors[2].0);
+        let n_elements = scales.len() * self.group_size;
+        assert_eq!(
+            packed.len() * 8,
+            n_elements * self.bits,
+            "Packed data doesn't match {} groups of {} {}-bit values",
+            scales.len(),
+            self.group_size,
+            self.bits
+        );
+        let mut out = alloc_zeros::<T>(&self.device, n_elements).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(n_elements, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut out,
+                        packed,
+                        scales,
+                        zeros,
+                        n_elements as i64,
+                        self.group_size as i32,
+                    ),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
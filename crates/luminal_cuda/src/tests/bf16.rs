@@ -0,0 +1,215 @@
+use dfdx::prelude::*;
+use rand::{rngs::StdRng, SeedableRng};
+
+use luminal::prelude::bf16;
+
+#[allow(unused_imports)]
+use dfdx::prelude::{
+    Axes as DAxes, Axes2 as DAxes2, Axes3 as DAxes3, Axes4 as DAxes4, Axes5 as DAxes5,
+    Axis as DAxis, Const as DConst, *,
+};
+#[allow(unused_imports)]
+use luminal::{
+    prelude::{
+        Axes as LAxes, Axes2 as LAxes2, Axes3 as LAxes3, Axes4 as LAxes4, Axes5 as LAxes5,
+        Axis as LAxis, Const as LConst, *,
+    },
+    tests::{random_vec, random_vec_rng},
+};
+
+use crate::CudaBf16Compiler;
+
+// dfdx has no bf16 dtype, so references are computed in f32 from the same bf16-rounded inputs
+
+fn round_bf16(data: &[f32]) -> Vec<f32> {
+    data.iter().map(|v| bf16::from_f32(*v).to_f32()).collect()
+}
+
+/// bf16 keeps 8 bits of mantissa, so compare to within 1% of the expected magnitude
+fn assert_bf16_close(a_vec: &[f32], b_vec: &[f32]) {
+    assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
+    for (i, (a, b)) in a_vec.iter().zip(b_vec.iter()).enumerate() {
+        if (a - b).abs() > 1e-2 * b.abs().max(1.) {
+            panic!("{a} is not close to {b}, index {i}");
+        }
+    }
+}
+
+macro_rules! bf16_unary_test {
+    ($luminal_func: expr , $dfdx_func: expr , $name: ident, $($size: expr),+) => {
+        $(paste::paste! {
+            #[test]
+            fn [<$name _ $size>]() {
+                let mut rng = StdRng::seed_from_u64(1);
+                let data = round_bf16(&random_vec_rng($size, &mut rng));
+                let mut cx = Graph::new();
+                let a = cx.tensor::<R1<$size>>().set(data.clone());
+                let f: fn(GraphTensor<R1<$size>>) -> GraphTensor<R1<$size>> = $luminal_func;
+                let mut b = f(a).retrieve();
+                cx.compile(CudaBf16Compiler::default(), &mut b);
+                cx.execute();
+
+                let d_dev = Cpu::default();
+                let d_a = d_dev.tensor_from_vec(data, (DConst::<$size>,));
+                let f: fn(
+                    dfdx::prelude::Tensor<Rank1<$size>, f32, Cpu, NoneTape>,
+                ) -> dfdx::prelude::Tensor<Rank1<$size>, f32, Cpu, NoneTape> = $dfdx_func;
+                assert_bf16_close(&b.data(), &f(d_a).as_vec());
+            }
+        })+
+    };
+}
+
+macro_rules! bf16_binary_test {
+    ($luminal_func: expr , $dfdx_func: expr , $name: ident, $($size: expr),+) => {
+        $(paste::paste! {
+            #[test]
+            fn [<$name _ $size>]() {
+                let mut rng = StdRng::seed_from_u64(2);
+                let a_data = round_bf16(&random_vec_rng($size, &mut rng));
+                let b_data = round_bf16(&random_vec_rng($size, &mut rng));
+                let mut cx = Graph::new();
+                let a = cx.tensor::<R1<$size>>().set(a_data.clone());
+                let b = cx.tensor::<R1<$size>>().set(b_data.clone());
+                let f: fn(GraphTensor<R1<$size>>, GraphTensor<R1<$size>>) -> GraphTensor<R1<$size>> =
+                    $luminal_func;
+                let mut c = f(a, b).retrieve();
+                cx.compile(CudaBf16Compiler::default(), &mut c);
+                cx.execute();
+
+                let d_dev = Cpu::default();
+                let d_a = d_dev.tensor_from_vec(a_data, (DConst::<$size>,));
+                let d_b = d_dev.tensor_from_vec(b_data, (DConst::<$size>,));
+                let f: fn(
+                    dfdx::prelude::Tensor<Rank1<$size>, f32, Cpu, NoneTape>,
+                    dfdx::prelude::Tensor<Rank1<$size>, f32, Cpu, NoneTape>,
+                ) -> dfdx::prelude::Tensor<Rank1<$size>, f32, Cpu, NoneTape> = $dfdx_func;
+                assert_bf16_close(&c.data(), &f(d_a, d_b).as_vec());
+            }
+        })+
+    };
+}
+
+bf16_unary_test!(|a| a.sin(), |a| a.sin(), test_sin, 3, 50, 783, 4096);
+bf16_unary_test!(|a| a.sqrt(), |a| a.sqrt(), test_sqrt, 3, 50, 783, 4096);
+bf16_unary_test!(|a| a.recip(), |a| a.recip(), test_recip, 3, 50, 783, 4096);
+bf16_unary_test!(|a| a * a, |a| a.clone() * a, test_square, 3, 50, 783, 4096);
+bf16_unary_test!(|a| a.ln(), |a| a.ln(), test_ln, 3);
+
+bf16_binary_test!(|a, b| a + b, |a, b| a + b, test_add, 3, 50, 783, 4096);
+bf16_binary_test!(|a, b| a - b, |a, b| a - b, test_sub, 3, 50, 783, 4096);
+bf16_binary_test!(|a, b| a * b, |a, b| a * b, test_mul, 3, 50, 783, 4096);
+bf16_binary_test!(
+    |a, b| a / b,
+    |a, b| a * b.recip(),
+    test_div,
+    3,
+    50,
+    783,
+    4096
+);
+bf16_binary_test!(
+    |a, b| a.max(b),
+    |a, b| a.maximum(b),
+    test_max,
+    3,
+    50,
+    783,
+    4096
+);
+bf16_binary_test!(
+    |a, b| a.min(b),
+    |a, b| a.minimum(b),
+    test_min,
+    3,
+    50,
+    783,
+    4096
+);
+
+#[test]
+fn test_sum_reduce() {
+    let data = round_bf16(&random_vec(40960));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<1, 10, 4096>>().set(data.clone());
+    let mut b = a.sum_reduce::<_, LAxis<2>>().retrieve();
+    let mut c = a.sum_reduce::<_, LAxis<1>>().retrieve();
+    let mut d = a.sum_reduce::<_, LAxis<0>>().retrieve();
+
+    cx.compile(CudaBf16Compiler::default(), (&mut b, &mut c, &mut d));
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<1>, DConst::<10>, DConst::<4096>));
+    let d_b = d_a.clone().sum::<_, DAxis<2>>();
+    let d_c = d_a.clone().sum::<_, DAxis<1>>();
+    let d_d = d_a.sum::<_, DAxis<0>>();
+    assert_bf16_close(&b.data(), &d_b.as_vec());
+    assert_bf16_close(&c.data(), &d_c.as_vec());
+    assert_bf16_close(&d.data(), &d_d.as_vec());
+}
+
+#[test]
+fn test_max_reduce() {
+    let data = round_bf16(&random_vec(40960));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<1, 10, 4096>>().set(data.clone());
+    let mut b = a.max_reduce::<_, LAxis<2>>().retrieve();
+    let mut c = a.max_reduce::<_, LAxis<1>>().retrieve();
+    let mut d = a.max_reduce::<_, LAxis<0>>().retrieve();
+
+    cx.compile(CudaBf16Compiler::default(), (&mut b, &mut c, &mut d));
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<1>, DConst::<10>, DConst::<4096>));
+    let d_b = d_a.clone().max::<_, DAxis<2>>();
+    let d_c = d_a.clone().max::<_, DAxis<1>>();
+    let d_d = d_a.max::<_, DAxis<0>>();
+    // Maxes of bf16 values are exact
+    assert_eq!(b.data(), d_b.as_vec());
+    assert_eq!(c.data(), d_c.as_vec());
+    assert_eq!(d.data(), d_d.as_vec());
+}
+
+#[test]
+fn test_mean_reduce() {
+    let data = round_bf16(&random_vec(40960));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<1, 10, 4096>>().set(data.clone());
+    let mut b = a.mean_reduce::<_, LAxis<2>>().retrieve();
+    let mut c = a.mean_reduce::<_, LAxis<1>>().retrieve();
+    let mut d = a.mean_reduce::<_, LAxis<0>>().retrieve();
+
+    cx.compile(CudaBf16Compiler::default(), (&mut b, &mut c, &mut d));
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<1>, DConst::<10>, DConst::<4096>));
+    let d_b = d_a.clone().mean::<_, DAxis<2>>();
+    let d_c = d_a.clone().mean::<_, DAxis<1>>();
+    let d_d = d_a.mean::<_, DAxis<0>>();
+    assert_bf16_close(&b.data(), &d_b.as_vec());
+    assert_bf16_close(&c.data(), &d_c.as_vec());
+    assert_bf16_close(&d.data(), &d_d.as_vec());
+}
+
+#[test]
+fn test_matmul_simple() {
+    let mut cx = Graph::new();
+    let a_data = round_bf16(&random_vec(256 * 256));
+    let b_data = round_bf16(&random_vec(256 * 256));
+    let a = cx.tensor::<R2<256, 256>>().set(a_data.clone());
+    let b = cx.tensor::<R2<256, 256>>().set(b_data.clone());
+    let mut c = a.matmul(b).retrieve();
+
+    cx.compile(CudaBf16Compiler::default(), &mut c);
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor_from_vec(a_data, (DConst::<256>, DConst::<256>));
+    let d_b = d_dev.tensor_from_vec(b_data, (DConst::<256>, DConst::<256>));
+    let d_c = d_a.matmul(d_b);
+
+    assert_bf16_close(&c.data(), &d_c.as_vec());
+}
@@ -852,3 +852,107 @@ fn test_movement() {
 
     assert_exact(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_mish() {
+    // Includes large negative inputs where softplus underflows
+    let data = vec![
+        -100., -50., -20., -10., -5., -2., -1., -0.5, 0., 0.5, 1., 2., 5., 10., 20., 50.,
+    ];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<16>>().set(data.clone());
+    let mut b = a.mish().retrieve();
+
+    cx.compile(CudaCompiler::<f16>::default(), &mut b);
+    cx.execute();
+
+    assert_close_precision(
+        &b.data(),
+        &data
+            .into_iter()
+            .map(|x: f32| {
+                let x = f16::from_f32(x).to_f32();
+                f16::from_f32(x * (x.max(0.) + (-x.abs()).exp().ln_1p()).tanh()).to_f32()
+            })
+            .collect::<Vec<_>>(),
+        2,
+    );
+}
+
+#[test]
+fn test_matmul_f32_accumulation() {
+    const M: usize = 64;
+    const K: usize = 4096;
+    const N: usize = 64;
+    // Positive inputs so sums grow large enough for half precision steps to matter
+    let round = |v: Vec<f32>| {
+        v.into_iter()
+            .map(|x| f16::from_f32(x.abs()).to_f32())
+            .collect_vec()
+    };
+    let mut rng = StdRng::seed_from_u64(0);
+    let a_data = round(random_vec_rng(M * K, &mut rng));
+    let b_data = round(random_vec_rng(K * N, &mut rng));
+    let expected = (0..M * N)
+        .map(|i| {
+            (0..K)
+                .map(|k| a_data[i / N * K + k] as f64 * b_data[k * N + i % N] as f64)
+                .sum::<f64>()
+        })
+        .collect_vec();
+
+    let run = |f32_accumulation: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<M, K>>().set(a_data.clone());
+        let b = cx.tensor::<R2<K, N>>().set(b_data.clone());
+        let mut c = a.matmul(b).retrieve();
+        let mut compiler = crate::CudaFp16Compiler::default();
+        compiler.1 .7 .1 = f32_accumulation;
+        cx.compile(compiler, &mut c);
+        cx.execute();
+        // Mean absolute error against the f64 reference
+        c.data()
+            .iter()
+            .zip(&expected)
+            .map(|(c, e)| (*c as f64 - e).abs())
+            .sum::<f64>()
+            / expected.len() as f64
+    };
+    let (f32_error, f16_error) = (run(true), run(false));
+    println!("f32 accumulation error: {f32_error} f16 accumulation error: {f16_error}");
+    assert!(f32_error < f16_error);
+}
+
+#[test]
+fn test_host_round_trip() {
+    let data = random_vec(100);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let buffer = crate::CudaData::<f16>::from_host(&dev, &data);
+    assert_exact(
+        &buffer.to_host(),
+        &data
+            .iter()
+            .map(|i| f16::from_f32(*i).to_f32())
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_pow() {
+    let bases: Vec<f32> = vec![0.5, 1.7, 2.0, -2.0, -1.5, 3.0];
+    let exponents: Vec<f32> = vec![0.5, -1.3, 2.5, 3.0, 2.0, -1.0];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<6>>().set(bases.clone());
+    let b = cx.tensor::<R1<6>>().set(exponents.clone());
+    let mut c = (a.log2() * b).exp2().retrieve();
+    cx.compile(CudaCompiler::<f16>::default(), &mut c);
+    cx.execute();
+    assert_close(
+        &c.data(),
+        &bases
+            .iter()
+            .zip(&exponents)
+            .map(|(a, b)| f16::from_f32(a.powf(*b)).to_f32())
+            .collect::<Vec<_>>(),
+    );
+}
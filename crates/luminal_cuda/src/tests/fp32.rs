@@ -782,3 +782,3468 @@ fn test_movement() {
 
     assert_exact(&c.data(), &d_c.as_vec());
 }
+
+#[test]
+fn test_mish() {
+    // Includes large negative inputs where softplus underflows
+    let data = vec![
+        -100., -50., -20., -10., -5., -2., -1., -0.5, 0., 0.5, 1., 2., 5., 10., 20., 50.,
+    ];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<16>>().set(data.clone());
+    let mut b = a.mish().retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+
+    assert_close(
+        &b.data(),
+        &data
+            .into_iter()
+            .map(|x: f32| x * (x.max(0.) + (-x.abs()).exp().ln_1p()).tanh())
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_memory_budget() {
+    use std::sync::atomic::Ordering;
+    // A budget of its own, since a device's is shared with every other test
+    let budget = crate::MemoryBudget::default();
+    budget.limit.store(1000, Ordering::Relaxed);
+
+    // Threads racing for the last of the budget can't overshoot it together
+    let reserved = std::thread::scope(|s| {
+        let threads = (0..8)
+            .map(|_| s.spawn(|| (0..10).filter(|_| budget.reserve(100).is_ok()).count()))
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .sum::<usize>()
+    });
+    assert_eq!(reserved, 10);
+    assert_eq!(budget.used.load(Ordering::Relaxed), 1000);
+    match budget.reserve(1) {
+        Err(crate::CudaError::BudgetExceeded {
+            used,
+            requested,
+            limit,
+        }) => assert_eq!((used, requested, limit), (1000, 1, 1000)),
+        _ => panic!("Reservation past the memory budget wasn't rejected"),
+    }
+    budget.release(100);
+    assert!(budget.reserve(100).is_ok());
+}
+
+#[test]
+fn test_gather_nd() {
+    let data = random_vec(16);
+    let coords = vec![0., 1., 2., 3., 3., 0., 1., 1.];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 4>>().set(data.clone());
+    let c = cx.tensor::<R2<4, 2>>().set(coords.clone());
+    let gathered = cx
+        .add_op(
+            crate::CudaGatherNd::<f32>::new(luminal_cudarc::driver::CudaDevice::new(0).unwrap(), 2)
+                .unwrap(),
+        )
+        .input(c.id, 0, c.shape)
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b =
+        GraphTensor::<R1<4>>::from_id(gathered, R1::<4>::to_tracker(), a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+
+    assert_exact(
+        &b.data(),
+        &coords
+            .chunks(2)
+            .map(|c| data[c[0] as usize * 4 + c[1] as usize])
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_transfer_cuda_data() {
+    let data = random_vec(64);
+    let src_dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let dst_dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let src = crate::CudaData::from_slice(src_dev.htod_sync_copy(&data).unwrap());
+    let dst = crate::transfer_cuda_data(&src, &dst_dev);
+
+    assert_exact(&dst_dev.dtoh_sync_copy(&dst.0).unwrap(), &data);
+}
+
+#[test]
+fn test_soft_label_cross_entropy() {
+    let logits = random_vec(40).into_iter().map(|i| i * 8.).collect_vec();
+    // Three soft targets and one near-one-hot target on class 3
+    let mut target = random_vec(30)
+        .chunks(10)
+        .flat_map(|row| {
+            let sum = row.iter().map(|i| i + 0.5).sum::<f32>();
+            row.iter().map(|i| (i + 0.5) / sum).collect_vec()
+        })
+        .collect_vec();
+    target.extend((0..10).map(|i| if i == 3 { 0.991 } else { 0.001 }));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 10>>().set(logits.clone());
+    let t = cx.tensor::<R2<4, 10>>().set(target.clone());
+    let loss = cx
+        .add_op(
+            crate::CudaSoftLabelCrossEntropy::<f32>::new(
+                luminal_cudarc::driver::CudaDevice::new(0).unwrap(),
+            )
+            .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .input(t.id, 0, t.shape)
+        .finish();
+    let mut b = GraphTensor::<R1<4>>::from_id(loss, R1::<4>::to_tracker(), a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+
+    let log_softmax = logits
+        .chunks(10)
+        .flat_map(|row| {
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let log_sum = row.iter().map(|i| (i - max).exp()).sum::<f32>().ln() + max;
+            row.iter().map(move |i| i - log_sum)
+        })
+        .collect_vec();
+    let reference = log_softmax
+        .chunks(10)
+        .zip(target.chunks(10))
+        .map(|(l, t)| -l.iter().zip(t).map(|(l, t)| l * t).sum::<f32>())
+        .collect_vec();
+    let out = b.data();
+    assert_close(&out, &reference);
+    // The near-one-hot row should approximate the hard-target loss
+    assert!((out[3] + log_softmax[33]).abs() < 0.1 * (1. + log_softmax[33].abs()));
+}
+
+#[test]
+fn test_histogram() {
+    // Evenly spread values plus a few outliers on either side
+    let mut data = (0..1000).map(|i| i as f32 / 1000.).collect_vec();
+    data.extend([-3., -0.5, 1.5, 7.]);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<1004>>().set(data.clone());
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let clamped = cx
+        .add_op(crate::CudaHistogram::<f32>::new(dev.clone(), 8, 0., 1., false).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let dropped = cx
+        .add_op(crate::CudaHistogram::<f32>::new(dev, 8, 0., 1., true).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b =
+        GraphTensor::<R1<8>>::from_id(clamped, R1::<8>::to_tracker(), a.graph_ref).retrieve();
+    let mut c =
+        GraphTensor::<R1<8>>::from_id(dropped, R1::<8>::to_tracker(), a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    cx.execute();
+
+    let histogram = |drop: bool| {
+        let mut counts = vec![0u32; 8];
+        for &x in &data {
+            if drop && !(0. ..=1.).contains(&x) {
+                continue;
+            }
+            counts[((x * 8.) as i32).clamp(0, 7) as usize] += 1;
+        }
+        counts
+    };
+    // Counts are integers, so they come back as u32
+    let counts = |id| {
+        cx.get_tensor_ref(id, 0)
+            .unwrap()
+            .data
+            .as_any()
+            .downcast_ref::<Vec<u32>>()
+            .unwrap()
+            .clone()
+    };
+    assert_eq!(counts(b.id), histogram(false));
+    assert_eq!(counts(c.id), histogram(true));
+
+    // An empty range has no bins to count into
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    assert!(matches!(
+        crate::CudaHistogram::<f32>::new(dev, 8, 1., 1., false),
+        Err(crate::CudaError::InvalidOp(_))
+    ));
+}
+
+#[test]
+fn test_dequantize_q4_0() {
+    // A single Q4_0 block: an f16 scale followed by 16 bytes holding 32 nibbles
+    let quants = (0..32u8).map(|i| (i * 7) % 16).collect_vec();
+    let scale = f16::from_f32(0.5);
+    let mut block = scale.to_le_bytes().to_vec();
+    block.extend((0..16).map(|i| quants[i] | (quants[i + 16] << 4)));
+
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let packed = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&block[2..]).unwrap(),
+    ));
+    let scales = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&[f16::from_le_bytes([block[0], block[1]]).to_f32()])
+            .unwrap(),
+    ));
+    let zeros = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&[8.0f32]).unwrap(),
+    ));
+    assert!(matches!(
+        crate::CudaDequantize::<f32>::new(dev.clone(), 3, 32),
+        Err(crate::CudaError::InvalidOp(_))
+    ));
+    assert!(matches!(
+        crate::CudaDequantize::<f32>::new(dev.clone(), 4, 33),
+        Err(crate::CudaError::InvalidOp(_))
+    ));
+    let mut op = crate::CudaDequantize::<f32>::new(dev.clone(), 4, 32).unwrap();
+    let out = op.process(vec![
+        (
+            luminal::op::InputTensor::Borrowed(&packed),
+            R1::<16>::to_tracker(),
+        ),
+        (
+            luminal::op::InputTensor::Borrowed(&scales),
+            R1::<1>::to_tracker(),
+        ),
+        (
+            luminal::op::InputTensor::Borrowed(&zeros),
+            R1::<1>::to_tracker(),
+        ),
+    ]);
+    let out = out[0]
+        .data
+        .as_any()
+        .downcast_ref::<crate::CudaData<f32>>()
+        .unwrap();
+
+    super::assert_cuda_close(
+        out,
+        &quants
+            .into_iter()
+            .map(|q| (q as f32 - 8.) * 0.5)
+            .collect_vec(),
+        0.,
+        0.,
+    );
+}
+
+#[test]
+fn test_nan_to_num() {
+    let data = vec![1., f32::NAN, -2.5, f32::INFINITY, f32::NEG_INFINITY, 0.];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<6>>().set(data);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let defaults = cx
+        .add_op(crate::CudaNanToNum::<f32>::new(dev.clone(), None, None, None).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let configured = cx
+        .add_op(crate::CudaNanToNum::<f32>::new(dev, Some(-1.), Some(100.), Some(-100.)).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b =
+        GraphTensor::<R1<6>>::from_id(defaults, R1::<6>::to_tracker(), a.graph_ref).retrieve();
+    let mut c =
+        GraphTensor::<R1<6>>::from_id(configured, R1::<6>::to_tracker(), a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    cx.execute();
+
+    assert_exact(&b.data(), &[1., 0., -2.5, f32::MAX, f32::MIN, 0.]);
+    assert_exact(&c.data(), &[1., -1., -2.5, 100., -100., 0.]);
+}
+
+#[derive(Debug, PartialEq)]
+struct Unsupported;
+impl luminal::op::Operator for Unsupported {
+    fn process(
+        &mut self,
+        _: Vec<(luminal::op::InputTensor, ShapeTracker)>,
+    ) -> Vec<luminal::prelude::Tensor> {
+        unimplemented!()
+    }
+}
+
+#[test]
+#[should_panic(expected = "The CUDA backend doesn't support these ops: Unsupported (node ")]
+fn test_unsupported_op_compile() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let b = cx.add_op(Unsupported).input(a.id, 0, a.shape).finish();
+    let mut b = GraphTensor::<R1<3>>::from_id(b, R1::<3>::to_tracker(), a.graph_ref).retrieve();
+    let mut c = (a * 2.).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+}
+
+#[test]
+fn test_unsupported_op_check() {
+    // Everything the compiler lowers passes
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let mut c = (a * 2.).exp().sum_reduce::<_, LAxis<0>>().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut c);
+    assert!(crate::check_cuda_ops(&mut cx).is_ok());
+
+    // An op added afterwards doesn't
+    cx.add_op(Unsupported).input(c.id, 0, c.shape).finish();
+    let err = crate::check_cuda_ops(&mut cx).unwrap_err();
+    assert!(matches!(&err, crate::CudaError::UnsupportedOps(ops) if ops.len() == 1));
+    assert!(err
+        .to_string()
+        .starts_with("The CUDA backend doesn't support these ops: Unsupported (node "));
+
+    // Wrapping it doesn't hide it
+    cx.compile(crate::CudaErrorReportCompiler, ());
+    let err = crate::check_cuda_ops(&mut cx).unwrap_err();
+    assert!(matches!(&err, crate::CudaError::UnsupportedOps(ops) if ops.len() == 1));
+}
+
+#[test]
+fn test_assert_cuda_close() {
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let data = crate::CudaData::from_slice(dev.htod_sync_copy(&[1., 2., 3.0001f32]).unwrap());
+    super::assert_cuda_close(&data, &[1., 2.001, 3.], 1e-3, 0.);
+    super::assert_cuda_close(&data, &[1.01, 2.02, 3.03], 0., 1e-2);
+}
+
+#[test]
+#[should_panic(expected = "3.0001 is not close to 3.1, index 2")]
+fn test_assert_cuda_not_close() {
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let data = crate::CudaData::from_slice(dev.htod_sync_copy(&[1., 2., 3.0001f32]).unwrap());
+    super::assert_cuda_close(&data, &[1., 2., 3.1], 1e-3, 1e-3);
+}
+
+#[test]
+fn test_argsort() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R2<2, 6>>()
+        .set(vec![0.1, 2.5, -1., 2.5, 0.3, 0., 3., -2., 1., 1., 5., 0.]);
+    let b = cx
+        .add_op(
+            crate::CudaArgSort::<f32>::new(luminal_cudarc::driver::CudaDevice::new(0).unwrap())
+                .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b =
+        GraphTensor::<R2<2, 6>>::from_id(b, R2::<2, 6>::to_tracker(), a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+
+    // Indices are integers, so they come back as i32
+    assert_eq!(
+        cx.get_tensor_ref(b.id, 0)
+            .unwrap()
+            .data
+            .as_any()
+            .downcast_ref::<Vec<i32>>()
+            .unwrap(),
+        &[1, 3, 4, 0, 5, 2, 4, 0, 2, 3, 5, 1]
+    );
+}
+
+#[test]
+#[should_panic(expected = "Argsort input must be contiguous")]
+fn test_argsort_permuted_input() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+    let a = a.permute::<R2<3, 2>, _>();
+    let b = cx
+        .add_op(
+            crate::CudaArgSort::<f32>::new(luminal_cudarc::driver::CudaDevice::new(0).unwrap())
+                .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b =
+        GraphTensor::<R2<3, 2>>::from_id(b, R2::<3, 2>::to_tracker(), a.graph_ref).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+}
+
+#[test]
+fn test_cuda_data_bytes_round_trip() {
+    let dev = crate::cuda_device(0);
+    let values = random_vec(100)
+        .into_iter()
+        .map(luminal::prelude::f16::from_f32)
+        .collect_vec();
+    let data = crate::CudaData::from_slice(dev.htod_sync_copy(&values).unwrap());
+    let bytes = data.to_bytes();
+    assert_eq!(
+        bytes,
+        values.iter().flat_map(|v| v.to_le_bytes()).collect_vec()
+    );
+
+    let reloaded = crate::CudaData::<luminal::prelude::f16>::from_bytes(&dev, &bytes).unwrap();
+    assert_eq!(reloaded.to_vec(), values);
+
+    // A trailing half element is rejected rather than dropped
+    assert!(matches!(
+        crate::CudaData::<luminal::prelude::f16>::from_bytes(&dev, &bytes[1..]),
+        Err(crate::CudaError::ByteLength {
+            len: 199,
+            element_size: 2
+        })
+    ));
+}
+
+#[test]
+fn test_multi_head_reshape() {
+    let data = random_vec(4 * 64);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 64>>().set(data.clone());
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let split = cx
+        .add_op(crate::CudaMultiHeadReshape::<f32>::split(dev.clone(), 8, 8).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let split =
+        GraphTensor::<R3<8, 4, 8>>::from_id(split, R3::<8, 4, 8>::to_tracker(), a.graph_ref);
+    let merged = cx
+        .add_op(crate::CudaMultiHeadReshape::<f32>::merge(dev, 8, 8).unwrap())
+        .input(split.id, 0, split.shape)
+        .finish();
+    let mut merged =
+        GraphTensor::<R2<4, 64>>::from_id(merged, R2::<4, 64>::to_tracker(), a.graph_ref)
+            .retrieve();
+    let mut split = split.retrieve();
+    let mut reference = a
+        .reshape::<R3<4, 8, 8>>()
+        .permute::<_, LAxes3<1, 0, 2>>()
+        .contiguous()
+        .retrieve();
+
+    cx.compile(
+        CudaCompiler::<f32>::default(),
+        (&mut split, &mut merged, &mut reference),
+    );
+    cx.execute();
+
+    assert_exact(&split.data(), &reference.data());
+    assert_exact(&merged.data(), &data);
+}
+
+#[test]
+fn test_pinned_staging() {
+    let data = random_vec(4096);
+    let run = |pinned: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4096>>().set(data.clone());
+        let mut b = (a.exp() * 2.).retrieve();
+        if pinned {
+            cx.compile(
+                (
+                    CudaCompiler::<f32>::default(),
+                    crate::PinnedStagingCompiler::<f32>::default(),
+                ),
+                &mut b,
+            );
+        } else {
+            cx.compile(CudaCompiler::<f32>::default(), &mut b);
+        }
+        let now = std::time::Instant::now();
+        for _ in 0..10 {
+            cx.execute();
+        }
+        let elapsed = now.elapsed();
+        (b.data(), elapsed)
+    };
+    let (pageable, pageable_time) = run(false);
+    let (pinned, pinned_time) = run(true);
+    println!("Pageable: {pageable_time:?} Pinned: {pinned_time:?}");
+
+    assert_exact(&pinned, &pageable);
+}
+
+#[test]
+fn test_accumulate() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let (buf_data, a_data, b_data, c_data) = (
+        random_vec_rng(12, &mut rng),
+        random_vec_rng(12, &mut rng),
+        random_vec_rng(12, &mut rng),
+        random_vec_rng(4, &mut rng),
+    );
+    let mut cx = Graph::new();
+    let buf = cx.tensor::<R2<3, 4>>().set(buf_data);
+    let a = cx.tensor::<R2<3, 4>>().set(a_data);
+    let b = cx.tensor::<R2<3, 4>>().set(b_data);
+    let c = cx.tensor::<R1<4>>().set(c_data);
+    let c_expanded = c.expand::<R2<3, 4>, _>();
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let mut acc = buf.id;
+    for src in [a, b, c_expanded] {
+        acc = cx
+            .add_op(crate::CudaAccumulate::<f32>::new(src.shape, dev.clone(), &cx.dyn_map).unwrap())
+            .input(acc, 0, buf.shape)
+            .input(src.id, 0, src.shape)
+            .finish();
+    }
+    let mut acc = GraphTensor::<R2<3, 4>>::from_id(acc, buf.shape, buf.graph_ref).retrieve();
+    let mut reference = (buf + a + b + c_expanded).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut acc, &mut reference));
+    cx.execute();
+
+    assert_close(&acc.data(), &reference.data());
+}
+
+#[test]
+fn test_qkv_split() {
+    let data = random_vec(4 * 12);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 12>>().set(data.clone());
+    let mut splits = (0..3usize)
+        .map(|i| {
+            a.slice((.., Expression::from(i * 4)..Expression::from((i + 1) * 4)))
+                .realize::<R2<4, 4>>()
+                .contiguous()
+                .retrieve()
+        })
+        .collect_vec();
+    let mut heads = splits
+        .iter()
+        .map(|s| {
+            s.reshape::<R3<4, 2, 2>>()
+                .permute::<_, LAxes3<1, 0, 2>>()
+                .contiguous()
+                .retrieve()
+        })
+        .collect_vec();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut splits, &mut heads));
+    cx.execute();
+
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&data).unwrap(),
+    ));
+    for (head_dim, references) in [(None, &splits), (Some(2), &heads)] {
+        let mut op = crate::CudaQKVSplit::<f32>::new(dev.clone(), [4, 4, 4], head_dim).unwrap();
+        let outs = op.process(vec![(
+            luminal::op::InputTensor::Borrowed(&inp),
+            R2::<4, 12>::to_tracker(),
+        )]);
+        for (out, reference) in outs.iter().zip(references.iter()) {
+            super::assert_cuda_close(
+                out.data
+                    .as_any()
+                    .downcast_ref::<crate::CudaData<f32>>()
+                    .unwrap(),
+                &reference.data(),
+                0.,
+                0.,
+            );
+        }
+    }
+}
+
+#[test]
+fn test_raw_f16_upload() {
+    let values = random_vec(64)
+        .into_iter()
+        .map(luminal::prelude::f16::from_f32)
+        .collect_vec();
+    let bytes = values.iter().flat_map(|v| v.to_le_bytes()).collect_vec();
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let inp = luminal::prelude::Tensor::new(crate::RawF16Bytes(bytes.clone()));
+
+    let mut to_f16 = crate::prim::CudaCopyToDevice::<luminal::prelude::f16>::new(dev.clone());
+    let out = to_f16.process(vec![(
+        luminal::op::InputTensor::Borrowed(&inp),
+        R1::<64>::to_tracker(),
+    )]);
+    let out = out[0]
+        .data
+        .as_any()
+        .downcast_ref::<crate::CudaData<luminal::prelude::f16>>()
+        .unwrap();
+    assert_eq!(out.to_bytes(), bytes);
+
+    let mut to_f32 = crate::prim::CudaCopyToDevice::<f32>::new(dev);
+    let out = to_f32.process(vec![(
+        luminal::op::InputTensor::Borrowed(&inp),
+        R1::<64>::to_tracker(),
+    )]);
+    super::assert_cuda_close(
+        out[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap(),
+        &values.iter().map(|v| v.to_f32()).collect_vec(),
+        0.,
+        0.,
+    );
+}
+
+#[test]
+fn test_ring_append() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<2>>();
+    let b = cx
+        .add_op(
+            crate::CudaRingAppend::<f32>::new(
+                luminal_cudarc::driver::CudaDevice::new(0).unwrap(),
+                6,
+            )
+            .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b = GraphTensor::<R1<6>>::from_id(b, R1::<6>::to_tracker(), a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    a.set(vec![1., 2.]);
+    cx.execute();
+    assert_exact(&b.data(), &[0., 0., 0., 0., 1., 2.]);
+
+    for frame in [[3., 4.], [5., 6.], [7., 8.]] {
+        b.drop();
+        a.set(frame.to_vec());
+        cx.execute();
+    }
+    assert_exact(&b.data(), &[3., 4., 5., 6., 7., 8.]);
+}
+
+#[test]
+fn test_multi_reduce() {
+    let data = random_vec(2 * 3 * 4);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<2, 3, 4>>().set(data);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let sum = cx
+        .add_op(
+            crate::CudaMultiReduce::<f32>::sum(vec![0, 2], a.shape, dev.clone(), &cx.dyn_map)
+                .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .finish();
+    let max = cx
+        .add_op(crate::CudaMultiReduce::<f32>::max(vec![0, 2], a.shape, dev, &cx.dyn_map).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut sum = GraphTensor::<R1<3>>::from_id(sum, R1::<3>::to_tracker(), a.graph_ref).retrieve();
+    let mut max = GraphTensor::<R1<3>>::from_id(max, R1::<3>::to_tracker(), a.graph_ref).retrieve();
+    let mut chained_sum = a
+        .sum_reduce::<_, LAxis<2>>()
+        .sum_reduce::<_, LAxis<0>>()
+        .retrieve();
+    let mut chained_max = a
+        .max_reduce::<_, LAxis<2>>()
+        .max_reduce::<_, LAxis<0>>()
+        .retrieve();
+
+    cx.compile(
+        CudaCompiler::<f32>::default(),
+        (&mut sum, &mut max, &mut chained_sum, &mut chained_max),
+    );
+    cx.execute();
+
+    assert_close(&sum.data(), &chained_sum.data());
+    assert_exact(&max.data(), &chained_max.data());
+}
+
+#[test]
+fn test_retrieval_map() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let mut b = a.exp2().retrieve();
+    let c = (b * 2.).sin();
+    let original_b = b.id;
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+
+    // The marked node now points to the copy back to the host
+    assert_eq!(cx.retrieved_id(original_b), Some(b.id));
+    assert!(cx
+        .node_weight(b.id)
+        .unwrap()
+        .as_any()
+        .is::<crate::prim::CudaCopyFromDevice<f32>>());
+
+    // Grab an extra output after compilation
+    let c_id = crate::add_retrieval::<f32>(&mut cx, c.id);
+    assert_eq!(cx.retrieved_id(c.id), Some(c_id));
+    cx.execute();
+    let c_data = cx
+        .get_tensor_ref(c_id, 0)
+        .unwrap()
+        .data
+        .as_any()
+        .downcast_ref::<Vec<f32>>()
+        .unwrap()
+        .clone();
+    assert_close(&c_data, &[4., 8., 16.].map(|x: f32| x.sin()));
+    assert_close(&b.data(), &[2., 4., 8.]);
+
+    crate::remove_retrieval::<f32>(&mut cx, c.id);
+    assert!(cx.retrieved_id(c.id).is_none());
+    assert!(!cx.contains_node(c_id));
+}
+
+#[test]
+fn test_retrieval_through_wrappers() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let mut b = a.exp2().retrieve();
+    let c = (b * 2.).sin();
+
+    // Every op, including the copies to the device, is wrapped
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::CudaErrorReportCompiler,
+        ),
+        &mut b,
+    );
+    let c_id = crate::add_retrieval::<f32>(&mut cx, c.id);
+    cx.execute();
+    let c_data = cx
+        .get_tensor_ref(c_id, 0)
+        .unwrap()
+        .data
+        .as_any()
+        .downcast_ref::<Vec<f32>>()
+        .unwrap()
+        .clone();
+    assert_close(&c_data, &[4., 8., 16.].map(|x: f32| x.sin()));
+    crate::remove_retrieval::<f32>(&mut cx, c.id);
+
+    let c_data = crate::execute_until::<f32>(&mut cx, c.id);
+    assert_close(
+        c_data.data.as_any().downcast_ref::<Vec<f32>>().unwrap(),
+        &[4., 8., 16.].map(|x: f32| x.sin()),
+    );
+}
+
+#[test]
+fn test_reduce_broadcast_div() {
+    let data = random_vec(3 * 8).into_iter().map(|x| x + 1.).collect_vec();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<3, 8>>().set(data);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let mut fused = vec![
+        crate::CudaReduceBroadcastDiv::<f32>::sum(1, dev.clone()).unwrap(),
+        crate::CudaReduceBroadcastDiv::<f32>::max(1, dev).unwrap(),
+    ]
+    .into_iter()
+    .map(|op| {
+        let id = cx.add_op(op).input(a.id, 0, a.shape).finish();
+        GraphTensor::<R2<3, 8>>::from_id(id, a.shape, a.graph_ref).retrieve()
+    })
+    .collect_vec();
+    let mut unfused = vec![
+        (a / a.sum_reduce::<_, LAxis<1>>().expand()).retrieve(),
+        (a / a.max_reduce::<_, LAxis<1>>().expand()).retrieve(),
+    ];
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut fused, &mut unfused));
+    cx.execute();
+
+    for (fused, unfused) in fused.iter().zip(unfused.iter()) {
+        assert_close(&fused.data(), &unfused.data());
+    }
+}
+
+#[test]
+fn test_weight_prefetch() {
+    use petgraph::visit::EdgeRef;
+    let mut rng = StdRng::seed_from_u64(4);
+    let inp_data = random_vec_rng(4, &mut rng);
+    let weight_data = (0..3)
+        .map(|_| random_vec_rng(16, &mut rng))
+        .collect::<Vec<_>>();
+    let run = |prefetch: bool| {
+        let mut cx = Graph::new();
+        let inp = cx.tensor::<R2<1, 4>>().set(inp_data.clone());
+        let weights = weight_data
+            .iter()
+            .map(|w| cx.tensor::<R2<4, 4>>().set(w.clone()))
+            .collect::<Vec<_>>();
+        let mut out = inp
+            .matmul(weights[0])
+            .relu()
+            .matmul(weights[1])
+            .relu()
+            .matmul(weights[2])
+            .retrieve();
+        // Hand the weights over in reverse to check they get put back in layer order
+        let weight_ids = weights.iter().map(|w| w.id).collect::<Vec<_>>();
+        if prefetch {
+            cx.compile(
+                (
+                    CudaCompiler::<f32>::default(),
+                    crate::CudaPrefetchCompiler::<f32>::new(
+                        weight_ids.iter().rev().copied().collect(),
+                    ),
+                ),
+                &mut out,
+            );
+        } else {
+            cx.compile(CudaCompiler::<f32>::default(), &mut out);
+        }
+
+        // Map each prefetch, in execution order, back to the host weight it fetches
+        let prefetched = petgraph::algo::toposort(&cx.graph, None)
+            .unwrap()
+            .into_iter()
+            .filter(|n| {
+                cx.node_weight(*n)
+                    .unwrap()
+                    .as_any()
+                    .is::<crate::CudaPrefetch<f32>>()
+            })
+            .map(|n| {
+                let copy = cx
+                    .edges_directed(n, petgraph::Direction::Incoming)
+                    .find(|e| !e.weight().is_schedule())
+                    .unwrap()
+                    .source();
+                cx.neighbors_directed(copy, petgraph::Direction::Incoming)
+                    .next()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        cx.execute();
+        (prefetched, weight_ids, out.data())
+    };
+    let (none, _, reference) = run(false);
+    assert!(none.is_empty());
+    let (prefetched, weight_ids, out) = run(true);
+    assert_eq!(prefetched, weight_ids);
+    assert_close(&out, &reference);
+}
+
+fn run_assert_sums_to_one(scale: f32) -> Vec<f32> {
+    let data = random_vec(3 * 8);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<3, 8>>().set(data);
+    let sums = (a.softmax::<LAxis<1>>() * scale).sum_reduce::<_, LAxis<1>>();
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let checked = cx
+        .add_op(
+            crate::CudaAssert::<f32>::new(
+                "probabilities sum to 1",
+                Some((0.99, 1.01)),
+                sums.shape,
+                dev,
+                &cx.dyn_map,
+            )
+            .unwrap(),
+        )
+        .input(sums.id, 0, sums.shape)
+        .finish();
+    let mut checked = GraphTensor::<R1<3>>::from_id(checked, sums.shape, a.graph_ref).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut checked);
+    cx.execute();
+    checked.data()
+}
+
+#[test]
+fn test_assert_passes_through() {
+    assert_close(&run_assert_sums_to_one(1.), &[1.; 3]);
+}
+
+#[test]
+#[should_panic(
+    expected = "Assertion failed: probabilities sum to 1: 3 of 3 elements weren't within [0.99, 1.01], first at index 0"
+)]
+fn test_assert_violated() {
+    run_assert_sums_to_one(2.);
+}
+
+#[test]
+fn test_grouped_matmul() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let inp_data = random_vec_rng(4 * 3, &mut rng);
+    let weight_data = random_vec_rng(2 * 3 * 2, &mut rng);
+    let expert_data = vec![1., 0., 0., 1.];
+    let mut cx = Graph::new();
+    let inp = cx.tensor::<R2<4, 3>>().set(inp_data.clone());
+    let weights = cx.tensor::<R3<2, 3, 2>>().set(weight_data.clone());
+    let experts = cx.tensor::<R1<4>>().set(expert_data.clone());
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let out = cx
+        .add_op(crate::CudaGroupedMatMul::<f32>::new(dev).unwrap())
+        .input(inp.id, 0, inp.shape)
+        .input(weights.id, 0, weights.shape)
+        .input(experts.id, 0, experts.shape)
+        .finish();
+    let mut out =
+        GraphTensor::<R2<4, 2>>::from_id(out, R2::<4, 2>::to_tracker(), inp.graph_ref).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    cx.execute();
+
+    // Loop over the tokens, multiplying each by its assigned expert
+    let mut expected = vec![0.; 4 * 2];
+    for (t, expert) in expert_data.iter().enumerate() {
+        let weight = &weight_data[*expert as usize * 6..][..6];
+        for n in 0..2 {
+            expected[t * 2 + n] = (0..3)
+                .map(|k| inp_data[t * 3 + k] * weight[k * 2 + n])
+                .sum();
+        }
+    }
+    assert_close(&out.data(), &expected);
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn test_grouped_matmul_check_range() {
+    let mut cx = Graph::new();
+    let inp = cx.tensor::<R2<2, 3>>().set(vec![1.; 6]);
+    let weights = cx.tensor::<R3<2, 3, 2>>().set(vec![1.; 12]);
+    let experts = cx.tensor::<R1<2>>().set(vec![0., 2.]);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let mut grouped = crate::CudaGroupedMatMul::<f32>::new(dev).unwrap();
+    grouped.check_range = true;
+    let out = cx
+        .add_op(grouped)
+        .input(inp.id, 0, inp.shape)
+        .input(weights.id, 0, weights.shape)
+        .input(experts.id, 0, experts.shape)
+        .finish();
+    let mut out =
+        GraphTensor::<R2<2, 2>>::from_id(out, R2::<2, 2>::to_tracker(), inp.graph_ref).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    cx.execute();
+}
+
+#[test]
+fn test_typed_half_retrieval() {
+    use luminal::prelude::{bf16, f16};
+    let data = random_vec(64);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let inp = luminal::prelude::Tensor::new(data.clone());
+    let mut to_device = crate::prim::CudaCopyToDevice::<f16>::new(dev.clone());
+    let on_device = to_device.process(vec![(
+        luminal::op::InputTensor::Borrowed(&inp),
+        R1::<64>::to_tracker(),
+    )]);
+    let half = on_device[0]
+        .data
+        .as_any()
+        .downcast_ref::<crate::CudaData<f16>>()
+        .unwrap()
+        .to_vec();
+
+    // Compare against the f32 readback converted back down to f16
+    let mut from_device = crate::prim::CudaCopyFromDevice::<f16>::new(dev.clone());
+    let readback = from_device.process(vec![(
+        luminal::op::InputTensor::Borrowed(&on_device[0]),
+        R1::<64>::to_tracker(),
+    )]);
+    let readback = readback[0]
+        .data
+        .as_any()
+        .downcast_ref::<Vec<f32>>()
+        .unwrap();
+    assert_eq!(half.len(), readback.len());
+    for (h, f) in half.iter().zip(readback) {
+        assert_eq!(*h, f16::from_f32(*f));
+    }
+
+    let bf = data.iter().map(|v| bf16::from_f32(*v)).collect_vec();
+    let bytes = bf.iter().flat_map(|v| v.to_le_bytes()).collect_vec();
+    assert_eq!(
+        crate::CudaData::<bf16>::from_bytes(&dev, &bytes)
+            .unwrap()
+            .to_vec(),
+        bf
+    );
+}
+
+#[test]
+fn test_kernel_names_interned() {
+    let codes = (0..50)
+        .map(|i| {
+            format!("extern \"C\" __global__ void kernel(float *out) {{ out[0] = {i}.0 + 0.125; }}")
+        })
+        .collect_vec();
+    // Load every kernel twice onto two separate device handles
+    for _ in 0..2 {
+        let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+        for code in &codes {
+            crate::compile_and_load_kernel(code.clone(), &dev).unwrap();
+            crate::compile_and_load_kernel(code.clone(), &dev).unwrap();
+        }
+    }
+    let names = codes
+        .iter()
+        .map(|c| format!("kernel_{}", crate::hash(c)))
+        .collect_vec();
+    let interned = crate::KERNEL_NAMES.get().unwrap().lock().unwrap().clone();
+    // Each kernel's name is stored once, and interning it again reuses that copy
+    assert_eq!(
+        names
+            .iter()
+            .filter(|n| interned.contains(n.as_str()))
+            .count(),
+        50
+    );
+    for name in &names {
+        let stored = *interned.get(name.as_str()).unwrap();
+        assert!(std::ptr::eq(stored, crate::intern_kernel_name(name)));
+    }
+}
+
+#[test]
+fn test_cuda_data_cast() {
+    use luminal::prelude::f16;
+    let data = random_vec(128);
+    let dev = crate::cuda_device(0);
+    let weight = crate::CudaData::from_slice(dev.htod_sync_copy(&data).unwrap());
+    let half = weight.cast::<f16>();
+    assert_eq!(
+        half.to_vec(),
+        data.iter().map(|v| f16::from_f32(*v)).collect_vec()
+    );
+    // The original stays usable, and casting back up only loses f16 precision
+    super::assert_cuda_close(&weight, &data, 0., 0.);
+    super::assert_cuda_close(&half.cast::<f32>(), &data, 1e-3, 1e-3);
+    // Widening converts straight across, so it's exact
+    assert_eq!(
+        weight.cast::<f64>().to_vec(),
+        data.iter().map(|v| *v as f64).collect_vec()
+    );
+
+    // Later casts between the same types reuse the loaded kernel
+    let kernels = || crate::CAST_KERNELS.get().unwrap().lock().unwrap().len();
+    let loaded = kernels();
+    weight.cast::<f16>();
+    half.cast::<f32>();
+    assert_eq!(kernels(), loaded);
+}
+
+#[test]
+fn test_chrome_trace() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 8>>().set(random_vec(32));
+    let b = cx.tensor::<R1<4>>().set(random_vec(4));
+    let mut c = ((a.exp() * 2.).sum_reduce::<_, LAxis<1>>() + b).retrieve();
+    let trace = crate::CudaTrace::new(luminal_cudarc::driver::CudaDevice::new(0).unwrap());
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::CudaTraceCompiler(trace.clone()),
+        ),
+        &mut c,
+    );
+    cx.execute();
+
+    // One event per op, each starting after the last one finished
+    let events = trace.events();
+    assert_eq!(events.len(), cx.node_count());
+    assert_eq!(
+        events.iter().map(|e| e.node).sorted().collect_vec(),
+        cx.node_indices().map(|n| n.index()).sorted().collect_vec()
+    );
+    assert_eq!(events[0].start_us, 0.);
+    for (prev, next) in events.iter().tuple_windows() {
+        assert!(prev.duration_us >= 0.);
+        assert!(prev.start_us + prev.duration_us <= next.start_us + 1.);
+    }
+
+    let json = trace.to_chrome_json();
+    assert!(json.starts_with("{\"traceEvents\":[{\"name\":\""));
+    assert!(json.ends_with("}}]}"));
+    assert_eq!(json.matches("\"ph\":\"X\"").count(), events.len());
+
+    trace.clear();
+    assert!(trace.events().is_empty());
+}
+
+#[test]
+fn test_reduce_initial_value() {
+    let data = random_vec(2 * 4);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 4>>().set(data.clone());
+    let floor = cx.tensor::<R1<2>>().set(vec![0.6, -10.]);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let sum = cx
+        .add_op(
+            crate::CudaSumReduce::<f32>::with_init(
+                1,
+                a.shape,
+                dev.clone(),
+                &cx.dyn_map,
+                crate::ReduceInit::Constant(10.),
+            )
+            .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .finish();
+    let max = cx
+        .add_op(
+            crate::CudaMaxReduce::<f32>::with_init(
+                1,
+                a.shape,
+                dev,
+                &cx.dyn_map,
+                crate::ReduceInit::Tensor,
+            )
+            .unwrap(),
+        )
+        .input(a.id, 0, a.shape)
+        .input(floor.id, 0, floor.shape)
+        .finish();
+    let mut sum = GraphTensor::<R1<2>>::from_id(sum, R1::<2>::to_tracker(), a.graph_ref).retrieve();
+    let mut max = GraphTensor::<R1<2>>::from_id(max, R1::<2>::to_tracker(), a.graph_ref).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut sum, &mut max));
+    cx.execute();
+
+    let rows = data.chunks(4).collect_vec();
+    assert_close(
+        &sum.data(),
+        &rows
+            .iter()
+            .map(|r| r.iter().sum::<f32>() + 10.)
+            .collect_vec(),
+    );
+    // The first row's floor is above every element, the second's is below
+    assert_close(
+        &max.data(),
+        &[
+            0.6,
+            rows[1].iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b)),
+        ],
+    );
+}
+
+#[test]
+fn test_diff_copy_compiler() {
+    use luminal::op::Function;
+    // A host function reading an input straight from another host function gets a pointless round trip through the device
+    let build = |collapse_copies: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let host = cx
+            .add_op(Function(
+                "HostDouble".to_string(),
+                Box::new(|inp| {
+                    let data = inp[0]
+                        .0
+                        .borrowed()
+                        .data
+                        .as_any()
+                        .downcast_ref::<Vec<f32>>()
+                        .unwrap();
+                    vec![luminal::prelude::Tensor::new(
+                        data.iter().map(|v| v * 2.).collect_vec(),
+                    )]
+                }),
+            ))
+            .input(a.id, 0, a.shape)
+            .finish();
+        let mut out = GraphTensor::<R1<4>>::from_id(host, a.shape, a.graph_ref)
+            .exp()
+            .retrieve();
+        if collapse_copies {
+            cx.compile(
+                (
+                    crate::prim::CudaPrimitiveCompiler::<f32>::default(),
+                    crate::prim::CopyCompiler::<f32>::default(),
+                ),
+                &mut out,
+            );
+        } else {
+            cx.compile(
+                crate::prim::CudaPrimitiveCompiler::<f32>::default(),
+                &mut out,
+            );
+        }
+        (cx, a.id, host)
+    };
+    let (before, a, host) = build(false);
+    let (after, _, _) = build(true);
+    let diff = diff_graphs(&before, &after);
+    println!("{diff}");
+
+    // Only the copy to the device and straight back are gone, with the host function now reading the input directly
+    let round_trip = before
+        .neighbors_directed(host, petgraph::Direction::Incoming)
+        .collect_vec();
+    assert_eq!(
+        diff.removed_nodes,
+        vec![
+            (
+                before
+                    .neighbors_directed(round_trip[0], petgraph::Direction::Incoming)
+                    .next()
+                    .unwrap(),
+                "CudaCopyToDevice".to_string()
+            ),
+            (round_trip[0], "CudaCopyFromDevice".to_string()),
+        ]
+    );
+    assert!(diff.added_nodes.is_empty() && diff.replaced_nodes.is_empty());
+    assert!(diff.added_edges.is_empty());
+    assert_eq!(diff.removed_edges.len(), 2);
+    assert_eq!(diff.rewired_edges.len(), 1);
+    let (old, new) = diff.rewired_edges[0];
+    assert_eq!((old.source, old.target), (round_trip[0], host));
+    assert_eq!((new.source, new.target), (a, host));
+}
+
+#[test]
+fn test_online_softmax() {
+    let scores = random_vec(256).into_iter().map(|s| s * 8.).collect_vec();
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let mut op = crate::CudaOnlineSoftmax::<f32>::new(dev.clone()).unwrap();
+    // With V as the identity, the output is the softmax itself
+    let mut out = vec![];
+    for tile in 0..4 {
+        let score_tile = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(&scores[tile * 64..][..64]).unwrap(),
+        ));
+        let v_tile = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(
+                &(0..64 * 256)
+                    .map(|i| (i / 256 + tile * 64 == i % 256) as i32 as f32)
+                    .collect_vec(),
+            )
+            .unwrap(),
+        ));
+        out = op.process(vec![
+            (
+                luminal::op::InputTensor::Borrowed(&score_tile),
+                R2::<1, 64>::to_tracker(),
+            ),
+            (
+                luminal::op::InputTensor::Borrowed(&v_tile),
+                R2::<64, 256>::to_tracker(),
+            ),
+        ]);
+    }
+
+    let max = scores.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+    let sum = scores.iter().map(|s| (s - max).exp()).sum::<f32>();
+    let softmax = scores.iter().map(|s| (s - max).exp() / sum).collect_vec();
+    super::assert_cuda_close(
+        out[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap(),
+        &softmax,
+        1e-6,
+        1e-4,
+    );
+}
+
+#[derive(LuminalPrint, LuminalEqFalse)]
+struct CudaBadLaunch(std::sync::Arc<luminal_cudarc::driver::CudaDevice>);
+
+impl luminal::op::Operator for CudaBadLaunch {
+    fn process(&mut self, inp: Vec<(luminal::op::InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        use luminal_cudarc::driver::{LaunchAsync, LaunchConfig};
+        let inp = crate::get_buffer_from_tensor::<f32>(&inp[0].0);
+        let mut out = self.0.alloc_zeros::<f32>(inp.len()).unwrap();
+        let function = crate::compile_and_load_kernel(
+            "extern \"C\" __global__ void kernel(float *out, const float *inp) {
+    out[threadIdx.x] = inp[threadIdx.x];
+}"
+            .to_string(),
+            &self.0,
+        )
+        .unwrap();
+        // More threads per block than any device supports
+        let cfg = LaunchConfig {
+            grid_dim: (1, 1, 1),
+            block_dim: (2048, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        unsafe { function.launch(cfg, (&mut out, inp)) }.unwrap();
+        vec![Tensor::new(crate::CudaData::from_slice(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn std::any::Any>) -> Option<Box<dyn std::any::Any>> {
+        crate::cuda_op_marker(key)
+    }
+}
+
+#[test]
+#[should_panic(expected = "CUDA error in CudaBadLaunch with input shapes [[2, 3]]")]
+fn test_error_report() {
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+    let b = cx
+        .add_op(CudaBadLaunch(dev))
+        .input(a.id, 0, a.shape)
+        .finish();
+    let mut b = GraphTensor::<R2<2, 3>>::from_id(b, a.shape, a.graph_ref).retrieve();
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::CudaErrorReportCompiler,
+        ),
+        &mut b,
+    );
+    cx.execute();
+}
+
+#[test]
+fn test_share_weights() {
+    use luminal_cudarc::driver::DevicePtr;
+    let data = random_vec(6);
+    let mut cx1 = Graph::new();
+    let weight1 = cx1.named_tensor::<R2<2, 3>>("Weight").set(data.clone());
+    let mut out1 = weight1.exp().retrieve();
+    cx1.compile(CudaCompiler::<f32>::default(), &mut out1);
+    let weights1 = downstream(&weight1, &cx1);
+    cx1.keep_tensors(&weights1);
+    cx1.execute();
+    delete_inputs(&weights1, &mut cx1);
+
+    // The second graph never uploads the weight
+    let mut cx2 = Graph::new();
+    let weight2 = cx2.named_tensor::<R2<2, 3>>("Weight").set(vec![0.; 6]);
+    let mut out2 = weight2.exp().retrieve();
+    cx2.compile(CudaCompiler::<f32>::default(), &mut out2);
+    let weights2 = downstream(&weight2, &cx2);
+    cx2.keep_tensors(&weights2);
+    delete_inputs(&weights2, &mut cx2);
+
+    crate::share_weights::<f32, _, _>(&weights1, &cx1, &weights2, &mut cx2);
+    let buffers = [(&cx1, &weights1), (&cx2, &weights2)].map(|(cx, weights)| {
+        let data = cx.tensors[&(weights[0], 0)]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap();
+        assert!(data.is_shared());
+        *data.0.device_ptr()
+    });
+    assert_eq!(buffers[0], buffers[1]);
+
+    cx2.execute();
+    assert_close(
+        &out2.data(),
+        &data.iter().map(|d| d.exp()).collect::<Vec<_>>(),
+    );
+
+    // Dropping one graph leaves the weight usable from the other
+    drop(cx1);
+    out2.drop();
+    cx2.execute();
+    assert_close(
+        &out2.data(),
+        &data.iter().map(|d| d.exp()).collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_cosine_sim() {
+    let mut a_data = random_vec(24);
+    // A zero vector has no direction, so its similarities are 0
+    a_data[8..16].fill(0.);
+    let b_data = random_vec(32);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let a = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&a_data).unwrap(),
+    ));
+    let b = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&b_data).unwrap(),
+    ));
+    let out = crate::CudaCosineSim::<f32>::new(1e-8, dev)
+        .unwrap()
+        .process(vec![
+            (
+                luminal::op::InputTensor::Borrowed(&a),
+                R2::<3, 8>::to_tracker(),
+            ),
+            (
+                luminal::op::InputTensor::Borrowed(&b),
+                R2::<4, 8>::to_tracker(),
+            ),
+        ]);
+
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt().max(1e-8);
+    let expected = a_data
+        .chunks(8)
+        .cartesian_product(b_data.chunks(8))
+        .map(|(x, y)| x.iter().zip(y).map(|(x, y)| x * y).sum::<f32>() / (norm(x) * norm(y)))
+        .collect_vec();
+    assert!(expected[4..8].iter().all(|s| *s == 0.));
+    super::assert_cuda_close(
+        out[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap(),
+        &expected,
+        1e-6,
+        1e-4,
+    );
+}
+
+#[test]
+fn test_varlen_kv_gather() {
+    const D: usize = 4;
+    let lengths = [3, 5];
+    let keys = random_vec(8 * D);
+    let values = random_vec(8 * D);
+    let queries = random_vec(2 * D);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let upload = |data: &[f32]| {
+        luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(data).unwrap(),
+        ))
+    };
+    let to_vec = |t: &luminal::prelude::Tensor| {
+        t.data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap()
+            .to_vec()
+    };
+    // Gather a packed cache for the given sequence lengths, then attend each query over its sequence on the host
+    let attend = |cache_range: std::ops::Range<usize>, lengths: &[usize], queries: &[f32]| {
+        let max_len = *lengths.iter().max().unwrap();
+        let mut op = crate::CudaVarlenKVGather::<f32>::new(max_len, dev.clone()).unwrap();
+        let lens = upload(&lengths.iter().map(|l| *l as f32).collect_vec());
+        let mut gather = |data: &[f32]| {
+            let cache = upload(&data[cache_range.start * D..cache_range.end * D]);
+            let out = op.process(vec![
+                (
+                    luminal::op::InputTensor::Borrowed(&cache),
+                    ShapeTracker::new(&[cache_range.len().into(), D.into()]),
+                ),
+                (
+                    luminal::op::InputTensor::Borrowed(&lens),
+                    ShapeTracker::new(&[lengths.len().into()]),
+                ),
+            ]);
+            (to_vec(&out[0]), to_vec(&out[1]))
+        };
+        let ((k, mask), (v, _)) = (gather(&keys), gather(&values));
+        let mut out = vec![];
+        for (b, q) in queries.chunks(D).enumerate() {
+            let scores = (0..max_len)
+                .map(|s| {
+                    let key = &k[(b * max_len + s) * D..][..D];
+                    q.iter().zip(key).map(|(q, k)| q * k).sum::<f32>() + mask[b * max_len + s]
+                })
+                .collect_vec();
+            let max = scores.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b));
+            let sum = scores.iter().map(|s| (s - max).exp()).sum::<f32>();
+            out.push(
+                (0..D)
+                    .map(|i| {
+                        (0..max_len)
+                            .map(|s| (scores[s] - max).exp() / sum * v[(b * max_len + s) * D + i])
+                            .sum::<f32>()
+                    })
+                    .collect_vec(),
+            );
+        }
+        out
+    };
+
+    let batched = attend(0..8, &lengths[..], &queries);
+    let first = attend(0..3, &lengths[..1], &queries[..D]);
+    let second = attend(3..8, &lengths[1..], &queries[D..]);
+    assert_close(&batched[0], &first[0]);
+    assert_close(&batched[1], &second[0]);
+}
+
+#[test]
+fn test_mean_var() {
+    let data = random_vec(32)
+        .into_iter()
+        .map(|x| x * 4. + 1.)
+        .collect_vec();
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&data).unwrap(),
+    ));
+    let means = data
+        .chunks(16)
+        .map(|row| row.iter().sum::<f32>() / 16.)
+        .collect_vec();
+    let sq_dev = |i: usize| {
+        data[i * 16..][..16]
+            .iter()
+            .map(|x| (x - means[i]).powi(2))
+            .sum::<f32>()
+    };
+    let dyn_map = rustc_hash::FxHashMap::default();
+    for unbiased in [false, true] {
+        let mut op = crate::CudaMeanVar::<f32>::new(
+            1,
+            unbiased,
+            R2::<2, 16>::to_tracker(),
+            dev.clone(),
+            &dyn_map,
+        )
+        .unwrap();
+        let out = op.process(vec![(
+            luminal::op::InputTensor::Borrowed(&inp),
+            R2::<2, 16>::to_tracker(),
+        )]);
+        let get = |t: &luminal::prelude::Tensor| {
+            t.data
+                .as_any()
+                .downcast_ref::<crate::CudaData<f32>>()
+                .unwrap()
+                .to_vec()
+        };
+        let n = if unbiased { 15. } else { 16. };
+        assert_close(&get(&out[0]), &means);
+        assert_close(&get(&out[1]), &[sq_dev(0) / n, sq_dev(1) / n]);
+    }
+}
+
+#[test]
+fn test_affine() {
+    let mut cx = Graph::new();
+    let x = cx.tensor::<R3<2, 4, 3>>().set(random_vec(24));
+    let gamma = cx.tensor::<R1<4>>().set(random_vec(4));
+    let beta = cx.tensor::<R1<4>>().set(random_vec(4));
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let out = cx
+        .add_op(crate::CudaAffine::<f32>::new(1, x.shape, dev, &cx.dyn_map).unwrap())
+        .input(x.id, 0, x.shape)
+        .input(gamma.id, 0, gamma.shape)
+        .input(beta.id, 0, beta.shape)
+        .finish();
+    let mut out = GraphTensor::<R3<2, 4, 3>>::from_id(out, x.shape, x.graph_ref).retrieve();
+    let mut reference = (x * gamma.expand::<R3<2, 4, 3>, LAxes2<0, 2>>()
+        + beta.expand::<R3<2, 4, 3>, LAxes2<0, 2>>())
+    .retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), (&mut out, &mut reference));
+    cx.execute();
+
+    assert_close(&out.data(), &reference.data());
+}
+
+#[test]
+fn test_repetition_penalty() {
+    let logits = random_vec(16);
+    let history = [2., 5., 5., 9.];
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let logits_tensor = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&logits).unwrap(),
+    ));
+    let history_tensor = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&history).unwrap(),
+    ));
+    let out = crate::CudaApplyRepetitionPenalty::<f32>::new(1.3, dev)
+        .unwrap()
+        .process(vec![
+            (
+                luminal::op::InputTensor::Borrowed(&logits_tensor),
+                R2::<1, 16>::to_tracker(),
+            ),
+            (
+                luminal::op::InputTensor::Borrowed(&history_tensor),
+                R1::<4>::to_tracker(),
+            ),
+        ]);
+
+    let expected = logits
+        .iter()
+        .enumerate()
+        .map(|(i, l)| match i {
+            2 | 5 | 9 if *l > 0. => l / 1.3,
+            2 | 5 | 9 => l * 1.3,
+            _ => *l,
+        })
+        .collect_vec();
+    let out = out[0]
+        .data
+        .as_any()
+        .downcast_ref::<crate::CudaData<f32>>()
+        .unwrap()
+        .to_vec();
+    assert_close(&out, &expected);
+    // Logits outside the history are copied as is
+    for i in (0..16).filter(|i| ![2, 5, 9].contains(i)) {
+        assert_eq!(out[i], logits[i]);
+    }
+}
+
+#[test]
+fn test_kl_div() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let logits = (random_vec_rng(40, &mut rng), random_vec_rng(40, &mut rng));
+    let softmax = |x: &[f32]| {
+        x.chunks(10)
+            .flat_map(|row| {
+                let sum = row.iter().map(|x| x.exp()).sum::<f32>();
+                row.iter().map(move |x| x.exp() / sum)
+            })
+            .collect_vec()
+    };
+    let (mut p, q) = (softmax(&logits.0), softmax(&logits.1));
+    // Give one row of p a zero, which contributes nothing
+    let moved = p[13];
+    p[13] = 0.;
+    p[14] += moved;
+    let kl = |p: &[f32], q: &[f32]| {
+        p.chunks(10)
+            .zip(q.chunks(10))
+            .map(|(p, q)| {
+                p.iter()
+                    .zip(q)
+                    .filter(|(p, _)| **p > 0.)
+                    .map(|(p, q)| p * (p / q).ln())
+                    .sum::<f32>()
+            })
+            .collect_vec()
+    };
+
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let run = |mut op: crate::CudaKLDiv<f32>, p: &[f32], q: &[f32]| {
+        let p = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(p).unwrap(),
+        ));
+        let q = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(q).unwrap(),
+        ));
+        let out = op.process(vec![
+            (
+                luminal::op::InputTensor::Borrowed(&p),
+                R2::<4, 10>::to_tracker(),
+            ),
+            (
+                luminal::op::InputTensor::Borrowed(&q),
+                R2::<4, 10>::to_tracker(),
+            ),
+        ]);
+        out[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap()
+            .to_vec()
+    };
+    assert_close(
+        &run(crate::CudaKLDiv::new(dev.clone()).unwrap(), &p, &q),
+        &kl(&p, &q),
+    );
+    assert_close(
+        &run(
+            crate::CudaKLDiv::from_logits(dev.clone()).unwrap(),
+            &logits.0,
+            &logits.1,
+        ),
+        &kl(&softmax(&logits.0), &q),
+    );
+}
+
+#[test]
+fn test_softmax_dim() {
+    let data = random_vec(15);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&data).unwrap(),
+    ));
+    let mut expected = vec![0.; 15];
+    for col in 0..3 {
+        let sum = (0..5).map(|r| data[r * 3 + col].exp()).sum::<f32>();
+        for r in 0..5 {
+            expected[r * 3 + col] = data[r * 3 + col].exp() / sum;
+        }
+    }
+
+    let mut op = crate::CudaSoftmax::<f32>::new(0, dev).unwrap();
+    let run = |op: &mut crate::CudaSoftmax<f32>| {
+        op.process(vec![(
+            luminal::op::InputTensor::Borrowed(&inp),
+            R2::<5, 3>::to_tracker(),
+        )])[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap()
+            .to_vec()
+    };
+    let strided = run(&mut op);
+    op.transpose_min_dim = 0;
+    let transposed = run(&mut op);
+    assert_close(&strided, &expected);
+    assert_close(&transposed, &strided);
+}
+
+#[test]
+fn test_cuda_arch() {
+    use luminal_cudarc::driver::sys::CUdevice_attribute;
+    let dev = crate::cuda_device(0);
+    let major = dev
+        .attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR)
+        .unwrap();
+    let minor = dev
+        .attribute(CUdevice_attribute::CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR)
+        .unwrap();
+    assert_eq!(crate::cuda_arch(&dev), format!("sm_{major}{minor}"));
+
+    crate::set_cuda_arch(0, Some("sm_75"));
+    assert_eq!(crate::cuda_arch(&dev), "sm_75");
+    crate::set_cuda_arch(0, None);
+    assert_eq!(crate::cuda_arch(&dev), format!("sm_{major}{minor}"));
+}
+
+#[test]
+fn test_ptx_cache() {
+    let dir = std::env::temp_dir().join(format!("luminal_ptx_cache_test_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    crate::set_ptx_cache_dir(Some(dir.clone()));
+    let code =
+        "extern \"C\" __global__ void kernel(float *out) { out[threadIdx.x] = 2.5; }".to_string();
+    let compiles = || crate::NVRTC_COMPILES.with(|c| c.get());
+    // Each device handle loads its own modules, so only the disk cache can skip compiling
+    let load = || {
+        let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+        crate::compile_and_load_kernel(code.clone(), &dev).unwrap();
+    };
+
+    let start = compiles();
+    load();
+    assert_eq!(compiles(), start + 1);
+    // Other tests compiling at the same time share the cache directory
+    let name = format!("kernel_{}", crate::hash(&code));
+    let entries = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.file_name().unwrap().to_string_lossy().starts_with(&name))
+        .collect_vec();
+    assert_eq!(entries.len(), 1);
+    load();
+    assert_eq!(compiles(), start + 1);
+
+    // A corrupt entry gets recompiled and rewritten
+    std::fs::write(&entries[0], "not ptx").unwrap();
+    load();
+    assert_eq!(compiles(), start + 2);
+    assert_ne!(std::fs::read_to_string(&entries[0]).unwrap(), "not ptx");
+    load();
+    assert_eq!(compiles(), start + 2);
+
+    crate::set_ptx_cache_dir(None);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_shared_device() {
+    let devices = (0..2)
+        .map(|_| {
+            let mut cx = Graph::new();
+            let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+            let mut b = a.exp().retrieve();
+            cx.compile(CudaCompiler::<f32>::default(), &mut b);
+            cx.node_indices()
+                .filter_map(|n| {
+                    cx.node_weight(n)
+                        .unwrap()
+                        .as_any()
+                        .downcast_ref::<crate::prim::CudaCopyToDevice<f32>>()
+                        .map(|op| op.device().clone())
+                })
+                .next()
+                .unwrap()
+        })
+        .collect_vec();
+    assert!(std::sync::Arc::ptr_eq(&devices[0], &devices[1]));
+    assert!(std::sync::Arc::ptr_eq(&devices[0], &crate::cuda_device(0)));
+}
+
+#[test]
+fn test_compiler_on_device() {
+    if luminal_cudarc::driver::CudaDevice::count().unwrap() < 2 {
+        // Needs a second GPU
+        return;
+    }
+    let data = random_vec(6);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(data.clone());
+    let mut b = a.exp().retrieve();
+    cx.compile(crate::cuda_compiler_on_device::<f32>(1), &mut b);
+    let device = crate::graph_device::<f32>(&cx);
+    assert_eq!(device.ordinal(), 1);
+    cx.execute();
+
+    assert_close(&b.data(), &data.iter().map(|d| d.exp()).collect_vec());
+}
+
+#[test]
+fn test_kernel_compile_error() {
+    let dev = crate::cuda_device(0);
+    let code = "extern \"C\" __global__ void kernel(float *out) { out[0] = undefined_value; }";
+    let err = crate::compile_and_load_kernel(code.to_string(), &dev).unwrap_err();
+    assert!(err.log.contains("undefined_value"));
+    assert!(err.source.contains("out[0] = undefined_value;"));
+    assert!(err.to_string().contains(&err.source));
+
+    // Ops building their kernel return the error rather than panicking
+    let mut op = crate::CudaFusedElementwise::<f32>::new(
+        "undefined_value".to_string(),
+        dev,
+        std::ptr::null(),
+    );
+    let err = op.compile(&[]).unwrap_err();
+    assert!(err.source.contains("undefined_value"));
+}
+
+#[test]
+fn test_cuda_include_path() {
+    let dir = crate::cuda_include_path().unwrap();
+    assert!(dir.join("cuda_fp16.h").is_file());
+
+    // An override without the headers is skipped in favour of the search
+    crate::set_cuda_include_path(Some(std::env::temp_dir()));
+    assert_eq!(crate::cuda_include_path().unwrap(), dir);
+    crate::set_cuda_include_path(None);
+}
+
+#[test]
+fn test_parallel_sum_reduce() {
+    let data = random_vec(4 * 8192);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&data).unwrap(),
+    ));
+    let dyn_map = rustc_hash::FxHashMap::default();
+    let mut op = crate::CudaSumReduce::<f32>::with_init(
+        1,
+        R2::<4, 8192>::to_tracker(),
+        dev.clone(),
+        &dyn_map,
+        crate::ReduceInit::Constant(1.5),
+    )
+    .unwrap();
+    let mut run = |parallel_min_dim: usize| {
+        op.parallel_min_dim = parallel_min_dim;
+        let now = std::time::Instant::now();
+        let mut out = vec![];
+        for _ in 0..10 {
+            out = op.process(vec![(
+                luminal::op::InputTensor::Borrowed(&inp),
+                R2::<4, 8192>::to_tracker(),
+            )]);
+        }
+        dev.synchronize().unwrap();
+        let elapsed = now.elapsed();
+        let out = out[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap()
+            .to_vec();
+        (out, elapsed)
+    };
+    let (serial, serial_time) = run(usize::MAX);
+    let (parallel, parallel_time) = run(1024);
+    println!("Serial: {serial_time:?} Parallel: {parallel_time:?}");
+
+    let expected = data
+        .chunks(8192)
+        .map(|row| row.iter().sum::<f32>() + 1.5)
+        .collect_vec();
+    assert_close(&parallel, &expected);
+    assert_close(&serial, &expected);
+}
+
+#[test]
+fn test_parallel_max_reduce() {
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let dyn_map = rustc_hash::FxHashMap::default();
+    let mut padded = ShapeTracker::new(&[2.into(), 1500.into()]);
+    padded.pad(&[(0.into(), 0.into()), (0.into(), 37.into())]);
+    for shape in [
+        ShapeTracker::new(&[3.into(), 1000.into()]),
+        ShapeTracker::new(&[2.into(), 1537.into(), 3.into()]),
+        ShapeTracker::new(&[5.into(), 4099.into()]),
+        padded,
+    ] {
+        // Negative values, so padding or a zero init would show up in the max
+        let data = random_vec(shape.n_physical_elements().to_usize().unwrap())
+            .into_iter()
+            .map(|x| x - 1.)
+            .collect_vec();
+        let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(&data).unwrap(),
+        ));
+        let mut op = crate::CudaMaxReduce::<f32>::new(1, shape, dev.clone(), &dyn_map).unwrap();
+        let mut run = |parallel_min_dim: usize| {
+            op.parallel_min_dim = parallel_min_dim;
+            op.process(vec![(luminal::op::InputTensor::Borrowed(&inp), shape)])[0]
+                .data
+                .as_any()
+                .downcast_ref::<crate::CudaData<f32>>()
+                .unwrap()
+                .to_vec()
+        };
+        let serial = run(usize::MAX);
+        let parallel = run(1);
+        assert_exact(&parallel, &serial);
+        assert!(parallel.iter().all(|x| *x < 0.));
+    }
+}
+
+#[test]
+fn test_cublas_matmul_matches_primitives() {
+    let run = |use_cublas: bool| {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let a = cx.tensor::<R2<3, 5>>().set(random_vec_rng(3 * 5, &mut rng));
+        let b = cx.tensor::<R2<5, 7>>().set(random_vec_rng(5 * 7, &mut rng));
+        let b_t = cx.tensor::<R2<7, 5>>().set(random_vec_rng(7 * 5, &mut rng));
+        let c = cx
+            .tensor::<R3<2, 3, 5>>()
+            .set(random_vec_rng(2 * 3 * 5, &mut rng));
+        let mut outs = (
+            a.matmul(b).retrieve(),
+            a.matmul(b_t.permute()).retrieve(),
+            c.matmul(b).retrieve(),
+            c.matmul(b_t.permute()).retrieve(),
+        );
+        if use_cublas {
+            cx.compile(
+                <(GenericCompiler, CudaCompiler<f32>)>::default(),
+                (&mut outs.0, &mut outs.1, &mut outs.2, &mut outs.3),
+            );
+        } else {
+            cx.compile(
+                <(
+                    GenericCompiler,
+                    crate::prim::CudaPrimitiveCompiler<f32>,
+                    crate::prim::CopyCompiler<f32>,
+                )>::default(),
+                (&mut outs.0, &mut outs.1, &mut outs.2, &mut outs.3),
+            );
+        }
+        cx.execute();
+        (outs.0.data(), outs.1.data(), outs.2.data(), outs.3.data())
+    };
+    let (cublas, primitives) = (run(true), run(false));
+    assert_close(&cublas.0, &primitives.0);
+    assert_close(&cublas.1, &primitives.1);
+    assert_close(&cublas.2, &primitives.2);
+    assert_close(&cublas.3, &primitives.3);
+}
+
+#[test]
+fn test_tiled_matmul() {
+    let cpu_matmul = |a: &[f32], b: &[f32], (k, n): (usize, usize), row: usize| {
+        (0..n)
+            .map(|j| (0..k).map(|i| a[row * k + i] * b[i * n + j]).sum::<f32>())
+            .collect_vec()
+    };
+
+    // Dimensions that aren't multiples of the tile, with a transposed right hand side
+    let mut rng = StdRng::seed_from_u64(0);
+    let a_data = random_vec_rng(37 * 53, &mut rng);
+    let b_t_data = random_vec_rng(29 * 53, &mut rng);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<37, 53>>().set(a_data.clone());
+    let b_t = cx.tensor::<R2<29, 53>>().set(b_t_data.clone());
+    let dev = crate::cuda_device(0);
+    let out = cx
+        .add_op(crate::CudaTiledMatmul2D::<f32>::new(16, dev.clone()).unwrap())
+        .input(a.id, 0, a.shape)
+        .input(b_t.id, 0, b_t.permute::<R2<53, 29>, LAxes2<1, 0>>().shape)
+        .finish();
+    let mut out =
+        GraphTensor::<R2<37, 29>>::from_id(out, R2::<37, 29>::to_tracker(), a.graph_ref).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    cx.execute();
+    let b_data = (0..53)
+        .flat_map(|k| (0..29).map(move |n| (k, n)))
+        .map(|(k, n)| b_t_data[n * 53 + k])
+        .collect_vec();
+    let expected = (0..37)
+        .flat_map(|row| cpu_matmul(&a_data, &b_data, (53, 29), row))
+        .collect_vec();
+    assert_close(&out.data(), &expected);
+
+    // Compare against cuBLAS on a large square matmul
+    const N: usize = 1024;
+    let mut rng = StdRng::seed_from_u64(1);
+    let a_data = random_vec_rng(N * N, &mut rng);
+    let b_data = random_vec_rng(N * N, &mut rng);
+    let run = |tiled: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<N, N>>().set(a_data.clone());
+        let b = cx.tensor::<R2<N, N>>().set(b_data.clone());
+        let mut out = if tiled {
+            let out = cx
+                .add_op(crate::CudaTiledMatmul2D::<f32>::new(16, dev.clone()).unwrap())
+                .input(a.id, 0, a.shape)
+                .input(b.id, 0, b.shape)
+                .finish();
+            GraphTensor::<R2<N, N>>::from_id(out, R2::<N, N>::to_tracker(), a.graph_ref)
+        } else {
+            a.matmul(b)
+        }
+        .retrieve();
+        cx.compile(<(GenericCompiler, CudaCompiler<f32>)>::default(), &mut out);
+        cx.execute();
+        let start = std::time::Instant::now();
+        cx.execute();
+        (out.data(), start.elapsed())
+    };
+    let (tiled, tiled_time) = run(true);
+    let (cublas, cublas_time) = run(false);
+    println!("Tiled: {tiled_time:?} cuBLAS: {cublas_time:?}");
+    for row in (0..N).step_by(97) {
+        assert_close(
+            &tiled[row * N..][..N],
+            &cpu_matmul(&a_data, &b_data, (N, N), row),
+        );
+    }
+    assert_close(&tiled, &cublas);
+}
+
+#[test]
+fn test_fused_mean_reduce() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    let d_dev = Cpu::default();
+
+    // Static dimensions, including one long enough for the block per output kernel
+    let data = random_vec(2 * 5 * 1100);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<2, 5, 1100>>().set(data.clone());
+    let mut b = a.mean_reduce::<_, LAxis<2>>().retrieve();
+    let mut c = a.mean_reduce::<_, LAxis<1>>().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaMeanReduce<f32>>()), 2);
+    assert_eq!(count_ops(&cx, |o| o.is::<crate::prim::CudaRecip<f32>>()), 0);
+    cx.execute();
+    let d_a = d_dev.tensor_from_vec(data, (DConst::<2>, DConst::<5>, DConst::<1100>));
+    assert_close(&b.data(), &d_a.clone().mean::<_, DAxis<2>>().as_vec());
+    assert_close(&c.data(), &d_a.mean::<_, DAxis<1>>().as_vec());
+
+    // A dynamic reduced dimension takes its size at run time
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'a'>, LConst<7>)>();
+    let mut b = a.mean_reduce::<_, LAxis<0>>().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaMeanReduce<f32>>()), 1);
+    for size in [1, 3, 40, 1500] {
+        let data = random_vec(size * 7);
+        a.set_dyn(data.clone(), &[size, 7]);
+        cx.execute();
+        let d_a = d_dev.tensor_from_vec(data, (size, DConst::<7>));
+        assert_close(&b.data(), &d_a.mean::<_, DAxis<0>>().as_vec());
+        b.drop();
+    }
+
+    // A permuted read of the sum isn't fused, since the mean would be written in the sum's layout
+    let data = random_vec(2 * 5 * 7);
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 5, 7>>().set(data.clone());
+        let sum = a.sum_reduce::<_, LAxis<2>>().permute::<R2<5, 2>, _>();
+        let mut b = (sum * cx.constant_expr(7).recip().expand()).retrieve();
+        if cuda {
+            cx.compile(CudaCompiler::<f32>::default(), &mut b);
+            assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaMeanReduce<f32>>()), 0);
+        }
+        cx.execute();
+        b.data()
+    };
+    assert_close(&run(true), &run(false));
+}
+
+#[test]
+fn test_min_reduce() {
+    let dev = crate::cuda_device(0);
+    let dyn_map = rustc_hash::FxHashMap::default();
+    let mut padded = ShapeTracker::new(&[2.into(), 1500.into()]);
+    padded.pad(&[(0.into(), 0.into()), (0.into(), 37.into())]);
+    // Shapes with the physical (front, dim, back) sizes around the reduced dimension
+    for (shape, (front, dim, back)) in [
+        (ShapeTracker::new(&[3.into(), 1000.into()]), (3, 1000, 1)),
+        (
+            ShapeTracker::new(&[2.into(), 1537.into(), 3.into()]),
+            (2, 1537, 3),
+        ),
+        (ShapeTracker::new(&[5.into(), 4099.into()]), (5, 4099, 1)),
+        (ShapeTracker::new(&[7.into(), 13.into()]), (7, 13, 1)),
+        (padded, (2, 1500, 1)),
+    ] {
+        // Positive values, so padding or a zero init would show up in the min
+        let data = random_vec(front * dim * back)
+            .into_iter()
+            .map(|x| x + 1.)
+            .collect_vec();
+        let expected = (0..front * back)
+            .map(|i| {
+                (0..dim)
+                    .map(|d| data[(i / back * dim + d) * back + i % back])
+                    .fold(f32::INFINITY, f32::min)
+            })
+            .collect_vec();
+        let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(&data).unwrap(),
+        ));
+        let mut op = crate::CudaMinReduce::<f32>::new(1, shape, dev.clone(), &dyn_map).unwrap();
+        for parallel_min_dim in [usize::MAX, 1] {
+            op.parallel_min_dim = parallel_min_dim;
+            let out = op.process(vec![(luminal::op::InputTensor::Borrowed(&inp), shape)]);
+            assert_exact(
+                &out[0]
+                    .data
+                    .as_any()
+                    .downcast_ref::<crate::CudaData<f32>>()
+                    .unwrap()
+                    .to_vec(),
+                &expected,
+            );
+        }
+    }
+
+    // Negating around a max reduce compiles to a min reduce
+    let data = random_vec(4 * 37);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 37>>().set(data.clone());
+    let mut b = (-(-a).max_reduce::<_, LAxis<1>>()).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    let ops = cx
+        .node_indices()
+        .map(|n| format!("{:?}", cx.node_weight(n).unwrap()))
+        .collect_vec();
+    assert!(ops.iter().any(|o| o.starts_with("CudaMinReduce")));
+    assert!(!ops.iter().any(|o| o.starts_with("CudaMaxReduce")));
+    cx.execute();
+    let expected = data
+        .chunks(37)
+        .map(|row| row.iter().copied().fold(f32::INFINITY, f32::min))
+        .collect_vec();
+    assert_exact(&b.data(), &expected);
+
+    // A permuted read of the max isn't fused, since the min would be written in the max's layout
+    let data = random_vec(2 * 3 * 4);
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+        let max = (-a).max_reduce::<_, LAxis<2>>().permute::<R2<3, 2>, _>();
+        let mut b = (-max).retrieve();
+        if cuda {
+            cx.compile(CudaCompiler::<f32>::default(), &mut b);
+            assert!(!cx
+                .node_indices()
+                .any(|n| format!("{:?}", cx.node_weight(n).unwrap()).starts_with("CudaMinReduce")));
+        }
+        cx.execute();
+        b.data()
+    };
+    assert_exact(&run(true), &run(false));
+}
+
+#[test]
+fn test_prod_reduce() {
+    let dev = crate::cuda_device(0);
+    let dyn_map = rustc_hash::FxHashMap::default();
+    let mut padded = ShapeTracker::new(&[2.into(), 40.into()]);
+    padded.pad(&[(0.into(), 0.into()), (3.into(), 5.into())]);
+    // Shapes with the physical (front, dim, back) sizes around the reduced dimension
+    for (shape, (front, dim, back)) in [
+        (ShapeTracker::new(&[3.into(), 17.into()]), (3, 17, 1)),
+        (
+            ShapeTracker::new(&[2.into(), 1537.into(), 3.into()]),
+            (2, 1537, 3),
+        ),
+        (padded, (2, 40, 1)),
+    ] {
+        // Values near 1 keep long products finite
+        let mut data = random_vec(front * dim * back)
+            .into_iter()
+            .map(|x| 1. + x * 0.01)
+            .collect_vec();
+        // A zero in the first output's reduction
+        data[back] = 0.;
+        let expected = (0..front * back)
+            .map(|i| {
+                (0..dim)
+                    .map(|d| data[(i / back * dim + d) * back + i % back])
+                    .product::<f32>()
+            })
+            .collect_vec();
+        let inp = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+            dev.htod_sync_copy(&data).unwrap(),
+        ));
+        let mut op = crate::CudaProdReduce::<f32>::new(1, shape, dev.clone(), &dyn_map).unwrap();
+        for parallel_min_dim in [usize::MAX, 1] {
+            op.parallel_min_dim = parallel_min_dim;
+            let out = op.process(vec![(luminal::op::InputTensor::Borrowed(&inp), shape)]);
+            let out = out[0]
+                .data
+                .as_any()
+                .downcast_ref::<crate::CudaData<f32>>()
+                .unwrap()
+                .to_vec();
+            assert_eq!(out[0], 0.);
+            assert_close(&out, &expected);
+        }
+    }
+}
+
+#[test]
+fn test_elementwise_block_size_sweep() {
+    const N: usize = 1 << 24;
+    let a_data = random_vec(N);
+    let b_data = random_vec(N);
+    let dev = crate::cuda_device(0);
+    let a = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&a_data).unwrap(),
+    ));
+    let b = luminal::prelude::Tensor::new(crate::CudaData::from_slice(
+        dev.htod_sync_copy(&b_data).unwrap(),
+    ));
+    let dyn_map = rustc_hash::FxHashMap::default();
+    let shape = R1::<N>::to_tracker();
+    let mut op = crate::CudaAdd::<f32>::new(shape, shape, dev.clone(), &dyn_map).unwrap();
+    let expected = a_data.iter().zip(&b_data).map(|(a, b)| a + b).collect_vec();
+    for block_size in [64, 128, 256, 512, 1024] {
+        op.block_size = block_size;
+        let mut run = || {
+            op.process(vec![
+                (luminal::op::InputTensor::Borrowed(&a), shape),
+                (luminal::op::InputTensor::Borrowed(&b), shape),
+            ])
+        };
+        // Warmup
+        run();
+        dev.synchronize().unwrap();
+        let now = std::time::Instant::now();
+        let mut out = vec![];
+        for _ in 0..10 {
+            out = run();
+        }
+        dev.synchronize().unwrap();
+        println!("Block size {block_size}: {:?}", now.elapsed() / 10);
+        let out = out[0]
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap()
+            .to_vec();
+        assert_exact(&out, &expected);
+    }
+}
+
+#[test]
+fn test_elementwise_block_size_compiler() {
+    let a_data = random_vec(1000);
+    let b_data = random_vec(1000);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<1000>>().set(a_data.clone());
+    let b = cx.tensor::<R1<1000>>().set(b_data.clone());
+    let mut c = ((a + b).sin() * a).retrieve();
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::ElementwiseBlockSizeCompiler::new(96),
+        ),
+        &mut c,
+    );
+    cx.execute();
+
+    assert_close(
+        &c.data(),
+        &a_data
+            .iter()
+            .zip(&b_data)
+            .map(|(a, b)| (a + b).sin() * a)
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_fused_div() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    let run = |fuse: bool| {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let a = cx.tensor::<R2<6, 5>>().set(random_vec_rng(6 * 5, &mut rng));
+        let b = cx.tensor::<R2<6, 5>>().set(
+            random_vec_rng(6 * 5, &mut rng)
+                .into_iter()
+                .map(|x| x + 1.)
+                .collect_vec(),
+        );
+        let c = cx.tensor::<R1<5>>().set(vec![1., 2., 3., 4., 5.]);
+        // Plain, permuted and broadcasted divisions fuse
+        let mut plain = (a / b).retrieve();
+        let mut permuted = (a.permute::<_, LAxes2<1, 0>>() / b.permute()).retrieve();
+        let mut broadcasted = (a / c.expand::<R2<6, 5>, _>()).retrieve();
+        // A reciprocal with another consumer is left alone, though the mul and add around it still fuse
+        let b_recip = b.recip();
+        let mut shared = (a * b_recip + b_recip).retrieve();
+        let outs = (&mut plain, &mut permuted, &mut broadcasted, &mut shared);
+        if fuse {
+            cx.compile(<(GenericCompiler, CudaCompiler<f32>)>::default(), outs);
+            assert_eq!(
+                count_ops(&cx, |o| o.is::<crate::CudaFusedElementwise<f32>>()),
+                4
+            );
+            assert_eq!(count_ops(&cx, |o| o.is::<crate::prim::CudaRecip<f32>>()), 1);
+        } else {
+            cx.compile(
+                <(
+                    GenericCompiler,
+                    crate::prim::CudaPrimitiveCompiler<f32>,
+                    crate::prim::CopyCompiler<f32>,
+                )>::default(),
+                outs,
+            );
+        }
+        cx.execute();
+        (
+            plain.data(),
+            permuted.data(),
+            broadcasted.data(),
+            shared.data(),
+        )
+    };
+    let (fused, unfused) = (run(true), run(false));
+    assert_close(&fused.0, &unfused.0);
+    assert_close(&fused.1, &unfused.1);
+    assert_close(&fused.2, &unfused.2);
+    assert_close(&fused.3, &unfused.3);
+}
+
+#[test]
+fn test_fused_elementwise_chain() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    let run = |fuse: bool| {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let a = cx.tensor::<R2<4, 9>>().set(random_vec_rng(4 * 9, &mut rng));
+        let b = cx.tensor::<R2<4, 9>>().set(random_vec_rng(4 * 9, &mut rng));
+        let c = cx.tensor::<R1<9>>().set(random_vec_rng(9, &mut rng));
+        // exp2 -> mul -> add -> sin all fold into one kernel
+        let mut chain = ((a.exp2() * b + c.expand()).sin()).retrieve();
+        // Retrieving an intermediate splits the chain around it
+        let mut intermediate = (a.exp2() * b).retrieve();
+        let mut split = (intermediate * c.expand()).sin().retrieve();
+        let outs = (&mut chain, &mut intermediate, &mut split);
+        if fuse {
+            cx.compile(<(GenericCompiler, CudaCompiler<f32>)>::default(), outs);
+        } else {
+            cx.compile(
+                <(
+                    GenericCompiler,
+                    crate::prim::CudaPrimitiveCompiler<f32>,
+                    crate::prim::CopyCompiler<f32>,
+                )>::default(),
+                outs,
+            );
+        }
+        let kernels = (
+            count_ops(&cx, |o| o.is::<crate::CudaFusedElementwise<f32>>()),
+            count_ops(&cx, |o| {
+                o.is::<crate::prim::CudaExp2<f32>>()
+                    || o.is::<crate::prim::CudaMul<f32>>()
+                    || o.is::<crate::prim::CudaAdd<f32>>()
+                    || o.is::<crate::prim::CudaSin<f32>>()
+            }),
+        );
+        cx.execute();
+        (chain.data(), intermediate.data(), split.data(), kernels)
+    };
+    let (fused, unfused) = (run(true), run(false));
+    assert_eq!(fused.3, (3, 0));
+    assert_close(&fused.0, &unfused.0);
+    assert_close(&fused.1, &unfused.1);
+    assert_close(&fused.2, &unfused.2);
+}
+
+#[test]
+fn test_tanh() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    // Large magnitudes overflow the exp in the sigmoid decomposition
+    let data = (0..64).map(|i| (i as f32 - 32.) * 4.).collect_vec();
+    let expected = data.iter().map(|x| x.tanh()).collect_vec();
+
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<8, 8>>().set(data.clone());
+    let mut b = a.tanh().retrieve();
+    let mut c = a.permute::<_, LAxes2<1, 0>>().tanh().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c));
+    assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaTanh<f32>>()), 2);
+    cx.execute();
+    assert_close(&b.data(), &expected);
+    let transposed = (0..64).map(|i| expected[i % 8 * 8 + i / 8]).collect_vec();
+    assert_close(&c.data(), &transposed);
+
+    // A dynamic dimension takes its size at run time
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'a'>, LConst<8>)>();
+    let mut b = a.tanh().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaTanh<f32>>()), 1);
+    for rows in [1, 8] {
+        a.set_dyn(data[..rows * 8].to_vec(), &[rows, 8]);
+        cx.execute();
+        assert_close(&b.data(), &expected[..rows * 8]);
+        b.drop();
+    }
+}
+
+#[test]
+fn test_gelu() {
+    // erf(x) = 2 / sqrt(pi) * integral of exp(-t^2) from 0 to x, by Simpson's rule
+    let erf = |x: f64| {
+        let steps = 1000;
+        let h = x / steps as f64;
+        let f = |t: f64| (-t * t).exp();
+        let sum = (1..steps)
+            .map(|i| f(i as f64 * h) * if i % 2 == 0 { 2. } else { 4. })
+            .sum::<f64>()
+            + f(0.)
+            + f(x);
+        sum * h / 3. * 2. / std::f64::consts::PI.sqrt()
+    };
+    let data = (0..256).map(|i| (i as f32 - 128.) / 16.).collect_vec();
+    let tanh_gelu = data
+        .iter()
+        .map(|&x| {
+            let x = x as f64;
+            (0.5 * x
+                * (1. + ((2. / std::f64::consts::PI).sqrt() * (x + 0.044715 * x * x * x)).tanh()))
+                as f32
+        })
+        .collect_vec();
+    let erf_gelu = data
+        .iter()
+        .map(|&x| (0.5 * x as f64 * (1. + erf(x as f64 / 2f64.sqrt()))) as f32)
+        .collect_vec();
+
+    // Compiles with the gelu kernel using the approximation, or with only the primitive kernels
+    let run = |approximation: Option<crate::GeluApproximation>| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<16, 16>>().set(data.clone());
+        let mut b = a.gelu().retrieve();
+        if let Some(approximation) = approximation {
+            let mut compiler = CudaCompiler::<f32>::default();
+            compiler.1 .2 .1 = approximation;
+            cx.compile((GenericCompiler::default(), compiler), &mut b);
+        } else {
+            cx.compile(
+                <(
+                    GenericCompiler,
+                    crate::prim::CudaPrimitiveCompiler<f32>,
+                    crate::prim::CopyCompiler<f32>,
+                )>::default(),
+                &mut b,
+            );
+        }
+        let kernels = cx
+            .node_indices()
+            .filter(|n| {
+                let op = cx.node_weight(*n).unwrap().as_any();
+                !op.is::<crate::prim::CudaConstant<f32>>()
+                    && !op.is::<crate::prim::CudaCopyToDevice<f32>>()
+                    && !op.is::<crate::prim::CudaCopyFromDevice<f32>>()
+                    && !op.is::<luminal::op::Function>()
+            })
+            .count();
+        cx.execute();
+        let now = std::time::Instant::now();
+        for _ in 0..10 {
+            cx.execute();
+        }
+        (b.data(), kernels, now.elapsed() / 10)
+    };
+
+    let (fused, fused_kernels, fused_time) = run(Some(crate::GeluApproximation::Tanh));
+    assert_close(&fused, &tanh_gelu);
+    let (unfused, unfused_kernels, unfused_time) = run(None);
+    assert_close(&unfused, &tanh_gelu);
+    println!(
+        "Fused: {fused_kernels} kernels in {fused_time:?} Unfused: {unfused_kernels} kernels in {unfused_time:?}"
+    );
+    assert_eq!(fused_kernels, 1);
+    assert!(unfused_kernels > fused_kernels);
+
+    let (exact, _, _) = run(Some(crate::GeluApproximation::Erf));
+    assert_close(&exact, &erf_gelu);
+}
+
+#[test]
+fn test_gather_int_indexes() {
+    // Past 2^24, f32 can't hold every integer, so 2^24 + 1 would round down to the row before it
+    const N: usize = (1 << 24) + 3;
+    let mut cx = Graph::new();
+    let indexes = cx
+        .named_tensor::<R1<3>>("Indexes")
+        .set(vec![(1i32 << 24) + 1, 5, (1 << 24) + 2]);
+    let model: luminal::nn::embedding::Embedding<N, 1> = InitModule::initialize(&mut cx);
+    model
+        .weight
+        .set((0..N).map(|i| (i % 1000) as f32).collect::<Vec<_>>());
+    let mut out = model.forward(indexes).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut out);
+    cx.execute();
+
+    assert_exact(
+        &out.data(),
+        &[((1 << 24) + 1) % 1000, 5, ((1 << 24) + 2) % 1000].map(|i| i as f32),
+    );
+}
+
+#[test]
+fn test_int_float_conversion() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(vec![1.7, -2.5, 16777216., 3.]);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let ints = cx
+        .add_op(crate::CudaFloatToInt::<f32, i32>::new(a.shape, dev.clone(), &cx.dyn_map).unwrap())
+        .input(a.id, 0, a.shape)
+        .finish();
+    let floats = cx
+        .add_op(crate::CudaIntToFloat::<i32, f32>::new(a.shape, dev, &cx.dyn_map).unwrap())
+        .input(ints, 0, a.shape)
+        .finish();
+    let mut b = GraphTensor::<R1<4>>::from_id(floats, a.shape, a.graph_ref).retrieve();
+
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+
+    assert_exact(&b.data(), &[1., -2., 16777216., 3.]);
+}
+
+#[test]
+fn test_buffer_reuse() {
+    use luminal_cudarc::driver::DevicePtr;
+    crate::set_buffer_reuse(true);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<8, 8>>().set(random_vec(64));
+    let mut b = a.sum_reduce::<_, LAxis<1>>().keep();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    let output_ptr = |cx: &Graph| {
+        *cx.get_tensor_ref(b.id, 0)
+            .unwrap()
+            .data
+            .as_any()
+            .downcast_ref::<crate::CudaData<f32>>()
+            .unwrap()
+            .0
+            .device_ptr()
+    };
+
+    cx.execute();
+    let first = output_ptr(&cx);
+    // Freeing the output pools its buffer, which the next execution picks back up
+    cx.drop_tensors(b);
+    cx.execute();
+    let second = output_ptr(&cx);
+    crate::set_buffer_reuse(false);
+
+    assert_eq!(first, second);
+}
+
+#[test]
+fn test_fused_contiguous_chain() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    let data = random_vec(32);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<4, 8>>().set(data.clone());
+    // Padding on both sides of the chain makes sure the valid masks compose
+    let mut b = a
+        .pad::<R2<4, 10>, _, _>(&[(0, 0), (0, 2)])
+        .permute::<_, LAxes2<1, 0>>()
+        .reshape::<R2<5, 8>>()
+        .pad::<R2<5, 9>, _, _>(&[(0, 0), (1, 0)])
+        .permute::<_, LAxes2<1, 0>>()
+        .reshape::<R1<45>>()
+        .retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    assert_eq!(
+        count_ops(&cx, |o| o.is::<crate::prim::CudaContiguous<f32>>()),
+        1
+    );
+    cx.execute();
+
+    let transposed = (0..40)
+        .map(|i| {
+            let (j, k) = (i / 4, i % 4);
+            if j < 8 {
+                data[k * 8 + j]
+            } else {
+                0.
+            }
+        })
+        .collect::<Vec<_>>();
+    let expected = (0..45)
+        .map(|i| {
+            let (c, r) = (i / 5, i % 5);
+            if c >= 1 {
+                transposed[r * 8 + c - 1]
+            } else {
+                0.
+            }
+        })
+        .collect::<Vec<_>>();
+    assert_exact(&b.data(), &expected);
+}
+
+#[test]
+fn test_dedupe_copy_to_device() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    let data = random_vec(16);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<16>>().set(data.clone());
+    // Two copies of the same input already in the graph, each feeding its own consumer
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let copies = [0, 1].map(|_| {
+        let copy = cx
+            .add_op(crate::prim::CudaCopyToDevice::<f32>::new(dev.clone()))
+            .input(a.id, 0, a.shape)
+            .finish();
+        GraphTensor::<R1<16>>::from_id(copy, a.shape, a.graph_ref)
+    });
+    let mut b = copies[0].exp2().retrieve();
+    let mut c = copies[1].sin().retrieve();
+    let mut d = a.exp2().sin().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut b, &mut c, &mut d));
+    assert_eq!(
+        count_ops(&cx, |o| o.is::<crate::prim::CudaCopyToDevice<f32>>()),
+        1
+    );
+    cx.execute();
+
+    assert_close(
+        &b.data(),
+        &data.iter().map(|i| i.exp2()).collect::<Vec<_>>(),
+    );
+    assert_close(&c.data(), &data.iter().map(|i| i.sin()).collect::<Vec<_>>());
+    assert_close(
+        &d.data(),
+        &data.iter().map(|i| i.exp2().sin()).collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_host_round_trip() {
+    let data = random_vec(100);
+    let dev = luminal_cudarc::driver::CudaDevice::new(0).unwrap();
+    let buffer = crate::CudaData::<f32>::from_host(&dev, &data);
+    assert_exact(&buffer.to_host(), &data);
+
+    // Device buffers can be fed straight into a graph
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<100>>().set_dyn(buffer, &[100]);
+    let mut b = a.exp2().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+    assert_close(
+        &b.data(),
+        &data.iter().map(|i| i.exp2()).collect::<Vec<_>>(),
+    );
+}
+
+/// Held by tests that set the device's stream, which is shared by every test using the device
+static DEVICE_STREAM: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[test]
+fn test_cuda_streams() {
+    use luminal_cudarc::driver::{result, DevicePtrMut};
+    let _stream = DEVICE_STREAM.lock().unwrap_or_else(|e| e.into_inner());
+    let data = random_vec(1 << 16);
+    let copied = random_vec(1 << 16);
+    let dev = crate::cuda_device(0);
+    let compute = std::sync::Arc::new(dev.fork_default_stream().unwrap());
+    let copy = dev.fork_default_stream().unwrap();
+    crate::set_cuda_stream(&dev, Some(compute));
+
+    // Run an elementwise op on the compute stream while an upload runs on the copy stream
+    let mut upload = dev.alloc_zeros::<f32>(copied.len()).unwrap();
+    copy.wait_for_default().unwrap();
+    unsafe { result::memcpy_htod_async(*upload.device_ptr_mut(), &copied, copy.stream) }.unwrap();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<{ 1 << 16 }>>().set(data.clone());
+    let mut b = a.exp2().retrieve();
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            crate::PinnedStagingCompiler::<f32>::default(),
+        ),
+        &mut b,
+    );
+    cx.execute();
+    dev.wait_for(&copy).unwrap();
+    dev.synchronize().unwrap();
+    crate::set_cuda_stream(&dev, None);
+
+    assert_close(
+        &b.data(),
+        &data.iter().map(|i| i.exp2()).collect::<Vec<_>>(),
+    );
+    assert_exact(&dev.dtoh_sync_copy(&upload).unwrap(), &copied);
+}
+
+#[test]
+fn test_cuda_stream_overlap() {
+    use luminal_cudarc::driver::{LaunchAsync, LaunchConfig};
+    let _stream = DEVICE_STREAM.lock().unwrap_or_else(|e| e.into_inner());
+    let data = random_vec(1 << 16);
+    let dev = crate::cuda_device(0);
+    let wait = crate::compile_and_load_kernel(
+        "extern \"C\" __global__ void kernel(volatile int *flag, int *seen) {
+    long long start = clock64();
+    while (flag[0] == 0 && clock64() - start < 4000000000LL) {}
+    seen[0] = flag[0];
+}"
+        .to_string(),
+        &dev,
+    )
+    .unwrap();
+    let signal = crate::compile_and_load_kernel(
+        "extern \"C\" __global__ void kernel(int *flag) { flag[0] = 1; }".to_string(),
+        &dev,
+    )
+    .unwrap();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<{ 1 << 16 }>>().set(data.clone());
+    let mut b = a.exp2().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    let (flag, mut seen) = (
+        dev.alloc_zeros::<i32>(1).unwrap(),
+        dev.alloc_zeros::<i32>(1).unwrap(),
+    );
+    let stream = std::sync::Arc::new(dev.fork_default_stream().unwrap());
+    crate::set_cuda_stream(&dev, Some(stream.clone()));
+
+    // Block the default stream until the graph's stream signals it. If running the graph waited on the
+    // default stream, the signal would only come once the wait gave up.
+    let cfg = LaunchConfig {
+        grid_dim: (1, 1, 1),
+        block_dim: (1, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    unsafe { wait.launch(cfg, (&flag, &mut seen)) }.unwrap();
+    cx.execute();
+    let result = b.data();
+    unsafe { signal.launch_on_stream(&stream, cfg, (&flag,)) }.unwrap();
+    crate::set_cuda_stream(&dev, None);
+    dev.synchronize().unwrap();
+
+    assert_eq!(dev.dtoh_sync_copy(&seen).unwrap(), [1]);
+    assert_close(&result, &data.iter().map(|i| i.exp2()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_zero_element_tensors() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'a'>, LConst<4>)>();
+    let b = cx.tensor::<(Dyn<'a'>, LConst<4>)>();
+    let mut added = (a + b).retrieve();
+    let mut summed = a.sum_reduce::<_, LAxis<1>>().retrieve();
+    let mut summed_empty_dim = a.sum_reduce::<_, LAxis<0>>().retrieve();
+    let mut permuted = a
+        .permute::<(LConst<4>, Dyn<'a'>), _>()
+        .contiguous()
+        .retrieve();
+    cx.compile(
+        CudaCompiler::<f32>::default(),
+        (
+            &mut added,
+            &mut summed,
+            &mut summed_empty_dim,
+            &mut permuted,
+        ),
+    );
+    a.set_dyn(Vec::<f32>::new(), &[0, 4]);
+    b.set_dyn(Vec::<f32>::new(), &[0, 4]);
+    cx.execute();
+    assert!(added.data().is_empty());
+    assert!(summed.data().is_empty());
+    assert!(permuted.data().is_empty());
+    // Reducing an empty dimension leaves the identity
+    assert_exact(&summed_empty_dim.data(), &[0.; 4]);
+
+    // The graph still runs once the tensors have elements again
+    let (data_a, data_b) = (random_vec(8), random_vec(8));
+    a.set_dyn(data_a.clone(), &[2, 4]);
+    b.set_dyn(data_b.clone(), &[2, 4]);
+    cx.execute();
+    assert_close(
+        &added.data(),
+        &data_a
+            .iter()
+            .zip(&data_b)
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_min_max_index_expressions() {
+    use luminal::prelude::symbolic::{BigExpression, Term};
+    use luminal_cudarc::driver::{LaunchAsync, LaunchConfig};
+    let dev = crate::cuda_device(0);
+    let z = BigExpression::from('z');
+    let a = BigExpression::from('a');
+    let exprs = [
+        z.clone().max(a.clone()).min(z.clone() * 2 + 1),
+        (z.clone() - 3).max(1).min(a.clone() - z.clone() / 2).max(0),
+        (z.clone() % 7)
+            .min(z.clone() / 3)
+            .max(a.clone().min(z.clone()) - 2)
+            + 4,
+        BigExpression::from(40) - z.clone().min(a.clone()).max(z.clone() - 20),
+        // A negative constant operand
+        BigExpression {
+            terms: vec![
+                Term::Num(-3),
+                Term::Var('z'),
+                Term::Sub,
+                Term::Var('a'),
+                Term::Min,
+            ],
+        },
+    ];
+    let a_value = 9;
+    let n = 64;
+    for expr in exprs {
+        let function = crate::compile_and_load_kernel(
+            format!(
+                "extern \"C\" __global__ void kernel(int *out, const int a) {{
+    int idx = threadIdx.x;
+    out[idx] = {};
+}}",
+                crate::expr_to_cuda_string(expr.clone())
+            ),
+            &dev,
+        )
+        .unwrap();
+        let mut out = dev.alloc_zeros::<i32>(n).unwrap();
+        let cfg = LaunchConfig {
+            grid_dim: (1, 1, 1),
+            block_dim: (n as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        unsafe { function.launch(cfg, (&mut out, a_value as i32)) }.unwrap();
+        let out = dev.dtoh_sync_copy(&out).unwrap();
+        for (z, rendered) in out.into_iter().enumerate() {
+            let vars = [('a', a_value), ('z', z)].into_iter().collect();
+            assert_eq!(
+                rendered,
+                expr.exec(&vars).unwrap() as i32,
+                "{expr:?} with z = {z}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_sliced_padded_validity() {
+    let data = random_vec(6 * 5);
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<6, 5>>().set(data.clone());
+        let view = a
+            .slice((.., Expression::from(2)..))
+            .realize::<R2<6, 3>>()
+            .pad::<R2<9, 3>, _, _>(&[(1, 2), (0, 0)]);
+        // The validity mask of the padded, sliced view combines comparisons with logical ands
+        let valid = crate::expr_to_cuda_string(view.shape.valid_expression());
+        assert!(valid.contains(">=") && valid.contains("&&"), "{valid}");
+        let mut b = view.contiguous().retrieve();
+        if cuda {
+            cx.compile(CudaCompiler::<f32>::default(), &mut b);
+        }
+        cx.execute();
+        b.data()
+    };
+    assert_exact(&run(true), &run(false));
+}
+
+#[test]
+fn test_wide_index_expressions() {
+    use luminal_cudarc::driver::{LaunchAsync, LaunchConfig};
+    // A transposed view of 3 * 2^30 elements, past what an int indexes
+    let (rows, cols) = (3usize, 1usize << 30);
+    let mut shape = ShapeTracker::new(&[rows.into(), cols.into()]);
+    shape.permute(&[1, 0]);
+    assert_eq!(crate::index_type(&[shape]), "long long");
+    let small = ShapeTracker::new(&[3.into(), 4.into()]);
+    assert_eq!(crate::index_type(&[small]), "int");
+    // Dynamic dimensions can grow past an int
+    let dynamic = ShapeTracker::new(&[3.into(), 'a'.into()]);
+    assert_eq!(crate::index_type(&[dynamic]), "long long");
+    // Constants past an int are rendered as 64-bit literals
+    let large = crate::expr_to_cuda_string(symbolic::BigExpression::from('z') * (cols * 4));
+    assert!(large.contains("(4294967296LL)"), "{large}");
+
+    // Evaluate the index expression over the last logical elements without allocating the tensor
+    let dev = crate::cuda_device(0);
+    let (idx, valid) = crate::get_idx_valid_exps(shape);
+    let function = crate::compile_and_load_kernel(
+        format!(
+            "extern \"C\" __global__ void kernel(long long *out, const long long start) {{
+    long long idx = start + threadIdx.x;
+    out[threadIdx.x] = ({valid}) == 0 ? -1 : {idx};
+}}"
+        ),
+        &dev,
+    )
+    .unwrap();
+    let n = 256;
+    let start = (rows * cols - n) as i64;
+    let mut out = dev.alloc_zeros::<i64>(n).unwrap();
+    let cfg = LaunchConfig {
+        grid_dim: (1, 1, 1),
+        block_dim: (n as u32, 1, 1),
+        shared_mem_bytes: 0,
+    };
+    unsafe { function.launch(cfg, (&mut out, start)) }.unwrap();
+    let out = dev.dtoh_sync_copy(&out).unwrap();
+    for (i, physical) in out.into_iter().enumerate() {
+        let logical = start as usize + i;
+        assert_eq!(
+            physical as usize,
+            (logical % rows) * cols + logical / rows,
+            "logical index {logical}"
+        );
+    }
+}
+
+#[test]
+fn test_static_index_folding() {
+    // Permuting around dimensions of size 1 reads memory in order, so the index needs no arithmetic
+    let mut shape = ShapeTracker::new(&[1.into(), 8.into(), 1.into()]);
+    shape.permute(&[2, 1, 0]);
+    assert_eq!(
+        crate::get_idx_valid_exps(shape),
+        ("idx".to_string(), "1".to_string())
+    );
+    // Slices drop their unbounded ends and the modulo on the outermost dimension
+    let mut shape = ShapeTracker::new(&[4.into(), 8.into()]);
+    shape.slice(&[(1.into(), i32::MAX.into()), (0.into(), i32::MAX.into())]);
+    let (idx, valid) = crate::get_idx_valid_exps(shape);
+    assert!(
+        !idx.contains(&i32::MAX.to_string()) && !idx.contains("% 3"),
+        "{idx}"
+    );
+    assert_eq!(valid, "1");
+
+    // Folded kernels compute the same results
+    let data = random_vec(8);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<1, 8, 1>>().set(data.clone());
+    let mut b = a
+        .permute::<R3<1, 8, 1>, LAxes3<2, 1, 0>>()
+        .exp2()
+        .retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    cx.execute();
+    assert_close(
+        &b.data(),
+        &data.iter().map(|i| i.exp2()).collect::<Vec<_>>(),
+    );
+}
+
+#[test]
+fn test_pow() {
+    // Positive bases with fractional exponents, and negative bases with integer exponents
+    let bases: Vec<f32> = vec![0.5, 1.7, 2.0, 3.3, 0.1, -2.0, -1.5, -3.0, -0.5, 4.0];
+    let exponents: Vec<f32> = vec![0.5, -1.3, 3.0, 2.25, 0.75, 3.0, 2.0, -1.0, 4.0, 0.0];
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<10>>().set(bases.clone());
+    let b = cx.tensor::<R1<10>>().set(exponents.clone());
+    let mut c = (a.log2() * b).exp2().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut c);
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaPow<f32>>()), 1);
+    cx.execute();
+    assert_close(
+        &c.data(),
+        &bases
+            .iter()
+            .zip(&exponents)
+            .map(|(a, b)| a.powf(*b))
+            .collect::<Vec<_>>(),
+    );
+
+    // A broadcast exponent matches the CPU graph for positive bases
+    let data = random_vec(24)
+        .into_iter()
+        .map(|i| i + 1.)
+        .collect::<Vec<_>>();
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 6>>().set(data.clone());
+        let e = cx.tensor::<R1<6>>().set(vec![0.5, 2., -1., 3., 1.5, 0.]);
+        let mut c = (a.log2() * e.expand::<R2<4, 6>, _>()).exp2().retrieve();
+        if cuda {
+            cx.compile(CudaCompiler::<f32>::default(), &mut c);
+        }
+        cx.execute();
+        c.data()
+    };
+    assert_close(&run(true), &run(false));
+}
+
+#[test]
+fn test_fused_rms_norm() {
+    // Rows longer than a block, with a non-default epsilon
+    let data = random_vec(2 * 3 * 1000);
+    let weight_data = random_vec(1000);
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 1000>>().set(data.clone());
+        let w = cx.tensor::<R1<1000>>().set(weight_data.clone());
+        let mut b = (a.std_norm::<2, _>(1e-3) * w.expand()).retrieve();
+        if cuda {
+            cx.compile(CudaCompiler::<f32>::default(), &mut b);
+            let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+                cx.node_indices()
+                    .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+                    .count()
+            };
+            // The square, mean, add, sqrt, recip and both multiplies become one launch
+            assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaRMSNorm<f32>>()), 1);
+            assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaMeanReduce<f32>>()), 0);
+            assert_eq!(
+                count_ops(&cx, |o| o.is::<crate::CudaFusedElementwise<f32>>()),
+                0
+            );
+        }
+        cx.execute();
+        b.data()
+    };
+    assert_close(&run(true), &run(false));
+}
+
+#[test]
+fn test_fused_softmax() {
+    // The last row would overflow a naive exp
+    let mut data = random_vec(3 * 300);
+    for v in &mut data[600..] {
+        *v = *v * 10. + 1000.;
+    }
+    let square = random_vec(3 * 3);
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 300>>().set(data.clone());
+        let mut rows = a.softmax::<1>().retrieve();
+        // Short strided rows
+        let mut cols = a.reshape::<R3<3, 30, 10>>().softmax::<1>().retrieve();
+        // Normalizing transposed exps, or dividing by the sums broadcast along the wrong dim, isn't a softmax
+        let x = cx.tensor::<R2<3, 3>>().set(square.clone());
+        let exp = (x - x.max_reduce::<_, LAxis<1>>().expand()).exp();
+        let sum = exp.sum_reduce::<_, LAxis<1>>();
+        let mut transposed =
+            (exp.permute::<_, LAxes2<1, 0>>() / sum.expand::<_, LAxis<1>>()).retrieve();
+        let mut wrong_dim = (exp / sum.expand::<_, LAxis<0>>()).retrieve();
+        if cuda {
+            cx.compile(
+                CudaCompiler::<f32>::default(),
+                (&mut rows, &mut cols, &mut transposed, &mut wrong_dim),
+            );
+            let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+                cx.node_indices()
+                    .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+                    .count()
+            };
+            assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaSoftmax<f32>>()), 2);
+            // Only the unfused square's max is left
+            assert_eq!(count_ops(&cx, |o| o.is::<crate::CudaMaxReduce<f32>>()), 1);
+        }
+        cx.execute();
+        (
+            rows.data(),
+            cols.data(),
+            transposed.data(),
+            wrong_dim.data(),
+        )
+    };
+    let (rows, cols, transposed, wrong_dim) = run(true);
+    let (cpu_rows, cpu_cols, cpu_transposed, cpu_wrong_dim) = run(false);
+    assert!(rows.iter().chain(&cols).all(|v| v.is_finite()));
+    assert_close(&rows, &cpu_rows);
+    assert_close(&cols, &cpu_cols);
+    assert_close(&transposed, &cpu_transposed);
+    assert_close(&wrong_dim, &cpu_wrong_dim);
+}
+
+#[test]
+fn test_reverse() {
+    let data = random_vec(2 * 3 * 4);
+    let weight = random_vec(4 * 5);
+    let run = |cuda: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+        let w = cx.tensor::<R2<4, 5>>().set(weight.clone());
+        let mut rev = a.reverse::<LAxis<2>>().contiguous().retrieve();
+        let mut rev_sliced = (a
+            .reverse::<LAxes2<1, 2>>()
+            .slice((.., ..Expression::from(2), Expression::from(1)..))
+            .realize::<R3<2, 2, 3>>()
+            .exp()
+            + 1.)
+            .retrieve();
+        let mut summed = (a.reverse::<LAxis<1>>() * a)
+            .sum_reduce::<_, LAxis<1>>()
+            .retrieve();
+        let mut matmul = a.reverse::<LAxis<2>>().matmul(w).retrieve();
+        if cuda {
+            cx.compile(
+                CudaCompiler::<f32>::default(),
+                (&mut rev, &mut rev_sliced, &mut summed, &mut matmul),
+            );
+        }
+        cx.execute();
+        [rev.data(), rev_sliced.data(), summed.data(), matmul.data()]
+    };
+    for (cuda, cpu) in run(true).iter().zip(run(false)) {
+        assert_close(cuda, &cpu);
+    }
+}
+
+#[test]
+fn test_cuda_available() {
+    // Probing never panics, whether or not there's a device
+    let available = crate::cuda_available();
+    assert!(!crate::cuda_device_available(usize::MAX));
+    assert_eq!(
+        crate::try_cuda_compiler_on_device::<f32>(0).is_ok(),
+        available
+    );
+    assert!(crate::try_cuda_device(usize::MAX).is_err());
+    assert!(crate::try_cuda_compiler_on_device::<f32>(usize::MAX).is_err());
+}
+
+#[test]
+fn test_scalar_operand() {
+    let data = random_vec(6 * 5);
+    let run = |scalar: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<6, 5>>().set(data.clone());
+        let mut added = (a + 3.).retrieve();
+        let mut scaled = (a.permute::<R2<5, 6>, _>() * 0.5).retrieve();
+        // The padded region is zero plus the constant
+        let mut padded = (a.pad::<R2<6, 7>, usize, usize>(&[(0, 0), (1, 1)]) + 1e-6).retrieve();
+        let outs = (&mut added, &mut scaled, &mut padded);
+        if scalar {
+            cx.compile(<(GenericCompiler, CudaCompiler<f32>)>::default(), outs);
+        } else {
+            cx.compile(
+                <(
+                    GenericCompiler,
+                    crate::prim::CudaPrimitiveCompiler<f32>,
+                    crate::prim::CopyCompiler<f32>,
+                )>::default(),
+                outs,
+            );
+        }
+        let scalar_ops = cx
+            .node_indices()
+            .filter(|n| {
+                cx.node_weight(*n)
+                    .unwrap()
+                    .as_any()
+                    .is::<crate::CudaScalarBinary<f32>>()
+            })
+            .collect::<Vec<_>>();
+        // Each scalar op only reads its tensor input
+        assert!(scalar_ops.iter().all(|n| cx.get_sources(*n).len() == 1));
+        cx.execute();
+        (added.data(), scaled.data(), padded.data(), scalar_ops.len())
+    };
+    let (scalar, two_input) = (run(true), run(false));
+    assert_eq!((scalar.3, two_input.3), (3, 0));
+    assert_close(&scalar.0, &two_input.0);
+    assert_close(&scalar.1, &two_input.1);
+    assert_close(&scalar.2, &two_input.2);
+}
+
+#[test]
+fn test_gather_out_of_range() {
+    let table = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+    let rows = [2., 0., 7., -1., 3.];
+    let run = |mode: crate::GatherOutOfRange| {
+        let mut cx = Graph::new();
+        let indexes = cx.tensor::<R1<5>>().set(rows.to_vec());
+        let model: luminal::nn::embedding::Embedding<4, 3> = InitModule::initialize(&mut cx);
+        model.weight.set(table.clone());
+        let mut out = model.forward(indexes).retrieve();
+        let mut compiler = CudaCompiler::<f32>::default();
+        compiler.1 .6 .1 = mode;
+        cx.compile(compiler, &mut out);
+        cx.execute();
+        out.data()
+    };
+    let gather = |row: Option<usize>| {
+        row.map(|r| table[r * 3..(r + 1) * 3].to_vec())
+            .unwrap_or(vec![0.; 3])
+    };
+    let zeroed = [Some(2), Some(0), None, None, Some(3)]
+        .into_iter()
+        .flat_map(gather)
+        .collect::<Vec<_>>();
+    let clamped = [2, 0, 3, 0, 3]
+        .into_iter()
+        .flat_map(|r| gather(Some(r)))
+        .collect::<Vec<_>>();
+    assert_exact(&run(crate::GatherOutOfRange::Zero), &zeroed);
+    assert_exact(&run(crate::GatherOutOfRange::Clamp), &clamped);
+}
+
+#[test]
+fn test_scatter_add() {
+    let dst_data = random_vec(4 * 3);
+    let src_data = random_vec(7 * 3);
+    // Repeated rows, and two indexes outside the destination
+    let indexes = vec![1., 3., 1., 0., 1., 5., -1.];
+    let mut expected = dst_data.clone();
+    for (i, row) in indexes.iter().enumerate() {
+        if (0. ..4.).contains(row) {
+            for c in 0..3 {
+                expected[*row as usize * 3 + c] += src_data[i * 3 + c];
+            }
+        }
+    }
+    let run = |deterministic: bool| {
+        let mut cx = Graph::new();
+        let dst = cx.tensor::<R2<4, 3>>().set(dst_data.clone());
+        let idx = cx.tensor::<R1<7>>().set(indexes.clone());
+        let src = cx.tensor::<R2<7, 3>>().set(src_data.clone());
+        let out = cx
+            .add_op(
+                crate::CudaScatterAdd::<f32>::new(crate::cuda_device(0), deterministic).unwrap(),
+            )
+            .input(dst.id, 0, dst.shape)
+            .input(idx.id, 0, idx.shape)
+            .input(src.id, 0, src.shape)
+            .finish();
+        let mut out = GraphTensor::<R2<4, 3>>::from_id(out, dst.shape, dst.graph_ref).retrieve();
+        cx.compile(CudaCompiler::<f32>::default(), &mut out);
+        cx.execute();
+        out.data()
+    };
+    assert_close(&run(false), &expected);
+    let sorted = run(true);
+    assert_close(&sorted, &expected);
+    assert_exact(&run(true), &sorted);
+}
+
+#[test]
+fn test_launch_stats() {
+    let a_data = random_vec(4 * 8);
+    let b_data = random_vec(4 * 8);
+    let run = |stats: Option<crate::CudaLaunchStats>| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 8>>().set(a_data.clone());
+        let b = cx.tensor::<R2<4, 8>>().set(b_data.clone());
+        let mut c = (a * b).sum_reduce::<_, LAxis<1>>().retrieve();
+        let mut d = (a + b).retrieve();
+        cx.compile(
+            <(
+                GenericCompiler,
+                crate::prim::CudaPrimitiveCompiler<f32>,
+                crate::prim::CopyCompiler<f32>,
+            )>::default(),
+            (&mut c, &mut d),
+        );
+        if let Some(stats) = stats {
+            cx.compile(crate::CudaLaunchStatsCompiler(stats), ());
+        }
+        cx.execute();
+        (c.data(), d.data())
+    };
+    let stats = crate::CudaLaunchStats::new();
+    let (c, d) = run(Some(stats.clone()));
+    let (c_ref, d_ref) = run(None);
+    assert_exact(&c, &c_ref);
+    assert_exact(&d, &d_ref);
+
+    let recorded = stats.stats();
+    for op in ["CudaMul", "CudaSumReduce", "CudaAdd"] {
+        assert_eq!(recorded[op].executions, 1, "{op}");
+        assert_eq!(recorded[op].launches, 1, "{op}");
+    }
+    assert_eq!(recorded["CudaMul"].elements, 2 * 4 * 8);
+    assert_eq!(recorded["CudaSumReduce"].elements, 4 * 8);
+    // Copies move memory without launching kernels
+    assert_eq!(stats.total_launches(), 3);
+    stats.clear();
+    assert_eq!(stats.total_launches(), 0);
+}
+
+#[test]
+fn test_execute_does_not_recompile() {
+    let data = random_vec(16);
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<16>>().set(data.clone());
+    let mut b = a.log2().exp2().sin().sqrt().recip().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+    let compiles = || crate::NVRTC_COMPILES.with(|c| c.get());
+
+    // Kernels are compiled when the ops are built, so executing only launches them
+    let start = compiles();
+    cx.execute();
+    let first = b.data();
+    b.drop();
+    cx.execute();
+    assert_eq!(compiles(), start);
+    assert_exact(&b.data(), &first);
+}
+
+#[test]
+fn test_fused_residual_activation() {
+    let count_ops = |cx: &Graph, is_op: fn(&dyn std::any::Any) -> bool| {
+        cx.node_indices()
+            .filter(|n| is_op(cx.node_weight(*n).unwrap().as_any()))
+            .count()
+    };
+    // Positive inputs keep the square roots real
+    let mut rng = StdRng::seed_from_u64(0);
+    let a_data = random_vec_rng(4 * 8, &mut rng)
+        .into_iter()
+        .map(|i| i.abs() + 0.1)
+        .collect_vec();
+    let b_data = random_vec_rng(4 * 8, &mut rng)
+        .into_iter()
+        .map(|i| i.abs() + 0.1)
+        .collect_vec();
+    let run = |fuse: bool| {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 8>>().set(a_data.clone());
+        let b = cx.tensor::<R2<4, 8>>().set(b_data.clone());
+        // The residual add folds into the sqrt, whether it's read as laid out or transposed
+        let mut residual = ((a * b + a).sqrt()).retrieve();
+        let mut transposed = (a + b)
+            .permute::<_, LAxes2<1, 0>>()
+            .sqrt()
+            .contiguous()
+            .retrieve();
+        // A residual with a second consumer has to be written out
+        let shared = b * a;
+        let mut two_consumers = (shared.sqrt() + shared).retrieve();
+        let outs = (&mut residual, &mut transposed, &mut two_consumers);
+        if fuse {
+            cx.compile(<(GenericCompiler, CudaCompiler<f32>)>::default(), outs);
+        } else {
+            cx.compile(
+                <(
+                    GenericCompiler,
+                    crate::prim::CudaPrimitiveCompiler<f32>,
+                    crate::prim::CopyCompiler<f32>,
+                )>::default(),
+                outs,
+            );
+        }
+        let kernels = (
+            count_ops(&cx, |o| o.is::<crate::CudaFusedElementwise<f32>>()),
+            count_ops(&cx, |o| {
+                o.is::<crate::prim::CudaMul<f32>>()
+                    || o.is::<crate::prim::CudaAdd<f32>>()
+                    || o.is::<crate::prim::CudaSqrt<f32>>()
+            }),
+        );
+        cx.execute();
+        (
+            residual.data(),
+            transposed.data(),
+            two_consumers.data(),
+            kernels,
+        )
+    };
+    let (fused, unfused) = (run(true), run(false));
+    assert_eq!(fused.3, (3, 1));
+    assert_close(&fused.0, &unfused.0);
+    assert_close(&fused.1, &unfused.1);
+    assert_close(&fused.2, &unfused.2);
+}
+
+#[test]
+fn test_execute_timed() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<1024, 1024>>().set(random_vec(1024 * 1024));
+    let b = cx.tensor::<R2<1024, 1024>>().set(random_vec(1024 * 1024));
+    // Kept on the device rather than retrieved, so nothing copies back and waits on the kernels
+    let mut c = a.matmul(b).keep();
+    cx.compile(CudaCompiler::<f32>::default(), &mut c);
+    let dev = crate::cuda_device(0);
+
+    let timing = crate::execute_timed(&mut cx, &dev, true);
+    // The stream is idle by the time the clock is read
+    assert!(
+        unsafe { luminal_cudarc::driver::sys::cuStreamQuery(*dev.cu_stream()) }
+            .result()
+            .is_ok()
+    );
+    let device_ms = timing.device_ms.unwrap();
+    assert!(device_ms > 0.);
+    assert!(device_ms <= timing.wall.as_secs_f32() * 1000.);
+    assert!(crate::execute_timed(&mut cx, &dev, false)
+        .device_ms
+        .is_none());
+}
+
+#[test]
+fn test_execute_until() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set(vec![4., 5., 6.]);
+    // Kept so fusion doesn't fold it into its consumer
+    let mut c = (a + b).keep();
+    let mut d = (c * a).exp2().retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), (&mut c, &mut d));
+
+    let c_data = crate::execute_until::<f32>(&mut cx, c.id);
+    assert_exact(
+        c_data.data.as_any().downcast_ref::<Vec<f32>>().unwrap(),
+        &[5., 7., 9.],
+    );
+    assert!(cx.get_tensor_ref(d.id, 0).is_none());
+
+    // The temporary copy is gone and the rest of the graph still runs
+    cx.execute();
+    assert_close(&d.data(), &[5f32.exp2(), 14f32.exp2(), 27f32.exp2()]);
+}
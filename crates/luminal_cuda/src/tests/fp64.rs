@@ -0,0 +1,111 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use luminal::{
+    op::{InputTensor, Operator},
+    prelude::*,
+};
+
+use crate::{
+    cuda_device,
+    prim::{CudaExp2, CudaLog2, CudaRecip, CudaSqrt, CudaSumReduce},
+    CudaArgSort, CudaData, CudaNanToNum,
+};
+
+// Ops are run directly on f64 buffers, since graph inputs and outputs go through f32
+
+fn run_f64(mut op: impl Operator, data: &[f64], shape: ShapeTracker) -> Vec<f64> {
+    let dev = cuda_device(0);
+    let inp = Tensor::new(CudaData::from_slice(dev.htod_sync_copy(data).unwrap()));
+    op.process(vec![(InputTensor::Borrowed(&inp), shape)])[0]
+        .data
+        .as_any()
+        .downcast_ref::<CudaData<f64>>()
+        .unwrap()
+        .to_vec()
+}
+
+fn assert_f64_close(a_vec: &[f64], b_vec: &[f64], rtol: f64) {
+    assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
+    for (i, (a, b)) in a_vec.iter().zip(b_vec.iter()).enumerate() {
+        if (a - b).abs() > rtol * b.abs() {
+            panic!("{a} is not close to {b}, index {i}");
+        }
+    }
+}
+
+fn random_f64(n: usize) -> Vec<f64> {
+    let mut rng = StdRng::seed_from_u64(0);
+    (0..n).map(|_| rng.gen_range(0.1..4.0)).collect()
+}
+
+#[test]
+fn test_unary() {
+    let data = random_f64(783);
+    let shape = R1::<783>::to_tracker();
+    let dev = cuda_device(0);
+    let expected = |f: fn(f64) -> f64| data.iter().copied().map(f).collect::<Vec<_>>();
+    assert_f64_close(
+        &run_f64(CudaLog2::<f64>::new(dev.clone()).unwrap(), &data, shape),
+        &expected(f64::log2),
+        1e-14,
+    );
+    assert_f64_close(
+        &run_f64(CudaExp2::<f64>::new(dev.clone()).unwrap(), &data, shape),
+        &expected(f64::exp2),
+        1e-14,
+    );
+    // sqrt and the reciprocal are correctly rounded, so they match exactly
+    assert_eq!(
+        run_f64(CudaSqrt::<f64>::new(dev.clone()).unwrap(), &data, shape),
+        expected(f64::sqrt)
+    );
+    assert_eq!(
+        run_f64(CudaRecip::<f64>::new(dev.clone()).unwrap(), &data, shape),
+        expected(f64::recip)
+    );
+}
+
+#[test]
+fn test_sum_reduce() {
+    let data = random_f64(4 * 8192);
+    let shape = R2::<4, 8192>::to_tracker();
+    let dyn_map = rustc_hash::FxHashMap::default();
+    let expected = data
+        .chunks(8192)
+        .map(|row| row.iter().sum::<f64>())
+        .collect::<Vec<_>>();
+    // Both the thread per output and block per output kernels accumulate in double
+    for parallel_min_dim in [usize::MAX, 1] {
+        let mut op = CudaSumReduce::<f64>::new(1, shape, cuda_device(0), &dyn_map).unwrap();
+        op.parallel_min_dim = parallel_min_dim;
+        assert_f64_close(&run_f64(op, &data, shape), &expected, 1e-12);
+    }
+}
+
+#[test]
+fn test_beyond_f32() {
+    // Finite values past f32's range stay finite, and the default replacements are f64's bounds
+    let data = [1e300, f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -1e-300];
+    let op = CudaNanToNum::<f64>::new(cuda_device(0), None, None, None).unwrap();
+    assert_eq!(
+        run_f64(op, &data, R1::<5>::to_tracker()),
+        [1e300, 0., f64::MAX, f64::MIN, -1e-300]
+    );
+
+    // Values only a double can tell apart are still ordered
+    let data = [1., 1. + 1e-12, 1e300, 1e299];
+    let inp = Tensor::new(CudaData::from_slice(
+        cuda_device(0).htod_sync_copy(&data).unwrap(),
+    ));
+    let mut op = CudaArgSort::<f64>::new(cuda_device(0)).unwrap();
+    let out = op.process(vec![(InputTensor::Borrowed(&inp), R1::<4>::to_tracker())]);
+    assert_eq!(
+        out[0]
+            .data
+            .as_any()
+            .downcast_ref::<CudaData<i32>>()
+            .unwrap()
+            .to_vec(),
+        [2, 3, 1, 0]
+    );
+}
@@ -1,5 +1,38 @@
+use crate::{CudaData, CudaFloat};
+
+mod bf16;
 mod fp16;
 mod fp32;
+mod fp64;
+
+/// Copy device data to the host and ensure it's within `atol + rtol * |expected|` of the expected values
+pub fn assert_cuda_close<T: CudaFloat>(
+    device_data: &CudaData<T>,
+    expected: &[f32],
+    atol: f32,
+    rtol: f32,
+) {
+    let data = device_data
+        .0
+        .device()
+        .dtoh_sync_copy(&*device_data.0)
+        .unwrap();
+    assert_eq!(
+        data.len(),
+        expected.len(),
+        "Number of elements doesn't match"
+    );
+    for (i, (a, b)) in data
+        .into_iter()
+        .map(|a| a.to_f32())
+        .zip(expected)
+        .enumerate()
+    {
+        if a.is_nan() || (a - b).abs() > atol + rtol * b.abs() {
+            panic!("{a} is not close to {b}, index {i}");
+        }
+    }
+}
 
 #[macro_export]
 macro_rules! single_unary_test {
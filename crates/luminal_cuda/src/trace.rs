@@ -0,0 +1,433 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    fmt::Write,
+    panic::AssertUnwindSafe,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use luminal_cudarc::driver::{result, sys, CudaDevice};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    capture_kernels, kernel_launches, memory_in_use, on_device_stream, set_up_devices,
+    take_captured_kernels,
+};
+
+use luminal::{
+    op::{Function, InputTensor, Operator},
+    prelude::*,
+};
+
+/// A timed op execution
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    /// Debug name of the op
+    pub name: String,
+    pub node: usize,
+    /// Microseconds since the first traced op started
+    pub start_us: f64,
+    pub duration_us: f64,
+}
+
+/// Records op execution times from CUDA events, for exporting a timeline.
+/// Handles are cheap to clone and all share the same recordings.
+#[derive(Clone)]
+pub struct CudaTrace {
+    device: Arc<CudaDevice>,
+    recorded: Rc<RefCell<Recorded>>,
+}
+
+/// Op names, nodes and their start and end events, which get destroyed along with the last trace handle
+#[derive(Default)]
+struct Recorded(Vec<(String, usize, sys::CUevent, sys::CUevent)>);
+
+impl Drop for Recorded {
+    fn drop(&mut self) {
+        for (_, _, start, end) in self.0.drain(..) {
+            unsafe {
+                result::event::destroy(start).unwrap();
+                result::event::destroy(end).unwrap();
+            }
+        }
+    }
+}
+
+impl CudaTrace {
+    pub fn new(device: Arc<CudaDevice>) -> Self {
+        Self {
+            device,
+            recorded: Default::default(),
+        }
+    }
+
+    fn record(&self) -> sys::CUevent {
+        let event = result::event::create(sys::CUevent_flags::CU_EVENT_DEFAULT).unwrap();
+        on_device_stream(&self.device, |stream| unsafe {
+            result::event::record(event, stream)
+        })
+        .unwrap();
+        event
+    }
+
+    /// Op timings in execution order, waiting on any ops still running on the device
+    pub fn events(&self) -> Vec<TraceEvent> {
+        let recorded = self.recorded.borrow();
+        let Some((_, _, origin, _)) = recorded.0.first() else {
+            return vec![];
+        };
+        recorded
+            .0
+            .iter()
+            .map(|(name, node, start, end)| unsafe {
+                sys::cuEventSynchronize(*end).result().unwrap();
+                TraceEvent {
+                    name: name.clone(),
+                    node: *node,
+                    start_us: result::event::elapsed(*origin, *start).unwrap() as f64 * 1000.,
+                    duration_us: result::event::elapsed(*start, *end).unwrap() as f64 * 1000.,
+                }
+            })
+            .collect()
+    }
+
+    /// Chrome trace JSON, which can be loaded in `chrome://tracing` or Perfetto
+    pub fn to_chrome_json(&self) -> String {
+        let mut json = "{\"traceEvents\":[".to_string();
+        for (i, event) in self.events().into_iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(
+                json,
+                "{{\"name\":\"{}\",\"cat\":\"op\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":0,\"tid\":0,\"args\":{{\"node\":{}}}}}",
+                escape_json(&event.name),
+                event.start_us,
+                event.duration_us,
+                event.node
+            )
+            .unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Drop all recorded events, for instance between runs
+    pub fn clear(&self) {
+        *self.recorded.borrow_mut() = Recorded::default();
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The op a `CudaTraced`, `CudaErrorReport` or `CudaLaunchCounted` runs, looking through nested wrappers
+pub(crate) fn unwrap_op(mut op: &dyn Operator) -> &dyn Operator {
+    loop {
+        let any = op.as_any();
+        op = if let Some(traced) = any.downcast_ref::<CudaTraced>() {
+            traced.op.as_ref()
+        } else if let Some(report) = any.downcast_ref::<CudaErrorReport>() {
+            report.op.as_ref()
+        } else if let Some(counted) = any.downcast_ref::<CudaLaunchCounted>() {
+            counted.op.as_ref()
+        } else {
+            return op;
+        };
+    }
+}
+
+/// Times the wrapped op with events recorded on the device's stream around it
+#[derive(LuminalEqFalse)]
+pub struct CudaTraced {
+    op: Box<dyn Operator>,
+    node: usize,
+    trace: CudaTrace,
+}
+
+impl std::fmt::Debug for CudaTraced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaTraced({:?})", self.op)
+    }
+}
+
+impl Operator for CudaTraced {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let start = self.trace.record();
+        let out = self.op.process(inp);
+        let end = self.trace.record();
+        self.trace
+            .recorded
+            .borrow_mut()
+            .0
+            .push((format!("{:?}", self.op), self.node, start, end));
+        out
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        self.op.custom(key, input)
+    }
+}
+
+/// Wrap every op in the graph so its execution gets recorded into the trace. Run this last.
+#[derive(LuminalPrint)]
+pub struct CudaTraceCompiler(pub CudaTrace);
+
+impl Compiler for CudaTraceCompiler {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let op = graph.graph.node_weight_mut(node).unwrap();
+            if op.as_any().is::<CudaTraced>() {
+                continue;
+            }
+            let inner =
+                std::mem::replace(op, Box::new(Function(String::new(), Box::new(|_| vec![]))));
+            *op = Box::new(CudaTraced {
+                op: inner,
+                node: node.index(),
+                trace: self.0.clone(),
+            });
+        }
+    }
+}
+
+/// Turns a panic inside the wrapped op, such as a failed kernel launch, into a report of what was running:
+/// the op, its input shapes, any kernels it compiled while running, the device's status and the live buffer size.
+#[derive(LuminalEqFalse)]
+pub struct CudaErrorReport {
+    op: Box<dyn Operator>,
+    node: usize,
+}
+
+impl std::fmt::Debug for CudaErrorReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaErrorReport({:?})", self.op)
+    }
+}
+
+impl Operator for CudaErrorReport {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shapes = inp
+            .iter()
+            .map(|(_, s)| {
+                s.shape()
+                    .into_iter()
+                    .map(|d| d.to_usize().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        capture_kernels();
+        let out = std::panic::catch_unwind(AssertUnwindSafe(|| self.op.process(inp)));
+        let kernels = take_captured_kernels();
+        let error = match out {
+            Ok(out) => return out,
+            Err(e) => e,
+        };
+
+        let message = error
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| error.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        let mut report = format!(
+            "CUDA error in {:?} with input shapes {shapes:?} (node {})\nError: {message}\nDevice status: ",
+            self.op, self.node
+        );
+        match unsafe { sys::cuCtxSynchronize() }.result() {
+            Ok(()) => report.push_str("ok"),
+            Err(e) => write!(report, "{e}").unwrap(),
+        }
+        for ordinal in set_up_devices() {
+            write!(
+                report,
+                "\nLive buffers on device {ordinal}: {} bytes",
+                memory_in_use(ordinal)
+            )
+            .unwrap();
+        }
+        for kernel in kernels {
+            write!(report, "\nKernel:\n{kernel}").unwrap();
+        }
+        panic!("{report}");
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        self.op.custom(key, input)
+    }
+}
+
+/// Wrap every op in the graph so failures during execution panic with a `CudaErrorReport`. Run this last.
+#[derive(LuminalPrint, Default)]
+pub struct CudaErrorReportCompiler;
+
+impl Compiler for CudaErrorReportCompiler {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let op = graph.graph.node_weight_mut(node).unwrap();
+            if op.as_any().is::<CudaErrorReport>() {
+                continue;
+            }
+            let inner =
+                std::mem::replace(op, Box::new(Function(String::new(), Box::new(|_| vec![]))));
+            *op = Box::new(CudaErrorReport {
+                op: inner,
+                node: node.index(),
+            });
+        }
+    }
+}
+
+/// Kernel launches made by one type of op
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LaunchStats {
+    /// Times an op of this type ran
+    pub executions: usize,
+    pub launches: usize,
+    /// Grid size times block size, summed over the launches
+    pub threads: u64,
+    /// Elements of the inputs the ops ran on
+    pub elements: u64,
+}
+
+/// Kernel launch counts per op type, keyed by the op's name. Handles are cheap to clone and all share the same
+/// counts.
+#[derive(Clone, Default)]
+pub struct CudaLaunchStats(Rc<RefCell<FxHashMap<String, LaunchStats>>>);
+
+impl CudaLaunchStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts for each op type that has run
+    pub fn stats(&self) -> FxHashMap<String, LaunchStats> {
+        self.0.borrow().clone()
+    }
+
+    /// Kernels launched by all ops
+    pub fn total_launches(&self) -> usize {
+        self.0.borrow().values().map(|s| s.launches).sum()
+    }
+
+    /// Reset all counts, for instance between runs
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+/// Counts the kernels the wrapped op launches
+#[derive(LuminalEqFalse)]
+pub struct CudaLaunchCounted {
+    op: Box<dyn Operator>,
+    name: String,
+    stats: CudaLaunchStats,
+}
+
+impl std::fmt::Debug for CudaLaunchCounted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaLaunchCounted({:?})", self.op)
+    }
+}
+
+impl Operator for CudaLaunchCounted {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let elements = inp
+            .iter()
+            .map(|(_, s)| s.n_elements().to_usize().unwrap_or_default() as u64)
+            .sum::<u64>();
+        let (launches_before, threads_before) = kernel_launches();
+        let out = self.op.process(inp);
+        let (launches, threads) = kernel_launches();
+        let mut stats = self.stats.0.borrow_mut();
+        let stats = stats.entry(self.name.clone()).or_default();
+        stats.executions += 1;
+        stats.launches += launches - launches_before;
+        stats.threads += threads - threads_before;
+        stats.elements += elements;
+        out
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        self.op.custom(key, input)
+    }
+}
+
+/// Wrap every op in the graph so the kernels it launches are counted into the stats. This only observes
+/// launches, and doesn't change what runs. Run this last.
+#[derive(LuminalPrint)]
+pub struct CudaLaunchStatsCompiler(pub CudaLaunchStats);
+
+impl Compiler for CudaLaunchStatsCompiler {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let op = graph.graph.node_weight_mut(node).unwrap();
+            if op.as_any().is::<CudaLaunchCounted>() {
+                continue;
+            }
+            // Op types are told apart by their name, without any fields
+            let name = format!("{op:?}")
+                .split(|c: char| !c.is_alphanumeric() && c != '_')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            let inner =
+                std::mem::replace(op, Box::new(Function(String::new(), Box::new(|_| vec![]))));
+            *op = Box::new(CudaLaunchCounted {
+                op: inner,
+                name,
+                stats: self.0.clone(),
+            });
+        }
+    }
+}
+
+/// How long one execution of a graph took
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExecutionTiming {
+    /// Host time from starting execution to the device finishing every kernel it launched
+    pub wall: Duration,
+    /// Device time between events recorded on the stream before and after execution, if asked for
+    pub device_ms: Option<f32>,
+}
+
+/// Execute the graph and wait for the device to finish before reading the clock. Kernel launches are asynchronous,
+/// so timing `execute` alone can stop while kernels are still running. With `events`, the device time is also
+/// measured with events around the execution, which leaves out host overhead before the first launch.
+pub fn execute_timed(graph: &mut Graph, device: &Arc<CudaDevice>, events: bool) -> ExecutionTiming {
+    // Work queued before this execution shouldn't count towards it
+    device.synchronize().unwrap();
+    let record = || {
+        let event = result::event::create(sys::CUevent_flags::CU_EVENT_DEFAULT).unwrap();
+        on_device_stream(device, |stream| unsafe {
+            result::event::record(event, stream)
+        })
+        .unwrap();
+        event
+    };
+    let now = Instant::now();
+    let start = events.then(record);
+    graph.execute();
+    let end = events.then(record);
+    device.synchronize().unwrap();
+    let wall = now.elapsed();
+    let device_ms = start.zip(end).map(|(start, end)| unsafe {
+        let elapsed = result::event::elapsed(start, end).unwrap();
+        result::event::destroy(start).unwrap();
+        result::event::destroy(end).unwrap();
+        elapsed
+    });
+    ExecutionTiming { wall, device_ms }
+}
@@ -0,0 +1,731 @@
+use std::{any::Any, marker::PhantomData, sync::Arc};
+
+use luminal_cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr};
+
+use luminal::{op::*, prelude::*};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    alloc_zeros, compile_and_load_kernel, constant, cuda_device, cuda_op_marker,
+    elementwise_launch_config, get_buffer_from_tensor, get_idx_valid_exps, index_type,
+    input_dyn_dims,
+    prim::{CudaAdd, CudaExp2, CudaLog2, CudaMul, CudaRecip},
+    render_dyn_dim_inputs, CudaCompileError, CudaData, CudaFloat, CudaInt, LaunchOnDeviceStream,
+    DEFAULT_BLOCK_SIZE,
+};
+
+/// Special kernel for mish, computed as x * tanh(softplus(x)) in the accumulator type
+#[derive(LuminalEqTrue, LuminalPrint, Clone)]
+pub struct CudaMish<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaMish<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {acc} x = ({valid}) == 0 ? ({acc})0.0 : ({acc})inp[{idx}];
+        // Stable softplus: max(x, 0) + log(1 + exp(-|x|))
+        {acc} softplus = fmax(x, ({acc})0.0) + log1p(exp(-fabs(x)));
+        out[idx] = ({type_name})(x * tanh(softplus));
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            block_size: DEFAULT_BLOCK_SIZE,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaMish<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace the mish pattern with a special kernel. This must run before the subtraction compiler.
+#[derive(LuminalPrint, Default)]
+pub struct MishCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> MishCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for MishCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        // Look for the mish pattern
+        // mul(x, tanh(ln(add(exp(x), 1))))
+        let inp = node();
+        let exp_mul = binary::<CudaMul<T>>(inp.clone(), constant::<T>(1.0 / f32::ln(2.)));
+        let softplus = binary::<CudaMul<T>>(
+            unary::<CudaLog2<T>>(binary::<CudaAdd<T>>(
+                unary::<CudaExp2<T>>(exp_mul.clone()),
+                constant::<T>(1.),
+            )),
+            constant::<T>(f32::ln(2.)),
+        );
+        // tanh(x) = sigmoid(x * 2) * 2 - 1
+        let neg = binary::<CudaMul<T>>(
+            binary::<CudaMul<T>>(softplus, constant::<T>(2.)),
+            constant::<T>(-1.),
+        );
+        let sigmoid = binary::<CudaMul<T>>(
+            constant::<T>(1.),
+            unary::<CudaRecip<T>>(binary::<CudaAdd<T>>(
+                constant::<T>(1.),
+                unary::<CudaExp2<T>>(binary::<CudaMul<T>>(neg, constant::<T>(1.0 / f32::ln(2.)))),
+            )),
+        );
+        let tanh = binary::<CudaAdd<T>>(
+            binary::<CudaMul<T>>(sigmoid, constant::<T>(2.)),
+            binary::<CudaMul<T>>(constant::<T>(1.), constant::<T>(-1.)),
+        );
+        let mish = binary::<CudaMul<T>>(inp.clone(), tanh);
+
+        let mut s = mish.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[mish.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            let (_, out_order, src_shape) = graph
+                .edges_connecting(s.get(&inp), s.get(&exp_mul))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap();
+            let fused = graph
+                .add_op(CudaMish::<T>::new(src_shape, dev.clone(), &graph.dyn_map).unwrap())
+                .input(s.get(&inp), out_order, src_shape)
+                .finish();
+
+            // Create edges to dests
+            let mish = s.get(&mish);
+            move_outgoing_edge(mish, fused, graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                mish,
+                fused,
+            );
+
+            // Remove the old ops
+            graph.remove_node(mish);
+            s.try_delete();
+        }
+    }
+}
+
+/// Special kernel for tanh, computed in f32 (or f64 for doubles) with the hardware tanh
+#[derive(LuminalEqTrue, LuminalPrint, Clone)]
+pub struct CudaTanh<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaTanh<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let acc = T::accumulator_type_name();
+        let tanh = if acc == "double" { "tanh" } else { "tanhf" };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {acc} x = ({valid}) == 0 ? ({acc})0.0 : ({acc})inp[{idx}];
+        out[idx] = ({type_name}){tanh}(x);
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            block_size: DEFAULT_BLOCK_SIZE,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaTanh<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace the tanh pattern with a special kernel. The decomposition overflows exp for large inputs,
+/// which the hardware tanh doesn't. This must run after the mish compiler and before the subtraction compiler.
+#[derive(LuminalPrint, Default)]
+pub struct TanhCompiler<T: CudaFloat>(pub usize, PhantomData<T>);
+
+impl<T: CudaFloat> TanhCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for TanhCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        // tanh(x) = sigmoid(x * 2) * 2 - 1
+        let inp = node();
+        let double = binary::<CudaMul<T>>(inp.clone(), constant::<T>(2.));
+        let neg = binary::<CudaMul<T>>(double.clone(), constant::<T>(-1.));
+        let sigmoid = binary::<CudaMul<T>>(
+            constant::<T>(1.),
+            unary::<CudaRecip<T>>(binary::<CudaAdd<T>>(
+                constant::<T>(1.),
+                unary::<CudaExp2<T>>(binary::<CudaMul<T>>(neg, constant::<T>(1.0 / f32::ln(2.)))),
+            )),
+        );
+        let tanh = binary::<CudaAdd<T>>(
+            binary::<CudaMul<T>>(sigmoid, constant::<T>(2.)),
+            binary::<CudaMul<T>>(constant::<T>(1.), constant::<T>(-1.)),
+        );
+
+        let mut s = tanh.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[tanh.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            let (_, out_order, src_shape) = graph
+                .edges_connecting(s.get(&inp), s.get(&double))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap();
+            let fused = graph
+                .add_op(CudaTanh::<T>::new(src_shape, dev.clone(), &graph.dyn_map).unwrap())
+                .input(s.get(&inp), out_order, src_shape)
+                .finish();
+
+            // Create edges to dests
+            let tanh = s.get(&tanh);
+            move_outgoing_edge(tanh, fused, graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                tanh,
+                fused,
+            );
+
+            // Remove the old ops
+            graph.remove_node(tanh);
+            s.try_delete();
+        }
+    }
+}
+
+/// How `CudaGelu` evaluates GELU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GeluApproximation {
+    /// `0.5 * x * (1 + tanh(sqrt(2 / pi) * (x + 0.044715 * x^3)))`, matching the graph's `gelu`
+    #[default]
+    Tanh,
+    /// The exact `0.5 * x * (1 + erf(x / sqrt(2)))`
+    Erf,
+}
+
+/// Special kernel for gelu, computed in f32 (or f64 for doubles)
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaGelu<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub approximation: GeluApproximation,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaGelu<T> {
+    pub fn new(
+        shape: ShapeTracker,
+        approximation: GeluApproximation,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let type_name = T::type_name();
+        let acc = T::accumulator_type_name();
+        let (tanh, erf) = if acc == "double" {
+            ("tanh", "erf")
+        } else {
+            ("tanhf", "erff")
+        };
+        let gelu = match approximation {
+            GeluApproximation::Tanh => format!(
+                "({acc})0.5 * x * (({acc})1.0 + {tanh}(({acc}){} * (x + ({acc})0.044715 * x * x * x)))",
+                (2. / std::f64::consts::PI).sqrt()
+            ),
+            GeluApproximation::Erf => format!(
+                "({acc})0.5 * x * (({acc})1.0 + {erf}(x * ({acc}){}))",
+                std::f64::consts::FRAC_1_SQRT_2
+            ),
+        };
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {acc} x = ({valid}) == 0 ? ({acc})0.0 : ({acc})inp[{idx}];
+        out[idx] = ({type_name})({gelu});
+    }}
+}}");
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            approximation,
+            block_size: DEFAULT_BLOCK_SIZE,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaGelu<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Replace the gelu pattern with a special kernel, evaluated with the compiler's approximation. Defaults to the
+/// tanh approximation, which is what the graph computes. `GeluApproximation::Erf` swaps in the exact gelu.
+/// This must run after the tanh compiler.
+#[derive(LuminalPrint, Default)]
+pub struct GeluCompiler<T: CudaFloat>(pub usize, pub GeluApproximation, PhantomData<T>);
+
+impl<T: CudaFloat> GeluCompiler<T> {
+    /// Compile for the device with this ordinal
+    pub fn new(ordinal: usize) -> Self {
+        Self(ordinal, GeluApproximation::default(), PhantomData)
+    }
+}
+
+impl<T: CudaFloat> Compiler for GeluCompiler<T> {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
+        let dev = cuda_device(self.0);
+        // gelu(x) = x * 0.5 * (tanh((x + x * x * x * 0.044715) * sqrt(2 / pi)) + 1)
+        let inp = node();
+        let half = binary::<CudaMul<T>>(inp.clone(), constant::<T>(0.5));
+        let cube =
+            binary::<CudaMul<T>>(binary::<CudaMul<T>>(inp.clone(), inp.clone()), inp.clone());
+        let inner = binary::<CudaMul<T>>(
+            binary::<CudaAdd<T>>(
+                inp.clone(),
+                binary::<CudaMul<T>>(cube, constant::<T>(0.044715)),
+            ),
+            constant::<T>((2. / std::f32::consts::PI).sqrt()),
+        );
+        let gelu = binary::<CudaMul<T>>(
+            half.clone(),
+            binary::<CudaAdd<T>>(unary::<CudaTanh<T>>(inner), constant::<T>(1.)),
+        );
+
+        let mut s = gelu.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[gelu.id]) {
+                // An intermediate node can't be deleted
+                continue;
+            }
+            let (_, out_order, src_shape) = graph
+                .edges_connecting(s.get(&inp), s.get(&half))
+                .next()
+                .unwrap()
+                .weight()
+                .as_data()
+                .unwrap();
+            let fused = graph
+                .add_op(CudaGelu::<T>::new(src_shape, self.1, dev.clone(), &graph.dyn_map).unwrap())
+                .input(s.get(&inp), out_order, src_shape)
+                .finish();
+
+            // Create edges to dests
+            let gelu = s.get(&gelu);
+            move_outgoing_edge(gelu, fused, graph);
+            move_references(
+                &mut remap,
+                &mut graph.no_delete,
+                &mut graph.to_retrieve,
+                gelu,
+                fused,
+            );
+
+            // Remove the old ops
+            graph.remove_node(gelu);
+            s.try_delete();
+        }
+    }
+}
+
+/// Replace NaN, +Inf and -Inf in a contiguous tensor with finite values.
+/// Unset replacements default to 0, the dtype's max and the dtype's min respectively.
+#[derive(LuminalEqFalse, LuminalPrint, Clone)]
+pub struct CudaNanToNum<T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub nan: f64,
+    pub pos_inf: f64,
+    pub neg_inf: f64,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: CudaFloat> CudaNanToNum<T> {
+    pub fn new(
+        device: Arc<CudaDevice>,
+        nan: Option<f64>,
+        pos_inf: Option<f64>,
+        neg_inf: Option<f64>,
+    ) -> Result<Self, CudaCompileError> {
+        let (type_name, acc) = (T::type_name(), T::accumulator_type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp, long long numel, double nan_value, double pos_inf_value, double neg_inf_value) {{
+    long long idx = (long long)blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {acc} x = ({acc})inp[idx];
+        if (isnan(x)) {{
+            x = ({acc})nan_value;
+        }} else if (isinf(x)) {{
+            x = ({acc})(x > 0 ? pos_inf_value : neg_inf_value);
+        }}
+        out[idx] = ({type_name})x;
+    }}
+}}"
+        );
+        let dtype_max = match T::type_name() {
+            "__half" => f16::MAX.to_f64(),
+            "__nv_bfloat16" => bf16::MAX.to_f64(),
+            "double" => f64::MAX,
+            _ => f32::MAX as f64,
+        };
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            nan: nan.unwrap_or(0.0),
+            pos_inf: pos_inf.unwrap_or(dtype_max),
+            neg_inf: neg_inf.unwrap_or(-dtype_max),
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaNanToNum<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let shape = tensors[0].1;
+        assert!(
+            shape.is_contiguous() && !shape.is_sliced() && !shape.is_padded(),
+            "NaN-to-num input must be contiguous"
+        );
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let mut out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, DEFAULT_BLOCK_SIZE),
+                    (
+                        &mut out,
+                        inp,
+                        inp_size,
+                        self.nan,
+                        self.pos_inf,
+                        self.neg_inf,
+                    ),
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        cuda_op_marker(key)
+    }
+}
+
+/// Convert an integer tensor to floats, such as token ids that need to feed float ops
+#[derive(LuminalEqTrue, LuminalPrint, Clone)]
+pub struct CudaIntToFloat<I, T> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<(I, T)>,
+}
+
+impl<I: CudaInt, T: CudaFloat> CudaIntToFloat<I, T> {
+    pub fn new(
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let (int_name, type_name) = (I::type_name(), T::type_name());
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {int_name} *inp, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        out[idx] = ({type_name})(float)(({valid}) == 0 ? ({int_name})0 : inp[{idx}]);
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            block_size: DEFAULT_BLOCK_SIZE,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<I: CudaInt, T: CudaFloat> Operator for CudaIntToFloat<I, T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<I>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = alloc_zeros::<T>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
+
+/// Convert a float tensor to integers, truncating towards zero like a C cast
+#[derive(LuminalEqTrue, LuminalPrint, Clone)]
+pub struct CudaFloatToInt<T, I> {
+    function: CudaFunction,
+    device: Arc<CudaDevice>,
+    pub block_size: u32,
+    dyn_symbols: Vec<char>,
+    dyn_map: *const FxHashMap<char, usize>,
+    _phantom: PhantomData<(T, I)>,
+}
+
+impl<T: CudaFloat, I: CudaInt> CudaFloatToInt<T, I> {
+    pub fn new(
+        shape: ShapeTracker,
+        device: Arc<CudaDevice>,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Result<Self, CudaCompileError> {
+        let (idx, valid) = get_idx_valid_exps(shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
+        let index = index_type(&[shape]);
+        let (type_name, int_name) = (T::type_name(), I::type_name());
+        let acc = T::accumulator_type_name();
+        let code = format!(
+            "
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({int_name} *out, const {type_name} *inp, {index} numel{rendered}) {{
+    {index} idx = ({index})blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        out[idx] = ({int_name})(({valid}) == 0 ? ({acc})0.0 : ({acc})inp[{idx}]);
+    }}
+}}"
+        );
+        Ok(Self {
+            function: compile_and_load_kernel(code, &device)?,
+            device,
+            block_size: DEFAULT_BLOCK_SIZE,
+            dyn_symbols,
+            dyn_map,
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: CudaFloat, I: CudaInt> Operator for CudaFloatToInt<T, I>
+where
+    CudaData<I>: Data,
+{
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
+        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+        let out = alloc_zeros::<I>(&self.device, inp_size).unwrap();
+        let mut params = vec![
+            (&out).as_kernel_param(),
+            inp.as_kernel_param(),
+            inp_size.as_kernel_param(),
+        ];
+        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch_on_device_stream(
+                    &self.device,
+                    elementwise_launch_config(inp_size, self.block_size),
+                    &mut params,
+                )
+                .unwrap();
+        }
+
+        vec![Tensor::new(CudaData::new(out))]
+    }
+
+    fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise_block_size" {
+            self.block_size = *input.downcast::<u32>().unwrap();
+        }
+        cuda_op_marker(key)
+    }
+}
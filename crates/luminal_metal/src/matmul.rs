@@ -306,7 +306,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
             let mut dims = (0..src2_shape.len()).collect::<Vec<_>>();
             dims.swap(src2_shape.len() - 2, src2_shape.len() - 1);
             src2_shape.permute(&dims);
-            // If src1 is padded or sliced, or batch dim isn't first, we need to make it contiguous
+            // If src1 is padded, sliced or reversed, or batch dim isn't first, we need to make it contiguous
             if src1_shape
                 .indexes
                 .iter()
@@ -315,6 +315,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 .any(|(a, b)| a != *b)
                 || src1_shape.is_sliced()
                 || src1_shape.is_padded()
+                || src1_shape.is_reversed()
             {
                 src1 = graph
                     .add_op(MetalContiguous::<T>::new(
@@ -327,7 +328,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                     .finish();
                 src1_shape = src1_shape.contiguous();
             }
-            // If src2 is padded or sliced, or batch dim isn't first, we need to make it contiguous
+            // If src2 is padded, sliced or reversed, or batch dim isn't first, we need to make it contiguous
             if src2_shape
                 .indexes
                 .iter()
@@ -337,6 +338,7 @@ impl<T: MetalFloat> Compiler for MetalMatMulCompiler<T> {
                 .any(|(a, b)| a != *b)
                 || src2_shape.is_sliced()
                 || src2_shape.is_padded()
+                || src2_shape.is_reversed()
             {
                 src2 = graph
                     .add_op(MetalContiguous::<T>::new(
@@ -37,6 +37,13 @@ impl Loader for DfdxDeferredLoader {
                 inp_func.1 = Box::new(move |_| {
                     // Get memmapped tensor
                     let bytes = std::fs::read(format!("{path}/{s}")).unwrap();
+                    #[cfg(feature = "cuda")]
+                    if bytes.len() == n_elements * 2 {
+                        // Upload half-precision weights to the device as-is
+                        return vec![Tensor {
+                            data: Box::new(luminal_cuda::RawF16Bytes(bytes)),
+                        }];
+                    }
                     let data: Vec<f32> = if bytes.len() == n_elements * 2 {
                         // Half-precision
                         bytes
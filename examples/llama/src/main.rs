@@ -37,7 +37,7 @@ fn main() {
     let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..model::LAYERS)
         .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
         .collect();
-    cache_src.set_dyn(vec![], &[1, model::HEADS, 0, model::HEAD_DIM]);
+    cache_src.set_dyn(Vec::<f32>::new(), &[1, model::HEADS, 0, model::HEAD_DIM]);
     let model = model::Llama::initialize(&mut cx);
     let (logits, mut cache_dest) =
         model.forward((input, Some(cache_src.clone()), PhantomData::<Dyn<'t'>>));
@@ -3,6 +3,7 @@
 //! Spec: https://github.com/philpax/ggml/blob/gguf-spec/docs/gguf.md
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use luminal::prelude::f16;
 use std::collections::HashMap;
 
 pub const DEFAULT_ALIGNMENT: u64 = 32;
@@ -300,3 +301,50 @@ impl GgmlDType {
         }
     }
 }
+
+impl GgmlDType {
+    /// Bytes taken up by `n_elements` values of this type
+    pub fn n_bytes(&self, n_elements: usize) -> usize {
+        match self {
+            Self::F32 => n_elements * 4,
+            Self::F16 => n_elements * 2,
+            // Blocks of 32 values sharing an f16 scale
+            Self::Q4_0 => n_elements / 32 * 18,
+            Self::Q8_0 => n_elements / 32 * 34,
+            _ => panic!("Unsupported dtype: {self:?}"),
+        }
+    }
+}
+
+/// Dequantize the raw bytes of a tensor into f32s
+pub fn dequantize(bytes: &[u8], data_type: GgmlDType) -> Vec<f32> {
+    let scale = |b: &[u8]| f16::from_le_bytes([b[0], b[1]]).to_f32();
+    match data_type {
+        GgmlDType::F32 => bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect(),
+        GgmlDType::F16 => bytes.chunks_exact(2).map(scale).collect(),
+        // The low nibbles hold the first 16 values and the high nibbles the last 16, offset by 8
+        GgmlDType::Q4_0 => bytes
+            .chunks_exact(18)
+            .flat_map(|block| {
+                let d = scale(block);
+                let quants = &block[2..];
+                quants
+                    .iter()
+                    .map(move |q| ((q & 0xF) as i32 - 8) as f32 * d)
+                    .chain(quants.iter().map(move |q| ((q >> 4) as i32 - 8) as f32 * d))
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+        GgmlDType::Q8_0 => bytes
+            .chunks_exact(34)
+            .flat_map(|block| {
+                let d = scale(block);
+                block[2..].iter().map(move |q| *q as i8 as f32 * d)
+            })
+            .collect(),
+        _ => panic!("Unsupported dtype: {data_type:?}"),
+    }
+}
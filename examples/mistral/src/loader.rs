@@ -5,10 +5,7 @@ use luminal::{op::Function, prelude::*};
 use crate::gguf::*;
 
 #[cfg(not(feature = "metal"))]
-use {
-    itertools::Itertools,
-    std::io::{Read, Seek},
-};
+use std::io::{Read, Seek};
 #[cfg(feature = "metal")]
 use {
     luminal_metal::MetalBuffer,
@@ -81,27 +78,22 @@ impl Loader for MetalQ8Loader {
     }
 }
 
+/// Loads weights from a GGUF file, dequantizing F32, F16, Q4_0 and Q8_0 tensors to f32.
+/// Weights are looked up by their state dict names, with `/` separators swapped for the `.` GGUF uses.
 #[cfg(not(feature = "metal"))]
-pub struct Q8Loader(String);
+pub struct GgufLoader(String);
 
 #[cfg(not(feature = "metal"))]
-impl Q8Loader {
+impl GgufLoader {
     pub fn new<S: Into<String>>(path: S) -> Self {
         Self(path.into())
     }
 }
 
 #[cfg(not(feature = "metal"))]
-impl Loader for Q8Loader {
-    type Output = Vec<NodeIndex>;
-    fn load<M: SerializeModule>(self, model: &M, graph: &mut Graph) -> Self::Output {
-        #[repr(C, packed)]
-        #[derive(Clone, Copy)]
-        struct Q8Block {
-            delta: f16,
-            weights: [i8; 32],
-        }
-
+impl Loader for GgufLoader {
+    type Output = ();
+    fn load<M: SerializeModule>(self, model: &M, graph: &mut Graph) {
         // Read metadata from file
         let mut reader = File::open(&self.0).unwrap();
         let Content {
@@ -111,7 +103,6 @@ impl Loader for Q8Loader {
         } = Content::read(&mut reader).unwrap();
 
         // Create weight loading closures
-        let mut q8_weights = vec![];
         for (weight_name, node_index) in state_dict(model) {
             if let Some(loading_node) = graph
                 .graph
@@ -119,16 +110,11 @@ impl Loader for Q8Loader {
                 .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
             {
                 let file_path = self.0.clone();
-                let (n_elements, buffer_offset, data_type) =
-                    tensor_infos.remove(&weight_name.replace('/', ".")).unwrap();
-                let n_bytes = match data_type {
-                    GgmlDType::F32 => n_elements * 4,
-                    GgmlDType::Q8_0 => {
-                        q8_weights.push(node_index);
-                        n_elements + (n_elements / 16)
-                    }
-                    _ => panic!("Unsupported dtype: {data_type:?}"),
-                };
+                let gguf_name = weight_name.replace('/', ".");
+                let (n_elements, buffer_offset, data_type) = tensor_infos
+                    .remove(&gguf_name)
+                    .unwrap_or_else(|| panic!("{gguf_name} isn't in {file_path}"));
+                let n_bytes = data_type.n_bytes(n_elements);
                 loading_node.1 = Box::new(move |_| {
                     // Load all bytes
                     let mut bytes = vec![0; n_bytes];
@@ -138,38 +124,126 @@ impl Loader for Q8Loader {
                     ))
                     .unwrap();
                     file.read_exact(&mut bytes).unwrap();
-                    // Dequantize into f32
-                    let data: Vec<f32> = match data_type {
-                        GgmlDType::F32 => bytes
-                            .into_iter()
-                            .chunks(4)
-                            .into_iter()
-                            .map(|c| {
-                                let c = c.collect::<Vec<_>>();
-                                f32::from_le_bytes([c[0], c[1], c[2], c[3]])
-                            })
-                            .collect(),
-                        GgmlDType::Q8_0 => bytes
-                            .into_iter()
-                            .chunks(34)
-                            .into_iter()
-                            .map(|c| {
-                                let chunk = c.collect::<Vec<_>>();
-                                unsafe { chunk.align_to::<Q8Block>().1[0] }
-                            })
-                            .flat_map(|chunk| {
-                                chunk
-                                    .weights
-                                    .into_iter()
-                                    .map(move |i| i as f32 * chunk.delta.to_f32())
-                            })
-                            .collect(),
-                        _ => panic!("Unsupported dtype: {data_type:?}"),
-                    };
-                    vec![Tensor::new(data)]
+                    vec![Tensor::new(dequantize(&bytes, data_type))]
                 });
             }
         }
-        q8_weights
+    }
+}
+
+#[cfg(all(test, not(feature = "metal")))]
+mod tests {
+    use std::io::Write;
+
+    use luminal::prelude::*;
+
+    use super::GgufLoader;
+
+    /// A model with a tensor of each supported type
+    struct Tiny {
+        embed: GraphTensor<R2<2, 32>>,
+        norm: GraphTensor<R1<32>>,
+        proj: GraphTensor<R2<32, 2>>,
+        bias: GraphTensor<R1<3>>,
+    }
+
+    impl SerializeModule for Tiny {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("token_embd/weight", self.embed);
+            s.tensor("output_norm/weight", self.norm);
+            s.tensor("output/weight", self.proj);
+            s.tensor("output/bias", self.bias);
+        }
+    }
+
+    #[test]
+    fn test_gguf_loader() {
+        let f16_bytes = |v: f32| f16::from_f32(v).to_le_bytes();
+        // Q8_0: two blocks with a scale of 0.5
+        let q8 = (0..2)
+            .flat_map(|b| {
+                let mut block = f16_bytes(0.5).to_vec();
+                block.extend((0..32).map(|i| (i - 16 + b) as i8 as u8));
+                block
+            })
+            .collect::<Vec<_>>();
+        let q8_expected = (0..2)
+            .flat_map(|b| (0..32).map(move |i| (i - 16 + b) as f32 * 0.5))
+            .collect::<Vec<_>>();
+        let f16s = (0..32)
+            .flat_map(|i| f16_bytes(i as f32 * 0.25))
+            .collect::<Vec<_>>();
+        let f16_expected = (0..32).map(|i| i as f32 * 0.25).collect::<Vec<_>>();
+        // Q4_0: two blocks with a scale of 2, low nibbles counting up and high nibbles counting down
+        let q4 = (0..2)
+            .flat_map(|_| {
+                let mut block = f16_bytes(2.).to_vec();
+                block.extend((0..16u8).map(|j| j | ((15 - j) << 4)));
+                block
+            })
+            .collect::<Vec<_>>();
+        let q4_expected = (0..2)
+            .flat_map(|_| {
+                (0..16)
+                    .map(|j| (j - 8) as f32 * 2.)
+                    .chain((0..16).map(|j| (15 - j - 8) as f32 * 2.))
+            })
+            .collect::<Vec<_>>();
+        let f32_expected = vec![1.5, -2., 3.25];
+        let f32s = f32_expected
+            .iter()
+            .flat_map(|v: &f32| v.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        // A GGUF v3 file with no metadata: (name, dims, dtype, data)
+        let tensors = [
+            ("token_embd.weight", vec![32u64, 2], 8u32, q8),
+            ("output_norm.weight", vec![32], 1, f16s),
+            ("output.weight", vec![2, 32], 2, q4),
+            ("output.bias", vec![3], 0, f32s),
+        ];
+        let mut header = vec![];
+        header.extend(0x46554747u32.to_le_bytes());
+        header.extend(3u32.to_le_bytes());
+        header.extend((tensors.len() as u64).to_le_bytes());
+        header.extend(0u64.to_le_bytes());
+        let mut data = vec![];
+        for (name, dims, dtype, bytes) in &tensors {
+            header.extend((name.len() as u64).to_le_bytes());
+            header.extend(name.as_bytes());
+            header.extend((dims.len() as u32).to_le_bytes());
+            for d in dims {
+                header.extend(d.to_le_bytes());
+            }
+            header.extend(dtype.to_le_bytes());
+            header.extend((data.len() as u64).to_le_bytes());
+            data.extend(bytes);
+            data.resize(data.len().div_ceil(32) * 32, 0);
+        }
+        header.resize(header.len().div_ceil(32) * 32, 0);
+        let path = std::env::temp_dir().join("luminal_mistral_gguf_loader_test.gguf");
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(&header).unwrap();
+        file.write_all(&data).unwrap();
+
+        let mut cx = Graph::new();
+        let model = Tiny {
+            embed: cx.named_tensor("Embed"),
+            norm: cx.named_tensor("Norm"),
+            proj: cx.named_tensor("Proj"),
+            bias: cx.named_tensor("Bias"),
+        };
+        GgufLoader::new(path.to_str().unwrap()).load(&model, &mut cx);
+        let (embed, norm, proj, bias) = (
+            model.embed.retrieve(),
+            model.norm.retrieve(),
+            model.proj.retrieve(),
+            model.bias.retrieve(),
+        );
+        cx.execute();
+        assert_eq!(embed.data(), q8_expected);
+        assert_eq!(norm.data(), f16_expected);
+        assert_eq!(proj.data(), q4_expected);
+        assert_eq!(bias.data(), f32_expected);
     }
 }
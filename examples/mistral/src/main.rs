@@ -1,11 +1,14 @@
 use std::{
     io::{self, Write},
     marker::PhantomData,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
 use colored::Colorize;
+use itertools::Itertools;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use rust_tokenizers::tokenizer::{SentencePieceBpeTokenizer, Tokenizer, TruncationStrategy};
 
 mod gguf;
@@ -15,6 +18,8 @@ mod model;
 use crate::model::KVCache;
 use luminal::{prelude::*, shape::symbolic::Expression};
 
+const EOS_TOKEN: i64 = 2;
+
 // Command args parser
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -26,12 +31,60 @@ pub struct CLIArgs {
     /// Prompt for the model
     #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/asimov.txt"))]
     prompt: String,
+
+    /// Evaluate perplexity over a text file instead of generating
+    #[clap(long = "perplexity")]
+    perplexity: Option<String>,
+
+    /// Number of beams to decode with. One beam decodes greedily
+    #[clap(short = 'b', long = "num_beams", default_value = "1")]
+    num_beams: usize,
+
+    /// Sampling temperature. 0 always picks the most likely token
+    #[clap(long = "temperature", default_value = "0.7")]
+    temperature: f32,
+
+    /// Only sample from this many of the most likely tokens
+    #[clap(long = "top-k")]
+    top_k: Option<usize>,
+
+    /// Only sample from the most likely tokens covering this much probability
+    #[clap(long = "top-p")]
+    top_p: Option<f32>,
+
+    /// Penalize the logits of tokens already in the sequence by this factor. 1 disables the penalty
+    #[clap(long = "repetition-penalty", default_value = "1.0")]
+    repetition_penalty: f32,
+
+    /// Only penalize tokens among this many most recent ones
+    #[clap(long = "repetition-window")]
+    repetition_window: Option<usize>,
+
+    /// Keep generating past the end of sequence token
+    #[clap(long = "ignore-eos")]
+    ignore_eos: bool,
+
+    /// Seed for sampling. A random one is picked and printed if not given, so the run can be replayed
+    #[clap(long = "seed")]
+    seed: Option<u64>,
 }
 
 fn main() {
     let cli_args = CLIArgs::parse();
     let tokenizer =
         SentencePieceBpeTokenizer::from_file("setup/mistral_tokenizer.model", false).unwrap();
+    // Check the perplexity text before the model is loaded, it needs a token to predict after the first
+    let perplexity_ids = cli_args.perplexity.as_ref().map(|path| {
+        let token_ids = encode(&tokenizer, &std::fs::read_to_string(path).unwrap());
+        if token_ids.len() < 2 {
+            eprintln!(
+                "{path} encodes to {} token(s), perplexity needs at least 2",
+                token_ids.len()
+            );
+            std::process::exit(1);
+        }
+        token_ids
+    });
 
     print!("Defining graph");
     io::stdout().flush().unwrap();
@@ -43,7 +96,7 @@ fn main() {
     let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..model::NUM_LAYERS)
         .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
         .collect();
-    cache_src.set_dyn(vec![], &[1, model::N_KV_HEADS, 0, model::HEAD_DIM]);
+    cache_src.set_dyn(Vec::<f32>::new(), &[1, model::N_KV_HEADS, 0, model::HEAD_DIM]);
     let model = model::MistralLM::initialize(&mut cx);
     let (logits, mut cache_dest) =
         model.forward((input, Some(cache_src.clone()), PhantomData::<Dyn<'t'>>));
@@ -58,7 +111,7 @@ fn main() {
         loader::MetalQ8Loader::new("setup/mistral-7b-instruct-v0.2.Q8_0.gguf")
             .load(&model, &mut cx);
     #[cfg(not(feature = "metal"))]
-    loader::Q8Loader::new("setup/mistral-7b-instruct-v0.2.Q8_0.gguf").load(&model, &mut cx);
+    loader::GgufLoader::new("setup/mistral-7b-instruct-v0.2.Q8_0.gguf").load(&model, &mut cx);
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     print!("Compiling graph");
@@ -71,6 +124,9 @@ fn main() {
             luminal_metal::MetalQuantizedCompiler::<f32>::new(quantized_weight_nodes),
             #[cfg(feature = "cuda")]
             luminal_cuda::CudaCompiler::<f32>::default(),
+            // Stage the per-step input and logits copies through pinned memory
+            #[cfg(feature = "cuda")]
+            luminal_cuda::PinnedStagingCompiler::<f32>::default(),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
             luminal::compilers::CPUCompiler::default(),
         ),
@@ -98,6 +154,32 @@ fn main() {
 
     // Now that weights are loaded, delete the loading nodes so they don't run again
     delete_inputs(&model_weights, &mut cx);
+
+    if let Some(token_ids) = perplexity_ids {
+        print!("Evaluating {} tokens", token_ids.len());
+        io::stdout().flush().unwrap();
+        let now = Instant::now();
+        // Stream tokens through the cached decode graph one at a time
+        let mut pos = 0;
+        let ppl = perplexity(&token_ids, |token_id| {
+            input.set_dyn(vec![token_id as f32], &[1, 1]);
+            cx.set_dyn_dim('p', pos);
+            cx.set_dyn_dim('t', pos + 1);
+            cx.execute();
+            if pos == 0 {
+                delete_inputs(&cache_src_set, &mut cx);
+            }
+            pos += 1;
+            let dist = logits.data();
+            logits.drop();
+            transfer_data_same_graph(&cache_dest_set, &cache_src_set, &mut cx);
+            dist
+        });
+        println!("\t - {}ms", now.elapsed().as_millis());
+        println!("Perplexity: {ppl:.3}");
+        return;
+    }
+
     // Run prompt processing pass
     let mut input_ids = encode(&tokenizer, &cli_args.prompt);
     input.set_dyn(
@@ -107,50 +189,100 @@ fn main() {
     cx.set_dyn_dim('t', input_ids.len());
     print!("Processing Prompt");
     io::stdout().flush().unwrap();
-    let now = Instant::now();
-    cx.execute();
-    let elapsed_ms = now.elapsed().as_millis();
+    let elapsed_ms = timed_execute(&mut cx).as_millis();
     println!(
         "\t - {elapsed_ms}ms ({:.2} tok/s)",
         1000.0 * (input_ids.len() as f64) / (elapsed_ms as f64)
     );
     delete_inputs(&cache_src_set, &mut cx);
-    let output_id = sample_index(&logits.data());
-    logits.drop();
-    input_ids.push(output_id);
 
-    // Decode token
-    print!(
-        "{}{}",
-        cli_args.prompt.white().bold(),
-        decode(&tokenizer, &[output_id]).bright_green()
-    );
-    io::stdout().flush().unwrap();
+    if cli_args.num_beams > 1 {
+        // Each beam carries its own copy of the KV cache along with its position
+        let cache = cache_dest_set
+            .iter()
+            .map(|n| cx.tensors.remove(&(*n, 0)).unwrap())
+            .collect::<Vec<_>>();
+        let first_logits = logits.data();
+        logits.drop();
+        let now = Instant::now();
+        let output_ids = beam_search(
+            (cache, input_ids.len()),
+            first_logits,
+            cli_args.num_beams,
+            cli_args.gen_tokens as usize,
+            Some(EOS_TOKEN),
+            |(cache, pos), token_id| {
+                for (node, tensor) in cache_src_set.iter().zip(cache.drain(..)) {
+                    cx.tensors.insert((*node, 0), tensor);
+                }
+                input.set_dyn(vec![token_id as f32], &[1, 1]);
+                cx.set_dyn_dim('p', *pos);
+                cx.set_dyn_dim('t', *pos + 1);
+                cx.execute();
+                *pos += 1;
+                *cache = cache_dest_set
+                    .iter()
+                    .map(|n| cx.tensors.remove(&(*n, 0)).unwrap())
+                    .collect();
+                let dist = logits.data();
+                logits.drop();
+                dist
+            },
+        );
+        println!(
+            "{}{}",
+            cli_args.prompt.white().bold(),
+            decode(&tokenizer, &output_ids).bright_green()
+        );
+        println!("Beam search finished in {}ms", now.elapsed().as_millis());
+        return;
+    }
 
-    // Swap caches
-    transfer_data_same_graph(&cache_dest_set, &cache_src_set, &mut cx);
+    let sampler = Sampler {
+        temperature: cli_args.temperature,
+        top_k: cli_args.top_k,
+        top_p: cli_args.top_p,
+        repetition_penalty: cli_args.repetition_penalty,
+        repetition_window: cli_args.repetition_window,
+    };
+    let seed = cli_args.seed.unwrap_or_else(rand::random);
+    if cli_args.seed.is_none() {
+        println!("Sampling with seed {seed}");
+    }
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let first_logits = logits.data();
+    logits.drop();
 
-    // Decode loop
+    // Stream each token out as it's generated
+    print!("{}", cli_args.prompt.white().bold());
+    io::stdout().flush().unwrap();
     let mut token_decode_times = vec![];
-    for _ in 0..cli_args.gen_tokens {
-        input.set_dyn(vec![*input_ids.last().unwrap() as f32], &[1, 1]);
-        cx.set_dyn_dim('p', input_ids.len() - 1);
-        cx.set_dyn_dim('t', input_ids.len());
+    let mut pos = input_ids.len();
+    generate(
+        &mut input_ids,
+        first_logits,
+        cli_args.gen_tokens as usize,
+        (!cli_args.ignore_eos).then_some(EOS_TOKEN),
+        |logits, history| sampler.sample(logits, history, &mut rng),
+        |token_id| decode(&tokenizer, &[token_id]),
+        |token_id| {
+            // Swap caches
+            transfer_data_same_graph(&cache_dest_set, &cache_src_set, &mut cx);
+            input.set_dyn(vec![token_id as f32], &[1, 1]);
+            cx.set_dyn_dim('p', pos);
+            cx.set_dyn_dim('t', pos + 1);
+            pos += 1;
 
-        let now = Instant::now();
-        cx.execute();
-        token_decode_times.push(now.elapsed().as_micros());
-
-        // Sample tokens
-        let output_id = sample_index(&logits.data());
-        logits.drop();
-        input_ids.push(output_id);
-        print!("{}", decode(&tokenizer, &[output_id]).bright_green());
-        io::stdout().flush().unwrap();
-
-        // Swap caches
-        transfer_data_same_graph(&cache_dest_set, &cache_src_set, &mut cx);
-    }
+            token_decode_times.push(timed_execute(&mut cx).as_micros());
+            let dist = logits.data();
+            logits.drop();
+            dist
+        },
+        |_, text| {
+            print!("{}", text.bright_green());
+            io::stdout().flush().unwrap();
+        },
+    );
     let avg_token_time = token_decode_times
         .iter()
         .map(|t| *t as f32 / 1000.)
@@ -163,6 +295,49 @@ fn main() {
     );
 }
 
+/// Execute the graph, returning how long it took. CUDA kernels launch asynchronously, so wait for them to finish
+/// before reading the clock
+fn timed_execute(cx: &mut Graph) -> Duration {
+    #[cfg(feature = "cuda")]
+    {
+        luminal_cuda::execute_timed(cx, &luminal_cuda::cuda_device(0), false).wall
+    }
+    #[cfg(not(feature = "cuda"))]
+    {
+        let now = Instant::now();
+        cx.execute();
+        now.elapsed()
+    }
+}
+
+/// Generate up to `max_tokens` tokens following `tokens`, appending them, and stop early after `eos`.
+/// `logits` are the next token logits for the sequence so far. `sample` picks a token from logits and the history,
+/// and `step` feeds a picked token into the model and returns the logits after it. `on_token` is called with each
+/// token's id and decoded text as soon as it's picked, so generation can be streamed out. Returns the generated tokens.
+#[allow(clippy::too_many_arguments)]
+fn generate(
+    tokens: &mut Vec<i64>,
+    mut logits: Vec<f32>,
+    max_tokens: usize,
+    eos: Option<i64>,
+    mut sample: impl FnMut(&[f32], &[i64]) -> i64,
+    decode: impl Fn(i64) -> String,
+    mut step: impl FnMut(i64) -> Vec<f32>,
+    mut on_token: impl FnMut(i64, &str),
+) -> Vec<i64> {
+    let start = tokens.len();
+    for i in 0..max_tokens {
+        let token_id = sample(&logits, tokens);
+        tokens.push(token_id);
+        on_token(token_id, &decode(token_id));
+        if Some(token_id) == eos || i + 1 == max_tokens {
+            break;
+        }
+        logits = step(token_id);
+    }
+    tokens[start..].to_vec()
+}
+
 fn encode(tokenizer: &SentencePieceBpeTokenizer, text: &str) -> Vec<i64> {
     let mut vector = tokenizer
         .encode(text, None, text.len(), &TruncationStrategy::LongestFirst, 0)
@@ -177,11 +352,436 @@ fn decode(tokenizer: &SentencePieceBpeTokenizer, token_ids: &[i64]) -> String {
         .replace("<0x0A>", "\n")
 }
 
-// Currently just an argmax, do actual sampling here
-fn sample_index(dist: &[f32]) -> i64 {
+/// Perplexity of a token sequence, given a function returning the next token logits after feeding in each token.
+/// The sequence needs at least 2 tokens.
+fn perplexity(token_ids: &[i64], mut next_token_logits: impl FnMut(i64) -> Vec<f32>) -> f64 {
+    let mut total_nll = 0.;
+    for window in token_ids.windows(2) {
+        total_nll += token_nll(&next_token_logits(window[0]), window[1] as usize);
+    }
+    (total_nll / (token_ids.len() - 1) as f64).exp()
+}
+
+/// Negative log-likelihood of the target token under the softmax of the logits
+fn token_nll(logits: &[f32], target: usize) -> f64 {
+    -log_softmax(logits)[target]
+}
+
+/// A candidate sequence during beam search
+struct Beam<S> {
+    tokens: Vec<i64>,
+    log_prob: f64,
+    /// Model state that has seen every token but the last
+    state: S,
+    /// Next token logits, if they've already been computed
+    logits: Option<Vec<f32>>,
+    finished: bool,
+}
+
+/// Beam search decoding, keeping the `num_beams` sequences with the highest cumulative log-probability.
+/// `step` feeds a token into a beam's model state (such as its KV cache) and returns the next token logits.
+/// Each step expands every beam by its top `num_beams` tokens and prunes back down to the best `num_beams`.
+/// Beams that produce `eos` stop growing but stay in the running. Returns the tokens of the best beam.
+fn beam_search<S: Clone>(
+    state: S,
+    first_logits: Vec<f32>,
+    num_beams: usize,
+    max_tokens: usize,
+    eos: Option<i64>,
+    mut step: impl FnMut(&mut S, i64) -> Vec<f32>,
+) -> Vec<i64> {
+    let mut beams = vec![Beam {
+        tokens: vec![],
+        log_prob: 0.,
+        state,
+        logits: Some(first_logits),
+        finished: false,
+    }];
+    for _ in 0..max_tokens {
+        if beams.iter().all(|b| b.finished) {
+            break;
+        }
+        // Score every continuation: (parent, next token, cumulative log-prob)
+        let mut candidates = vec![];
+        for (i, beam) in beams.iter_mut().enumerate() {
+            if beam.finished {
+                candidates.push((i, None, beam.log_prob));
+                continue;
+            }
+            let logits = match beam.logits.take() {
+                Some(logits) => logits,
+                None => step(&mut beam.state, *beam.tokens.last().unwrap()),
+            };
+            let log_probs = log_softmax(&logits);
+            let top = (0..log_probs.len())
+                .sorted_by(|a, b| log_probs[*b].total_cmp(&log_probs[*a]))
+                .take(num_beams);
+            for token in top {
+                candidates.push((i, Some(token as i64), beam.log_prob + log_probs[token]));
+            }
+        }
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+        candidates.truncate(num_beams);
+
+        beams = candidates
+            .into_iter()
+            .map(|(parent, token, log_prob)| {
+                let parent = &beams[parent];
+                let mut tokens = parent.tokens.clone();
+                tokens.extend(token);
+                Beam {
+                    tokens,
+                    log_prob,
+                    state: parent.state.clone(),
+                    logits: None,
+                    finished: parent.finished || token == eos,
+                }
+            })
+            .collect();
+    }
+    beams
+        .into_iter()
+        .max_by(|a, b| a.log_prob.total_cmp(&b.log_prob))
+        .unwrap()
+        .tokens
+}
+
+/// Log-softmax of the logits, in f64
+fn log_softmax(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, b| a.max(*b)) as f64;
+    let log_sum_exp = logits
+        .iter()
+        .map(|l| (*l as f64 - max).exp())
+        .sum::<f64>()
+        .ln()
+        + max;
+    logits.iter().map(|l| *l as f64 - log_sum_exp).collect()
+}
+
+/// The index of the largest logit
+fn argmax(dist: &[f32]) -> i64 {
     dist.iter()
         .enumerate()
         .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
         .unwrap()
         .0 as i64
 }
+
+/// Picks each generated token from the next token logits
+#[derive(Debug, Clone, Copy)]
+struct Sampler {
+    /// Logits are divided by this before the softmax. 0 always picks the most likely token
+    temperature: f32,
+    /// Only sample from this many of the most likely tokens
+    top_k: Option<usize>,
+    /// Only sample from the smallest set of most likely tokens whose probability reaches this
+    top_p: Option<f32>,
+    /// Positive logits of tokens already in the sequence are divided by this and negative ones multiplied by it
+    repetition_penalty: f32,
+    /// Only penalize tokens among this many most recent ones
+    repetition_window: Option<usize>,
+}
+
+impl Sampler {
+    /// The probability of sampling each token. Tokens cut by top-k and top-p get 0 and the rest are renormalized
+    fn distribution(&self, logits: &[f32]) -> Vec<f64> {
+        let scaled = logits
+            .iter()
+            .map(|l| *l / self.temperature)
+            .collect::<Vec<_>>();
+        let probs = log_softmax(&scaled)
+            .into_iter()
+            .map(f64::exp)
+            .collect::<Vec<_>>();
+        let ranked = (0..probs.len())
+            .sorted_by(|a, b| probs[*b].total_cmp(&probs[*a]))
+            .collect::<Vec<_>>();
+        let mut keep = self.top_k.unwrap_or(usize::MAX).clamp(1, ranked.len());
+        if let Some(top_p) = self.top_p {
+            // Top-p applies to what's left after top-k
+            let kept_mass = ranked[..keep].iter().map(|i| probs[*i]).sum::<f64>();
+            let mut mass = 0.;
+            for (n, i) in ranked[..keep].iter().enumerate() {
+                mass += probs[*i] / kept_mass;
+                if mass >= top_p as f64 {
+                    keep = n + 1;
+                    break;
+                }
+            }
+        }
+        let kept_mass = ranked[..keep].iter().map(|i| probs[*i]).sum::<f64>();
+        let mut dist = vec![0.; probs.len()];
+        for i in &ranked[..keep] {
+            dist[*i] = probs[*i] / kept_mass;
+        }
+        dist
+    }
+
+    /// Apply the repetition penalty to the logits of tokens in the history, counting each token once
+    fn penalize(&self, logits: &[f32], history: &[i64]) -> Vec<f32> {
+        let mut logits = logits.to_vec();
+        let window = self.repetition_window.unwrap_or(history.len());
+        for token in history[history.len().saturating_sub(window)..]
+            .iter()
+            .unique()
+        {
+            let logit = &mut logits[*token as usize];
+            if *logit > 0. {
+                *logit /= self.repetition_penalty;
+            } else {
+                *logit *= self.repetition_penalty;
+            }
+        }
+        logits
+    }
+
+    /// Pick the next token given the sequence so far. Penalties apply before any filtering
+    fn sample(&self, logits: &[f32], history: &[i64], rng: &mut impl Rng) -> i64 {
+        let logits = self.penalize(logits, history);
+        if self.temperature <= 0. {
+            return argmax(&logits);
+        }
+        let dist = self.distribution(&logits);
+        let mut r = rng.gen::<f64>();
+        for (i, p) in dist.iter().enumerate() {
+            if r < *p {
+                return i as i64;
+            }
+            r -= p;
+        }
+        // Rounding left us past the end, so take the last token we could have picked
+        dist.iter().rposition(|p| *p > 0.).unwrap() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::{argmax, beam_search, generate, perplexity, Sampler};
+
+    #[test]
+    fn test_perplexity() {
+        // Toy bigram model, each row holds the next token logits for a token
+        let table = [
+            [0.5f32.ln(), 0.25f32.ln(), 0.25f32.ln()],
+            // Logits are shift invariant
+            [0.25f32.ln() + 3., 0.25f32.ln() + 3., 0.5f32.ln() + 3.],
+            [0.125f32.ln(), 0.75f32.ln(), 0.125f32.ln()],
+        ];
+        let ppl = perplexity(&[0, 1, 2, 1], |t| table[t as usize].to_vec());
+        // p(1|0) = 0.25, p(2|1) = 0.5, p(1|2) = 0.75
+        let expected = (0.25f64 * 0.5 * 0.75).powf(-1. / 3.);
+        assert!((ppl - expected).abs() < 1e-4, "{ppl} != {expected}");
+    }
+
+    #[test]
+    fn test_beam_search_single_beam_is_greedy() {
+        // Toy model whose logits depend on the last token and the position, which is its only state
+        let model = |pos: &mut usize, token: i64| {
+            *pos += 1;
+            (0..8)
+                .map(|i| ((token * 7 + *pos as i64 * 3 + i) as f32).sin() * 4.)
+                .collect::<Vec<_>>()
+        };
+        let first_logits = model(&mut 0, 1);
+        let eos = 5;
+
+        let mut greedy = vec![];
+        let (mut pos, mut logits) = (1, first_logits.clone());
+        for _ in 0..10 {
+            let token = argmax(&logits);
+            greedy.push(token);
+            if token == eos {
+                break;
+            }
+            logits = model(&mut pos, token);
+        }
+
+        let beam = beam_search(1, first_logits, 1, 10, Some(eos), model);
+        assert_eq!(beam, greedy);
+    }
+
+    #[test]
+    fn test_sampler_cutoffs() {
+        // Probabilities 0.4, 0.3, 0.2, 0.1 in a shuffled order
+        let logits = [0.2f32.ln(), 0.4f32.ln(), 0.1f32.ln(), 0.3f32.ln()];
+        let sampler = |top_k, top_p| Sampler {
+            temperature: 1.,
+            top_k,
+            top_p,
+            repetition_penalty: 1.,
+            repetition_window: None,
+        };
+        let assert_dist = |dist: Vec<f64>, expected: [f64; 4]| {
+            for (p, e) in dist.iter().zip(expected) {
+                assert!((p - e).abs() < 1e-5, "{dist:?} != {expected:?}");
+            }
+        };
+        assert_dist(
+            sampler(None, None).distribution(&logits),
+            [0.2, 0.4, 0.1, 0.3],
+        );
+        // Top-k keeps the two most likely, renormalized
+        assert_dist(
+            sampler(Some(2), None).distribution(&logits),
+            [0., 0.4 / 0.7, 0., 0.3 / 0.7],
+        );
+        // Top-k past the vocab keeps everything
+        assert_dist(
+            sampler(Some(100), None).distribution(&logits),
+            [0.2, 0.4, 0.1, 0.3],
+        );
+        // 0.4 + 0.3 reaches 0.65
+        assert_dist(
+            sampler(None, Some(0.65)).distribution(&logits),
+            [0., 0.4 / 0.7, 0., 0.3 / 0.7],
+        );
+        // Top-p over the top 3, where 0.4 / 0.9 + 0.3 / 0.9 doesn't reach 0.8
+        assert_dist(
+            sampler(Some(3), Some(0.8)).distribution(&logits),
+            [0.2 / 0.9, 0.4 / 0.9, 0., 0.3 / 0.9],
+        );
+
+        // Samples only ever land in the kept set
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = [0; 4];
+        for _ in 0..2000 {
+            counts[sampler(Some(2), None).sample(&logits, &[], &mut rng) as usize] += 1;
+        }
+        assert_eq!((counts[0], counts[2]), (0, 0));
+        assert!(counts[1] > counts[3] && counts[3] > 0);
+
+        // Zero temperature is an argmax
+        let greedy = Sampler {
+            temperature: 0.,
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 1.,
+            repetition_window: None,
+        };
+        for _ in 0..10 {
+            assert_eq!(greedy.sample(&logits, &[], &mut rng), argmax(&logits));
+        }
+    }
+
+    #[test]
+    fn test_seeded_sampling_is_reproducible() {
+        let sampler = Sampler {
+            temperature: 0.8,
+            top_k: Some(6),
+            top_p: Some(0.9),
+            repetition_penalty: 1.2,
+            repetition_window: Some(4),
+        };
+        // Logits that depend on the last token, like a model would
+        let generate = |seed| {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            let mut tokens = vec![0];
+            for _ in 0..64 {
+                let last = *tokens.last().unwrap();
+                let logits = (0..10)
+                    .map(|i| ((last * 5 + i) as f32).sin())
+                    .collect::<Vec<_>>();
+                tokens.push(sampler.sample(&logits, &tokens, &mut rng));
+            }
+            tokens
+        };
+        assert_eq!(generate(42), generate(42));
+        assert_ne!(generate(42), generate(43));
+    }
+
+    #[test]
+    fn test_repetition_penalty() {
+        let logits = [2., -1., 3., 0.5, -3.];
+        let sampler = |repetition_window| Sampler {
+            temperature: 0.,
+            top_k: None,
+            top_p: None,
+            repetition_penalty: 2.,
+            repetition_window,
+        };
+        // Repeats are only penalized once, positive logits are halved and negative ones doubled
+        let history = [0, 1, 0, 2, 0];
+        assert_eq!(
+            sampler(None).penalize(&logits, &history),
+            [1., -2., 1.5, 0.5, -3.]
+        );
+        // The window only looks at the last two tokens
+        assert_eq!(
+            sampler(Some(2)).penalize(&logits, &history),
+            [1., -1., 1.5, 0.5, -3.]
+        );
+        // The penalty applies before picking a token, so the repeated 2 loses out to the fresh 0
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(sampler(None).sample(&logits, &[2], &mut rng), 0);
+    }
+
+    #[test]
+    fn test_generate_streams_tokens() {
+        // Toy model that always predicts the token after the last one
+        let next_logits = |token: i64| {
+            (0..6)
+                .map(|i| if i == (token + 1) % 6 { 1. } else { 0. })
+                .collect::<Vec<_>>()
+        };
+        let run = |max_tokens, eos| {
+            let mut tokens = vec![0];
+            let mut streamed = vec![];
+            let generated = generate(
+                &mut tokens,
+                next_logits(0),
+                max_tokens,
+                eos,
+                |logits, _| argmax(logits),
+                |token_id| format!("<{token_id}>"),
+                next_logits,
+                |token_id, text| streamed.push((token_id, text.to_string())),
+            );
+            assert_eq!(tokens[1..], generated);
+            (generated, streamed)
+        };
+        // Stops at the budget, streaming every token with its text
+        let (generated, streamed) = run(3, None);
+        assert_eq!(generated, [1, 2, 3]);
+        assert_eq!(
+            streamed,
+            [
+                (1, "<1>".to_string()),
+                (2, "<2>".to_string()),
+                (3, "<3>".to_string())
+            ]
+        );
+        // Stops after the EOS token
+        assert_eq!(run(10, Some(4)).0, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_generate_stops_at_eos() {
+        const EOS: i64 = 2;
+        let run = |eos| {
+            // A fake sampler that emits EOS on the third token
+            let mut fake_tokens = [5, 7, EOS, 9, 11].into_iter();
+            let mut steps = 0;
+            let generated = generate(
+                &mut vec![1],
+                vec![0.; 16],
+                5,
+                eos,
+                |_, _| fake_tokens.next().unwrap(),
+                |token_id| token_id.to_string(),
+                |_| {
+                    steps += 1;
+                    vec![0.; 16]
+                },
+                |_, _| {},
+            );
+            (generated, steps)
+        };
+        // Nothing more is run through the model once EOS comes out
+        assert_eq!(run(Some(EOS)), (vec![5, 7, EOS], 2));
+        // Ignoring EOS runs through the whole budget
+        assert_eq!(run(None), (vec![5, 7, EOS, 9, 11], 4));
+    }
+}
@@ -30,6 +30,10 @@ impl Compiler for MatMul2DCompiler {
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            // Strides can't express reversed dimensions
+            if srcs.iter().any(|(_, _, sh)| sh.is_reversed()) {
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
@@ -134,6 +138,9 @@ impl Compiler for BatchMatMul2DCompiler {
             let (mul, sum_reduce) = (s.get(&mul), s.get(&sum_reduce));
             // Insert MatMul2D op
             let mut srcs = graph.get_sources(mul);
+            if srcs.iter().any(|(_, _, sh)| sh.is_reversed()) {
+                continue;
+            }
             // Undo expansions and permute
             srcs[0].2.remove_dim(2);
             srcs[1].2.remove_dim(1);
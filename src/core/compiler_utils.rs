@@ -12,7 +12,7 @@ use itertools::Itertools;
 use petgraph::{
     algo::toposort,
     stable_graph::{EdgeIndex, EdgeReference, NodeIndex, StableGraph},
-    visit::EdgeRef,
+    visit::{EdgeRef, IntoEdgeReferences},
     Direction,
 };
 use regex::Regex;
@@ -558,6 +558,142 @@ pub fn move_incoming_edge<N, E: Clone>(
     }
 }
 
+/// An edge in a graph diff. Data edges carry their (input order, output order, shape), schedule edges carry nothing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DiffEdge {
+    pub source: NodeIndex,
+    pub target: NodeIndex,
+    pub data: Option<(u8, u8, ShapeTracker)>,
+}
+
+impl std::fmt::Display for DiffEdge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.source.index(), self.target.index())?;
+        match self.data {
+            Some((input, output, shape)) => write!(
+                f,
+                " (input {input}, output {output}, shape {:?})",
+                shape.shape()
+            ),
+            None => write!(f, " (schedule)"),
+        }
+    }
+}
+
+/// Changes between two versions of a graph, with nodes matched up by id
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphDiff {
+    /// Ids and op names of nodes only in the new graph
+    pub added_nodes: Vec<(NodeIndex, String)>,
+    /// Ids and op names of nodes only in the old graph
+    pub removed_nodes: Vec<(NodeIndex, String)>,
+    /// Nodes whose op changed, with the old and new op names
+    pub replaced_nodes: Vec<(NodeIndex, String, String)>,
+    pub added_edges: Vec<DiffEdge>,
+    pub removed_edges: Vec<DiffEdge>,
+    /// Data edges feeding the same input of the same node, but from a different source or with a different shape
+    pub rewired_edges: Vec<(DiffEdge, DiffEdge)>,
+}
+
+impl GraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl std::fmt::Display for GraphDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (id, op) in &self.added_nodes {
+            writeln!(f, "+ node {} {op}", id.index())?;
+        }
+        for (id, op) in &self.removed_nodes {
+            writeln!(f, "- node {} {op}", id.index())?;
+        }
+        for (id, old, new) in &self.replaced_nodes {
+            writeln!(f, "~ node {} {old} => {new}", id.index())?;
+        }
+        for edge in &self.added_edges {
+            writeln!(f, "+ edge {edge}")?;
+        }
+        for edge in &self.removed_edges {
+            writeln!(f, "- edge {edge}")?;
+        }
+        for (old, new) in &self.rewired_edges {
+            writeln!(f, "~ edge {old} => {new}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Diff two graphs, for instance the same graph built twice and compiled with and without a pass.
+/// Nodes are matched by id, so both graphs must have been built the same way.
+pub fn diff_graphs(before: &Graph, after: &Graph) -> GraphDiff {
+    let op_name = |graph: &Graph, id: NodeIndex| format!("{:?}", graph.node_weight(id).unwrap());
+    let mut diff = GraphDiff::default();
+    for id in before.node_indices().sorted() {
+        if after.contains_node(id) {
+            let (old, new) = (op_name(before, id), op_name(after, id));
+            if old != new {
+                diff.replaced_nodes.push((id, old, new));
+            }
+        } else {
+            diff.removed_nodes.push((id, op_name(before, id)));
+        }
+    }
+    for id in after.node_indices().sorted() {
+        if !before.contains_node(id) {
+            diff.added_nodes.push((id, op_name(after, id)));
+        }
+    }
+
+    // Data edges are keyed by the input they feed, schedule edges by their endpoints
+    let edges = |graph: &Graph| {
+        let (mut data, mut schedule) = (FxHashMap::default(), FxHashSet::default());
+        for e in graph.graph.edge_references() {
+            let edge = DiffEdge {
+                source: e.source(),
+                target: e.target(),
+                data: e.weight().as_data(),
+            };
+            match edge.data {
+                Some((input, _, _)) => {
+                    data.insert((e.target(), input), edge);
+                }
+                None => {
+                    schedule.insert((e.source(), e.target()));
+                }
+            }
+        }
+        (data, schedule)
+    };
+    let ((before_data, before_schedule), (after_data, after_schedule)) =
+        (edges(before), edges(after));
+    for (key, edge) in before_data.iter().sorted_by_key(|(k, _)| **k) {
+        match after_data.get(key) {
+            Some(new) if new != edge => diff.rewired_edges.push((*edge, *new)),
+            Some(_) => {}
+            None => diff.removed_edges.push(*edge),
+        }
+    }
+    for (key, edge) in after_data.iter().sorted_by_key(|(k, _)| **k) {
+        if !before_data.contains_key(key) {
+            diff.added_edges.push(*edge);
+        }
+    }
+    let schedule_edge = |(source, target): (NodeIndex, NodeIndex)| DiffEdge {
+        source,
+        target,
+        data: None,
+    };
+    for key in before_schedule.difference(&after_schedule).sorted() {
+        diff.removed_edges.push(schedule_edge(*key));
+    }
+    for key in after_schedule.difference(&before_schedule).sorted() {
+        diff.added_edges.push(schedule_edge(*key));
+    }
+    diff
+}
+
 pub trait TraitObjEq {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
@@ -34,6 +34,10 @@ pub struct Graph {
     pub no_delete: rustc_hash::FxHashSet<NodeIndex>,
     /// Tensors marked in this set need to be retrieved later (mostly for optimizers to insert copy back calls, the graph itself doesn't treat these differently)
     pub to_retrieve: rustc_hash::FxHashSet<NodeIndex>,
+    /// Tensors marked in this set are freed after each use and recomputed when a later consumer needs them
+    pub recomputable: rustc_hash::FxHashSet<NodeIndex>,
+    /// Maps tensors marked for retrieval before compilation to the nodes holding their data after compilation
+    pub retrieval_map: rustc_hash::FxHashMap<NodeIndex, NodeIndex>,
     /// A list of current node to run, source nodes, and view nodes to delete after execution.
     #[allow(clippy::type_complexity)]
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<((NodeIndex, u8), ShapeTracker)>)>>,
@@ -114,6 +118,14 @@ impl Graph {
         }
     }
 
+    /// Mark tensors to be freed after each use and recomputed from their sources when another consumer needs them,
+    /// trading compute for memory. Their sources are kept alive until the last recomputation.
+    pub fn mark_recomputable<T: ToIds>(&mut self, tensors: T) {
+        for id in tensors.to_ids() {
+            self.recomputable.insert(id);
+        }
+    }
+
     /// Set a tensor's data
     pub fn set_tensor(&mut self, id: NodeIndex, ind: u8, tensor: Tensor) {
         self.tensors.insert((id, ind), tensor);
@@ -144,10 +156,30 @@ impl Graph {
 
     /// Compile the graph using the given compiler
     pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) {
-        compiler.compile(self, remap);
+        // Follow retrieved tensors through the compiler's remapping
+        let originals = self.to_retrieve.iter().copied().collect_vec();
+        let mut remapped = originals.clone();
+        compiler.compile(self, (remap, remapped.iter_mut().collect_vec()));
+        let remapped = originals
+            .into_iter()
+            .zip(remapped)
+            .collect::<FxHashMap<_, _>>();
+        for id in self.retrieval_map.values_mut() {
+            if let Some(new_id) = remapped.get(id) {
+                *id = *new_id;
+            }
+        }
+        for (original, new_id) in remapped {
+            self.retrieval_map.entry(original).or_insert(new_id);
+        }
         self.toposort();
     }
 
+    /// Get the node holding a retrieved tensor's data after compilation
+    pub fn retrieved_id(&self, original: NodeIndex) -> Option<NodeIndex> {
+        self.retrieval_map.get(&original).copied()
+    }
+
     /// Refresh the internally sorted graph
     pub(crate) fn toposort(&mut self) {
         self.linearized_graph = Some(
@@ -201,6 +233,49 @@ impl Graph {
         );
     }
 
+    /// Consumer counts for an execution. Recomputable tensors read their sources again each time they're recomputed.
+    /// If only a `needed` set of nodes runs, consumers outside of it aren't counted.
+    fn remaining_consumers(
+        &self,
+        needed: Option<&FxHashSet<NodeIndex>>,
+    ) -> FxHashMap<(NodeIndex, u8), usize> {
+        let mut remaining_consumers = self.consumers_map.as_ref().unwrap().clone();
+        if let Some(needed) = needed {
+            for node in needed {
+                for (source, output) in self
+                    .graph
+                    .edges_directed(*node, Direction::Outgoing)
+                    .filter(|e| !needed.contains(&e.target()))
+                    .filter_map(|e| e.weight().as_data().map(|(_, o, _)| (e.source(), o)))
+                {
+                    *remaining_consumers.get_mut(&(source, output)).unwrap() -= 1;
+                }
+            }
+        }
+        let mut runs = FxHashMap::default();
+        for node in &self.recomputable {
+            if needed.map(|n| !n.contains(node)).unwrap_or_default() {
+                continue;
+            }
+            let reruns = node_runs(
+                &self.graph,
+                &self.no_delete,
+                &self.recomputable,
+                needed,
+                *node,
+                &mut runs,
+            ) - 1;
+            for source in self
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .filter_map(|e| e.weight().as_data().map(|(_, o, _)| (e.source(), o)))
+            {
+                *remaining_consumers.get_mut(&source).unwrap() += reruns;
+            }
+        }
+        remaining_consumers
+    }
+
     /// Clear any remaining tensors that may be around from old executions
     pub fn reset(&mut self) {
         self.tensors.retain(|(n, _), _| self.no_delete.contains(n));
@@ -212,7 +287,7 @@ impl Graph {
         if self.linearized_graph.is_none() {
             self.toposort();
         }
-        let mut remaining_consumers = self.consumers_map.as_ref().unwrap().clone();
+        let mut remaining_consumers = self.remaining_consumers(None);
         let mut dim_stack = Vec::new();
 
         for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
@@ -220,6 +295,17 @@ impl Graph {
                 continue;
             }
 
+            recompute_sources(
+                &mut self.graph,
+                &mut self.tensors,
+                &self.no_delete,
+                &self.recomputable,
+                &self.dyn_map,
+                &mut remaining_consumers,
+                &mut dim_stack,
+                src_ids,
+            );
+
             let mut srcs = Vec::new();
             get_source_tensors(
                 &self.no_delete,
@@ -244,6 +330,13 @@ impl Graph {
             for (source, _) in src_ids {
                 *remaining_consumers.get_mut(source).unwrap() -= 1;
             }
+            free_recomputable_sources(
+                &mut self.tensors,
+                &self.no_delete,
+                &self.recomputable,
+                &remaining_consumers,
+                src_ids,
+            );
         }
         self.reset();
     }
@@ -277,6 +370,76 @@ impl Graph {
         }
     }
 
+    /// Execute only the nodes needed to produce the output of `target`, leaving its tensor in the graph.
+    /// On device backends the tensor stays on the device. Their `execute_until` helpers insert a copy back to the host
+    /// for the run instead.
+    pub fn execute_until(&mut self, target: NodeIndex) {
+        if self.linearized_graph.is_none() {
+            self.toposort();
+        }
+        // Collect the target and everything it depends on
+        let mut needed = FxHashSet::default();
+        let mut stack = vec![target];
+        while let Some(node) = stack.pop() {
+            if needed.insert(node) {
+                stack.extend(self.graph.neighbors_directed(node, Direction::Incoming));
+            }
+        }
+
+        let mut remaining_consumers = self.remaining_consumers(Some(&needed));
+        let mut dim_stack = Vec::new();
+        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
+            if !needed.contains(node) || self.tensors.contains_key(&(*node, 0)) {
+                continue;
+            }
+
+            recompute_sources(
+                &mut self.graph,
+                &mut self.tensors,
+                &self.no_delete,
+                &self.recomputable,
+                &self.dyn_map,
+                &mut remaining_consumers,
+                &mut dim_stack,
+                src_ids,
+            );
+
+            let mut srcs = Vec::new();
+            get_source_tensors(
+                &self.no_delete,
+                &mut self.tensors,
+                src_ids,
+                &remaining_consumers,
+                &mut srcs,
+            );
+
+            // Substitute in the dyn dims
+            for (_, st) in srcs.iter_mut() {
+                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+            }
+
+            // Execute
+            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
+            for (i, tensor) in tensors.into_iter().enumerate() {
+                self.tensors.insert((*node, i as u8), tensor);
+            }
+
+            // Bookkeep remaining consumers
+            for (source, _) in src_ids {
+                *remaining_consumers.get_mut(source).unwrap() -= 1;
+            }
+            free_recomputable_sources(
+                &mut self.tensors,
+                &self.no_delete,
+                &self.recomputable,
+                &remaining_consumers,
+                src_ids,
+            );
+        }
+        self.tensors
+            .retain(|(n, _), _| *n == target || self.no_delete.contains(n));
+    }
+
     /// Execute the graph with debug prints
     pub fn execute_debug(&mut self) {
         // Track the number of views pointing to each tensor so we know when to clear
@@ -284,8 +447,7 @@ impl Graph {
             self.toposort();
         }
         let mut dim_stack = Vec::new();
-        let tensors_ptr = &mut self.tensors as *mut _;
-        let mut remaining_consumers = self.consumers_map.as_ref().unwrap().clone();
+        let mut remaining_consumers = self.remaining_consumers(None);
         let mut op_times = FxHashMap::default();
 
         println!(
@@ -302,10 +464,20 @@ impl Graph {
             let op_name = format!("{:?}", self.graph.node_weight(*node).unwrap());
             print!("{}", op_name.bold().bright_green());
 
+            recompute_sources(
+                &mut self.graph,
+                &mut self.tensors,
+                &self.no_delete,
+                &self.recomputable,
+                &self.dyn_map,
+                &mut remaining_consumers,
+                &mut dim_stack,
+                src_ids,
+            );
             let mut srcs = Vec::new();
             get_source_tensors(
                 &self.no_delete,
-                tensors_ptr,
+                &mut self.tensors,
                 src_ids,
                 &remaining_consumers,
                 &mut srcs,
@@ -363,6 +535,13 @@ impl Graph {
             for (source, _) in src_ids {
                 *remaining_consumers.get_mut(source).unwrap() -= 1;
             }
+            free_recomputable_sources(
+                &mut self.tensors,
+                &self.no_delete,
+                &self.recomputable,
+                &remaining_consumers,
+                src_ids,
+            );
         }
 
         // Print out total times
@@ -416,6 +595,131 @@ impl DerefMut for Graph {
     }
 }
 
+/// How many times a node runs in one execution. A recomputable tensor is computed again each time a consumer runs
+/// after the first, so it runs once per run of each of its consumers.
+fn node_runs(
+    graph: &MainGraph,
+    no_delete: &FxHashSet<NodeIndex>,
+    recomputable: &FxHashSet<NodeIndex>,
+    needed: Option<&FxHashSet<NodeIndex>>,
+    node: NodeIndex,
+    runs: &mut FxHashMap<NodeIndex, usize>,
+) -> usize {
+    if !recomputable.contains(&node) || no_delete.contains(&node) {
+        return 1;
+    }
+    if let Some(n) = runs.get(&node) {
+        return *n;
+    }
+    let n = graph
+        .edges_directed(node, Direction::Outgoing)
+        .filter(|e| !e.weight().is_schedule())
+        .map(|e| e.target())
+        .filter(|consumer| needed.map(|n| n.contains(consumer)).unwrap_or(true))
+        .unique()
+        .map(|consumer| node_runs(graph, no_delete, recomputable, needed, consumer, runs))
+        .sum::<usize>()
+        .max(1);
+    runs.insert(node, n);
+    n
+}
+
+/// Recompute the sources of a node that were freed after an earlier use
+#[allow(clippy::too_many_arguments)]
+fn recompute_sources(
+    graph: &mut MainGraph,
+    tensors: &mut FxHashMap<(NodeIndex, u8), Tensor>,
+    no_delete: &FxHashSet<NodeIndex>,
+    recomputable: &FxHashSet<NodeIndex>,
+    dyn_map: &FxHashMap<char, usize>,
+    remaining_consumers: &mut FxHashMap<(NodeIndex, u8), usize>,
+    dim_stack: &mut Vec<i64>,
+    src_ids: &[((NodeIndex, u8), ShapeTracker)],
+) {
+    for (source, _) in src_ids {
+        if recomputable.contains(&source.0) && !tensors.contains_key(source) {
+            recompute_tensor(
+                graph,
+                tensors,
+                no_delete,
+                recomputable,
+                dyn_map,
+                remaining_consumers,
+                dim_stack,
+                source.0,
+            );
+        }
+    }
+}
+
+/// Free recomputable sources of a node that just ran, until another consumer needs them
+fn free_recomputable_sources(
+    tensors: &mut FxHashMap<(NodeIndex, u8), Tensor>,
+    no_delete: &FxHashSet<NodeIndex>,
+    recomputable: &FxHashSet<NodeIndex>,
+    remaining_consumers: &FxHashMap<(NodeIndex, u8), usize>,
+    src_ids: &[((NodeIndex, u8), ShapeTracker)],
+) {
+    for (source, _) in src_ids {
+        if recomputable.contains(&source.0)
+            && !no_delete.contains(&source.0)
+            && remaining_consumers[source] > 0
+        {
+            tensors.remove(source);
+        }
+    }
+}
+
+/// Rerun a node to regenerate its freed outputs, first recomputing any of its sources that were freed too
+#[allow(clippy::too_many_arguments)]
+fn recompute_tensor(
+    graph: &mut MainGraph,
+    tensors: &mut FxHashMap<(NodeIndex, u8), Tensor>,
+    no_delete: &FxHashSet<NodeIndex>,
+    recomputable: &FxHashSet<NodeIndex>,
+    dyn_map: &FxHashMap<char, usize>,
+    remaining_consumers: &mut FxHashMap<(NodeIndex, u8), usize>,
+    dim_stack: &mut Vec<i64>,
+    node: NodeIndex,
+) {
+    let src_ids = graph
+        .edges_directed(node, Direction::Incoming)
+        .filter_map(|e| e.weight().as_data().map(|i| (e.source(), i)))
+        .sorted_by_key(|(_, (i, _, _))| *i)
+        .map(|(a, (_, b, c))| ((a, b), c))
+        .collect::<Vec<_>>();
+    recompute_sources(
+        graph,
+        tensors,
+        no_delete,
+        recomputable,
+        dyn_map,
+        remaining_consumers,
+        dim_stack,
+        &src_ids,
+    );
+
+    let mut srcs = Vec::new();
+    get_source_tensors(no_delete, tensors, &src_ids, remaining_consumers, &mut srcs);
+    for (_, st) in srcs.iter_mut() {
+        st.resolve_global_dyn_dims_stack(dyn_map, dim_stack);
+    }
+    let outputs = graph.node_weight_mut(node).unwrap().process(srcs);
+    for (i, tensor) in outputs.into_iter().enumerate() {
+        tensors.insert((node, i as u8), tensor);
+    }
+    for (source, _) in &src_ids {
+        *remaining_consumers.get_mut(source).unwrap() -= 1;
+    }
+    free_recomputable_sources(
+        tensors,
+        no_delete,
+        recomputable,
+        remaining_consumers,
+        &src_ids,
+    );
+}
+
 /// Get source tensor array for a node
 fn get_source_tensors(
     no_delete: &FxHashSet<NodeIndex>,
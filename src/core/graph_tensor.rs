@@ -426,6 +426,16 @@ impl<S: Shape> ToData<S, Vec<f32>> for Vec<f32> {
         self
     }
 }
+impl<S: Shape> ToData<S, Vec<i32>> for Vec<i32> {
+    fn to_data_vec(self) -> Vec<i32> {
+        self
+    }
+}
+impl<S: Shape> ToData<S, Vec<u32>> for Vec<u32> {
+    fn to_data_vec(self) -> Vec<u32> {
+        self
+    }
+}
 impl<const A: usize> ToData<(Const<A>,), Vec<f32>> for [f32; A] {
     fn to_data_vec(self) -> Vec<f32> {
         self.to_vec()
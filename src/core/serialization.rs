@@ -8,6 +8,7 @@ use safetensors::tensor::{Dtype, View};
 use safetensors::{SafeTensorError, SafeTensors};
 use std::borrow::Cow;
 use std::fs::File;
+use std::path::Path;
 
 use super::module::state_dict;
 
@@ -93,12 +94,56 @@ impl Loader for StateDictLoader {
 pub struct SafeTensorLoader {
     /// The paths to the safetensors file
     paths: Vec<String>,
+    /// Which file each tensor lives in, if we were given an index of a sharded checkpoint
+    weight_map: Option<FxHashMap<String, String>>,
 }
 
 impl SafeTensorLoader {
     pub fn new<S: ToString>(paths: &[S]) -> Self {
         Self {
             paths: paths.iter().map(|s| s.to_string()).collect(),
+            weight_map: None,
+        }
+    }
+
+    /// Load from a sharded checkpoint, given its `model.safetensors.index.json`. Shards are resolved relative to the index file.
+    pub fn from_index<P: AsRef<Path>>(index_path: P) -> Self {
+        let index_path = index_path.as_ref();
+        let index: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string(index_path)
+                .unwrap_or_else(|e| panic!("Couldn't read index {}: {e}", index_path.display())),
+        )
+        .unwrap_or_else(|e| panic!("Index {} isn't valid json: {e}", index_path.display()));
+        let Some(weight_map) = index.get("weight_map").and_then(|m| m.as_object()) else {
+            panic!("Index {} has no weight_map", index_path.display());
+        };
+        let dir = index_path.parent().unwrap_or(Path::new(""));
+        let weight_map = weight_map
+            .iter()
+            .map(|(name, shard)| {
+                let Some(shard) = shard.as_str() else {
+                    panic!("Shard for tensor \"{name}\" isn't a file name");
+                };
+                (name.clone(), dir.join(shard).to_string_lossy().to_string())
+            })
+            .collect::<FxHashMap<_, _>>();
+        let paths = weight_map
+            .values()
+            .cloned()
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        for path in &paths {
+            if !Path::new(path).exists() {
+                panic!(
+                    "Shard {path} listed in index {} doesn't exist",
+                    index_path.display()
+                );
+            }
+        }
+        Self {
+            paths,
+            weight_map: Some(weight_map),
         }
     }
 }
@@ -112,15 +157,22 @@ impl Loader for SafeTensorLoader {
                 .node_weight_mut(node_index)
                 .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
             {
-                let file_paths = self.paths.clone();
+                let tensor_name = weight_name.replace('/', ".");
+                // With an index we know exactly which shard to look in
+                let file_paths = match &self.weight_map {
+                    Some(weight_map) => match weight_map.get(&tensor_name) {
+                        Some(path) => vec![path.clone()],
+                        None => panic!("Tensor \"{tensor_name}\" not found in index"),
+                    },
+                    None => self.paths.clone(),
+                };
                 loading_node.1 = Box::new(move |_| {
                     for file_path in file_paths.iter() {
                         let file = File::open(file_path).unwrap();
                         let buffer = unsafe { MmapOptions::new().map(&file).unwrap() };
                         let safetensors = SafeTensors::deserialize(&buffer).unwrap();
 
-                        if let Ok(tensor_view) = safetensors.tensor(&weight_name.replace('/', "."))
-                        {
+                        if let Ok(tensor_view) = safetensors.tensor(&tensor_name) {
                             // Convert to fp32
                             let bytes = tensor_view.data().to_vec();
                             let data: Vec<f32> = match tensor_view.dtype() {
@@ -219,7 +271,11 @@ impl<'a> std::convert::From<safetensors::tensor::TensorView<'a>> for Tensor {
 mod tests {
     use rand::{thread_rng, Rng};
 
-    use crate::{nn::transformer::Transformer, prelude::*, tests::assert_close};
+    use crate::{
+        nn::transformer::Transformer,
+        prelude::*,
+        tests::{assert_close, assert_exact},
+    };
 
     use super::*;
 
@@ -253,4 +309,86 @@ mod tests {
 
         assert_close(&out1, &out2.data());
     }
+
+    struct Sharded {
+        a: GraphTensor<R1<2>>,
+        b: GraphTensor<R1<3>>,
+    }
+
+    impl SerializeModule for Sharded {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("a", self.a);
+            s.tensor("b", self.b);
+        }
+    }
+
+    /// Write a two shard checkpoint and its index into a fresh directory
+    fn write_sharded_checkpoint(weight_map: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("luminal_shards_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, name, data) in [
+            ("model-00001-of-00002.safetensors", "a", vec![1.0f32, 2.0]),
+            ("model-00002-of-00002.safetensors", "b", vec![3.0, 4.0, 5.0]),
+        ] {
+            let header = format!(
+                r#"{{"{name}":{{"dtype":"F32","shape":[{}],"data_offsets":[0,{}]}}}}"#,
+                data.len(),
+                data.len() * 4
+            );
+            let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+            bytes.extend(header.bytes());
+            bytes.extend(data.iter().flat_map(|f| f.to_le_bytes()));
+            std::fs::write(dir.join(file), bytes).unwrap();
+        }
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            format!(r#"{{"metadata":{{"total_size":20}},"weight_map":{weight_map}}}"#),
+        )
+        .unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_sharded_safetensors() {
+        let dir = write_sharded_checkpoint(
+            r#"{"a":"model-00001-of-00002.safetensors","b":"model-00002-of-00002.safetensors"}"#,
+        );
+        let mut cx = Graph::new();
+        let model = Sharded {
+            a: cx.named_tensor("a"),
+            b: cx.named_tensor("b"),
+        };
+        SafeTensorLoader::from_index(dir.join("model.safetensors.index.json"))
+            .load(&model, &mut cx);
+        let mut a = model.a.retrieve();
+        let mut b = model.b.retrieve();
+        cx.compile(CPUCompiler::default(), (&mut a, &mut b));
+        cx.execute();
+
+        assert_exact(&a.data(), &[1.0, 2.0]);
+        assert_exact(&b.data(), &[3.0, 4.0, 5.0]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't exist")]
+    fn test_sharded_safetensors_missing_shard() {
+        let dir = write_sharded_checkpoint(
+            r#"{"a":"model-00001-of-00002.safetensors","b":"model-00003-of-00002.safetensors"}"#,
+        );
+        SafeTensorLoader::from_index(dir.join("model.safetensors.index.json"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Tensor \"b\" not found in index")]
+    fn test_sharded_safetensors_missing_tensor() {
+        let dir = write_sharded_checkpoint(r#"{"a":"model-00001-of-00002.safetensors"}"#);
+        let mut cx = Graph::new();
+        let model = Sharded {
+            a: cx.named_tensor("a"),
+            b: cx.named_tensor("b"),
+        };
+        SafeTensorLoader::from_index(dir.join("model.safetensors.index.json"))
+            .load(&model, &mut cx);
+    }
 }
@@ -134,6 +134,29 @@ impl<S: ExpressionStorage> GenericExpression<S> {
         reduce_triples(self)
     }
 
+    /// Fold constant arithmetic and drop identities anywhere in the expression, including on operands that are
+    /// themselves expressions, which `minimize` leaves alone. Variables are taken to be non-negative, and below
+    /// their value in `bounds` if they have one, so comparisons, divisions and modulos whose result follows from
+    /// those ranges fold away too.
+    pub fn simplify(self, bounds: &FxHashMap<char, usize>) -> Self {
+        let mut stack = vec![];
+        for term in self.terms {
+            match term {
+                Term::Num(_) | Term::Var(_) => stack.push(Node::Leaf(term)),
+                _ => {
+                    let a = stack.pop().unwrap();
+                    let b = stack.pop().unwrap();
+                    stack.push(simplify_node(term, a, b, bounds));
+                }
+            }
+        }
+        let mut terms = S::default();
+        if let Some(node) = stack.pop() {
+            node.write(&mut terms);
+        }
+        GenericExpression { terms }
+    }
+
     /// Minimum
     pub fn min<E: Into<Self>>(self, rhs: E) -> Self {
         let mut rhs = rhs.into();
@@ -185,6 +208,148 @@ impl<S: ExpressionStorage> GenericExpression<S> {
     }
 }
 
+/// An expression as a tree. Operations hold their left operand (the top of the stack when evaluating) first.
+#[derive(Clone, PartialEq)]
+enum Node {
+    Leaf(Term),
+    Op(Term, Box<Node>, Box<Node>),
+}
+
+impl Node {
+    fn num(&self) -> Option<i64> {
+        match self {
+            Node::Leaf(Term::Num(n)) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn write<S: ExpressionStorage>(self, terms: &mut S) {
+        match self {
+            Node::Leaf(term) => terms.push(term),
+            Node::Op(term, a, b) => {
+                b.write(terms);
+                a.write(terms);
+                terms.push(term);
+            }
+        }
+    }
+
+    /// The range of values the node can take, inclusive, with `None` where it's unbounded
+    fn range(&self, bounds: &FxHashMap<char, usize>) -> (Option<i64>, Option<i64>) {
+        let (term, a, b) = match self {
+            Node::Leaf(Term::Num(n)) => return (Some(*n), Some(*n)),
+            Node::Leaf(Term::Var(c)) => {
+                return (Some(0), bounds.get(c).map(|b| *b as i64 - 1));
+            }
+            Node::Leaf(_) => unreachable!(),
+            Node::Op(term, a, b) => (term, a.range(bounds), b.range(bounds)),
+        };
+        let both = |x: Option<i64>, y: Option<i64>, f: fn(i64, i64) -> i64| Some(f(x?, y?));
+        let non_negative = |r: (Option<i64>, Option<i64>)| r.0.is_some_and(|l| l >= 0);
+        match term {
+            Term::Add => (
+                both(a.0, b.0, i64::saturating_add),
+                both(a.1, b.1, i64::saturating_add),
+            ),
+            Term::Sub => (
+                both(a.0, b.1, i64::saturating_sub),
+                both(a.1, b.0, i64::saturating_sub),
+            ),
+            Term::Mul if non_negative(a) && non_negative(b) => (
+                both(a.0, b.0, i64::saturating_mul),
+                both(a.1, b.1, i64::saturating_mul),
+            ),
+            Term::Div if non_negative(a) && b.0.is_some_and(|l| l >= 1) => (
+                Some(b.1.map_or(0, |h| a.0.unwrap() / h)),
+                both(a.1, b.0, |x, y| x / y),
+            ),
+            Term::Mod if non_negative(a) && b.0.is_some_and(|l| l >= 1) => (
+                Some(0),
+                match (a.1, b.1) {
+                    (Some(x), Some(y)) => Some(x.min(y - 1)),
+                    (x, y) => x.or(y.map(|y| y - 1)),
+                },
+            ),
+            Term::Min => (
+                both(a.0, b.0, |x, y| x.min(y)),
+                match (a.1, b.1) {
+                    (Some(x), Some(y)) => Some(x.min(y)),
+                    (x, y) => x.or(y),
+                },
+            ),
+            Term::Max => (
+                match (a.0, b.0) {
+                    (Some(x), Some(y)) => Some(x.max(y)),
+                    (x, y) => x.or(y),
+                },
+                both(a.1, b.1, |x, y| x.max(y)),
+            ),
+            Term::And | Term::Or | Term::Gte | Term::Lt => (Some(0), Some(1)),
+            _ => (None, None),
+        }
+    }
+}
+
+/// Simplify `a term b`, where both operands are already simplified
+fn simplify_node(term: Term, a: Node, b: Node, bounds: &FxHashMap<char, usize>) -> Node {
+    let (a_num, b_num) = (a.num(), b.num());
+    if let (Some(x), Some(y)) = (a_num, b_num) {
+        let folded = match term {
+            Term::Add => x.checked_add(y),
+            Term::Sub => x.checked_sub(y),
+            Term::Mul => x.checked_mul(y),
+            // Leave division by zero for the kernel
+            Term::Div | Term::Mod if y == 0 => None,
+            _ => Some(term.as_op().unwrap()(x, y)),
+        };
+        if let Some(n) = folded {
+            return Node::Leaf(Term::Num(n));
+        }
+    }
+    let (a_range, b_range) = (a.range(bounds), b.range(bounds));
+    // a is always at most b, always less than b, or always at least b
+    let below = a_range.1.zip(b_range.0).is_some_and(|(x, y)| x <= y);
+    let less = a_range.1.zip(b_range.0).is_some_and(|(x, y)| x < y);
+    let above = a_range.0.zip(b_range.1).is_some_and(|(x, y)| x >= y);
+    let boolean = |r: (Option<i64>, Option<i64>)| r.0 >= Some(0) && r.1.is_some_and(|h| h <= 1);
+    let zero = Node::Leaf(Term::Num(0));
+    let one = Node::Leaf(Term::Num(1));
+    match (term, a_num, b_num) {
+        (Term::Add, Some(0), _) => b,
+        (Term::Add | Term::Sub, _, Some(0)) => a,
+        (Term::Sub, ..) if a == b => zero,
+        (Term::Mul, Some(0), _) | (Term::Mul, _, Some(0)) => zero,
+        (Term::Mul, Some(1), _) => b,
+        (Term::Mul | Term::Div, _, Some(1)) => a,
+        (Term::Div | Term::Mod, Some(0), _) => zero,
+        (Term::Mod, _, Some(1)) => zero,
+        // Values known to be smaller than the divisor
+        (Term::Div, ..) if a_range.0 >= Some(0) && less => zero,
+        (Term::Mod, ..) if a_range.0 >= Some(0) && less => a,
+        // Unbounded slices don't limit anything
+        (Term::Min, _, Some(UNBOUNDED)) => a,
+        (Term::Min, Some(UNBOUNDED), _) => b,
+        (Term::Max, Some(UNBOUNDED), _) | (Term::Max, _, Some(UNBOUNDED)) => {
+            Node::Leaf(Term::Num(UNBOUNDED))
+        }
+        (Term::Min, ..) if below => a,
+        (Term::Min, ..) if above => b,
+        (Term::Max, ..) if above => a,
+        (Term::Max, ..) if below => b,
+        (Term::Gte, ..) if above => one,
+        (Term::Gte, ..) if less => zero,
+        (Term::Lt, ..) if less => one,
+        (Term::Lt, ..) if above => zero,
+        (Term::And, Some(0), _) | (Term::And, _, Some(0)) => zero,
+        (Term::And, Some(_), _) if boolean(b_range) => b,
+        (Term::And, _, Some(_)) if boolean(a_range) => a,
+        (Term::Or, Some(0), _) if boolean(b_range) => b,
+        (Term::Or, _, Some(0)) if boolean(a_range) => a,
+        (Term::Or, Some(n), _) | (Term::Or, _, Some(n)) if n != 0 => one,
+        _ => Node::Op(term, Box::new(a), Box::new(b)),
+    }
+}
+
 fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> GenericExpression<S> {
     fn get_triples<S: ExpressionStorage>(
         exp: &GenericExpression<S>,
@@ -249,10 +414,10 @@ fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> Gener
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
                 }
                 // Remove min(i, inf) and min(inf, i)
-                (Some(Term::Num(a)), Term::Min, _) if a == i32::MAX => {
+                (Some(Term::Num(a)), Term::Min, _) if a == UNBOUNDED => {
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(a_ind)]);
                 }
-                (_, Term::Min, Some(Term::Num(b))) if b == i32::MAX => {
+                (_, Term::Min, Some(Term::Num(b))) if b == UNBOUNDED => {
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
                 }
                 // Remove min(i, 0) and min(0, i)
@@ -270,10 +435,10 @@ fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> Gener
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(a_ind)]);
                 }
                 // Remove max(i, inf) and max(inf, i)
-                (_, Term::Max, Some(Term::Num(i))) if i == i32::MAX => {
+                (_, Term::Max, Some(Term::Num(i))) if i == UNBOUNDED => {
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(a_ind)]);
                 }
-                (Some(Term::Num(i)), Term::Max, _) if i == i32::MAX => {
+                (Some(Term::Num(i)), Term::Max, _) if i == UNBOUNDED => {
                     remove_terms(&mut expr.terms, &[op_ind, unwrap_cont!(b_ind)]);
                 }
                 // Remove i + 0, i - 0 and 0 + i
@@ -366,7 +531,7 @@ fn reduce_triples<S: ExpressionStorage>(mut expr: GenericExpression<S>) -> Gener
 }
 
 fn reduce_add_sub<S: ExpressionStorage>(expr: GenericExpression<S>) -> GenericExpression<S> {
-    let mut stack: Vec<FxHashMap<Term, i32>> = Vec::new();
+    let mut stack: Vec<FxHashMap<Term, i64>> = Vec::new();
 
     for term in expr.terms.clone() {
         match term {
@@ -442,12 +607,12 @@ fn reduce_add_sub<S: ExpressionStorage>(expr: GenericExpression<S>) -> GenericEx
     GenericExpression { terms: s }
 }
 
-fn negate(mut expr: FxHashMap<Term, i32>) -> FxHashMap<Term, i32> {
+fn negate(mut expr: FxHashMap<Term, i64>) -> FxHashMap<Term, i64> {
     expr.values_mut().for_each(|i| *i = -*i);
     expr
 }
 
-fn combine(expr: &mut FxHashMap<Term, i32>, other: FxHashMap<Term, i32>) {
+fn combine(expr: &mut FxHashMap<Term, i64>, other: FxHashMap<Term, i64>) {
     for (k, v) in other {
         if let Some(x) = expr.get_mut(&k) {
             *x += v;
@@ -471,7 +636,7 @@ where
         for term in &self.terms {
             match term {
                 Term::Num(n) => stack.push(*n),
-                Term::Var(_) => stack.push(value as i32),
+                Term::Var(_) => stack.push(value as i64),
                 _ => {
                     let a = stack.pop().unwrap();
                     let b = stack.pop().unwrap();
@@ -489,7 +654,7 @@ where
     pub fn exec_stack(
         &self,
         variables: &FxHashMap<char, usize>,
-        stack: &mut Vec<i32>,
+        stack: &mut Vec<i64>,
     ) -> Option<usize> {
         for term in &self.terms {
             match term {
@@ -498,7 +663,7 @@ where
                 {
                     #[allow(clippy::needless_borrow)]
                     if let Some(n) = variables.get(&c) {
-                        stack.push(*n as i32)
+                        stack.push(*n as i64)
                     } else {
                         return None;
                     }
@@ -534,9 +699,13 @@ where
 }
 
 /// A single term of a symbolic expression such as a variable, number or operation.
+/// The upper bound given to dimensions that aren't sliced
+pub const UNBOUNDED: i64 = i32::MAX as i64;
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Term {
-    Num(i32),
+    /// Numbers are 64-bit, so strides and sizes of tensors past 2^31 elements are exact
+    Num(i64),
     Var(char),
     Add,
     Sub,
@@ -578,7 +747,7 @@ impl Default for Term {
 }
 
 impl Term {
-    pub fn as_op(self) -> Option<fn(i32, i32) -> i32> {
+    pub fn as_op(self) -> Option<fn(i64, i64) -> i64> {
         match self {
             Term::Add => Some(std::ops::Add::add),
             Term::Sub => Some(std::ops::Sub::sub),
@@ -587,10 +756,10 @@ impl Term {
             Term::Mod => Some(std::ops::Rem::rem),
             Term::Max => Some(core::cmp::Ord::max),
             Term::Min => Some(core::cmp::Ord::min),
-            Term::And => Some(|a, b| (a != 0 && b != 0) as i32),
-            Term::Or => Some(|a, b| (a != 0 || b != 0) as i32),
-            Term::Gte => Some(|a, b| (a >= b) as i32),
-            Term::Lt => Some(|a, b| (a < b) as i32),
+            Term::And => Some(|a, b| (a != 0 && b != 0) as i64),
+            Term::Or => Some(|a, b| (a != 0 || b != 0) as i64),
+            Term::Gte => Some(|a, b| (a >= b) as i64),
+            Term::Lt => Some(|a, b| (a < b) as i64),
             _ => None,
         }
     }
@@ -618,25 +787,25 @@ impl<S: ExpressionStorage> From<&char> for GenericExpression<S> {
 
 impl<S: ExpressionStorage> From<usize> for GenericExpression<S> {
     fn from(value: usize) -> Self {
-        GenericExpression::from(Term::Num(value as i32))
+        GenericExpression::from(Term::Num(value as i64))
     }
 }
 
 impl<S: ExpressionStorage> From<&usize> for GenericExpression<S> {
     fn from(value: &usize) -> Self {
-        GenericExpression::from(Term::Num(*value as i32))
+        GenericExpression::from(Term::Num(*value as i64))
     }
 }
 
 impl<S: ExpressionStorage> From<i32> for GenericExpression<S> {
     fn from(value: i32) -> Self {
-        GenericExpression::from(value as usize)
+        GenericExpression::from(Term::Num(value as i64))
     }
 }
 
 impl<S: ExpressionStorage> From<&i32> for GenericExpression<S> {
     fn from(value: &i32) -> Self {
-        GenericExpression::from(*value as usize)
+        GenericExpression::from(Term::Num(*value as i64))
     }
 }
 
@@ -734,8 +903,9 @@ impl<S: ExpressionStorage, E: Into<Self>> BitOr<E> for GenericExpression<S> {
 
 impl<S: ExpressionStorage> std::iter::Product for GenericExpression<S> {
     fn product<I: Iterator<Item = GenericExpression<S>>>(mut iter: I) -> Self {
+        // The empty product is 1, so scalars have one element
         let Some(mut p) = iter.next() else {
-            return 0.into();
+            return 1.into();
         };
         for n in iter {
             p = p * n;
@@ -776,6 +946,83 @@ mod tests {
         assert_eq!(reduced_expr, 'a'.into());
     }
 
+    #[test]
+    #[allow(clippy::modulo_one)]
+    fn test_simplify() {
+        let z = BigExpression::from('z');
+        let bounds = [('z', 8)].into_iter().collect();
+        // Dimensions of size 1 and a modulo by the full size fold away
+        let expr = (z.clone() / 8 % 1) * 8 + z.clone() / 1 % 8;
+        assert_eq!(expr.simplify(&bounds), z.clone());
+        // Comparisons that hold over the whole range fold to constants
+        let expr = (z.clone() % 8).gte(0) & (z.clone() % 8).lt(8);
+        assert_eq!(expr.simplify(&FxHashMap::default()), 1.into());
+        // Constant arithmetic folds even inside compound expressions
+        let expr = BigExpression {
+            terms: vec![
+                Term::Num(3),
+                Term::Num(4),
+                Term::Mul,
+                Term::Var('z'),
+                Term::Num(0),
+                Term::Add,
+                Term::Add,
+            ],
+        };
+        assert_eq!(
+            expr.simplify(&FxHashMap::default()).terms,
+            vec![Term::Num(12), Term::Var('z'), Term::Add]
+        );
+    }
+
+    #[test]
+    fn test_simplify_matches_exec() {
+        use crate::prelude::ShapeTracker;
+        let mut permuted = ShapeTracker::new(&[1.into(), 4.into(), 3.into()]);
+        permuted.permute(&[2, 0, 1]);
+        let mut padded = ShapeTracker::new(&[3.into(), 5.into()]);
+        padded.pad(&[(1.into(), 2.into()), (0.into(), 3.into())]);
+        let mut sliced = ShapeTracker::new(&[6.into(), 'a'.into()]);
+        sliced.slice(&[(2.into(), 5.into()), (1.into(), i32::MAX.into())]);
+        let mut expanded = ShapeTracker::new(&[4.into(), 2.into()]);
+        expanded.expand(1, 3.into());
+        for shape in [permuted, padded, sliced, expanded] {
+            let dyn_map = [('a', 7)].into_iter().collect::<FxHashMap<_, _>>();
+            let n = shape.n_elements().exec(&dyn_map).unwrap();
+            let bounds = [('z', n)].into_iter().collect();
+            for expr in [shape.index_expression(), shape.valid_expression()] {
+                let simplified = expr.clone().simplify(&bounds);
+                for z in 0..n {
+                    let mut vars = dyn_map.clone();
+                    vars.insert('z', z);
+                    assert_eq!(simplified.exec(&vars), expr.exec(&vars), "{expr:?} at {z}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_large_shapes() {
+        use crate::prelude::ShapeTracker;
+        // A transposed view of 3 * 2^30 elements, with strides and a size past an i32
+        let (rows, cols) = (3usize, 1usize << 30);
+        let mut shape = ShapeTracker::new(&[rows.into(), cols.into()]);
+        shape.permute(&[1, 0]);
+        assert_eq!(shape.n_elements().to_usize(), Some(rows * cols));
+        let index = shape.index_expression();
+        for logical in [rows * cols - 1, rows * cols - 2, cols * 2 + 5] {
+            let vars = [('z', logical)].into_iter().collect();
+            assert_eq!(
+                index.exec(&vars),
+                Some((logical % rows) * cols + logical / rows)
+            );
+        }
+        assert_eq!(
+            (Expression::from(cols) * 4).simplify(&FxHashMap::default()),
+            Expression::from(cols * 4)
+        );
+    }
+
     #[test]
     fn test_substitution() {
         let main = Expression::from('x') - 255;
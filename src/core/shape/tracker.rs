@@ -1,7 +1,7 @@
 use rustc_hash::FxHashMap;
 use tinyvec::ArrayVec;
 
-use super::symbolic::{BigExpression, Expression};
+use super::symbolic::{BigExpression, Expression, UNBOUNDED};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ShapeTracker {
@@ -10,6 +10,7 @@ pub struct ShapeTracker {
     pub fake: ArrayVec<[bool; 6]>,
     pub slices: ArrayVec<[(Expression, Expression); 6]>,
     pub padding: ArrayVec<[(Expression, Expression); 6]>,
+    pub reversed: ArrayVec<[bool; 6]>,
 }
 
 impl ShapeTracker {
@@ -20,6 +21,7 @@ impl ShapeTracker {
             fake: Default::default(),
             slices: Default::default(),
             padding: Default::default(),
+            reversed: Default::default(),
         };
         for (i, d) in dims.iter().enumerate() {
             s.dims.push(*d);
@@ -27,6 +29,7 @@ impl ShapeTracker {
             s.fake.push(false);
             s.slices.push((0.into(), i32::MAX.into())); // Unset upper bound slices are i32::MAX
             s.padding.push((0.into(), 0.into()));
+            s.reversed.push(false);
         }
         s
     }
@@ -47,6 +50,7 @@ impl ShapeTracker {
         self.fake.push(false);
         self.slices.push((0.into(), i32::MAX.into()));
         self.padding.push((0.into(), 0.into()));
+        self.reversed.push(false);
     }
 
     /// Add fake dim along a certian axis
@@ -66,6 +70,7 @@ impl ShapeTracker {
         }
         self.slices.remove(index);
         self.padding.remove(index);
+        self.reversed.remove(index);
         self.dims.remove(index)
     }
 
@@ -123,24 +128,28 @@ impl ShapeTracker {
         let mut acc = BigExpression::from(1);
         let logical = BigExpression::from('z');
         // Loop through all dims in current order
-        for (sh, stride, padding, slice, fake) in self.indexes.into_iter().rev().map(|i| {
-            (
-                self.dims[i],
-                strides[i].clone(),
-                self.padding[i],
-                self.slices[i],
-                self.fake[i],
-            )
-        }) {
+        for (sh, stride, padding, slice, fake, reversed) in
+            self.indexes.into_iter().rev().map(|i| {
+                (
+                    self.dims[i],
+                    strides[i].clone(),
+                    self.padding[i],
+                    self.slices[i],
+                    self.fake[i],
+                    self.reversed[i],
+                )
+            })
+        {
             let logical_sh =
                 (BigExpression::from(sh) + padding.0 + padding.1).min(slice.1) - slice.0;
             if !fake {
                 let dim_ind = (logical.clone() / acc.clone()) % logical_sh.clone();
-                ret = ret
-                    + (dim_ind - padding.0
-                        + (BigExpression::from(slice.0)
-                            - BigExpression::from(padding.0).min(slice.0)))
-                        * stride;
+                let mut physical_ind = dim_ind - padding.0
+                    + (BigExpression::from(slice.0) - BigExpression::from(padding.0).min(slice.0));
+                if reversed {
+                    physical_ind = BigExpression::from(sh) - 1 - physical_ind;
+                }
+                ret = ret + physical_ind * stride;
             }
             acc = acc.clone() * logical_sh.clone();
         }
@@ -179,37 +188,25 @@ impl ShapeTracker {
 
     /// The number of elements in this tensor, including pads and slices
     pub fn n_elements(&self) -> BigExpression {
-        let r = self
-            .indexes
+        self.indexes
             .into_iter()
             .map(|i| (i, BigExpression::from(self.dims[i])))
             // Add pads
             .map(|(i, dim)| (i, dim + self.padding[i].0 + self.padding[i].1))
             // Slice
             .map(|(i, dim)| dim.min(self.slices[i].1) - self.slices[i].0)
-            .product();
-        if r == 0.into() {
-            1.into()
-        } else {
-            r
-        }
+            .product()
     }
 
     /// The number of elements in this tensor, not including pads and slices
     pub fn n_physical_elements(&self) -> BigExpression {
-        let r = self
-            .dims
+        self.dims
             .into_iter()
             // Filter out fake dimensions
             .enumerate()
             .filter(|(i, _)| !self.fake[*i])
             .map(|(_, i)| i.into())
-            .product();
-        if r == 0.into() {
-            1.into()
-        } else {
-            r
-        }
+            .product()
     }
 
     /// The number of dimensions
@@ -244,7 +241,9 @@ impl ShapeTracker {
 
     /// Check if contiguous
     pub fn is_contiguous(&self) -> bool {
-        self.indexes.iter().enumerate().all(|(a, b)| a == *b) && self.fake.iter().all(|i| !*i)
+        self.indexes.iter().enumerate().all(|(a, b)| a == *b)
+            && self.fake.iter().all(|i| !*i)
+            && !self.is_reversed()
     }
 
     /// Realize the true shape
@@ -267,6 +266,25 @@ impl ShapeTracker {
         }
     }
 
+    /// Reverse the order of elements along an axis. Padding and slices already applied are mirrored, so the
+    /// logical view is reversed as a whole
+    pub fn reverse(&mut self, axis: usize) {
+        let i = self.indexes[axis];
+        if self.fake[i] {
+            return;
+        }
+        let (pad_start, pad_end) = self.padding[i];
+        let padded = self.dims[i] + pad_start + pad_end;
+        let (start, end) = self.slices[i];
+        if start.to_usize().map(|n| n != 0).unwrap_or(true)
+            || end.to_usize().map(|n| n as i64 != UNBOUNDED).unwrap_or(true)
+        {
+            self.slices[i] = (padded - end.min(padded), padded - start);
+        }
+        self.padding[i] = (pad_end, pad_start);
+        self.reversed[i] = !self.reversed[i];
+    }
+
     /// Add padding
     pub fn pad(&mut self, padding: &[(Expression, Expression)]) {
         for (i, (s, e)) in padding.iter().enumerate() {
@@ -274,7 +292,7 @@ impl ShapeTracker {
                 && self.slices[self.indexes[i]]
                     .1
                     .to_usize()
-                    .map(|n| n as i32 != i32::MAX)
+                    .map(|n| n as i64 != UNBOUNDED)
                     .unwrap_or(true))
                 || (s.to_usize().map(|n| n != 0).unwrap_or(true)
                     && self.slices[self.indexes[i]]
@@ -299,7 +317,7 @@ impl ShapeTracker {
     pub fn resolve_global_dyn_dims_stack(
         &mut self,
         dyn_dim_map: &FxHashMap<char, usize>,
-        stack: &mut Vec<i32>,
+        stack: &mut Vec<i64>,
     ) {
         for d in self.dims.iter_mut() {
             *d = d.exec_stack(dyn_dim_map, stack).unwrap().into();
@@ -317,10 +335,14 @@ impl ShapeTracker {
     pub fn is_sliced(&self) -> bool {
         self.slices.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
-                || e.to_usize().map(|n| n as i32 != i32::MAX).unwrap_or(true)
+                || e.to_usize().map(|n| n as i64 != UNBOUNDED).unwrap_or(true)
         })
     }
 
+    pub fn is_reversed(&self) -> bool {
+        self.reversed.iter().any(|r| *r)
+    }
+
     pub fn is_padded(&self) -> bool {
         self.padding.iter().any(|(b, e)| {
             b.to_usize().map(|i| i != 0).unwrap_or(true)
@@ -45,7 +45,8 @@ impl<S: Shape> GraphTensor<S> {
 
     /// Dynamically reshape with annotations for the shape tracker
     pub fn dyn_reshape<N: Shape>(mut self, shape: Vec<Expression>) -> GraphTensor<N> {
-        if !self.shape.indexes.iter().enumerate().all(|(a, b)| a == *b) {
+        if !self.shape.indexes.iter().enumerate().all(|(a, b)| a == *b) || self.shape.is_reversed()
+        {
             // Insert contiguous call
             self = self.contiguous();
         }
@@ -185,6 +186,17 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// Reverse the order of elements along the given axes
+    pub fn reverse<Ax: Axes>(mut self) -> GraphTensor<S>
+    where
+        S: HasAxes<Ax>,
+    {
+        for axis in Ax::as_array() {
+            self.shape.reverse(axis);
+        }
+        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+    }
+
     pub fn concat_along<Dst: Shape, Ax: Axes<Array = [usize; 1]>, Rhs: Shape>(
         self,
         rhs: GraphTensor<Rhs>,
@@ -481,4 +493,47 @@ mod tests {
 
         assert_close(&c.data(), &d_c.as_vec());
     }
+
+    #[test]
+    fn test_reverse() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<3, 4>>()
+            .set((0..12).map(|i| i as f32).collect::<Vec<_>>());
+        let rev = a.reverse::<LAxis<1>>().contiguous().retrieve();
+        let both = a.reverse::<LAxes2<0, 1>>().contiguous().retrieve();
+        let sliced_rev = a
+            .slice((.., Expression::from(1)..Expression::from(3)))
+            .reverse::<LAxis<1>>()
+            .contiguous()
+            .retrieve();
+        let rev_sliced = a
+            .reverse::<LAxis<1>>()
+            .slice((.., ..Expression::from(1)))
+            .contiguous()
+            .retrieve();
+        let padded_rev = a
+            .pad::<R2<3, 6>, usize, usize>(&[(0, 0), (0, 2)])
+            .reverse::<LAxis<1>>()
+            .contiguous()
+            .retrieve();
+        cx.execute();
+
+        assert_exact(
+            &rev.data(),
+            &[3., 2., 1., 0., 7., 6., 5., 4., 11., 10., 9., 8.],
+        );
+        assert_exact(
+            &both.data(),
+            &[11., 10., 9., 8., 7., 6., 5., 4., 3., 2., 1., 0.],
+        );
+        assert_exact(&sliced_rev.data(), &[2., 1., 6., 5., 10., 9.]);
+        assert_exact(&rev_sliced.data(), &[3., 7., 11.]);
+        assert_exact(
+            &padded_rev.data(),
+            &[
+                0., 0., 3., 2., 1., 0., 0., 0., 7., 6., 5., 4., 0., 0., 11., 10., 9., 8.,
+            ],
+        );
+    }
 }
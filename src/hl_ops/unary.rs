@@ -183,6 +183,22 @@ impl<S: Shape> GraphTensor<S> {
         (self * 2.0).sigmoid() * 2.0 - 1.0
     }
 
+    /// The GELU activation function, using the tanh approximation
+    pub fn gelu(self) -> GraphTensor<S> {
+        let inner = (self + self * self * self * 0.044715) * (2. / std::f32::consts::PI).sqrt();
+        self * 0.5 * (inner.tanh() + 1.0)
+    }
+
+    /// The softplus activation function
+    pub fn softplus(self) -> GraphTensor<S> {
+        (self.exp() + 1.0).ln()
+    }
+
+    /// The mish activation function
+    pub fn mish(self) -> GraphTensor<S> {
+        self * self.softplus().tanh()
+    }
+
     /// The leaky relu activation function
     pub fn leaky_relu(self, neg_slope: f32) -> GraphTensor<S> {
         self.relu() - (self * -neg_slope).relu()
@@ -341,4 +357,36 @@ mod tests {
         let d_b = d_a.tanh();
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_gelu() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(4);
+        let a = cx
+            .tensor::<(Dyn<'a'>, Dyn<'b'>)>()
+            .set_dyn(a_data.clone(), &[2, 2]);
+        let b = a.gelu().retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<2>));
+        let d_b = d_a.fast_gelu();
+        assert_close(&b.data(), &d_b.as_vec());
+    }
+
+    #[test]
+    fn test_mish() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(4);
+        let a = cx
+            .tensor::<(Dyn<'a'>, Dyn<'b'>)>()
+            .set_dyn(a_data.clone(), &[2, 2]);
+        let b = a.mish().retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<2>));
+        let d_b = d_a.clone() * (d_a.exp() + 1.0).ln().tanh();
+        assert_close(&b.data(), &d_b.as_vec());
+    }
 }
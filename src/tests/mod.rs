@@ -70,6 +70,175 @@ fn test_shapes() {
     assert_exact(&b.data(), &[1., 3., 2., 4.]);
 }
 
+#[test]
+fn test_scalar_outputs() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let b = cx.tensor::<R0>().set(vec![4.0]);
+    let c = cx.tensor::<R0>().set(vec![6.0]);
+    let mut sum = (a.sum_reduce() * 2.0).retrieve();
+    let mut add = (b + c).retrieve();
+    let mut mean = a.mean_reduce().retrieve();
+
+    cx.compile(GenericCompiler::default(), (&mut sum, &mut add, &mut mean));
+    cx.execute();
+
+    assert_exact(&sum.data(), &[12.]);
+    assert_exact(&add.data(), &[10.]);
+    assert_exact(&mean.data(), &[2.]);
+}
+
+#[test]
+fn test_execute_until() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let b = cx.tensor::<R1<3>>().set(vec![4.0, 5.0, 6.0]);
+    let c = a + b;
+    let d = (c * a).exp2().retrieve();
+
+    cx.execute_until(c.id);
+
+    assert_exact(&c.data(), &[5., 7., 9.]);
+    assert!(cx.get_tensor_ref(d.id, 0).is_none());
+}
+
+#[test]
+fn test_execute_until_frees() {
+    let owned = std::rc::Rc::new(std::cell::Cell::new(false));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let b = cx.tensor::<R1<3>>().set(vec![4.0, 5.0, 6.0]);
+    let c = a + b;
+    let owned_ref = owned.clone();
+    let d = cx
+        .add_op(crate::op::Function(
+            "Owned".to_string(),
+            Box::new(move |inp| {
+                owned_ref.set(matches!(inp[0].0, crate::op::InputTensor::Owned(_)));
+                vec![inp[0].0.borrowed().clone()]
+            }),
+        ))
+        .input(c.id, 0, c.shape)
+        .finish();
+    let d = GraphTensor::<R1<3>>::from_id(d, c.shape, c.graph_ref);
+    let _e = (c * 2.0).retrieve();
+
+    cx.execute_until(d.id);
+
+    assert_exact(&d.data(), &[5., 7., 9.]);
+    // The other consumer of c doesn't run, so d is its last consumer and takes it
+    assert!(owned.get());
+    assert!(cx.get_tensor_ref(c.id, 0).is_none());
+}
+
+#[test]
+fn test_execute_until_recomputable() {
+    let runs = std::rc::Rc::new(std::cell::Cell::new(0));
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let runs_ref = runs.clone();
+    let b = cx
+        .add_op(crate::op::Function(
+            "Counted".to_string(),
+            Box::new(move |inp| {
+                runs_ref.set(runs_ref.get() + 1);
+                vec![inp[0].0.borrowed().clone()]
+            }),
+        ))
+        .input(a.id, 0, a.shape)
+        .finish();
+    let b = GraphTensor::<R1<3>>::from_id(b, a.shape, a.graph_ref);
+    let c = (b * 2.0 + 1.0).sin() * b;
+    let _d = (b.exp2() + c).retrieve();
+    cx.mark_recomputable(b);
+
+    cx.execute_until(c.id);
+
+    assert_close(
+        &c.data(),
+        &[1.0, 2.0, 3.0].map(|x: f32| (x * 2.0 + 1.0).sin() * x),
+    );
+    // b is rerun for its second use in c, but not for the consumer that isn't needed
+    assert_eq!(runs.get(), 2);
+}
+
+#[test]
+fn test_recomputable() {
+    let run = |recompute: bool| {
+        let runs = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+        let runs_ref = runs.clone();
+        let b = cx
+            .add_op(crate::op::Function(
+                "Counted".to_string(),
+                Box::new(move |inp| {
+                    runs_ref.set(runs_ref.get() + 1);
+                    vec![inp[0].0.borrowed().clone()]
+                }),
+            ))
+            .input(a.id, 0, a.shape)
+            .finish();
+        let b = GraphTensor::<R1<3>>::from_id(b, a.shape, a.graph_ref);
+        let c = ((b * 2.0 + 1.0).sin() * b).retrieve();
+        if recompute {
+            cx.mark_recomputable(b);
+        }
+        cx.execute();
+        (c.data(), runs.get())
+    };
+    let (reference, reference_runs) = run(false);
+    let (recomputed, recomputed_runs) = run(true);
+
+    assert_exact(&recomputed, &reference);
+    assert_eq!(reference_runs, 1);
+    // The first consumer frees b, so the counted op is rerun to recompute it for the second
+    assert_eq!(recomputed_runs, 2);
+}
+
+#[test]
+fn test_recomputable_chain() {
+    fn counted(
+        x: GraphTensor<R1<3>>,
+        runs: &std::rc::Rc<std::cell::Cell<usize>>,
+    ) -> GraphTensor<R1<3>> {
+        let runs = runs.clone();
+        let id = x
+            .graph()
+            .add_op(crate::op::Function(
+                "Counted".to_string(),
+                Box::new(move |inp| {
+                    runs.set(runs.get() + 1);
+                    vec![inp[0].0.borrowed().clone()]
+                }),
+            ))
+            .input(x.id, 0, x.shape)
+            .finish();
+        GraphTensor::from_id(id, x.shape, x.graph_ref)
+    }
+    let run = |recompute: bool| {
+        let (b_runs, c_runs) = Default::default();
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+        // A recomputable tensor whose source is also recomputable
+        let b = counted(a, &b_runs);
+        let c = counted(b, &c_runs);
+        let d = (c.sin() + c.exp2() + b).retrieve();
+        if recompute {
+            cx.mark_recomputable((b, c));
+        }
+        cx.execute();
+        (d.data(), b_runs.get(), c_runs.get())
+    };
+    let (reference, ..) = run(false);
+    let (recomputed, b_runs, c_runs) = run(true);
+
+    assert_exact(&recomputed, &reference);
+    // c is rerun for its second consumer, which needs b again, and the add needs b a third time
+    assert_eq!(c_runs, 2);
+    assert_eq!(b_runs, 3);
+}
+
 /// Ensure two arrays are nearly equal
 pub fn assert_close(a_vec: &[f32], b_vec: &[f32]) {
     assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
@@ -116,6 +285,51 @@ pub fn assert_exact<T: PartialEq + Debug>(a_vec: &[T], b_vec: &[T]) {
     }
 }
 
+/// Ensure two arrays are nearly equal, reporting mismatches as coordinates in the given row-major shape
+pub fn assert_close_shaped(a_vec: &[f32], b_vec: &[f32], shape: &[usize], tolerance: f32) {
+    assert_eq!(a_vec.len(), b_vec.len(), "Number of elements doesn't match");
+    assert_eq!(
+        a_vec.len(),
+        shape.iter().product::<usize>(),
+        "Number of elements doesn't match shape {shape:?}"
+    );
+    let coords = |mut i: usize| {
+        let mut coords = vec![0; shape.len()];
+        for (c, dim) in coords.iter_mut().zip(shape).rev() {
+            *c = i % dim;
+            i /= dim;
+        }
+        coords
+    };
+    let diffs = a_vec.iter().zip(b_vec).map(|(a, b)| (a - b).abs());
+    let Some(first) = diffs.clone().position(|d| d.is_nan() || d > tolerance) else {
+        return;
+    };
+    let (max_ind, max_diff) = diffs
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap();
+    panic!(
+        "differ at {:?}: got {}, expected {}, diff {} (max diff {max_diff} at {:?})",
+        coords(first),
+        a_vec[first],
+        b_vec[first],
+        (a_vec[first] - b_vec[first]).abs(),
+        coords(max_ind),
+    );
+}
+
+#[test]
+#[should_panic(expected = "differ at [1, 2]: got 6.5, expected 6, diff 0.5 (max diff 2 at [2, 0])")]
+fn test_assert_close_shaped() {
+    let expected = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+    let mut actual = expected.clone();
+    assert_close_shaped(&actual, &expected, &[3, 4], 1e-3);
+    actual[6] = 6.5;
+    actual[8] = 10.;
+    assert_close_shaped(&actual, &expected, &[3, 4], 1e-3);
+}
+
 pub fn random_vec(n: usize) -> Vec<f32> {
     let mut rng = thread_rng();
     (0..n).map(|_| rng.gen_range(-0.5..0.5)).collect()